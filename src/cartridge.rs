@@ -1,15 +1,72 @@
 use std::error::Error;
 
+use crate::system::save::SaveType;
+
+// Strings the SDK libraries for each save type leave in the ROM, the longer FLASH markers first
+const SAVE_TYPE_MARKERS: [(&[u8], SaveType); 5] = [
+    (b"EEPROM_V", SaveType::Eeprom),
+    (b"SRAM_V", SaveType::Sram),
+    (b"FLASH1M_V", SaveType::Flash128),
+    (b"FLASH512_V", SaveType::Flash64),
+    (b"FLASH_V", SaveType::Flash64),
+];
+
 pub struct CartridgeInfo {
     pub title: String,
+    pub save_type: SaveType,
 }
 
 impl CartridgeInfo {
-    pub fn parse(data: &[u8]) -> Result<CartridgeInfo, Box<dyn Error>> {
+    /// Parses the cartridge header and detects the save type, using `default_save_type` if the ROM has no marker
+    pub fn parse(data: &[u8], default_save_type: SaveType) -> Result<CartridgeInfo, Box<dyn Error>> {
         assert!(data.len() >= 4);
 
         Ok(CartridgeInfo {
             title: std::str::from_utf8(&data[0xA0..0xA0 + 12])?.to_string(),
+            save_type: detect_save_type(data).unwrap_or(default_save_type),
         })
     }
 }
+
+fn detect_save_type(data: &[u8]) -> Option<SaveType> {
+    // The markers are word aligned
+    (0..data.len())
+        .step_by(4)
+        .find_map(|offset| SAVE_TYPE_MARKERS.iter().find(|(marker, _)| data[offset..].starts_with(marker)).map(|&(_, save_type)| save_type))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rom_with(marker: &[u8], offset: usize) -> Vec<u8> {
+        let mut rom = vec![0; 0x400];
+        rom[offset..offset + marker.len()].copy_from_slice(marker);
+        rom
+    }
+
+    #[test]
+    fn test_detect_save_type() {
+        let cases: [(&[u8], SaveType); 5] = [
+            (b"EEPROM_V124", SaveType::Eeprom),
+            (b"SRAM_V113", SaveType::Sram),
+            (b"FLASH_V126", SaveType::Flash64),
+            (b"FLASH512_V131", SaveType::Flash64),
+            (b"FLASH1M_V103", SaveType::Flash128),
+        ];
+        for (marker, save_type) in cases {
+            let cartridge = CartridgeInfo::parse(&rom_with(marker, 0x200), SaveType::Sram).unwrap();
+            assert_eq!(cartridge.save_type, save_type, "{}", std::str::from_utf8(marker).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_detect_save_type_fallback() {
+        let cartridge = CartridgeInfo::parse(&vec![0; 0x400], SaveType::Flash128).unwrap();
+        assert_eq!(cartridge.save_type, SaveType::Flash128);
+
+        // Unaligned markers don't count
+        let cartridge = CartridgeInfo::parse(&rom_with(b"FLASH_V126", 0x201), SaveType::Sram).unwrap();
+        assert_eq!(cartridge.save_type, SaveType::Sram);
+    }
+}
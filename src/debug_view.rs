@@ -0,0 +1,153 @@
+use std::io::{self, stdout};
+
+use ratatui::{
+    backend::{Backend, ClearType, CrosstermBackend},
+    buffer::Buffer,
+    layout::{Constraint, Layout, Rect},
+    style::Stylize,
+    text::Line,
+    widgets::{Block, Paragraph, Widget},
+    Terminal, TerminalOptions, Viewport,
+};
+
+use crate::{
+    debugger::{format_disassembly, format_dump},
+    symbols::SymbolTable,
+    system::{
+        cpu::{REGISTER_PC, REGISTER_SP},
+        GbaSystem,
+    },
+};
+
+pub const VIEW_HEIGHT: u16 = 20;
+// Instructions shown before and after the pc
+const DISASSEMBLY_CONTEXT: u32 = 4;
+const MEMORY_LINES: u32 = 7;
+
+/// What the debugger view shows, captured from the system while stopped
+#[derive(Debug, PartialEq, Eq)]
+pub struct DebugView {
+    pub registers: [u32; 16],
+    pub status: String,
+    pub disassembly: Vec<String>,
+    // The line of the instruction at the pc
+    pub pc_line: usize,
+    pub memory: Vec<String>,
+}
+
+impl DebugView {
+    /// Captures the registers, the instructions around the pc and the memory at `memory_address`
+    pub fn capture(system: &GbaSystem, symbols: &SymbolTable, memory_address: u32) -> Self {
+        let GbaSystem { cpu, mem, .. } = system;
+        let thumb = cpu.get_thumb_state();
+        let len = if thumb { 2 } else { 4 };
+        let pc = cpu.get_r(REGISTER_PC);
+        let start = pc.saturating_sub(DISASSEMBLY_CONTEXT * len);
+        let disassembly = format_disassembly(mem, symbols, start, 2 * DISASSEMBLY_CONTEXT + 1, thumb);
+        let memory = format_dump(mem, memory_address & !0xF, MEMORY_LINES * 16);
+        DebugView {
+            registers: std::array::from_fn(|r| cpu.get_r(r as u8)),
+            status: cpu.format_status(),
+            disassembly: disassembly.lines().map(str::to_owned).collect(),
+            pc_line: ((pc - start) / len) as usize,
+            memory: memory.lines().map(str::to_owned).collect(),
+        }
+    }
+}
+
+impl Widget for &DebugView {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        let [top, memory] = Layout::vertical([Constraint::Length(2 * DISASSEMBLY_CONTEXT as u16 + 3), Constraint::Min(0)]).areas(area);
+        let [registers, disassembly] = Layout::horizontal([Constraint::Length(40), Constraint::Min(0)]).areas(top);
+
+        let mut lines: Vec<Line> = (0..8)
+            .map(|r| Line::raw(format!("r{:<2}: {:08X}   r{:<2}: {:08X}", r, self.registers[r], r + 8, self.registers[r + 8])))
+            .collect();
+        lines.push(Line::raw(self.status.as_str()));
+        Paragraph::new(lines).block(Block::bordered().title("Registers")).render(registers, buf);
+
+        let lines: Vec<Line> = self
+            .disassembly
+            .iter()
+            .enumerate()
+            .map(|(i, line)| if i == self.pc_line { Line::raw(line.as_str()).reversed() } else { Line::raw(line.as_str()) })
+            .collect();
+        Paragraph::new(lines).block(Block::bordered().title("Disassembly")).render(disassembly, buf);
+
+        let lines: Vec<Line> = self.memory.iter().map(|line| Line::raw(line.as_str())).collect();
+        Paragraph::new(lines).block(Block::bordered().title("Memory")).render(memory, buf);
+    }
+}
+
+/// Draws the view at the top of the terminal in place of the register dump of the plain debugger
+pub struct DebugTui {
+    // Shown in the memory pane, the stack when none was dumped yet
+    pub memory_address: Option<u32>,
+    last_view: Option<DebugView>,
+}
+
+impl DebugTui {
+    pub fn new() -> Self {
+        Self {
+            memory_address: None,
+            last_view: None,
+        }
+    }
+
+    /// Redraws the view when anything in it changed, the output of commands stays below it until then
+    pub fn draw(&mut self, system: &GbaSystem, symbols: &SymbolTable) -> io::Result<()> {
+        let memory_address = self.memory_address.unwrap_or(system.cpu.get_r(REGISTER_SP));
+        let view = DebugView::capture(system, symbols, memory_address);
+        if self.last_view.as_ref() == Some(&view) {
+            return Ok(());
+        }
+
+        let mut backend = CrosstermBackend::new(stdout());
+        let width = backend.size()?.width;
+        backend.clear_region(ClearType::All)?;
+        let viewport = Viewport::Fixed(Rect::new(0, 0, width, VIEW_HEIGHT));
+        let mut terminal = Terminal::with_options(backend, TerminalOptions { viewport })?;
+        terminal.draw(|frame| {
+            frame.render_widget(&view, frame.area());
+            // The prompt follows below the view
+            frame.set_cursor_position((0, VIEW_HEIGHT));
+        })?;
+        self.last_view = Some(view);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::system::memory;
+
+    #[test]
+    fn test_capture() {
+        let mut system = GbaSystem::new(vec![0; memory::BIOS_LEN], vec![0; 0x100]).0;
+        system.mem.write_u32(0x02_000_010, 0xE3A00001); // MOV R0, #1
+        system.mem.write_u32(0x02_000_100, 0x44434241);
+        system.cpu.set_r(REGISTER_PC, 0x02_000_010);
+        system.cpu.set_r(3, 0x1234_5678);
+
+        let view = DebugView::capture(&system, &SymbolTable::default(), 0x02_000_104);
+        assert_eq!(view.registers[3], 0x1234_5678);
+        assert_eq!(view.registers[15], 0x02_000_010);
+        assert_eq!(view.status, system.cpu.format_status());
+        assert_eq!(view.disassembly.len(), 9);
+        assert_eq!(view.pc_line, 4);
+        assert!(view.disassembly[4].starts_with("02000010: E3A00001"), "{}", view.disassembly[4]);
+        assert!(view.disassembly[4].ends_with("MOV R0, #0x1"), "{}", view.disassembly[4]);
+        // The memory pane starts at the line of the address
+        assert_eq!(view.memory.len(), 7);
+        assert!(view.memory[0].starts_with("02000100: 41 42 43 44"));
+        assert!(view.memory[0].ends_with("|ABCD............|"));
+
+        let mut buf = Buffer::empty(Rect::new(0, 0, 100, VIEW_HEIGHT));
+        (&view).render(buf.area, &mut buf);
+        let text: String = buf.content().iter().map(|cell| cell.symbol()).collect();
+        for title in ["Registers", "Disassembly", "Memory", "r3 : 12345678", "MOV R0, #0x1"] {
+            assert!(text.contains(title), "{}", title);
+        }
+    }
+}
@@ -1,9 +1,132 @@
-use crate::system::{cpu::CPU, memory::Memory};
+use std::{fs, path::Path, str::FromStr};
+
+use crate::{
+    bitutil::get_bit,
+    cartridge::CartridgeInfo,
+    disassembler::{disassemble_arm, disassemble_thumb, format_instruction_arm, format_instruction_thumb},
+    elf::{self, Elf},
+    frame_limiter::Unthrottled,
+    logging::{self, Level},
+    png,
+    symbols::{parse_symbol_file, SymbolTable},
+    system::{
+        cpu::{format_mode, CPU, MODE_ABT, MODE_FIQ, MODE_IRQ, MODE_SVC, MODE_SYS, MODE_UND, MODE_USR, REGISTER_LR, REGISTER_PC, REGISTER_SP},
+        memory::{Memory, WatchKind},
+        save::SaveType,
+        GbaSystem,
+    },
+};
+
+#[cfg(feature = "tui")]
+use crate::debug_view::DebugTui;
+
+/// Temporary breakpoint at the return address of a call that is being stepped over
+struct StepOver {
+    return_address: u32,
+    // A recursive call returns to the same address with a deeper stack
+    sp: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Operand {
+    Register(u8),
+    Constant(u32),
+}
+
+impl Operand {
+    fn parse(s: &str) -> Option<Operand> {
+        if let Some(r) = parse_register(s) {
+            return Some(Operand::Register(r));
+        }
+        let hex = s.strip_prefix("0x").unwrap_or(s);
+        u32::from_str_radix(hex, 16).ok().map(Operand::Constant)
+    }
+
+    fn evaluate(self, cpu: &CPU) -> u32 {
+        match self {
+            Operand::Register(r) => cpu.get_r(r),
+            Operand::Constant(value) => value,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    Greater,
+}
+
+/// Condition of a breakpoint, like `r0 == 0x10`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BreakCondition {
+    lhs: Operand,
+    comparison: Comparison,
+    rhs: Operand,
+}
+
+impl BreakCondition {
+    /// Parses `<lhs> <op> <rhs>` with registers or hex constants as operands and ==, !=, < or > as operator
+    pub fn parse(parts: &[&str]) -> Option<BreakCondition> {
+        let [lhs, comparison, rhs] = parts else {
+            return None;
+        };
+        let comparison = match *comparison {
+            "==" => Comparison::Equal,
+            "!=" => Comparison::NotEqual,
+            "<" => Comparison::Less,
+            ">" => Comparison::Greater,
+            _ => return None,
+        };
+        Some(BreakCondition {
+            lhs: Operand::parse(lhs)?,
+            comparison,
+            rhs: Operand::parse(rhs)?,
+        })
+    }
+
+    fn holds(&self, cpu: &CPU) -> bool {
+        let (lhs, rhs) = (self.lhs.evaluate(cpu), self.rhs.evaluate(cpu));
+        match self.comparison {
+            Comparison::Equal => lhs == rhs,
+            Comparison::NotEqual => lhs != rhs,
+            Comparison::Less => lhs < rhs,
+            Comparison::Greater => lhs > rhs,
+        }
+    }
+}
+
+/// Whether the emulator stops at the reset vector with the debugger prompt or boots straight into the game
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StartMode {
+    Paused,
+    Running,
+}
+
+impl FromStr for StartMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "paused" => Ok(StartMode::Paused),
+            "running" => Ok(StartMode::Running),
+            _ => Err(format!("Unknown start mode: {}", s)),
+        }
+    }
+}
 
 pub struct Debugger {
-    breakpoints: Vec<u32>,
+    breakpoints: Vec<(u32, Option<BreakCondition>)>,
     pub running: bool,
-    step_mode: bool,
+    // Instructions left to step before stopping, 0 when not stepping
+    steps_left: u32,
+    step_over: Option<StepOver>,
+    symbols: SymbolTable,
+    // Shared with the frame limiter
+    pub unthrottled: Unthrottled,
+    #[cfg(feature = "tui")]
+    view: Option<DebugTui>,
 }
 
 impl Debugger {
@@ -11,63 +134,335 @@ impl Debugger {
         Self {
             breakpoints: Vec::new(),
             running: false,
-            step_mode: false,
+            steps_left: 0,
+            step_over: None,
+            symbols: SymbolTable::default(),
+            unthrottled: Unthrottled::default(),
+            #[cfg(feature = "tui")]
+            view: None,
+        }
+    }
+
+    pub fn with_start_mode(start_mode: StartMode) -> Self {
+        Self {
+            running: start_mode == StartMode::Running,
+            ..Self::new()
+        }
+    }
+
+    /// Executes the next instruction, while stopped `read_command` is asked for a command first. Returns whether a frame was finished.
+    pub fn step(&mut self, system: &mut GbaSystem, read_command: impl FnOnce() -> String) -> bool {
+        let stopped = !self.running || self.should_break(&mut system.cpu, &system.mem);
+        // Print current instruction before executing it, while running only when tracing. The terminal view replaces this while stopped.
+        let drawn = stopped && self.draw_view(system);
+        if !drawn && (stopped || logging::enabled(Level::Trace, module_path!())) {
+            println!();
+            system.cpu.print_registers();
+            system.cpu.print_status();
+            println!("{:08X}: {:08X}", 0x03007E9C, system.mem.read_u32(0x03007E9C));
+            print_next_instruction(&system.cpu, &system.mem);
+        }
+
+        if stopped {
+            self.running = false;
+            self.handle_command(&read_command(), system);
+        }
+
+        self.running && system.step_instruction()
+    }
+
+    /// Shows registers, disassembly and memory in a terminal view while stopped, instead of printing them
+    #[cfg(feature = "tui")]
+    pub fn enable_view(&mut self) {
+        self.view = Some(DebugTui::new());
+    }
+
+    /// Draws the terminal view if it is enabled, returns whether it was drawn
+    #[cfg(feature = "tui")]
+    fn draw_view(&mut self, system: &GbaSystem) -> bool {
+        let Some(view) = &mut self.view else {
+            return false;
+        };
+        match view.draw(system, &self.symbols) {
+            Ok(()) => true,
+            Err(e) => {
+                println!("Failed to draw the debugger view, falling back to printing: {}", e);
+                self.view = None;
+                false
+            }
         }
     }
 
-    pub fn add_breakpoint(&mut self, address: u32) {
-        self.breakpoints.push(address);
+    #[cfg(not(feature = "tui"))]
+    fn draw_view(&mut self, _system: &GbaSystem) -> bool {
+        false
+    }
+
+    pub fn add_symbols(&mut self, symbols: impl IntoIterator<Item = (u32, String)>) {
+        self.symbols.extend(symbols);
+    }
+
+    /// Reads symbols from an ELF file or a text file of `address name` lines
+    fn load_symbols(&mut self, path: &str) -> Result<usize, Box<dyn std::error::Error>> {
+        let data = fs::read(path)?;
+        let symbols = if elf::is_elf(&data) {
+            Elf::parse(&data)?.symbols
+        } else {
+            parse_symbol_file(&String::from_utf8_lossy(&data))
+        };
+        let count = symbols.len();
+        self.add_symbols(symbols);
+        Ok(count)
     }
 
-    pub fn should_break(&self, cpu: &CPU) -> bool {
-        self.step_mode || self.breakpoints.contains(&cpu.get_r(15))
+    /// A symbol name or a hex address
+    fn parse_address(&self, s: &str) -> Option<u32> {
+        self.symbols.address_of(s).or_else(|| u32::from_str_radix(s, 16).ok())
     }
 
-    pub fn handle_command(&mut self, command: &str, cpu: &mut CPU, mem: &mut Memory) {
+    /// Breaks when the pc reaches `address` and the condition holds, if there is one
+    pub fn add_breakpoint(&mut self, address: u32, condition: Option<BreakCondition>) {
+        self.breakpoints.push((address, condition));
+    }
+
+    pub fn should_break(&mut self, cpu: &mut CPU, mem: &Memory) -> bool {
+        let should_break = if let Some(immediate) = cpu.take_debug_halt() {
+            println!("BKPT #{:X} before {}", immediate, self.symbols.format_address(cpu.get_r(REGISTER_PC)));
+            true
+        } else if let Some(hit) = mem.take_watch_hit() {
+            let access = match hit.kind {
+                WatchKind::Read => "Read",
+                WatchKind::Write => "Write",
+            };
+            println!("Watchpoint: {} {:02X} at {:08X}", access, hit.value, hit.address);
+            true
+        } else {
+            let returned = self
+                .step_over
+                .as_ref()
+                .is_some_and(|step_over| cpu.get_r(REGISTER_PC) == step_over.return_address && cpu.get_r(REGISTER_SP) >= step_over.sp);
+            let breakpoint_hit = self
+                .breakpoints
+                .iter()
+                .any(|(address, condition)| *address == cpu.get_r(REGISTER_PC) && condition.is_none_or(|condition| condition.holds(cpu)));
+            if breakpoint_hit {
+                println!("Breakpoint at {}", self.symbols.format_address(cpu.get_r(REGISTER_PC)));
+            }
+            let stepped = self.steps_left == 1;
+            self.steps_left = self.steps_left.saturating_sub(1);
+            stepped || returned || breakpoint_hit
+        };
+        // Stopping for any reason ends stepping and stepping over a call
+        if should_break {
+            self.steps_left = 0;
+            self.step_over = None;
+        }
+        should_break
+    }
+
+    pub fn handle_command(&mut self, command: &str, system: &mut GbaSystem) {
         let parts: Vec<&str> = command.trim().split_whitespace().collect();
+        let GbaSystem { cpu, mem, .. } = system;
         match parts.get(0).map(|s| *s) {
             Some("c") | Some("continue") => {
                 self.running = true;
-                self.step_mode = false;
+                self.steps_left = 0;
             }
-            Some("s") | Some("step") => {
-                if let Some(n) = parts.get(1).and_then(|s| s.parse::<u32>().ok()) {
-                    // Step n instructions
+            Some("s") | Some("step") => match parts.get(1).map_or(Some(1), |s| s.parse::<u32>().ok()) {
+                // Breakpoints and watchpoints are still checked after every instruction
+                Some(n) if n > 0 => {
                     self.running = true;
-                    self.step_mode = true;
-                    for _ in 0..n - 1 {
-                        cpu.cycle(mem);
+                    self.steps_left = n;
+                }
+                _ => println!("Usage: s/step [n], with n > 0"),
+            },
+            Some("n") | Some("next") | Some("so") => {
+                self.running = true;
+                match call_return_address(cpu, mem) {
+                    Some(return_address) => {
+                        self.steps_left = 0;
+                        self.step_over = Some(StepOver {
+                            return_address,
+                            sp: cpu.get_r(REGISTER_SP),
+                        });
                     }
-                } else {
-                    // Single step
-                    self.running = true;
-                    self.step_mode = true;
+                    None => self.steps_left = 1,
                 }
             }
             Some("b") | Some("break") => {
+                let address = parts.get(1).and_then(|s| self.parse_address(s));
+                let condition = match parts.get(2) {
+                    None => Some(None),
+                    Some(&"if") => BreakCondition::parse(&parts[3..]).map(Some),
+                    Some(_) => None,
+                };
+                match (address, condition) {
+                    (Some(address), Some(condition)) => {
+                        self.add_breakpoint(address, condition);
+                        println!("Breakpoint added at {}", self.symbols.format_address(address));
+                    }
+                    _ => println!("Usage: b/break <addr|symbol> [if <reg|value> <==|!=|<|>> <reg|value>]"),
+                }
+            }
+            Some(command @ ("watch" | "rwatch")) => {
                 if let Some(addr) = parts.get(1).and_then(|s| u32::from_str_radix(s, 16).ok()) {
-                    self.add_breakpoint(addr);
-                    println!("Breakpoint added at {:08X}", addr);
+                    let kind = if command == "watch" { WatchKind::Write } else { WatchKind::Read };
+                    mem.add_watchpoint(addr, kind);
+                    println!("Watchpoint added at {:08X}", addr);
                 }
             }
             Some("p") | Some("print") => {
                 cpu.print_registers();
                 cpu.print_status();
+                println!("PC: {}", self.symbols.format_address(cpu.get_r(REGISTER_PC)));
             }
+            Some("banks") => cpu.print_banked(),
             Some("q") | Some("quit") => {
+                mem.flush_save().expect("Failed to write save file");
                 std::process::exit(0);
             }
             Some("r") | Some("read") => {
                 if let Some(addr) = parts.get(1).and_then(|s| u32::from_str_radix(s, 16).ok()) {
-                    println!("{:08X}: {:08X}", addr, mem.read_u32(addr));
+                    match mem.try_read_u32(addr) {
+                        Ok(value) => println!("{:08X}: {:08X}", addr, value),
+                        Err(fault) => println!("{}", fault),
+                    }
+                }
+            }
+            Some("w") | Some("write") => match (parts.get(1).and_then(|s| u32::from_str_radix(s, 16).ok()), parts.get(2).and_then(|s| u32::from_str_radix(s, 16).ok())) {
+                (Some(addr), Some(value)) => {
+                    if let Err(fault) = mem.try_write_u32(addr, value) {
+                        println!("{}", fault);
+                    }
+                }
+                _ => println!("Usage: w/write <addr> <hexvalue>"),
+            },
+            Some("d") | Some("dump") => {
+                let address = parts.get(1).and_then(|s| u32::from_str_radix(s, 16).ok());
+                let len = parts.get(2).map_or(Some(0x100), |s| u32::from_str_radix(s, 16).ok());
+                match (address, len) {
+                    (Some(address), Some(len)) => {
+                        print!("{}", format_dump(mem, address, len));
+                        // The memory pane follows the last dump
+                        #[cfg(feature = "tui")]
+                        if let Some(view) = &mut self.view {
+                            view.memory_address = Some(address);
+                        }
+                    }
+                    _ => println!("Usage: d/dump <addr> [len]"),
+                }
+            }
+            Some("set") => match (parts.get(1), parts.get(2).and_then(|s| u32::from_str_radix(s, 16).ok())) {
+                (Some(register), Some(value)) => set_register(cpu, register, value),
+                _ => println!("Usage: set <reg> <hexvalue>"),
+            },
+            Some("u") | Some("disasm") => {
+                let address = parts.get(1).and_then(|s| self.parse_address(s));
+                let count = parts.get(2).map_or(Some(10), |s| s.parse::<u32>().ok());
+                let thumb = match parts.get(3) {
+                    None => Some(cpu.get_thumb_state()),
+                    Some(&"t") => Some(true),
+                    Some(&"a") => Some(false),
+                    Some(_) => None,
+                };
+                match (address, count, thumb) {
+                    (Some(address), Some(count), Some(thumb)) => print!("{}", format_disassembly(mem, &self.symbols, address, count, thumb)),
+                    _ => println!("Usage: u/disasm <addr|symbol> [count] [t|a]"),
                 }
             }
+            Some("loadsyms") => match parts.get(1) {
+                Some(path) => match self.load_symbols(path) {
+                    Ok(count) => println!("Loaded {} symbols from {}", count, path),
+                    Err(e) => println!("Failed to load symbols: {}", e),
+                },
+                None => println!("Usage: loadsyms <path>"),
+            },
+            Some("loadrom") => match parts.get(1) {
+                Some(path) => match load_rom(system, Path::new(path)) {
+                    Ok(title) => println!("Loaded {}", title),
+                    Err(e) => println!("Failed to load ROM: {}", e),
+                },
+                None => println!("Usage: loadrom <path>"),
+            },
+            Some("savestate") => match parts.get(1) {
+                Some(path) => match system.save_state(path) {
+                    Ok(()) => println!("State saved to {}", path),
+                    Err(e) => println!("Failed to save state: {}", e),
+                },
+                None => println!("Usage: savestate <path>"),
+            },
+            Some("loadstate") => match parts.get(1) {
+                Some(path) => match system.load_state(path) {
+                    Ok(()) => println!("State loaded from {}", path),
+                    Err(e) => println!("Failed to load state: {}", e),
+                },
+                None => println!("Usage: loadstate <path>"),
+            },
+            Some("trace") => match parts.get(1).map_or(Some(10), |s| s.parse::<usize>().ok()) {
+                Some(count) => print!("{}", format_trace(cpu, count)),
+                None => println!("Usage: trace [n]"),
+            },
+            Some("calls") => match parts.get(1) {
+                Some(&"on") => {
+                    cpu.set_branch_trace(true);
+                    println!("Recording branches");
+                }
+                Some(&"off") => cpu.set_branch_trace(false),
+                Some(path) => match cpu.branch_trace() {
+                    Some(branch_trace) => match fs::write(path, branch_trace.export_dot()) {
+                        Ok(()) => println!("Call graph saved to {}", path),
+                        Err(e) => println!("Failed to save call graph: {}", e),
+                    },
+                    None => println!("Branches aren't recorded, start with 'calls on'"),
+                },
+                None => match cpu.branch_trace() {
+                    Some(branch_trace) => {
+                        for (edge, count) in branch_trace.edges() {
+                            let kind = if edge.call { "call" } else { "branch" };
+                            println!("{} -> {} {} x{}", self.symbols.format_address(edge.source), self.symbols.format_address(edge.target), kind, count);
+                        }
+                    }
+                    None => println!("Usage: calls [on|off|path]"),
+                },
+            },
+            Some("reset") => {
+                system.reset();
+                println!("Reset");
+            }
+            Some("turbo") => println!("Turbo {}", if self.unthrottled.toggle() { "on" } else { "off" }),
+            Some("hash") => println!("Framebuffer hash: {:016X}", system.ppu.framebuffer_hash()),
+            Some("screenshot") => match parts.get(1) {
+                Some(path) => match system.ppu.with_framebuffer(|framebuffer| png::write_png(path, framebuffer)) {
+                    Ok(()) => println!("Screenshot saved to {}", path),
+                    Err(e) => println!("Failed to save screenshot: {}", e),
+                },
+                None => println!("Usage: screenshot <path>"),
+            },
             Some("h") | Some("help") => {
                 println!("Commands:");
                 println!("  c/continue - Continue execution");
                 println!("  s/step [n] - Step one or n instructions");
-                println!("  b/break <addr> - Set breakpoint at address");
+                println!("  n/next/so - Step over a call, otherwise step one instruction");
+                println!("  b/break <addr|symbol> [if <cond>] - Set breakpoint at address, only breaking if a condition like r0 == 0x10 holds");
+                println!("  watch <addr> - Break after a write to address");
+                println!("  rwatch <addr> - Break after a read from address");
                 println!("  p/print - Print CPU state");
+                println!("  banks - Print the banked SP, LR and SPSR of every mode");
+                println!("  trace [n] - Show the last n (default 10) executed instructions");
+                println!("  r/read <addr> - Read a word from memory");
+                println!("  w/write <addr> <value> - Write a word to memory");
+                println!("  d/dump <addr> [len] - Hex dump len (default 100) bytes from memory");
+                println!("  u/disasm <addr|symbol> [count] [t|a] - Disassemble count (default 10) instructions, in thumb or arm state if given");
+                println!("  set <reg> <value> - Set r0-r15, sp, lr, pc, cpsr or spsr");
+                println!("  calls [on|off|path] - Start or stop recording the taken branches, list them, or save the calls between them as a Graphviz graph");
+                println!("  reset - Reboot, keeping the cartridge and its save");
+                println!("  turbo - Toggle running as fast as possible instead of at the GBA's frame rate");
+                println!("  hash - Print a hash of the last frame, to compare against known good output");
+                println!("  loadsyms <path> - Load symbols from an ELF file or lines of <addr> <name>");
+                println!("  screenshot <path> - Save the last frame as a PNG");
+                println!("  loadrom <path> - Insert another cartridge and reboot, its save is read from the .sav file next to it");
+                println!("  savestate <path> - Save the machine state to a file");
+                println!("  loadstate <path> - Load the machine state from a file");
                 println!("  q/quit - Exit debugger");
                 println!("  h/help - Show this help");
             }
@@ -75,3 +470,439 @@ impl Debugger {
         }
     }
 }
+
+/// Swaps in the cartridge at `path` and returns its title
+fn load_rom(system: &mut GbaSystem, path: &Path) -> Result<String, Box<dyn std::error::Error>> {
+    let rom = fs::read(path)?;
+    let cartridge = CartridgeInfo::parse(&rom, SaveType::Sram)?;
+    system.insert_cartridge(rom, cartridge.save_type, Some(path.with_extension("sav")))?;
+    Ok(cartridge.title)
+}
+
+/// Returns where the next instruction returns to if it is a BL or BLX
+fn call_return_address(cpu: &CPU, mem: &Memory) -> Option<u32> {
+    let pc = cpu.get_r(REGISTER_PC);
+    let is_call = if cpu.get_thumb_state() {
+        let instruction = mem.read_u16(pc);
+        if instruction & 0xFF87 == 0x4780 {
+            // BLX Rm
+            return Some(pc + 2);
+        }
+        // BL and BLX with an offset are split into a prefix and suffix
+        instruction >> 11 == 0b11110 && matches!(mem.read_u16(pc + 2) >> 11, 0b11111 | 0b11101)
+    } else {
+        let instruction = mem.read_u32(pc);
+        // BL or BLX Rm, the unconditional instruction space isn't part of ARMv4T
+        instruction >> 28 != 0b1111 && (instruction & 0x0F000000 == 0x0B000000 || instruction & 0x0FFFFFF0 == 0x012FFF30)
+    };
+    is_call.then_some(pc + 4)
+}
+
+fn parse_register(name: &str) -> Option<u8> {
+    match name {
+        "sp" => Some(REGISTER_SP),
+        "lr" => Some(REGISTER_LR),
+        "pc" => Some(REGISTER_PC),
+        _ => name.strip_prefix('r')?.parse().ok().filter(|&r| r <= REGISTER_PC),
+    }
+}
+
+fn set_register(cpu: &mut CPU, name: &str, value: u32) {
+    match name.to_lowercase().as_str() {
+        "cpsr" => {
+            // Changing to an invalid mode would leave the cpu unusable
+            if ![MODE_USR, MODE_FIQ, MODE_IRQ, MODE_SVC, MODE_ABT, MODE_UND, MODE_SYS].contains(&(value as u8 & 0x1F)) {
+                println!("Invalid mode bits: {:05b}", value & 0x1F);
+                return;
+            }
+            cpu.cpsr = value;
+        }
+        "spsr" => {
+            if !cpu.current_mode_has_spsr() {
+                println!("{} mode has no SPSR", format_mode(cpu.get_mode()));
+                return;
+            }
+            cpu.set_spsr(value);
+        }
+        name => match parse_register(name) {
+            // Between instructions the pc holds the address of the next one, so this acts like a branch
+            Some(REGISTER_PC) => cpu.set_r(REGISTER_PC, value & !(cpu.instruction_len_in_bytes() - 1)),
+            Some(r) => cpu.set_r(r, value),
+            None => {
+                println!("Unknown register: {}", name);
+                return;
+            }
+        },
+    }
+    println!("{} = {:08X}", name, value);
+}
+
+/// Disassembles `count` instructions starting at `address`, stopping at unmapped memory
+pub fn format_disassembly(mem: &Memory, symbols: &SymbolTable, address: u32, count: u32, thumb: bool) -> String {
+    let mut output = String::new();
+    let len = if thumb { 2 } else { 4 };
+    let mut address = address & !(len - 1);
+    for _ in 0..count {
+        if !mem.is_mapped(address) || !mem.is_mapped(address + len - 1) {
+            output.push_str(&format!("{:08X}: unmapped\n", address));
+            break;
+        }
+        let line = if thumb {
+            let instruction = mem.read_u16(address);
+            // BL spans two instructions
+            let next_instruction = if mem.is_mapped(address + 2) { mem.read_u16(address + 2) } else { 0 };
+            format!(
+                "{}: {:04X}      {}",
+                symbols.format_address(address),
+                instruction,
+                disassemble_thumb(instruction, next_instruction, address)
+            )
+        } else {
+            let instruction = mem.read_u32(address);
+            format!("{}: {:08X}  {}", symbols.format_address(address), instruction, disassemble_arm(instruction, address))
+        };
+        output.push_str(&line);
+        output.push('\n');
+        address += len;
+    }
+    output
+}
+
+fn print_next_instruction(cpu: &CPU, mem: &Memory) {
+    let pc = cpu.get_r(REGISTER_PC);
+    if cpu.get_thumb_state() {
+        println!(
+            "Next thumb instruction at {:08X}: {}",
+            pc,
+            format_instruction_thumb(mem.fetch_u16(pc), mem.read_u16(pc.wrapping_add(2)), pc)
+        );
+    } else {
+        println!("Next arm instruction at {:08X}: {}", pc, format_instruction_arm(mem.fetch_u32(pc), pc));
+    }
+}
+
+/// Disassembles the last `count` executed instructions, oldest first
+fn format_trace(cpu: &CPU, count: usize) -> String {
+    let mut output = String::new();
+    for entry in cpu.trace().last(count) {
+        let line = if get_bit(entry.cpsr, 5) {
+            let (instruction, next_instruction) = (entry.instruction as u16, (entry.instruction >> 16) as u16);
+            format!("{:08X}: {:04X}      {}", entry.pc, instruction, disassemble_thumb(instruction, next_instruction, entry.pc))
+        } else {
+            format!("{:08X}: {:08X}  {}", entry.pc, entry.instruction, disassemble_arm(entry.instruction, entry.pc))
+        };
+        output.push_str(&format!("{:<48} CPSR: {:08X}\n", line, entry.cpsr));
+    }
+    output
+}
+
+/// Formats a hex and ASCII dump with 16 bytes per line, showing unmapped bytes as "--"
+pub fn format_dump(mem: &Memory, address: u32, len: u32) -> String {
+    let mut output = String::new();
+    let end = address.saturating_add(len);
+    for line in (address & !0xF..end).step_by(16) {
+        let mut hex = String::new();
+        let mut ascii = String::new();
+        for offset in 0..16 {
+            let byte_address = line + offset;
+            if offset == 8 {
+                hex.push(' ');
+            }
+            if !(address..end).contains(&byte_address) || !mem.is_mapped(byte_address) {
+                hex.push_str(if (address..end).contains(&byte_address) { "-- " } else { "   " });
+                ascii.push(' ');
+                continue;
+            }
+            let byte = mem.read_u8(byte_address);
+            hex.push_str(&format!("{:02X} ", byte));
+            ascii.push(if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' });
+        }
+        output.push_str(&format!("{:08X}: {} |{}|\n", line, hex, ascii));
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_disassembly() {
+        // Initializes the instruction LUT
+        CPU::new();
+        let mut bios = vec![0; 0x4000];
+        bios[0x3FF8..0x3FFC].copy_from_slice(&0xE3A00001u32.to_le_bytes()); // MOV R0, #1
+        bios[0x3FFC..0x4000].copy_from_slice(&0xF0000000u32.to_le_bytes());
+        let mem = Memory::new(bios, vec![0; 0x100]);
+
+        let disassembly = format_disassembly(&mem, &SymbolTable::default(), 0x3FF8, 4, false);
+        let lines: Vec<&str> = disassembly.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("00003FF8: E3A00001  MOV R0, "));
+        assert_eq!(lines[1], "00003FFC: F0000000  ???: F0000000");
+        assert_eq!(lines[2], "00004000: unmapped");
+
+        let disassembly = format_disassembly(&mem, &SymbolTable::default(), 0x3FFD, 2, true);
+        assert_eq!(disassembly, "00003FFC: 0000      MOVS R0, R0, LSL #0x0\n00003FFE: F000      ???: F000\n");
+    }
+
+    #[test]
+    fn test_symbols() {
+        let mut debugger = Debugger::new();
+        let mut system = GbaSystem::new(vec![0; 0x4000], vec![0; 0x100]).0;
+        let path = std::env::temp_dir().join(format!("gbae_test_{}.sym", std::process::id()));
+        fs::write(&path, "02000000 main\n02000008 loop\n").unwrap();
+        debugger.handle_command(&format!("loadsyms {}", path.display()), &mut system);
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(debugger.symbols.resolve(0x02_000_004).as_deref(), Some("main+0x4"));
+        assert!(format_disassembly(&system.mem, &debugger.symbols, 0x02_000_004, 1, false).starts_with("02000004 <main+0x4>: "));
+
+        // Breakpoints can be set by name
+        system.mem.write_u32(0x02_000_008, 0xEAFFFFFE); // B 02000008
+        system.cpu.set_r(REGISTER_PC, 0x02_000_000);
+        debugger.handle_command("b loop", &mut system);
+        debugger.handle_command("c", &mut system);
+        run_until_break(&mut debugger, &mut system);
+        assert_eq!(system.cpu.get_r(REGISTER_PC), 0x02_000_008);
+    }
+
+    #[test]
+    fn test_set_register() {
+        let mut debugger = Debugger::new();
+        let mut system = GbaSystem::new(vec![0; 0x4000], vec![0; 0x100]).0;
+
+        debugger.handle_command("set r3 DEADBEEF", &mut system);
+        assert_eq!(system.cpu.get_r(3), 0xDEADBEEF);
+        debugger.handle_command("set sp 3007F00", &mut system);
+        assert_eq!(system.cpu.get_r(REGISTER_SP), 0x03007F00);
+        debugger.handle_command("set pc 8000002", &mut system);
+        assert_eq!(system.cpu.get_r(REGISTER_PC), 0x08000000);
+        debugger.handle_command("set spsr 1F", &mut system);
+        assert_eq!(system.cpu.get_spsr(), 0x1F);
+
+        // Invalid input is rejected
+        debugger.handle_command("set r16 1", &mut system);
+        debugger.handle_command("set r1 xyz", &mut system);
+        assert_eq!(system.cpu.get_r(1), 0);
+        debugger.handle_command("set cpsr 0", &mut system);
+        assert_eq!(system.cpu.get_mode(), MODE_SVC);
+
+        debugger.handle_command("set cpsr 3F", &mut system);
+        assert_eq!(system.cpu.get_mode(), MODE_SYS);
+        assert!(system.cpu.get_thumb_state());
+        // Reported instead of panicking, as SYS mode has no SPSR
+        debugger.handle_command("set spsr 1F", &mut system);
+    }
+
+    /// Runs like the emulator loop until the debugger breaks
+    fn run_until_break(debugger: &mut Debugger, system: &mut GbaSystem) {
+        loop {
+            system.cpu.cycle(&mut system.mem);
+            if debugger.should_break(&mut system.cpu, &system.mem) {
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn test_start_paused() {
+        let mut system = GbaSystem::new(vec![0; 0x4000], vec![0; 0x100]).0;
+        let mut debugger = Debugger::with_start_mode(StartMode::Paused);
+        // Nothing is executed until a command resumes
+        for _ in 0..3 {
+            debugger.step(&mut system, || "p".to_owned());
+        }
+        assert_eq!(system.cpu.get_cycles(), 0);
+        assert_eq!(system.cpu.get_r(REGISTER_PC), 0x00_000_000);
+
+        debugger.step(&mut system, || "c".to_owned());
+        assert_eq!(system.cpu.get_r(REGISTER_PC), 0x00_000_004);
+        // Running doesn't ask for commands
+        debugger.step(&mut system, || unreachable!());
+        assert_eq!(system.cpu.get_r(REGISTER_PC), 0x00_000_008);
+
+        assert_eq!("running".parse(), Ok(StartMode::Running));
+        assert!(Debugger::with_start_mode(StartMode::Running).running);
+    }
+
+    #[test]
+    fn test_step_over() {
+        let mut debugger = Debugger::new();
+        let mut system = GbaSystem::new(vec![0; 0x4000], vec![0; 0x100]).0;
+        let program = [
+            0xE3A00003, // 02000000: MOV R0, #3
+            0xEB000000, // 02000004: BL 0200000C
+            0xEAFFFFFE, // 02000008: B 02000008
+            0xE92D4000, // 0200000C: STMFD SP!, {LR}
+            0xE2500001, // 02000010: SUBS R0, R0, #1
+            0x1BFFFFFC, // 02000014: BLNE 0200000C
+            0xE2811001, // 02000018: ADD R1, R1, #1
+            0xE8BD8000, // 0200001C: LDMFD SP!, {PC}
+        ];
+        for (i, instruction) in program.iter().enumerate() {
+            system.mem.write_u32(0x02_000_000 + i as u32 * 4, *instruction);
+        }
+        system.cpu.set_r(REGISTER_SP, 0x03_007_F00);
+        system.cpu.set_r(REGISTER_PC, 0x02_000_000);
+
+        // Not a call, so this steps a single instruction
+        debugger.handle_command("next", &mut system);
+        run_until_break(&mut debugger, &mut system);
+        assert_eq!(system.cpu.get_r(REGISTER_PC), 0x02_000_004);
+
+        debugger.handle_command("next", &mut system);
+        run_until_break(&mut debugger, &mut system);
+        assert_eq!(system.cpu.get_r(REGISTER_PC), 0x02_000_008);
+        assert_eq!(system.cpu.get_r(0), 0);
+        assert_eq!(system.cpu.get_r(1), 3);
+        assert!(debugger.step_over.is_none());
+
+        // Stepping over the recursive call skips the returns of the deeper calls to the same address
+        system.cpu.set_r(1, 0);
+        system.cpu.set_r(0, 3);
+        system.cpu.set_r(REGISTER_PC, 0x02_000_00C);
+        debugger.handle_command("s 2", &mut system);
+        run_until_break(&mut debugger, &mut system);
+        assert_eq!(system.cpu.get_r(REGISTER_PC), 0x02_000_014);
+        debugger.handle_command("so", &mut system);
+        run_until_break(&mut debugger, &mut system);
+        assert_eq!(system.cpu.get_r(REGISTER_PC), 0x02_000_018);
+        assert_eq!(system.cpu.get_r(1), 2);
+        assert_eq!(system.cpu.get_r(REGISTER_SP), 0x03_007_EFC);
+    }
+
+    #[test]
+    fn test_step_count() {
+        let mut debugger = Debugger::new();
+        let mut system = GbaSystem::new(vec![0; 0x4000], vec![0; 0x100]).0;
+        for i in 0..16 {
+            system.mem.write_u32(0x02_000_000 + i * 4, 0xE2800001); // ADD R0, R0, #1
+        }
+        system.cpu.set_r(REGISTER_PC, 0x02_000_000);
+
+        debugger.handle_command("s 0", &mut system);
+        assert!(!debugger.running);
+
+        debugger.handle_command("s 3", &mut system);
+        run_until_break(&mut debugger, &mut system);
+        assert_eq!(system.cpu.get_r(0), 3);
+
+        // Breakpoints stop stepping early
+        debugger.handle_command("b 2000014", &mut system);
+        debugger.handle_command("s 100", &mut system);
+        run_until_break(&mut debugger, &mut system);
+        assert_eq!(system.cpu.get_r(REGISTER_PC), 0x02_000_014);
+        assert_eq!(debugger.steps_left, 0);
+    }
+
+    #[test]
+    fn test_conditional_breakpoint() {
+        let mut debugger = Debugger::new();
+        let mut system = GbaSystem::new(vec![0; 0x4000], vec![0; 0x100]).0;
+        let program = [
+            0xE2800001, // 02000000: ADD R0, R0, #1
+            0xE3500020, // 02000004: CMP R0, #20
+            0x1AFFFFFC, // 02000008: BNE 02000000
+            0xEAFFFFFE, // 0200000C: B 0200000C
+        ];
+        for (i, instruction) in program.iter().enumerate() {
+            system.mem.write_u32(0x02_000_000 + i as u32 * 4, *instruction);
+        }
+        system.cpu.set_r(REGISTER_PC, 0x02_000_000);
+
+        debugger.handle_command("b 2000004 if r0 > 0x3", &mut system);
+        debugger.handle_command("b 2000000 if r0 == r1", &mut system);
+        debugger.handle_command("c", &mut system);
+        run_until_break(&mut debugger, &mut system);
+        assert_eq!(system.cpu.get_r(REGISTER_PC), 0x02_000_004);
+        assert_eq!(system.cpu.get_r(0), 4);
+
+        // Never holds inside the loop
+        debugger.breakpoints.clear();
+        debugger.handle_command("b 2000004 if 20 < r0", &mut system);
+        debugger.handle_command("b 200000C", &mut system);
+        system.cpu.set_r(1, 0x30);
+        debugger.handle_command("b 2000000 if r1 != 30", &mut system);
+        run_until_break(&mut debugger, &mut system);
+        assert_eq!(system.cpu.get_r(REGISTER_PC), 0x02_000_00C);
+        assert_eq!(system.cpu.get_r(0), 0x20);
+
+        // Invalid conditions aren't added
+        debugger.handle_command("b 2000000 if r0 >= 1", &mut system);
+        debugger.handle_command("b 2000000 if r0", &mut system);
+        debugger.handle_command("b 2000000 when r0 == 1", &mut system);
+        assert_eq!(debugger.breakpoints.len(), 3);
+    }
+
+    #[test]
+    fn test_bkpt() {
+        let mut debugger = Debugger::new();
+        let mut system = GbaSystem::new(vec![0; 0x4000], vec![0; 0x100]).0;
+        let program = [
+            0xE2800001, // 02000000: ADD R0, R0, #1
+            0xE1200070, // 02000004: BKPT #0
+            0xEAFFFFFC, // 02000008: B 02000000
+        ];
+        for (i, instruction) in program.iter().enumerate() {
+            system.mem.write_u32(0x02_000_000 + i as u32 * 4, *instruction);
+        }
+        system.cpu.set_r(REGISTER_PC, 0x02_000_000);
+
+        debugger.handle_command("c", &mut system);
+        run_until_break(&mut debugger, &mut system);
+        assert_eq!(system.cpu.get_r(REGISTER_PC), 0x02_000_008);
+        run_until_break(&mut debugger, &mut system);
+        assert_eq!(system.cpu.get_r(0), 2);
+    }
+
+    #[test]
+    fn test_trace() {
+        let mut system = GbaSystem::new(vec![0; 0x4000], vec![0; 0x100]).0;
+        system.cpu.set_trace_len(3);
+        let program = [
+            0xE3A00001, // 02000000: MOV R0, #1
+            0xE28F1005, // 02000004: ADD R1, PC, #5
+            0xE12FFF11, // 02000008: BX R1
+        ];
+        for (i, instruction) in program.iter().enumerate() {
+            system.mem.write_u32(0x02_000_000 + i as u32 * 4, *instruction);
+        }
+        system.mem.write_u16(0x02_000_010, 0x2002); // MOVS R0, #2
+        system.cpu.set_r(REGISTER_PC, 0x02_000_000);
+        for _ in 0..4 {
+            system.cpu.cycle(&mut system.mem);
+        }
+
+        let pcs: Vec<u32> = system.cpu.trace().last(10).map(|entry| entry.pc).collect();
+        assert_eq!(pcs, [0x02_000_004, 0x02_000_008, 0x02_000_010]);
+        let trace = format_trace(&system.cpu, 2);
+        let lines: Vec<&str> = trace.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("02000008: E12FFF11  BX R1"));
+        assert!(lines[0].ends_with("CPSR: 000000D3"));
+        assert!(lines[1].starts_with("02000010: 2002      MOVS R0, "));
+        assert!(lines[1].ends_with("CPSR: 000000F3"));
+    }
+
+    #[test]
+    fn test_format_dump() {
+        let mut mem = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+        mem.write_u32(0x02_000_000, u32::from_le_bytes(*b"GBA!"));
+        mem.write_u8(0x02_000_00F, 0xFF);
+        assert_eq!(
+            format_dump(&mem, 0x02_000_000, 0x10),
+            "02000000: 47 42 41 21 00 00 00 00  00 00 00 00 00 00 00 FF  |GBA!............|\n"
+        );
+
+        // Lines are aligned to 16 bytes
+        assert_eq!(format_dump(&mem, 0x02_000_002, 0x2), "02000000:       41 21                                       |  A!            |\n");
+
+        // Unmapped memory after the BIOS
+        assert_eq!(
+            format_dump(&mem, 0x00_003_FF8, 0x10),
+            "00003FF0:                          00 00 00 00 00 00 00 00  |        ........|\n\
+             00004000: -- -- -- -- -- -- -- --                           |                |\n"
+        );
+    }
+}
@@ -0,0 +1,87 @@
+use crate::{
+    bitutil::get_bit,
+    system::instructions::{
+        lut::{is_unconditional, InstructionLut},
+        Condition,
+    },
+};
+
+/// Disassembles the ARM instruction `word` at `address`, without needing a CPU or memory
+pub fn disassemble_arm(word: u32, address: u32) -> String {
+    // The unconditional instruction space isn't part of ARMv4T
+    if is_unconditional(word) {
+        return InstructionLut::decode_arm(word).disassemble(Condition::AL, address);
+    }
+    InstructionLut::decode_arm(word).disassemble(Condition::decode_arm(word), address)
+}
+
+/// Disassembles the Thumb instruction `halfword` at `address`, `next` is the halfword after it which completes a BL
+pub fn disassemble_thumb(halfword: u16, next: u16, address: u32) -> String {
+    InstructionLut::decode_thumb(halfword, next).disassemble(Condition::AL, address)
+}
+
+pub fn format_instruction_arm(instruction: u32, base_address: u32) -> String {
+    format!(
+        "{} ({:08X})\n\
+            Bit Index:   27 26 25 24 23 22 21 20   07 06 05 04\n\
+            Values:      {:<2} {:<2} {:<2} {:<2} {:<2} {:<2} {:<2} {:<4} {:<2} {:<2} {:<2} {:<2}",
+        disassemble_arm(instruction, base_address),
+        instruction,
+        get_bit(instruction, 27) as u32,
+        get_bit(instruction, 26) as u32,
+        get_bit(instruction, 25) as u32,
+        get_bit(instruction, 24) as u32,
+        get_bit(instruction, 23) as u32,
+        get_bit(instruction, 22) as u32,
+        get_bit(instruction, 21) as u32,
+        get_bit(instruction, 20) as u32,
+        get_bit(instruction, 7) as u32,
+        get_bit(instruction, 6) as u32,
+        get_bit(instruction, 5) as u32,
+        get_bit(instruction, 4) as u32,
+    )
+}
+
+pub fn format_instruction_thumb(instruction: u16, next_instruction: u16, base_address: u32) -> String {
+    format!(
+        "{} ({:04X}, next: {:04X})\n\
+            Bit Index:   15 14 13 12 11 10 09 08 07 06 05 04 03 02 01 00\n\
+            Values:      {:<2} {:<2} {:<2} {:<2} {:<2} {:<2} {:<2} {:<2} {:<2} {:<2} {:<2} {:<2} {:<2} {:<2} {:<2} {:<2}",
+        disassemble_thumb(instruction, next_instruction, base_address),
+        instruction,
+        next_instruction,
+        get_bit(instruction as u32, 15) as u32,
+        get_bit(instruction as u32, 14) as u32,
+        get_bit(instruction as u32, 13) as u32,
+        get_bit(instruction as u32, 12) as u32,
+        get_bit(instruction as u32, 11) as u32,
+        get_bit(instruction as u32, 10) as u32,
+        get_bit(instruction as u32, 9) as u32,
+        get_bit(instruction as u32, 8) as u32,
+        get_bit(instruction as u32, 7) as u32,
+        get_bit(instruction as u32, 6) as u32,
+        get_bit(instruction as u32, 5) as u32,
+        get_bit(instruction as u32, 4) as u32,
+        get_bit(instruction as u32, 3) as u32,
+        get_bit(instruction as u32, 2) as u32,
+        get_bit(instruction as u32, 1) as u32,
+        get_bit(instruction as u32, 0) as u32,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disassemble() {
+        assert_eq!(disassemble_arm(0xE1A01000, 0), "MOV R1, R0");
+        assert_eq!(disassemble_arm(0x0A000002, 0x08_000_000), "BEQ #08000010");
+        assert_eq!(disassemble_arm(0xE5910004, 0), "LDR R0, [R1, #+0x4]");
+        assert_eq!(disassemble_arm(0xF0000000, 0), "???: F0000000");
+
+        assert_eq!(disassemble_thumb(0x4248, 0, 0), "RSBS R0, R1, #0x0");
+        assert_eq!(disassemble_thumb(0xF000, 0xF802, 0x08_000_000), "BL #08000008");
+        assert_eq!(disassemble_thumb(0xBA08, 0, 0), "REV R0, R1");
+    }
+}
@@ -0,0 +1,235 @@
+use std::error::Error;
+
+use crate::system::{GbaSystem, Segment};
+
+const ELF_MAGIC: &[u8; 4] = b"\x7FELF";
+const ELFCLASS32: u8 = 1;
+const ELFDATA2LSB: u8 = 1;
+const EM_ARM: u16 = 40;
+const PT_LOAD: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+const SYMBOL_LEN: usize = 16;
+
+/// 32 bit little endian ARM ELF executable, as produced by homebrew toolchains
+pub struct Elf {
+    pub entry: u32,
+    pub segments: Vec<Segment>,
+    // Functions and variables, if the symbol table wasn't stripped
+    pub symbols: Vec<(u32, String)>,
+}
+
+pub fn is_elf(data: &[u8]) -> bool {
+    data.starts_with(ELF_MAGIC)
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16, Box<dyn Error>> {
+    Ok(u16::from_le_bytes(data.get(offset..offset + 2).ok_or("Unexpected end of ELF file")?.try_into()?))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, Box<dyn Error>> {
+    Ok(u32::from_le_bytes(data.get(offset..offset + 4).ok_or("Unexpected end of ELF file")?.try_into()?))
+}
+
+fn read_string(data: &[u8], offset: usize) -> Result<String, Box<dyn Error>> {
+    let bytes = data.get(offset..).ok_or("String outside of the ELF file")?;
+    let len = bytes.iter().position(|&b| b == 0).ok_or("Unterminated string")?;
+    Ok(String::from_utf8_lossy(&bytes[..len]).into_owned())
+}
+
+fn parse_symbols(data: &[u8]) -> Result<Vec<(u32, String)>, Box<dyn Error>> {
+    let section_headers = read_u32(data, 32)? as usize;
+    let section_header_len = read_u16(data, 46)? as usize;
+    let section_header_count = read_u16(data, 48)? as usize;
+    let section = |i: usize, field: usize| read_u32(data, section_headers + i * section_header_len + field);
+
+    let mut symbols = Vec::new();
+    for i in 0..section_header_count {
+        if section(i, 4)? != SHT_SYMTAB {
+            continue;
+        }
+        let (offset, len) = (section(i, 16)? as usize, section(i, 20)? as usize);
+        // The names are in the string table section linked to the symbol table
+        let strings = section(section(i, 24)? as usize, 16)? as usize;
+        for symbol in (offset..offset + len).step_by(SYMBOL_LEN) {
+            let info = *data.get(symbol + 12).ok_or("Symbol outside of the ELF file")?;
+            if !matches!(info & 0xF, STT_OBJECT | STT_FUNC) {
+                continue;
+            }
+            let name = read_string(data, strings + read_u32(data, symbol)? as usize)?;
+            // Thumb functions have bit 0 set
+            let address = read_u32(data, symbol + 4)? & if info & 0xF == STT_FUNC { !1 } else { !0 };
+            symbols.push((address, name));
+        }
+    }
+    Ok(symbols)
+}
+
+impl Elf {
+    pub fn parse(data: &[u8]) -> Result<Elf, Box<dyn Error>> {
+        if !is_elf(data) {
+            return Err("Not an ELF file".into());
+        }
+        if data.get(4) != Some(&ELFCLASS32) || data.get(5) != Some(&ELFDATA2LSB) || read_u16(data, 18)? != EM_ARM {
+            return Err("Only 32 bit little endian ARM ELF files are supported".into());
+        }
+
+        let entry = read_u32(data, 24)?;
+        let program_headers = read_u32(data, 28)? as usize;
+        let program_header_len = read_u16(data, 42)? as usize;
+        let program_header_count = read_u16(data, 44)? as usize;
+
+        let mut segments = Vec::new();
+        for i in 0..program_header_count {
+            let header = program_headers + i * program_header_len;
+            if read_u32(data, header)? != PT_LOAD {
+                continue;
+            }
+            let offset = read_u32(data, header + 4)? as usize;
+            let address = read_u32(data, header + 8)?;
+            let file_len = read_u32(data, header + 16)? as usize;
+            let memory_len = read_u32(data, header + 20)? as usize;
+
+            let mut segment = data.get(offset..offset + file_len).ok_or("Segment outside of the ELF file")?.to_vec();
+            segment.resize(memory_len.max(file_len), 0);
+            segments.push(Segment { address, data: segment });
+        }
+
+        Ok(Elf {
+            entry,
+            segments,
+            symbols: parse_symbols(data)?,
+        })
+    }
+
+    /// Copies the segments into memory and starts at the entry point instead of the cartridge, also after a reset.
+    /// Without a BIOS (`hle_bios`) its calls are emulated.
+    pub fn load(&self, system: &mut GbaSystem, hle_bios: bool) {
+        system.load_program(self.segments.clone(), self.entry, hle_bios);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::system::cpu::{MODE_SYS, REGISTER_PC};
+
+    /// ELF header and program headers for `segments` of (address, file data, memory length)
+    fn build_elf(entry: u32, segments: &[(u32, &[u8], u32)]) -> Vec<u8> {
+        let mut elf = vec![0; 52];
+        elf[..4].copy_from_slice(ELF_MAGIC);
+        elf[4] = ELFCLASS32;
+        elf[5] = ELFDATA2LSB;
+        elf[18..20].copy_from_slice(&EM_ARM.to_le_bytes());
+        elf[24..28].copy_from_slice(&entry.to_le_bytes());
+        elf[28..32].copy_from_slice(&52u32.to_le_bytes());
+        elf[42..44].copy_from_slice(&32u16.to_le_bytes());
+        elf[44..46].copy_from_slice(&(segments.len() as u16).to_le_bytes());
+
+        let mut offset = 52 + 32 * segments.len() as u32;
+        for &(address, data, memory_len) in segments {
+            for value in [PT_LOAD, offset, address, address, data.len() as u32, memory_len, 0b111, 4] {
+                elf.extend_from_slice(&value.to_le_bytes());
+            }
+            offset += data.len() as u32;
+        }
+        for (_, data, _) in segments {
+            elf.extend_from_slice(data);
+        }
+        elf
+    }
+
+    #[test]
+    fn test_load_elf() {
+        let data = build_elf(0x02_000_001, &[(0x02_000_000, &[1, 2, 3, 4], 8), (0x08_000_000, &[0xFF; 4], 4)]);
+        assert!(is_elf(&data));
+        let elf = Elf::parse(&data).unwrap();
+        assert_eq!(elf.segments.len(), 2);
+        assert!(elf.symbols.is_empty());
+
+        let mut system = GbaSystem::new(vec![0; 0x4000], vec![0; 0x100]).0;
+        system.mem.write_u32(0x02_000_004, 0xFFFFFFFF);
+        elf.load(&mut system, true);
+
+        assert_eq!(system.mem.read_u32(0x02_000_000), 0x04030201);
+        // The rest of the segment is zeroed
+        assert_eq!(system.mem.read_u32(0x02_000_004), 0);
+        // ROM segments are skipped
+        assert_eq!(system.mem.read_u32(0x08_000_000), 0);
+        assert!(system.cpu.get_thumb_state());
+        assert_eq!(system.cpu.get_r(REGISTER_PC), 0x02_000_000);
+        assert!(system.cpu.hle_bios.is_some());
+
+        // A reset loads the program again
+        system.mem.write_u32(0x02_000_000, 0);
+        system.cpu.set_r(REGISTER_PC, 0x02_000_010);
+        system.reset();
+        assert_eq!(system.mem.read_u32(0x02_000_000), 0x04030201);
+        assert_eq!(system.cpu.get_r(REGISTER_PC), 0x02_000_000);
+        assert!(system.cpu.hle_bios.is_some());
+    }
+
+    #[test]
+    fn test_load_elf_with_bios() {
+        // The last segment wraps around the end of the address space
+        let data = build_elf(0x02_000_000, &[(0x02_000_000, &[1, 2, 3, 4], 4), (0xFFFF_FFF0, &[0xFF; 0x20], 0x20)]);
+        let elf = Elf::parse(&data).unwrap();
+        let mut system = GbaSystem::new(vec![0; 0x4000], vec![0; 0x100]).0;
+        elf.load(&mut system, false);
+
+        // The BIOS that was given handles the BIOS calls
+        assert!(system.cpu.hle_bios.is_none());
+        assert_eq!(system.cpu.get_mode(), MODE_SYS);
+        assert_eq!(system.cpu.get_r(REGISTER_PC), 0x02_000_000);
+        system.reset();
+        assert!(system.cpu.hle_bios.is_none());
+        assert_eq!(system.cpu.get_r(REGISTER_PC), 0x02_000_000);
+        assert_eq!(system.mem.read_u32(0x02_000_000), 0x04030201);
+    }
+
+    #[test]
+    fn test_elf_symbols() {
+        let mut data = build_elf(0, &[]);
+        let strings = b"\0main\0counter\0file.c\0";
+        let symbols: [(u32, u32, u8); 4] = [(0, 0, 0), (1, 0x08_000_101, STT_FUNC), (6, 0x03_000_000, STT_OBJECT), (14, 0, 4)];
+
+        let strings_offset = data.len() as u32;
+        data.extend_from_slice(strings);
+        let symbols_offset = data.len() as u32;
+        for (name, value, info) in symbols {
+            for field in [name, value, 0] {
+                data.extend_from_slice(&field.to_le_bytes());
+            }
+            data.extend_from_slice(&[info, 0, 0, 0]);
+        }
+
+        // Null section, symbol table and string table
+        let section_headers = data.len() as u32;
+        let sections = [
+            [0; 10],
+            [0, SHT_SYMTAB, 0, 0, symbols_offset, 64, 2, 0, 4, 16],
+            [0, 3, 0, 0, strings_offset, strings.len() as u32, 0, 0, 1, 0],
+        ];
+        for field in sections.as_flattened() {
+            data.extend_from_slice(&field.to_le_bytes());
+        }
+        data[32..36].copy_from_slice(&section_headers.to_le_bytes());
+        data[46..48].copy_from_slice(&40u16.to_le_bytes());
+        data[48..50].copy_from_slice(&3u16.to_le_bytes());
+
+        let elf = Elf::parse(&data).unwrap();
+        assert_eq!(elf.symbols, [(0x08_000_100, "main".to_string()), (0x03_000_000, "counter".to_string())]);
+    }
+
+    #[test]
+    fn test_parse_invalid_elf() {
+        assert!(Elf::parse(&[0; 64]).is_err());
+        let mut data = build_elf(0, &[]);
+        data[4] = 2;
+        assert!(Elf::parse(&data).is_err());
+        // Truncated program headers
+        let data = build_elf(0, &[(0x02_000_000, &[1, 2, 3, 4], 4)]);
+        assert!(Elf::parse(&data[..60]).is_err());
+    }
+}
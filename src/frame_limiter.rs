@@ -0,0 +1,97 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+/// Turbo mode, shared so the display and the debugger can toggle it while the emulator runs
+#[derive(Debug, Clone, Default)]
+pub struct Unthrottled(Arc<AtomicBool>);
+
+impl Unthrottled {
+    pub fn get(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub fn set(&self, unthrottled: bool) {
+        self.0.store(unthrottled, Ordering::Relaxed);
+    }
+
+    /// Returns whether it's now unthrottled
+    pub fn toggle(&self) -> bool {
+        !self.0.fetch_xor(true, Ordering::Relaxed)
+    }
+}
+
+pub struct FrameLimiter {
+    frame_duration: Duration,
+    next_frame: Instant,
+    pub unthrottled: Unthrottled,
+}
+
+impl FrameLimiter {
+    pub fn new(frames_per_second: f64) -> Self {
+        Self {
+            frame_duration: Duration::from_secs_f64(1.0 / frames_per_second),
+            next_frame: Instant::now(),
+            unthrottled: Unthrottled::default(),
+        }
+    }
+
+    /// Returns how long to sleep to finish the current frame on time and schedules the next one
+    pub fn frame_delay(&mut self, now: Instant) -> Duration {
+        // Keeps next_frame current, so pacing picks up from now when turbo is turned off
+        if self.unthrottled.get() {
+            self.next_frame = now;
+            return Duration::ZERO;
+        }
+
+        self.next_frame += self.frame_duration;
+        if self.next_frame > now {
+            self.next_frame - now
+        } else {
+            // Don't try to catch up after falling behind, e.g. while paused in the debugger
+            self.next_frame = now;
+            Duration::ZERO
+        }
+    }
+
+    /// Sleeps until the end of the current frame
+    pub fn wait(&mut self) {
+        let delay = self.frame_delay(Instant::now());
+        if !delay.is_zero() {
+            sleep(delay);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frame_delay() {
+        let mut limiter = FrameLimiter::new(50.0);
+        let start = Instant::now();
+        limiter.next_frame = start;
+
+        assert_eq!(limiter.frame_delay(start), Duration::from_millis(20));
+        assert_eq!(limiter.frame_delay(start + Duration::from_millis(30)), Duration::from_millis(10));
+
+        // Falling behind drops the missed frames
+        assert_eq!(limiter.frame_delay(start + Duration::from_millis(100)), Duration::ZERO);
+        assert_eq!(limiter.frame_delay(start + Duration::from_millis(100)), Duration::from_millis(20));
+
+        let unthrottled = limiter.unthrottled.clone();
+        assert!(unthrottled.toggle());
+        assert_eq!(limiter.frame_delay(start + Duration::from_millis(100)), Duration::ZERO);
+        assert_eq!(limiter.frame_delay(start + Duration::from_millis(100)), Duration::ZERO);
+
+        // Turning turbo off paces from the current time again
+        assert!(!unthrottled.toggle());
+        assert_eq!(limiter.frame_delay(start + Duration::from_millis(100)), Duration::from_millis(20));
+    }
+}
@@ -0,0 +1,109 @@
+use crate::{
+    debugger::{Debugger, StartMode},
+    elf::Elf,
+    system::{apu::SAMPLE_RATE, bios, ppu::Framebuffer, GbaSystem},
+};
+
+/// Runs `frames` frames without a display and returns the last one with the audio of the whole run, without a BIOS the cartridge is started directly.
+/// With a `seed` the memory that is undefined at power on is filled with reproducible values.
+/// Started paused, the debugger reads its prompts from `commands` and the run ends early when they run out.
+pub fn run_headless(
+    bios: Option<Vec<u8>>,
+    rom: Vec<u8>,
+    elf: Option<&Elf>,
+    seed: Option<u64>,
+    frames: u32,
+    start_mode: StartMode,
+    commands: impl IntoIterator<Item = String>,
+) -> (Framebuffer, Vec<[i16; 2]>) {
+    let skip_bios = bios.is_none();
+    let (mut system, framebuffer) = GbaSystem::new(bios.unwrap_or_else(bios::hle_image), rom);
+    if let Some(seed) = seed {
+        system.mem.randomize(seed);
+    }
+    if let Some(elf) = elf {
+        elf.load(&mut system, skip_bios);
+    } else if skip_bios {
+        system.skip_bios();
+    }
+
+    // The sample buffer only holds a fraction of a second, so it's emptied every frame
+    let mut audio = Vec::new();
+    let mut chunk = [[0; 2]; SAMPLE_RATE as usize / 32];
+    let mut debugger = Debugger::with_start_mode(start_mode);
+    let mut commands = commands.into_iter();
+    let mut out_of_commands = false;
+    'frames: for _ in 0..frames {
+        if start_mode == StartMode::Running {
+            system.run_frame();
+        } else {
+            loop {
+                // Without commands left nothing more can resume, so the run ends
+                let finished = debugger.step(&mut system, || {
+                    commands.next().unwrap_or_else(|| {
+                        out_of_commands = true;
+                        String::new()
+                    })
+                });
+                if out_of_commands {
+                    break 'frames;
+                }
+                if finished {
+                    break;
+                }
+            }
+        }
+        let len = system.mem.audio_samples().pop_into(&mut chunk);
+        audio.extend_from_slice(&chunk[..len]);
+    }
+
+    let framebuffer = *framebuffer.read().unwrap();
+    (framebuffer, audio)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::system::ppu::hash_framebuffer;
+
+    #[test]
+    fn test_run_headless() {
+        let program: [u32; 6] = [
+            0xE3A00003, // MOV R0, #3
+            0xE2800B01, // ADD R0, R0, #400 (DISPCNT: mode 3, BG2)
+            0xE3A01301, // MOV R1, #04000000
+            0xE5810000, // STR R0, [R1]
+            0xE3A01406, // MOV R1, #06000000
+            0xE1C100B0, // STRH R0, [R1]
+        ];
+        let mut bios = vec![0; 0x4000];
+        for (i, instruction) in program.iter().chain(&[0xEAFFFFFE]).enumerate() {
+            bios[i * 4..i * 4 + 4].copy_from_slice(&instruction.to_le_bytes());
+        }
+
+        let (framebuffer, audio) = run_headless(Some(bios.clone()), vec![0; 0x100], None, None, 2, StartMode::Running, []);
+        // 0x0403 is red 3 and blue 1
+        assert_eq!(framebuffer[0][0], [24, 0, 8]);
+        assert_eq!(framebuffer[0][1], [0, 0, 0]);
+        // Sound is off, but samples are still output
+        assert!(!audio.is_empty() && audio.iter().all(|&sample| sample == [0, 0]));
+
+        // Runs are deterministic
+        assert_eq!(
+            hash_framebuffer(&framebuffer),
+            hash_framebuffer(&run_headless(Some(bios.clone()), vec![0; 0x100], None, None, 2, StartMode::Running, []).0)
+        );
+
+        // Started paused, nothing is executed until a command resumes
+        let commands = ["p", "p", "p"].map(str::to_owned);
+        let (paused_framebuffer, paused_audio) = run_headless(Some(bios.clone()), vec![0; 0x100], None, None, 2, StartMode::Paused, commands);
+        let untouched = run_headless(Some(bios.clone()), vec![0; 0x100], None, None, 0, StartMode::Running, []).0;
+        assert_eq!(hash_framebuffer(&paused_framebuffer), hash_framebuffer(&untouched));
+        assert!(paused_audio.is_empty());
+
+        let commands = ["p", "p", "c"].map(str::to_owned);
+        let (paused_framebuffer, paused_audio) = run_headless(Some(bios), vec![0; 0x100], None, None, 2, StartMode::Paused, commands);
+        assert_eq!(hash_framebuffer(&paused_framebuffer), hash_framebuffer(&framebuffer));
+        assert_eq!(paused_audio, audio);
+    }
+}
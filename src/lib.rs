@@ -0,0 +1,7 @@
+#[macro_use]
+pub mod logging;
+
+pub mod bitutil;
+pub mod disassembler;
+pub mod frame_limiter;
+pub mod system;
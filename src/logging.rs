@@ -0,0 +1,155 @@
+use std::{
+    fmt,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        RwLock,
+    },
+};
+
+/// How much is logged, each level includes the ones before it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Trace,
+}
+
+impl FromStr for Level {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(Level::Off),
+            "error" => Ok(Level::Error),
+            "warn" => Ok(Level::Warn),
+            "info" => Ok(Level::Info),
+            "trace" => Ok(Level::Trace),
+            _ => Err(format!("Unknown log level: {}", s)),
+        }
+    }
+}
+
+// The highest level enabled anywhere, which lets disabled messages be skipped without taking the lock
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(Level::Warn as u8);
+// The default level and the levels of modules given with `set_module_level`
+static LEVELS: RwLock<(Level, Vec<(String, Level)>)> = RwLock::new((Level::Warn, Vec::new()));
+
+/// Sets the level of all modules without a level of their own, warnings are logged by default
+pub fn set_level(level: Level) {
+    LEVELS.write().unwrap().0 = level;
+    update_max_level();
+}
+
+/// Sets the level of the modules starting with `module`, like `system::cpu`
+pub fn set_module_level(module: &str, level: Level) {
+    let mut levels = LEVELS.write().unwrap();
+    levels.1.retain(|(other, _)| other != module);
+    levels.1.push((module.to_owned(), level));
+    drop(levels);
+    update_max_level();
+}
+
+fn update_max_level() {
+    let levels = LEVELS.read().unwrap();
+    let max = levels.1.iter().map(|&(_, level)| level).fold(levels.0, Level::max);
+    MAX_LEVEL.store(max as u8, Ordering::Relaxed);
+}
+
+/// Applies a `--log` argument, either a level for all modules or `<module>=<level>`
+pub fn configure(arg: &str) -> Result<(), String> {
+    match arg.split_once('=') {
+        Some((module, level)) => set_module_level(module, level.parse()?),
+        None => set_level(arg.parse()?),
+    }
+    Ok(())
+}
+
+/// Whether messages of `level` from the module at `module_path` are logged
+pub fn enabled(level: Level, module_path: &str) -> bool {
+    if level as u8 > MAX_LEVEL.load(Ordering::Relaxed) {
+        return false;
+    }
+    let levels = LEVELS.read().unwrap();
+    let module = module_path.split_once("::").map_or("", |(_, module)| module);
+    // The longest matching module decides
+    let module_level = levels.1.iter().filter(|(prefix, _)| module.starts_with(prefix.as_str())).max_by_key(|(prefix, _)| prefix.len());
+    level <= module_level.map_or(levels.0, |&(_, level)| level)
+}
+
+#[cfg(not(test))]
+pub fn write(level: Level, args: fmt::Arguments) {
+    match level {
+        Level::Error | Level::Warn => eprintln!("{}", args),
+        _ => println!("{}", args),
+    }
+}
+
+// Tests collect the output of their own thread
+#[cfg(test)]
+thread_local! {
+    pub static OUTPUT: std::cell::RefCell<Vec<(Level, String)>> = const { std::cell::RefCell::new(Vec::new()) };
+}
+
+#[cfg(test)]
+pub fn write(level: Level, args: fmt::Arguments) {
+    OUTPUT.with(|output| output.borrow_mut().push((level, args.to_string())));
+}
+
+// The macros are available in every module declared after this one
+macro_rules! log {
+    ($level:expr, $($arg:tt)*) => {
+        if $crate::logging::enabled($level, module_path!()) {
+            $crate::logging::write($level, format_args!($($arg)*));
+        }
+    };
+}
+
+macro_rules! warn {
+    ($($arg:tt)*) => { log!($crate::logging::Level::Warn, $($arg)*) };
+}
+
+macro_rules! trace {
+    ($($arg:tt)*) => { log!($crate::logging::Level::Trace, $($arg)*) };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::system::{cpu::CPU, memory::Memory};
+
+    #[test]
+    fn test_levels() {
+        let mut bios = vec![0; 0x4000];
+        bios[0..4].copy_from_slice(&0xEE000010u32.to_le_bytes()); // MCR p0, 0, R0, c0, c0, 0
+        let mut mem = Memory::new(bios, vec![0; 0x100]);
+        let take_output = || OUTPUT.with(|output| std::mem::take(&mut *output.borrow_mut()));
+
+        // Disabled logging produces no output
+        set_level(Level::Off);
+        CPU::new().cycle(&mut mem);
+        assert!(take_output().is_empty());
+
+        // Warnings don't include the trace of each instruction
+        set_level(Level::Warn);
+        CPU::new().cycle(&mut mem);
+        assert_eq!(take_output().iter().map(|&(level, _)| level).collect::<Vec<_>>(), [Level::Warn]);
+
+        // A module can be traced on its own
+        set_module_level("system::cpu", Level::Trace);
+        CPU::new().cycle(&mut mem);
+        assert_eq!(take_output().len(), 2);
+        assert!(!enabled(Level::Trace, "gbae::system::memory"));
+        assert!(enabled(Level::Trace, "gbae::system::cpu"));
+
+        assert!(configure("loud").is_err());
+        configure("system::cpu=off").unwrap();
+        CPU::new().cycle(&mut mem);
+        // The coprocessor warning comes from another module
+        assert_eq!(take_output().len(), 1);
+        LEVELS.write().unwrap().1.clear();
+        set_level(Level::Warn);
+    }
+}
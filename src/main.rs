@@ -1,68 +1,167 @@
-#![feature(type_alias_impl_trait)]
-#![feature(bigint_helper_methods)]
-
-mod bitutil;
 mod cartridge;
+#[cfg(feature = "tui")]
+mod debug_view;
 mod debugger;
-mod system;
+mod elf;
+mod headless;
+mod png;
+mod symbols;
+mod wav;
 
 use cartridge::CartridgeInfo;
-use debugger::Debugger;
+use debugger::{Debugger, StartMode};
+use elf::Elf;
+use frame_limiter::{FrameLimiter, Unthrottled};
+use gbae::{bitutil, disassembler, frame_limiter, logging, system};
+use logging::Level;
 use std::{
     fs,
-    io::{stdin, stdout, Write},
+    io::{self, stdin, stdout, Write},
+    path::Path,
 };
 use system::{
-    cpu::CPU,
-    display::{Display, DisplayEvent},
-    memory::Memory,
-    ppu::PPU,
+    bios,
+    cpu::{CPU_FREQUENCY, DEFAULT_TRACE_LEN},
+    display::{self, Display, DisplayEvent, KeyMap, Scaling},
+    keypad::Button,
+    ppu,
+    save::SaveType,
+    GbaSystem,
 };
 use winit::event_loop::ControlFlow;
 
 fn main() {
-    let bios = fs::read("gba_bios.bin").expect("Failed to read bios");
-    let cartridge_data = fs::read("rom.gba").expect("Failed to read cartridge");
-    let cartridge = CartridgeInfo::parse(&cartridge_data).expect("Failed to parse cartridge info");
+    // Warnings are logged by default, --trace logs every instruction and --log=[<module>=]<level> sets the level
+    if std::env::args().any(|arg| arg == "--trace") {
+        logging::set_level(Level::Trace);
+    }
+    for arg in std::env::args().filter_map(|arg| arg.strip_prefix("--log=").map(str::to_owned)) {
+        logging::configure(&arg).unwrap();
+    }
+
+    // Without a BIOS the cartridge is started directly
+    let bios = if std::env::args().any(|arg| arg == "--skip-bios") {
+        None
+    } else {
+        match fs::read("gba_bios.bin") {
+            Ok(bios) => Some(bios),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                println!("gba_bios.bin not found, booting without a BIOS");
+                None
+            }
+            Err(e) => panic!("Failed to read bios: {}", e),
+        }
+    };
+    let rom_path = Path::new("rom.gba");
+    let rom = fs::read(rom_path).expect("Failed to read cartridge");
+    // ELF files are loaded into RAM at boot, with a cartridge of just an empty header inserted
+    let elf = elf::is_elf(&rom).then(|| Elf::parse(&rom).expect("Failed to parse ELF file"));
+    let cartridge_data = if elf.is_some() { vec![0; 0xC0] } else { rom };
+    // Used for ROMs without a save type marker
+    let default_save_type = std::env::args()
+        .find_map(|arg| arg.strip_prefix("--save-type=").map(|save_type| save_type.parse::<SaveType>().unwrap()))
+        .unwrap_or(SaveType::Sram);
+    let cartridge = CartridgeInfo::parse(&cartridge_data, default_save_type).expect("Failed to parse cartridge info");
     println!("Title: {}", cartridge.title);
+    println!("Save type: {:?}", cartridge.save_type);
+
+    // Makes the memory contents at power on reproducible instead of zeroes
+    let seed = std::env::args().find_map(|arg| arg.strip_prefix("--seed=").map(|seed| seed.parse::<u64>().expect("Invalid seed")));
 
-    let (mut ppu, framebuffer) = PPU::new();
-    let (mut display, event_loop) = Display::new(framebuffer);
+    // --start=running boots straight into the game, --start=paused shows the debugger prompt at the reset vector
+    let start_mode = std::env::args()
+        .find_map(|arg| arg.strip_prefix("--start=").map(str::to_owned))
+        .or_else(|| std::env::var("GBAE_START").ok())
+        .map(|start_mode| start_mode.parse::<StartMode>().unwrap());
+
+    // Runs the given number of frames and prints a hash of the last one, started paused the debugger commands are read from stdin
+    if let Some(frames) = std::env::args().find_map(|arg| arg.strip_prefix("--headless=").map(|frames| frames.parse::<u32>().expect("Invalid frame count"))) {
+        let commands = stdin().lines().map(|line| line.unwrap());
+        let (framebuffer, audio) = headless::run_headless(bios, cartridge_data, elf.as_ref(), seed, frames, start_mode.unwrap_or(StartMode::Running), commands);
+        println!("Framebuffer hash: {:016X}", ppu::hash_framebuffer(&framebuffer));
+        if let Some(path) = std::env::args().find_map(|arg| arg.strip_prefix("--audio-out=").map(str::to_owned)) {
+            wav::write_wav(path, &audio).expect("Failed to write audio");
+        }
+        return;
+    }
+
+    let skip_bios = bios.is_none();
+    let (mut system, framebuffer) = GbaSystem::new(bios.unwrap_or_else(bios::hle_image), cartridge_data);
+    system.mem.set_save_type(cartridge.save_type);
+    if let Some(seed) = seed {
+        system.mem.randomize(seed);
+    }
+    system.mem.load_save(rom_path.with_extension("sav")).expect("Failed to read save file");
+    // Faulting memory accesses are ignored, unless debugging the emulator
+    system.mem.set_strict(std::env::args().any(|arg| arg == "--strict-memory"));
+    system.mem.set_abort_on_fault(std::env::args().any(|arg| arg == "--memory-aborts"));
+    if std::env::args().any(|arg| arg == "--log-memory-faults") {
+        system.mem.set_fault_hook(|fault| println!("{}", fault));
+    }
+    let scaling = if std::env::args().any(|arg| arg == "--stretch") { Scaling::Stretch } else { Scaling::Integer };
+    // Each --bind=<button>:<key> replaces the default key of that button
+    let mut key_map = KeyMap::default();
+    for binding in std::env::args().filter_map(|arg| arg.strip_prefix("--bind=").map(str::to_owned)) {
+        let (button, key) = binding.split_once(':').expect("Expected --bind=<button>:<key>");
+        key_map.rebind(button.parse::<Button>().unwrap(), display::parse_key(key).unwrap());
+    }
+    // Toggled with Tab or the debugger's turbo command
+    let unthrottled = Unthrottled::default();
+    unthrottled.set(std::env::args().any(|arg| arg == "--max-speed"));
+    let (mut display, event_loop) = Display::new(framebuffer, system.mem.keypad().clone(), key_map, unthrottled.clone(), scaling);
     let event_loop_proxy = event_loop.create_proxy();
+    let trace_len = std::env::args()
+        .find_map(|arg| arg.strip_prefix("--trace-len=").map(|len| len.parse::<usize>().expect("Invalid trace length")))
+        .unwrap_or(DEFAULT_TRACE_LEN);
+    // Undefined instructions raise the exception, unless debugging the emulator
+    let strict_cpu = std::env::args().any(|arg| arg == "--strict-cpu");
+    // By default the debugger prompt is shown at the reset vector
+    let start_mode = start_mode.unwrap_or(StartMode::Paused);
+
+    // Built with the tui feature, --tui shows the debugger as a terminal view
+    #[cfg(feature = "tui")]
+    let tui = std::env::args().any(|arg| arg == "--tui");
 
     // Spawn emulator thread
     std::thread::spawn(move || {
-        let mut mem = Memory::new(bios, cartridge_data);
-        let mut cpu = CPU::new();
-        let mut debugger = Debugger::new();
+        if let Some(elf) = &elf {
+            elf.load(&mut system, skip_bios);
+        } else if skip_bios {
+            system.skip_bios();
+        }
+        system.cpu.set_trace_len(trace_len);
+        system.cpu.set_strict(strict_cpu);
+        let mut debugger = Debugger::with_start_mode(start_mode);
+        #[cfg(feature = "tui")]
+        if tui {
+            debugger.enable_view();
+        }
+        if let Some(elf) = &elf {
+            debugger.add_symbols(elf.symbols.clone());
+        }
 
-        println!("GBA Debugger. Type 'h' for help.");
+        const CPU_CYCLES_PER_FRAME: u64 = 280_896; // 228 scanlines of 1232 cycles
+        let mut frame_limiter = FrameLimiter::new(CPU_FREQUENCY as f64 / CPU_CYCLES_PER_FRAME as f64);
+        frame_limiter.unthrottled = unthrottled.clone();
+        debugger.unthrottled = unthrottled;
 
-        loop {
-            // Print current instruction before executing it
-            println!();
-            cpu.print_registers();
-            cpu.print_status();
-            println!("{:08X}: {:08X}", 0x03007E9C, mem.read_u32(0x03007E9C));
-            cpu.print_next_instruction(&mem);
-
-            if !debugger.running || debugger.should_break(&cpu) {
-                debugger.running = false;
-                print!("> ");
-                stdout().flush().unwrap();
-
-                let mut input = String::new();
-                stdin().read_line(&mut input).unwrap();
-                debugger.handle_command(&input, &mut cpu, &mut mem);
-            }
+        if start_mode == StartMode::Paused {
+            println!("GBA Debugger. Type 'h' for help.");
+        }
 
-            if debugger.running {
-                cpu.cycle(&mut mem);
-                const CPU_CYCLES_PER_FRAME: u64 = 2273;
-                while cpu.get_cycles() / CPU_CYCLES_PER_FRAME > ppu.get_frame_counter() {
-                    ppu.draw_frame(&mut mem);
-                    event_loop_proxy.send_event(DisplayEvent::RedrawRequested).unwrap();
-                }
+        let read_command = || {
+            print!("> ");
+            stdout().flush().unwrap();
+
+            let mut input = String::new();
+            stdin().read_line(&mut input).unwrap();
+            input
+        };
+        loop {
+            if debugger.step(&mut system, read_command) {
+                system.mem.flush_save().expect("Failed to write save file");
+                event_loop_proxy.send_event(DisplayEvent::RedrawRequested).unwrap();
+                frame_limiter.wait();
             }
         }
     });
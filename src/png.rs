@@ -0,0 +1,127 @@
+use std::{fs, io, path::Path};
+
+use crate::system::ppu::{Framebuffer, FRAMEBUFFER_HEIGHT, FRAMEBUFFER_WIDTH};
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+// Uncompressed deflate blocks hold at most this many bytes
+const STORED_BLOCK_LEN: usize = 0xFFFF;
+
+fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0u32, |crc, &byte| {
+        (0..8).fold(crc ^ byte as u32, |crc, _| if crc & 1 != 0 { crc >> 1 ^ 0xEDB8_8320 } else { crc >> 1 })
+    })
+}
+
+fn adler32(bytes: &[u8]) -> u32 {
+    let (a, b) = bytes.iter().fold((1u32, 0u32), |(a, b), &byte| {
+        let a = (a + byte as u32) % 65521;
+        (a, (b + a) % 65521)
+    });
+    b << 16 | a
+}
+
+fn write_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+/// Encodes the framebuffer as an 8 bit RGB PNG, stored without compression to keep the encoder small
+pub fn encode_png(framebuffer: &Framebuffer) -> Vec<u8> {
+    let mut png = PNG_SIGNATURE.to_vec();
+
+    let mut header = Vec::new();
+    header.extend_from_slice(&(FRAMEBUFFER_WIDTH as u32).to_be_bytes());
+    header.extend_from_slice(&(FRAMEBUFFER_HEIGHT as u32).to_be_bytes());
+    // Bit depth 8, color type RGB, default compression, filtering and no interlacing
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+    write_chunk(&mut png, b"IHDR", &header);
+
+    // Every line starts with filter type 0 (none)
+    let raw: Vec<u8> = framebuffer.iter().flat_map(|line| std::iter::once(0).chain(line.as_flattened().iter().copied())).collect();
+    let mut zlib = vec![0x78, 0x01];
+    let block_count = raw.len().div_ceil(STORED_BLOCK_LEN);
+    for (i, block) in raw.chunks(STORED_BLOCK_LEN).enumerate() {
+        zlib.push((i + 1 == block_count) as u8);
+        zlib.extend_from_slice(&(block.len() as u16).to_le_bytes());
+        zlib.extend_from_slice(&(!(block.len() as u16)).to_le_bytes());
+        zlib.extend_from_slice(block);
+    }
+    zlib.extend_from_slice(&adler32(&raw).to_be_bytes());
+    write_chunk(&mut png, b"IDAT", &zlib);
+
+    write_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+pub fn write_png(path: impl AsRef<Path>, framebuffer: &Framebuffer) -> io::Result<()> {
+    fs::write(path, encode_png(framebuffer))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads back the dimensions and pixels of a PNG written by `encode_png`
+    fn decode_png(png: &[u8]) -> (u32, u32, Vec<u8>) {
+        assert_eq!(png[..8], PNG_SIGNATURE);
+        let (mut width, mut height, mut zlib) = (0, 0, Vec::new());
+        let mut rest = &png[8..];
+        while !rest.is_empty() {
+            let len = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+            let (kind, data) = (&rest[4..8], &rest[8..8 + len]);
+            assert_eq!(crc32(&rest[4..8 + len]).to_be_bytes(), rest[8 + len..12 + len]);
+            match kind {
+                b"IHDR" => {
+                    width = u32::from_be_bytes(data[..4].try_into().unwrap());
+                    height = u32::from_be_bytes(data[4..8].try_into().unwrap());
+                    assert_eq!(data[8..], [8, 2, 0, 0, 0]);
+                }
+                b"IDAT" => zlib.extend_from_slice(data),
+                _ => (),
+            }
+            rest = &rest[12 + len..];
+        }
+
+        let mut raw = Vec::new();
+        let mut blocks = &zlib[2..];
+        loop {
+            let len = u16::from_le_bytes([blocks[1], blocks[2]]) as usize;
+            assert_eq!(!len as u16, u16::from_le_bytes([blocks[3], blocks[4]]));
+            raw.extend_from_slice(&blocks[5..5 + len]);
+            let is_final = blocks[0] & 1 != 0;
+            blocks = &blocks[5 + len..];
+            if is_final {
+                break;
+            }
+        }
+        assert_eq!(blocks, adler32(&raw).to_be_bytes());
+        (width, height, raw)
+    }
+
+    #[test]
+    fn test_encode_png() {
+        let mut framebuffer = [[[0; 3]; FRAMEBUFFER_WIDTH]; FRAMEBUFFER_HEIGHT];
+        framebuffer[0][0] = [255, 0, 0];
+        framebuffer[159][239] = [1, 2, 3];
+        framebuffer[80][100] = [0, 255, 0];
+
+        let (width, height, raw) = decode_png(&encode_png(&framebuffer));
+        assert_eq!((width, height), (240, 160));
+        assert_eq!(raw.len(), 160 * (1 + 240 * 3));
+        let pixel = |x: usize, y: usize| &raw[y * 721 + 1 + x * 3..][..3];
+        assert_eq!(pixel(0, 0), [255, 0, 0]);
+        assert_eq!(pixel(1, 0), [0, 0, 0]);
+        assert_eq!(pixel(239, 159), [1, 2, 3]);
+        assert_eq!(pixel(100, 80), [0, 255, 0]);
+    }
+
+    #[test]
+    fn test_checksums() {
+        assert_eq!(crc32(b"IEND"), 0xAE42_6082);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+    }
+}
@@ -0,0 +1,71 @@
+/// Function and variable names by address, from an ELF file or a symbol file
+#[derive(Debug, Default)]
+pub struct SymbolTable {
+    // Sorted by address for binary searching
+    symbols: Vec<(u32, String)>,
+}
+
+/// Parses lines of `<hex address> <name>`, also accepting the `<address> <type> <name>` lines of nm
+pub fn parse_symbol_file(text: &str) -> Vec<(u32, String)> {
+    text.lines()
+        .filter_map(|line| {
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            if !matches!(parts.len(), 2 | 3) {
+                return None;
+            }
+            let address = u32::from_str_radix(parts[0].strip_prefix("0x").unwrap_or(parts[0]), 16).ok()?;
+            Some((address, parts[parts.len() - 1].to_string()))
+        })
+        .collect()
+}
+
+impl SymbolTable {
+    pub fn extend(&mut self, symbols: impl IntoIterator<Item = (u32, String)>) {
+        self.symbols.extend(symbols);
+        self.symbols.sort();
+    }
+
+    pub fn address_of(&self, name: &str) -> Option<u32> {
+        self.symbols.iter().find(|(_, symbol)| symbol == name).map(|&(address, _)| address)
+    }
+
+    /// Names `address` by the closest symbol at or before it, like `main+0x14`
+    pub fn resolve(&self, address: u32) -> Option<String> {
+        let index = self.symbols.partition_point(|&(symbol_address, _)| symbol_address <= address).checked_sub(1)?;
+        let (symbol_address, name) = &self.symbols[index];
+        Some(match address - symbol_address {
+            0 => name.clone(),
+            offset => format!("{}+0x{:X}", name, offset),
+        })
+    }
+
+    /// The address in hex, followed by its symbol if there is one
+    pub fn format_address(&self, address: u32) -> String {
+        match self.resolve(address) {
+            Some(symbol) => format!("{:08X} <{}>", address, symbol),
+            None => format!("{:08X}", address),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve() {
+        let parsed = parse_symbol_file("08000200 main\n0x08000100 _start\n\n03000000 B irq_table\ninvalid line here too\n");
+        assert_eq!(parsed.len(), 3);
+        let mut symbols = SymbolTable::default();
+        symbols.extend(parsed);
+        assert_eq!(symbols.resolve(0x08000214).as_deref(), Some("main+0x14"));
+        assert_eq!(symbols.resolve(0x08000100).as_deref(), Some("_start"));
+        assert_eq!(symbols.resolve(0x080001FE).as_deref(), Some("_start+0xFE"));
+        assert_eq!(symbols.resolve(0x02000000), None);
+        assert_eq!(symbols.format_address(0x03000004), "03000004 <irq_table+0x4>");
+        assert_eq!(symbols.format_address(0x02000000), "02000000");
+
+        assert_eq!(symbols.address_of("main"), Some(0x08000200));
+        assert_eq!(symbols.address_of("missing"), None);
+    }
+}
@@ -0,0 +1,249 @@
+use std::{
+    collections::VecDeque,
+    io,
+    sync::{Arc, Mutex},
+};
+
+use super::{
+    cpu::CPU_FREQUENCY,
+    io_registers::IoRegisters,
+    psg::Psg,
+    state::{StateReader, StateWriter},
+};
+
+pub const REG_SOUNDCNT_H: u32 = 0x04_000_082;
+pub const REG_SOUNDCNT_X: u32 = 0x04_000_084;
+pub const REG_FIFO_A: u32 = 0x04_000_0A0;
+pub const REG_FIFO_B: u32 = 0x04_000_0A4;
+pub const FIFO_ADDRESSES: [u32; 2] = [REG_FIFO_A, REG_FIFO_B];
+
+// SOUNDCNT_H bits, each FIFO has a volume bit and a nibble of enable, timer and reset bits
+const SOUNDCNT_H_FULL_VOLUME: u16 = 1 << 2;
+const SOUNDCNT_H_RIGHT: u16 = 1 << 8;
+const SOUNDCNT_H_LEFT: u16 = 1 << 9;
+const SOUNDCNT_H_TIMER1: u16 = 1 << 10;
+const SOUNDCNT_H_RESET: u16 = 1 << 11;
+const SOUNDCNT_H_FIFO_SHIFT: u16 = 4;
+
+// SOUNDCNT_X bits
+pub const SOUNDCNT_X_ENABLE: u16 = 1 << 7;
+
+pub const SAMPLE_RATE: u32 = 32768;
+const CYCLES_PER_SAMPLE: u32 = (CPU_FREQUENCY / SAMPLE_RATE as u64) as u32;
+const FIFO_LEN: usize = 32;
+// DMA refills a FIFO with 16 bytes once half of it has been played
+const FIFO_REFILL_LEN: usize = 16;
+// Samples kept for the host before the oldest are dropped, a quarter of a second
+const SAMPLE_BUFFER_LEN: usize = SAMPLE_RATE as usize / 4;
+
+/// Stereo samples shared with the host audio backend, which takes them from the front
+#[derive(Debug, Clone, Default)]
+pub struct SampleBuffer(Arc<Mutex<VecDeque<[i16; 2]>>>);
+
+impl SampleBuffer {
+    fn push(&self, sample: [i16; 2]) {
+        let mut samples = self.0.lock().unwrap();
+        // A host that stopped consuming only gets the most recent samples
+        if samples.len() == SAMPLE_BUFFER_LEN {
+            samples.pop_front();
+        }
+        samples.push_back(sample);
+    }
+
+    /// Moves samples into `out` and returns how many there were, a host playing faster than the emulator runs fills the rest with silence
+    pub fn pop_into(&self, out: &mut [[i16; 2]]) -> usize {
+        let mut samples = self.0.lock().unwrap();
+        let len = samples.len().min(out.len());
+        for (out, sample) in out.iter_mut().zip(samples.drain(..len)) {
+            *out = sample;
+        }
+        len
+    }
+}
+
+#[derive(Debug, Default)]
+struct Fifo {
+    data: VecDeque<i8>,
+    // The sample being played, until the driving timer overflows again
+    sample: i8,
+}
+
+/// The two DirectSound channels, which play 8 bit samples queued into FIFO A and B at the rate of timer 0 or 1, mixed with the PSG.
+#[derive(Debug, Default)]
+pub struct Apu {
+    pub psg: Psg,
+    fifos: [Fifo; 2],
+    // Cycles since the last output sample
+    cycles: u32,
+    samples: SampleBuffer,
+}
+
+impl Apu {
+    /// The output, for the host to play
+    pub fn samples(&self) -> &SampleBuffer {
+        &self.samples
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        self.psg.save_state(state);
+        for fifo in &self.fifos {
+            state.write_block(&fifo.data.iter().map(|&sample| sample as u8).collect::<Vec<_>>());
+            state.write_u16(fifo.sample as u8 as u16);
+        }
+        state.write_u32(self.cycles);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        self.psg.load_state(state)?;
+        for fifo in &mut self.fifos {
+            let len = state.read_u32()? as usize;
+            if len > FIFO_LEN {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("FIFO of {} bytes", len)));
+            }
+            fifo.data = state.read_bytes(len)?.iter().map(|&sample| sample as i8).collect();
+            fifo.sample = state.read_u16()? as u8 as i8;
+        }
+        self.cycles = state.read_u32()?;
+        Ok(())
+    }
+
+    /// Empties the FIFOs and stops the PSG, the host keeps its sample buffer
+    pub fn reset(&mut self) {
+        self.psg = Psg::default();
+        self.fifos = Default::default();
+        self.cycles = 0;
+    }
+
+    /// Queues a byte written to FIFO A (0) or B (1), bytes written to a full FIFO are dropped
+    pub fn write_fifo(&mut self, fifo: usize, value: u8) {
+        let data = &mut self.fifos[fifo].data;
+        if data.len() < FIFO_LEN {
+            data.push_back(value as i8);
+        }
+    }
+
+    /// Applies a write to SOUNDCNT_H, setting a reset bit empties that FIFO
+    pub fn write_control(&mut self, soundcnt_h: u16) {
+        for (i, fifo) in self.fifos.iter_mut().enumerate() {
+            if soundcnt_h & SOUNDCNT_H_RESET << (i as u16 * SOUNDCNT_H_FIFO_SHIFT) != 0 {
+                *fifo = Fifo::default();
+            }
+        }
+    }
+
+    /// Plays the next samples of the FIFOs driven by `timer`, returns which FIFOs are running low and need a DMA refill
+    pub fn timer_overflow(&mut self, timer: usize, overflows: u32, soundcnt_h: u16) -> [bool; 2] {
+        let mut refill = [false; 2];
+        for (i, fifo) in self.fifos.iter_mut().enumerate() {
+            let uses_timer1 = soundcnt_h & SOUNDCNT_H_TIMER1 << (i as u16 * SOUNDCNT_H_FIFO_SHIFT) != 0;
+            if timer != uses_timer1 as usize {
+                continue;
+            }
+            for _ in 0..overflows {
+                // An empty FIFO keeps playing the last sample
+                if let Some(sample) = fifo.data.pop_front() {
+                    fifo.sample = sample;
+                }
+            }
+            refill[i] = fifo.data.len() <= FIFO_REFILL_LEN;
+        }
+        refill
+    }
+
+    /// Outputs a sample every `CYCLES_PER_SAMPLE` cpu cycles, the PSG is advanced up to each sample
+    pub fn step(&mut self, mut cycles: u32, io: &mut IoRegisters) {
+        while self.cycles + cycles >= CYCLES_PER_SAMPLE {
+            let until_sample = CYCLES_PER_SAMPLE - self.cycles;
+            self.psg.step(until_sample, io);
+            cycles -= until_sample;
+            self.cycles = 0;
+            self.samples.push(self.mix(io));
+        }
+        self.psg.step(cycles, io);
+        self.cycles += cycles;
+    }
+
+    fn mix(&self, io: &IoRegisters) -> [i16; 2] {
+        let (soundcnt_h, soundcnt_x) = (io.get_u16(REG_SOUNDCNT_H), io.get_u16(REG_SOUNDCNT_X));
+        if soundcnt_x & SOUNDCNT_X_ENABLE == 0 {
+            return [0, 0];
+        }
+        let mut output = self.psg.mix(io);
+        for (i, fifo) in self.fifos.iter().enumerate() {
+            let bits = soundcnt_h >> (i as u16 * SOUNDCNT_H_FIFO_SHIFT);
+            // Full volume scales the sample to the whole 16 bit range, half volume to half of it
+            let shift = if soundcnt_h & SOUNDCNT_H_FULL_VOLUME << i != 0 { 8 } else { 7 };
+            let sample = (fifo.sample as i16) << shift;
+            for (output, enable) in output.iter_mut().zip([SOUNDCNT_H_LEFT, SOUNDCNT_H_RIGHT]) {
+                if bits & enable != 0 {
+                    *output = output.saturating_add(sample);
+                }
+            }
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fifo_playback() {
+        let mut apu = Apu::default();
+        let mut io = IoRegisters::new();
+        // FIFO A at full volume on both sides driven by timer 0, FIFO B at half volume on the left driven by timer 1
+        let soundcnt_h = SOUNDCNT_H_FULL_VOLUME | SOUNDCNT_H_LEFT | SOUNDCNT_H_RIGHT | (SOUNDCNT_H_LEFT | SOUNDCNT_H_TIMER1) << SOUNDCNT_H_FIFO_SHIFT;
+        for value in [0x10, 0xF0, 0x20] {
+            apu.write_fifo(0, value);
+        }
+        apu.write_fifo(1, 0x40);
+        io.set_u16(REG_SOUNDCNT_H, soundcnt_h);
+        io.set_u16(REG_SOUNDCNT_X, SOUNDCNT_X_ENABLE);
+
+        // Nothing plays until the timers overflow
+        apu.step(CYCLES_PER_SAMPLE, &mut io);
+
+        assert_eq!(apu.timer_overflow(0, 1, soundcnt_h), [true, false]);
+        apu.step(CYCLES_PER_SAMPLE, &mut io);
+        apu.timer_overflow(0, 1, soundcnt_h);
+        apu.timer_overflow(1, 1, soundcnt_h);
+        apu.step(CYCLES_PER_SAMPLE, &mut io);
+        // Without the master enable there is silence
+        io.set_u16(REG_SOUNDCNT_X, 0);
+        apu.step(CYCLES_PER_SAMPLE, &mut io);
+
+        let mut samples = [[1; 2]; 5];
+        assert_eq!(apu.samples().pop_into(&mut samples), 4);
+        assert_eq!(samples[..4], [[0, 0], [0x1000, 0x1000], [-0x1000 + 0x2000, -0x1000], [0, 0]]);
+        assert_eq!(apu.samples().pop_into(&mut samples), 0);
+
+        // Resetting FIFO A leaves FIFO B alone
+        apu.write_control(SOUNDCNT_H_RESET);
+        assert_eq!(apu.timer_overflow(0, 1, soundcnt_h), [true, false]);
+        assert_eq!(apu.fifos[0].sample, 0);
+        assert_eq!(apu.fifos[1].sample, 0x40);
+    }
+
+    #[test]
+    fn test_save_state() {
+        let mut apu = Apu::default();
+        for value in [0x10, 0xF0, 0x20] {
+            apu.write_fifo(0, value);
+        }
+        apu.timer_overflow(0, 1, SOUNDCNT_H_FULL_VOLUME | SOUNDCNT_H_RIGHT);
+        apu.write_fifo(1, 0x80);
+        let mut state = StateWriter::new();
+        apu.save_state(&mut state);
+        let data = state.into_bytes();
+
+        let mut loaded = Apu::default();
+        loaded.load_state(&mut StateReader::new(&data).unwrap()).unwrap();
+        for (fifo, loaded_fifo) in apu.fifos.iter().zip(&loaded.fifos) {
+            assert_eq!(fifo.data, loaded_fifo.data);
+            assert_eq!(fifo.sample, loaded_fifo.sample);
+        }
+        assert_eq!(loaded.fifos[0].sample, 0x10);
+        assert_eq!(loaded.fifos[1].data, [-0x80]);
+    }
+}
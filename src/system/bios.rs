@@ -0,0 +1,252 @@
+use super::{
+    cpu::{CPU, REGISTER_PC},
+    interrupts::REG_IME,
+    memory::{Memory, BIOS_LEN},
+    ppu::REG_DISPCNT,
+};
+
+// The interrupt handler flags handled interrupts here for IntrWait
+const BIOS_IF: u32 = 0x03_007_FF8;
+// SoftReset starts in WRAM instead of the cartridge if this is set
+const RETURN_ADDRESS_SELECTOR: u32 = 0x03_007_FFA;
+
+/// Stands in for the BIOS ROM when there is none: only the interrupt vector, which calls the handler at 0x03FFFFFC like the real one
+const HLE_IRQ_HANDLER: [u32; 6] = [
+    0xE92D500F, // STMFD SP!, {R0-R3, R12, LR}
+    0xE3A00301, // MOV R0, #0x04000000
+    0xE28FE000, // ADD LR, PC, #0
+    0xE510F004, // LDR PC, [R0, #-4]
+    0xE8BD500F, // LDMFD SP!, {R0-R3, R12, LR}
+    0xE25EF004, // SUBS PC, LR, #4
+];
+const VECTOR_IRQ: usize = 0x18;
+
+pub fn hle_image() -> Vec<u8> {
+    let mut bios = vec![0; BIOS_LEN];
+    for (i, instruction) in HLE_IRQ_HANDLER.iter().enumerate() {
+        bios[VECTOR_IRQ + i * 4..VECTOR_IRQ + i * 4 + 4].copy_from_slice(&instruction.to_le_bytes());
+    }
+    bios
+}
+
+/// High level emulation of the BIOS calls, for running without a BIOS
+#[derive(Debug, Default, Clone, Copy)]
+pub struct HleBios {
+    // IntrWait runs again until the interrupt arrives, old flags must only be discarded the first time
+    waiting: bool,
+}
+
+impl HleBios {
+    /// Runs BIOS function `function` in place of the SWI
+    pub fn call(&mut self, function: u8, cpu: &mut CPU, mem: &mut Memory) {
+        match function {
+            0x00 => soft_reset(cpu, mem),
+            0x01 => register_ram_reset(cpu.get_r(0), mem),
+            0x04 => self.intr_wait(cpu, mem, cpu.get_r(0) != 0, cpu.get_r(1) as u16),
+            // VBlankIntrWait
+            0x05 => self.intr_wait(cpu, mem, true, 1),
+            0x06 => div(cpu, cpu.get_r(0) as i32, cpu.get_r(1) as i32),
+            // DivArm
+            0x07 => div(cpu, cpu.get_r(1) as i32, cpu.get_r(0) as i32),
+            0x08 => cpu.set_r(0, cpu.get_r(0).isqrt()),
+            0x0B => cpu_set(cpu.get_r(0), cpu.get_r(1), cpu.get_r(2), mem),
+            0x0C => cpu_fast_set(cpu.get_r(0), cpu.get_r(1), cpu.get_r(2), mem),
+            _ => warn!("Unimplemented BIOS function: {:02X}", function),
+        }
+    }
+
+    fn intr_wait(&mut self, cpu: &mut CPU, mem: &mut Memory, discard_old: bool, mask: u16) {
+        if discard_old && !self.waiting {
+            mem.write_u16(BIOS_IF, mem.read_u16(BIOS_IF) & !mask);
+        }
+        mem.write_u16(REG_IME, 1);
+
+        let flags = mem.read_u16(BIOS_IF);
+        if flags & mask != 0 {
+            mem.write_u16(BIOS_IF, flags & !mask);
+            self.waiting = false;
+        } else {
+            // Run the SWI again until the interrupt handler has flagged one of the interrupts
+            self.waiting = true;
+            cpu.set_r(REGISTER_PC, cpu.curr_instruction_address_from_execution_stage());
+        }
+    }
+}
+
+fn soft_reset(cpu: &mut CPU, mem: &mut Memory) {
+    let in_wram = mem.read_u8(RETURN_ADDRESS_SELECTOR) != 0;
+    for address in (0x03_007_E00..0x03_008_000).step_by(4) {
+        mem.write_u32(address, 0);
+    }
+    for r in 0..=12 {
+        cpu.set_r(r, 0);
+    }
+    cpu.skip_bios();
+    cpu.set_r(REGISTER_PC, if in_wram { 0x02_000_000 } else { 0x08_000_000 });
+}
+
+fn register_ram_reset(flags: u32, mem: &mut Memory) {
+    // The last 0x200 bytes of on-chip WRAM hold the stacks and are kept. Resetting the I/O registers (bits 5-7) isn't emulated
+    let regions = [(0x02_000_000, 0x40_000), (0x03_000_000, 0x7E00), (0x05_000_000, 0x400), (0x06_000_000, 0x18_000), (0x07_000_000, 0x400)];
+    for (i, (start, len)) in regions.into_iter().enumerate() {
+        if flags & 1 << i != 0 {
+            for address in (start..start + len).step_by(4) {
+                mem.write_u32(address, 0);
+            }
+        }
+    }
+    mem.write_u16(REG_DISPCNT, 0x80);
+}
+
+fn div(cpu: &mut CPU, numerator: i32, denominator: i32) {
+    if denominator == 0 {
+        // The BIOS never returns, pick something that keeps the game going
+        warn!("BIOS division by zero");
+        cpu.set_r(0, if numerator < 0 { -1i32 as u32 } else { 1 });
+        cpu.set_r(1, numerator as u32);
+        cpu.set_r(3, 1);
+        return;
+    }
+    let quotient = numerator.wrapping_div(denominator);
+    cpu.set_r(0, quotient as u32);
+    cpu.set_r(1, numerator.wrapping_rem(denominator) as u32);
+    cpu.set_r(3, quotient.unsigned_abs());
+}
+
+/// Copies or fills (bit 24) `control` bits 0-20 halfwords, or words with bit 26
+fn cpu_set(source: u32, destination: u32, control: u32, mem: &mut Memory) {
+    let count = control & 0x1F_FFFF;
+    let fill = control & 1 << 24 != 0;
+    if control & 1 << 26 != 0 {
+        let (source, destination) = (source & !0b11, destination & !0b11);
+        for i in 0..count {
+            let value = mem.read_u32(if fill { source } else { source + i * 4 });
+            mem.write_u32(destination + i * 4, value);
+        }
+    } else {
+        let (source, destination) = (source & !0b1, destination & !0b1);
+        for i in 0..count {
+            let value = mem.read_u16(if fill { source } else { source + i * 2 });
+            mem.write_u16(destination + i * 2, value);
+        }
+    }
+}
+
+/// Like `cpu_set` with words, in blocks of 8
+fn cpu_fast_set(source: u32, destination: u32, control: u32, mem: &mut Memory) {
+    let count = (control & 0x1F_FFFF).next_multiple_of(8);
+    cpu_set(source, destination, count | control & 1 << 24 | 1 << 26, mem);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::system::{
+        cpu::{MODE_SYS, REGISTER_SP},
+        interrupts::{Interrupt, REG_IE},
+    };
+
+    fn call(function: u8, cpu: &mut CPU, mem: &mut Memory) {
+        HleBios::default().call(function, cpu, mem);
+    }
+
+    #[test]
+    fn test_div() {
+        let mut cpu = CPU::new();
+        let mut mem = Memory::new(hle_image(), vec![0; 0x100]);
+
+        cpu.set_r(0, -7i32 as u32);
+        cpu.set_r(1, 2);
+        call(0x06, &mut cpu, &mut mem);
+        assert_eq!(cpu.get_r(0), -3i32 as u32);
+        assert_eq!(cpu.get_r(1), -1i32 as u32);
+        assert_eq!(cpu.get_r(3), 3);
+
+        // DivArm swaps the operands
+        cpu.set_r(0, 5);
+        cpu.set_r(1, 100);
+        call(0x07, &mut cpu, &mut mem);
+        assert_eq!((cpu.get_r(0), cpu.get_r(1), cpu.get_r(3)), (20, 0, 20));
+    }
+
+    #[test]
+    fn test_sqrt() {
+        let mut cpu = CPU::new();
+        let mut mem = Memory::new(hle_image(), vec![0; 0x100]);
+        for (value, root) in [(0, 0), (15, 3), (16, 4), (0xFFFF_FFFF, 0xFFFF)] {
+            cpu.set_r(0, value);
+            call(0x08, &mut cpu, &mut mem);
+            assert_eq!(cpu.get_r(0), root);
+        }
+    }
+
+    #[test]
+    fn test_cpu_set() {
+        let mut cpu = CPU::new();
+        let mut mem = Memory::new(hle_image(), vec![0; 0x100]);
+        mem.write_u32(0x02_000_000, 0x11223344);
+        mem.write_u32(0x02_000_004, 0x55667788);
+
+        // Copy 3 halfwords
+        cpu.set_r(0, 0x02_000_000);
+        cpu.set_r(1, 0x02_000_100);
+        cpu.set_r(2, 3);
+        call(0x0B, &mut cpu, &mut mem);
+        assert_eq!(mem.read_u32(0x02_000_100), 0x11223344);
+        assert_eq!(mem.read_u32(0x02_000_104), 0x00007788);
+
+        // CpuFastSet fills whole blocks of 8 words
+        cpu.set_r(1, 0x02_000_200);
+        cpu.set_r(2, 1 << 24 | 1);
+        call(0x0C, &mut cpu, &mut mem);
+        assert_eq!(mem.read_u32(0x02_000_21C), 0x11223344);
+        assert_eq!(mem.read_u32(0x02_000_220), 0);
+    }
+
+    #[test]
+    fn test_intr_wait() {
+        let mut cpu = CPU::new();
+        let mut mem = Memory::new(hle_image(), vec![0; 0x100]);
+        let mut bios = HleBios::default();
+        mem.write_u16(BIOS_IF, 1);
+
+        // The old VBlank flag is discarded, so the SWI at 0x08000100 runs again
+        cpu.set_r(REGISTER_PC, 0x08_000_108);
+        bios.call(0x05, &mut cpu, &mut mem);
+        assert_eq!(cpu.get_r(REGISTER_PC), 0x08_000_100);
+        assert_eq!(mem.read_u16(REG_IME), 1);
+
+        // Until the interrupt handler flags a new one
+        mem.write_u16(BIOS_IF, 1);
+        cpu.set_r(REGISTER_PC, 0x08_000_108);
+        bios.call(0x05, &mut cpu, &mut mem);
+        assert_eq!(cpu.get_r(REGISTER_PC), 0x08_000_108);
+        assert_eq!(mem.read_u16(BIOS_IF), 0);
+    }
+
+    #[test]
+    fn test_hle_irq_handler() {
+        let mut cpu = CPU::new();
+        let mut mem = Memory::new(hle_image(), vec![0; 0x100]);
+        cpu.skip_bios();
+        cpu.set_irq_disable(false);
+        // User handler at 0x03000000: MOV R0, #1; STR R0, [R1]; BX LR
+        for (i, instruction) in [0xE3A00001u32, 0xE5810000, 0xE12FFF1E].into_iter().enumerate() {
+            mem.write_u32(0x03_000_000 + i as u32 * 4, instruction);
+        }
+        mem.write_u32(0x03_007_FFC, 0x03_000_000);
+        cpu.set_r(1, 0x03_000_080);
+        mem.write_u16(REG_IE, 1);
+        mem.write_u16(REG_IME, 1);
+        mem.request_interrupt(Interrupt::VBlank);
+
+        // 4 instructions of the dispatcher, 3 of the handler and 2 to return
+        for _ in 0..9 {
+            cpu.cycle(&mut mem);
+        }
+        assert_eq!(mem.read_u32(0x03_000_080), 1);
+        assert_eq!(cpu.get_mode(), MODE_SYS);
+        assert_eq!(cpu.get_r(REGISTER_PC), 0x08_000_000);
+        assert_eq!(cpu.get_r(REGISTER_SP), 0x03_007_F00);
+    }
+}
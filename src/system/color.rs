@@ -0,0 +1,22 @@
+/// Converts a BGR555 color to RGB888, repeating the top bits so 0x1F maps to 0xFF
+pub fn bgr555_to_rgb888(color: u16) -> [u8; 3] {
+    let expand = |c: u16| ((c & 0x1F) << 3 | (c & 0x1F) >> 2) as u8;
+    [expand(color), expand(color >> 5), expand(color >> 10)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bgr555_to_rgb888() {
+        assert_eq!(bgr555_to_rgb888(0x0000), [0, 0, 0]);
+        assert_eq!(bgr555_to_rgb888(0x7FFF), [255, 255, 255]);
+        assert_eq!(bgr555_to_rgb888(0x001F), [255, 0, 0]);
+        assert_eq!(bgr555_to_rgb888(0x03E0), [0, 255, 0]);
+        assert_eq!(bgr555_to_rgb888(0x7C00), [0, 0, 255]);
+        assert_eq!(bgr555_to_rgb888(0x0010), [132, 0, 0]);
+        // The unused top bit is ignored
+        assert_eq!(bgr555_to_rgb888(0x8000), [0, 0, 0]);
+    }
+}
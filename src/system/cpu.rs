@@ -1,13 +1,14 @@
-use std::{thread::sleep, time::Duration};
+use crate::bitutil::{get_bit, get_bits32, set_bit32, set_bits32};
 
-use crate::{
-    bitutil::{get_bit, get_bits32, set_bit32, set_bits32},
-    system::instructions::{format_instruction_arm, format_instruction_thumb},
-};
+use std::io;
 
 use super::{
-    instructions::{lut::InstructionLut, Condition},
+    bios::HleBios,
+    instructions::{cache::InstructionCache, lut::is_unconditional, Condition, DecodedInstruction, CYCLES_S},
+    interrupts::Interrupt,
     memory::Memory,
+    state::{StateReader, StateWriter},
+    trace::{BranchEdge, BranchTrace, InstructionTrace, TraceEntry},
 };
 
 pub const MODE_USR: u8 = 0b10000;
@@ -25,8 +26,17 @@ pub const REGISTER_PC: u8 = 15;
 pub const INSTRUCTION_LEN_ARM: u32 = 4;
 pub const INSTRUCTION_LEN_THUMB: u32 = 2;
 
+pub const VECTOR_UNDEFINED: u32 = 0x04;
+pub const VECTOR_SWI: u32 = 0x08;
+pub const VECTOR_PREFETCH_ABORT: u32 = 0x0C;
+pub const VECTOR_DATA_ABORT: u32 = 0x10;
+pub const VECTOR_IRQ: u32 = 0x18;
+
+pub const CARTRIDGE_ENTRY: u32 = 0x08_000_000;
+
 pub const CPU_FREQUENCY: u64 = 16_776_000;
-pub const INSTRUCTION_TIME: Duration = Duration::from_nanos(1_000_000_000 / CPU_FREQUENCY);
+
+pub const DEFAULT_TRACE_LEN: usize = 64;
 
 pub fn format_mode(mode: u8) -> &'static str {
     match mode {
@@ -41,6 +51,11 @@ pub fn format_mode(mode: u8) -> &'static str {
     }
 }
 
+/// Whether the mode field of the CPSR holds one of the seven modes
+pub fn is_valid_mode(mode: u8) -> bool {
+    matches!(mode, MODE_USR | MODE_FIQ | MODE_IRQ | MODE_SVC | MODE_ABT | MODE_UND | MODE_SYS)
+}
+
 pub struct CPU {
     pub cpsr: u32, /* current program status register */
 
@@ -60,6 +75,22 @@ pub struct CPU {
 
     branch_happened: bool,
     cycles: u64,
+    trace: InstructionTrace,
+    // Taken branches are only recorded while enabled
+    branch_trace: Option<BranchTrace>,
+    // BIOS calls are emulated when running without a BIOS
+    pub hle_bios: Option<HleBios>,
+    // The immediate of an executed BKPT, for the debugger to stop at
+    debug_halt: Option<u16>,
+    // Undefined instructions panic instead of raising the exception
+    strict: bool,
+    instruction_cache: InstructionCache,
+}
+
+impl Default for CPU {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl CPU {
@@ -118,7 +149,11 @@ impl CPU {
     }
 
     pub fn get_spsr(&self) -> u32 {
-        match self.get_mode() {
+        self.get_spsr_in_mode(self.get_mode())
+    }
+
+    pub fn get_spsr_in_mode(&self, mode: u8) -> u32 {
+        match mode {
             MODE_SVC => self.spsr_svc,
             MODE_ABT => self.spsr_abt,
             MODE_UND => self.spsr_und,
@@ -140,8 +175,6 @@ impl CPU {
     }
 
     pub fn new() -> Self {
-        InstructionLut::initialize();
-
         let mut cpu = CPU {
             cpsr: 0,
 
@@ -161,42 +194,155 @@ impl CPU {
             branch_happened: false,
 
             cycles: 0,
+            trace: InstructionTrace::new(DEFAULT_TRACE_LEN),
+            branch_trace: None,
+            hle_bios: None,
+            debug_halt: None,
+            strict: false,
+            instruction_cache: InstructionCache::new(),
         };
         cpu.reset();
         cpu
     }
 
     pub fn cycle(&mut self, mem: &mut Memory) {
-        let decoded_instruction = if self.get_thumb_state() {
+        if self.irq_pending(mem) {
+            // The handler returns with SUBS PC, LR, #4 to the instruction that would have been executed next
+            let return_address = self.r[REGISTER_PC as usize].wrapping_add(INSTRUCTION_LEN_ARM);
+            self.raise_exception(MODE_IRQ, VECTOR_IRQ, return_address);
+        }
+
+        let pc = self.r[REGISTER_PC as usize];
+        // Fetches after a branch are non-sequential
+        let fetch_wait_cycles = mem.fetch_cycles(pc, self.instruction_len_in_bytes(), !self.branch_happened) - 1;
+        // Faults from outside the cpu, like the debugger reading memory, don't abort
+        mem.take_abort();
+        let cached = if self.get_thumb_state() {
             let instruction = self.fetch_thumb(mem);
-            self.r[REGISTER_PC as usize] += self.instruction_len_in_bytes();
-            InstructionLut::decode_thumb(instruction, self.fetch_thumb(mem))
+            let prefetch_abort = mem.take_abort();
+            self.r[REGISTER_PC as usize] = self.r[REGISTER_PC as usize].wrapping_add(self.instruction_len_in_bytes());
+            // Read without fetching, so it doesn't replace the prefetch in the BIOS latch
+            let next_instruction = mem.read_u16(self.r[REGISTER_PC as usize]);
+            // Only the instruction itself aborts, not the halfword after it
+            mem.take_abort();
+            self.trace.record(TraceEntry {
+                pc,
+                instruction: instruction as u32 | (next_instruction as u32) << 16,
+                cpsr: self.cpsr,
+            });
+            if prefetch_abort {
+                return self.prefetch_abort(pc, CYCLES_S + fetch_wait_cycles);
+            }
+            self.instruction_cache.take(pc, instruction as u32 | (next_instruction as u32) << 16, true)
         } else {
             let instruction = self.fetch_arm(mem);
-            self.r[REGISTER_PC as usize] += self.instruction_len_in_bytes();
-            let cond = Condition::decode_arm(instruction);
-            if !cond.check(self) {
+            self.trace.record(TraceEntry { pc, instruction, cpsr: self.cpsr });
+            if mem.take_abort() {
+                return self.prefetch_abort(pc, CYCLES_S + fetch_wait_cycles);
+            }
+            self.r[REGISTER_PC as usize] = self.r[REGISTER_PC as usize].wrapping_add(self.instruction_len_in_bytes());
+            // The unconditional space always executes, as an undefined instruction
+            if !is_unconditional(instruction) && !Condition::decode_arm(instruction).check(self) {
+                self.cycles += (CYCLES_S + fetch_wait_cycles) as u64;
+                self.branch_happened = false;
                 return;
             }
-            InstructionLut::decode_arm(instruction)
+            self.instruction_cache.take(pc, instruction, false)
         };
+        let decoded_instruction = &cached.instruction;
+        trace!("Executing {:08X}: {}", pc, decoded_instruction.disassemble(Condition::AL, pc));
 
         // Pc should be two instructions ahead of currently executed instruction
-        self.r[REGISTER_PC as usize] += self.instruction_len_in_bytes();
+        self.r[REGISTER_PC as usize] = self.r[REGISTER_PC as usize].wrapping_add(self.instruction_len_in_bytes());
         self.branch_happened = false;
+        let cycles = decoded_instruction.cycles(self) + decoded_instruction.wait_cycles(self, mem) + fetch_wait_cycles;
         decoded_instruction.execute(self, mem);
+        self.instruction_cache.put(cached);
+        // A faulting data access aborts, the handler returns to retry the instruction with SUBS PC, LR, #8
+        if mem.take_abort() {
+            self.raise_exception(MODE_ABT, VECTOR_DATA_ABORT, pc.wrapping_add(8));
+        }
 
         // If there was no branch set pc to the next instruction
         if !self.branch_happened {
-            self.r[REGISTER_PC as usize] -= self.instruction_len_in_bytes();
+            self.r[REGISTER_PC as usize] = self.r[REGISTER_PC as usize].wrapping_sub(self.instruction_len_in_bytes());
         }
 
-        // approximate cycle count for now
-        self.cycles += 2;
+        self.cycles += cycles as u64;
+    }
+
+    /// Fetching the instruction at `pc` faulted, the handler returns to retry it with SUBS PC, LR, #4
+    fn prefetch_abort(&mut self, pc: u32, cycles: u32) {
+        self.raise_exception(MODE_ABT, VECTOR_PREFETCH_ABORT, pc.wrapping_add(4));
+        self.cycles += cycles as u64;
+    }
+
+    /// Flags `interrupt` in IF, it is taken before the next instruction once it's enabled in IE and IME and IRQs are enabled
+    pub fn request_irq(&self, mem: &mut Memory, interrupt: Interrupt) {
+        mem.request_interrupt(interrupt);
+    }
+
+    /// Whether an IRQ is taken before the next instruction
+    pub fn irq_pending(&self, mem: &Memory) -> bool {
+        mem.interrupt_pending() && !self.get_irq_disable()
+    }
+
+    /// Enters an exception: banks the cpsr into the spsr of `mode`, stores the return address in the banked link register and jumps to `vector` in ARM state
+    pub fn raise_exception(&mut self, mode: u8, vector: u32, return_address: u32) {
+        let cpsr = self.cpsr;
+        self.set_mode(mode);
+        self.set_spsr(cpsr);
+        self.set_r(REGISTER_LR, return_address);
+        self.set_thumb_state(false);
+        self.set_irq_disable(true);
+        self.set_r(REGISTER_PC, vector);
+    }
+
+    pub fn request_debug_halt(&mut self, immediate: u16) {
+        self.debug_halt = Some(immediate);
+    }
+
+    /// Returns the immediate of the BKPT executed since the previous call
+    pub fn take_debug_halt(&mut self) -> Option<u16> {
+        self.debug_halt.take()
+    }
+
+    /// Sets the stacks and mode the BIOS leaves behind and starts at the cartridge entry point, for booting without a BIOS
+    pub fn skip_bios(&mut self) {
+        self.skip_boot();
+        self.hle_bios = Some(HleBios::default());
+    }
+
+    /// Sets the registers the BIOS boot leaves behind, the BIOS calls still go to the BIOS
+    pub fn skip_boot(&mut self) {
+        self.set_r_in_mode(REGISTER_SP, MODE_SVC, 0x03007FE0);
+        self.set_r_in_mode(REGISTER_SP, MODE_IRQ, 0x03007FA0);
+        self.set_r_in_mode(REGISTER_SP, MODE_USR, 0x03007F00);
+        self.cpsr = MODE_SYS as u32;
+        self.r[REGISTER_PC as usize] = CARTRIDGE_ENTRY;
+    }
+
+    /// Clears the registers as at power on and takes the reset exception, the instruction and branch traces are kept
+    pub fn power_on(&mut self) {
+        let trace = std::mem::replace(&mut self.trace, InstructionTrace::new(0));
+        *self = CPU {
+            trace,
+            branch_trace: self.branch_trace.take(),
+            strict: self.strict,
+            ..CPU::new()
+        };
+    }
 
-        sleep(INSTRUCTION_TIME);
+    /// Makes undefined instructions panic, which helps finding unimplemented ones
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
     }
 
+    pub fn is_strict(&self) -> bool {
+        self.strict
+    }
+
+    /// Takes the reset exception, which restarts execution at the start of the BIOS
     fn reset(&mut self) {
         self.set_mode(MODE_SVC);
         self.set_thumb_state(false);
@@ -206,15 +352,11 @@ impl CPU {
     }
 
     fn fetch_arm(&self, mem: &Memory) -> u32 {
-        mem.read_u32(self.r[REGISTER_PC as usize])
+        mem.fetch_u32(self.r[REGISTER_PC as usize])
     }
 
     fn fetch_thumb(&self, mem: &Memory) -> u16 {
-        mem.read_u16(self.r[REGISTER_PC as usize])
-    }
-
-    fn fetch_next_thumb(&self, mem: &Memory) -> u16 {
-        mem.read_u16(self.r[REGISTER_PC as usize] + INSTRUCTION_LEN_THUMB)
+        mem.fetch_u16(self.r[REGISTER_PC as usize])
     }
 
     pub fn instruction_len_in_bytes(&self) -> u32 {
@@ -225,6 +367,12 @@ impl CPU {
         }
     }
 
+    /// The pc as pc relative addressing sees it: two instructions ahead of the executing one and word aligned,
+    /// which only makes a difference in thumb state
+    pub fn pc_for_operand(&self) -> u32 {
+        self.r[REGISTER_PC as usize] & !0b11
+    }
+
     pub fn next_instruction_address_from_execution_stage(&self) -> u32 {
         self.r[REGISTER_PC as usize] - self.instruction_len_in_bytes()
     }
@@ -260,6 +408,14 @@ impl CPU {
         self.cpsr = set_bit32(self.cpsr, 28, v);
     }
 
+    /// The sticky Q flag of the ARMv5 saturating instructions
+    pub fn get_saturation_flag(&self) -> bool {
+        get_bit(self.cpsr, 27)
+    }
+    pub fn set_saturation_flag(&mut self, v: bool) {
+        self.cpsr = set_bit32(self.cpsr, 27, v);
+    }
+
     pub fn get_irq_disable(&self) -> bool {
         get_bit(self.cpsr, 7)
     }
@@ -297,6 +453,51 @@ impl CPU {
         self.cycles
     }
 
+    pub fn trace(&self) -> &InstructionTrace {
+        &self.trace
+    }
+
+    /// Keeps the last `len` executed instructions, 0 disables tracing
+    pub fn set_trace_len(&mut self, len: usize) {
+        self.trace = InstructionTrace::new(len);
+    }
+
+    /// Starts recording the taken branches, or stops and discards them
+    pub fn set_branch_trace(&mut self, enabled: bool) {
+        self.branch_trace = enabled.then(BranchTrace::default);
+    }
+
+    pub fn branch_trace(&self) -> Option<&BranchTrace> {
+        self.branch_trace.as_ref()
+    }
+
+    /// Records a branch from `source` to the pc, if branches are traced
+    pub fn record_branch(&mut self, source: u32, call: bool) {
+        let target = self.r[REGISTER_PC as usize];
+        if let Some(branch_trace) = &mut self.branch_trace {
+            branch_trace.record(BranchEdge { source, target, call });
+        }
+    }
+
+    fn state_registers(&mut self) -> impl Iterator<Item = &mut u32> {
+        let registers = [&mut self.r[..], &mut self.r_svc, &mut self.r_abt, &mut self.r_und, &mut self.r_irq, &mut self.r_fiq];
+        let spsrs = [&mut self.spsr_svc, &mut self.spsr_abt, &mut self.spsr_und, &mut self.spsr_irq, &mut self.spsr_fiq];
+        std::iter::once(&mut self.cpsr).chain(registers.into_iter().flatten()).chain(spsrs)
+    }
+
+    pub fn save_state(&mut self, state: &mut StateWriter) {
+        self.state_registers().for_each(|r| state.write_u32(*r));
+        state.write_u64(self.cycles);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        for r in self.state_registers() {
+            *r = state.read_u32()?;
+        }
+        self.cycles = state.read_u64()?;
+        Ok(())
+    }
+
     pub fn print_registers(&self) {
         for i in (0..16u8).step_by(4) {
             println!(
@@ -313,31 +514,324 @@ impl CPU {
         }
     }
 
+    /// The banked SP, LR and SPSR of each mode, user mode shares its registers with system mode
+    pub fn format_banked(&self) -> String {
+        let mut output = String::new();
+        for mode in [MODE_USR, MODE_SVC, MODE_IRQ, MODE_FIQ, MODE_ABT, MODE_UND] {
+            output.push_str(&format!(
+                "{}: SP: {:08X}   LR: {:08X}",
+                format_mode(mode),
+                self.get_r_in_mode(REGISTER_SP, mode),
+                self.get_r_in_mode(REGISTER_LR, mode)
+            ));
+            if mode != MODE_USR {
+                output.push_str(&format!("   SPSR: {:08X}", self.get_spsr_in_mode(mode)));
+            }
+            output.push('\n');
+        }
+        output
+    }
+
+    pub fn print_banked(&self) {
+        print!("{}", self.format_banked());
+    }
+
     pub fn print_status(&self) {
-        println!(
-            "CPSR: {:08X} [{}{}{}{}{}{}{}] MODE: {}",
+        println!("{}", self.format_status());
+    }
+
+    pub fn format_status(&self) -> String {
+        format!(
+            "CPSR: {:08X} [{}{}{}{}{}{}{}{}] MODE: {}",
             self.cpsr,
             if self.get_negative_flag() { 'N' } else { '-' },
             if self.get_zero_flag() { 'Z' } else { '-' },
             if self.get_carry_flag() { 'C' } else { '-' },
             if self.get_overflow_flag() { 'V' } else { '-' },
+            if self.get_saturation_flag() { 'Q' } else { '-' },
             if self.get_irq_disable() { 'I' } else { '-' },
             if self.get_fiq_disable() { 'F' } else { '-' },
             if self.get_thumb_state() { 'T' } else { '-' },
             format_mode(self.get_mode()),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bios_with(instructions: &[u32]) -> Vec<u8> {
+        let mut bios = vec![0; 0x4000];
+        for (i, instruction) in instructions.iter().enumerate() {
+            bios[i * 4..i * 4 + 4].copy_from_slice(&instruction.to_le_bytes());
+        }
+        bios
+    }
+
+    #[test]
+    fn test_cycle_counts() {
+        // MOV R0, #1; MOVEQ R0, #2; LDR R1, [R0]; B .
+        let mut mem = Memory::new(bios_with(&[0xE3A00001, 0x03A00002, 0xE5901000, 0xEAFFFFFE]), vec![0; 0x100]);
+        let mut cpu = CPU::new();
+
+        cpu.cycle(&mut mem);
+        assert_eq!(cpu.get_cycles(), 1);
+        cpu.cycle(&mut mem);
+        assert_eq!(cpu.get_cycles(), 2);
+        cpu.cycle(&mut mem);
+        assert_eq!(cpu.get_cycles(), 5);
+        cpu.cycle(&mut mem);
+        assert_eq!(cpu.get_cycles(), 8);
+        assert_eq!(cpu.get_r(REGISTER_PC), 0x0000000C);
+    }
+
+    #[test]
+    fn test_wait_cycles() {
+        // LDR R1, [R0]
+        let mut mem = Memory::new(bios_with(&[0xE5901000; 2]), vec![0; 0x100]);
+        let mut cpu = CPU::new();
+
+        // On-board WRAM takes 5 more cycles for a word than on-chip WRAM
+        cpu.set_r(0, 0x03_000_000);
+        cpu.cycle(&mut mem);
+        let on_chip = cpu.get_cycles();
+        cpu.set_r(0, 0x02_000_000);
+        cpu.cycle(&mut mem);
+        assert_eq!(cpu.get_cycles() - on_chip, on_chip + 5);
+    }
+
+    #[test]
+    fn test_irq_dispatch() {
+        use crate::system::interrupts::{REG_IE, REG_IF, REG_IME};
+
+        // MOV R0, #1 everywhere
+        let mut mem = Memory::new(bios_with(&[0xE3A00001; 16]), vec![0; 0x100]);
+        let mut cpu = CPU::new();
+        cpu.set_mode(MODE_SYS);
+        cpu.set_r(REGISTER_PC, 0x00000008);
+
+        mem.write_u16(REG_IE, Interrupt::VBlank.mask());
+        mem.write_u16(REG_IME, 1);
+        cpu.request_irq(&mut mem, Interrupt::VBlank);
+        assert_eq!(mem.read_u16(REG_IF), Interrupt::VBlank.mask());
+
+        // Masked interrupts stay pending
+        assert!(!cpu.irq_pending(&mem));
+        cpu.cycle(&mut mem);
+        assert_eq!(cpu.get_mode(), MODE_SYS);
+        assert_eq!(cpu.get_r(REGISTER_PC), 0x0000000C);
+
+        cpu.set_irq_disable(false);
+        assert!(cpu.irq_pending(&mem));
+        let cpsr = cpu.get_cpsr();
+        cpu.cycle(&mut mem);
+        assert_eq!(cpu.get_mode(), MODE_IRQ);
+        assert_eq!(cpu.get_spsr(), cpsr);
+        assert!(cpu.get_irq_disable());
+        assert_eq!(cpu.get_r(REGISTER_LR), 0x00000010);
+        // The first handler instruction at the vector has been executed
+        assert_eq!(cpu.get_r(REGISTER_PC), VECTOR_IRQ + INSTRUCTION_LEN_ARM);
+
+        // The handler acknowledges the interrupt by writing the flag back
+        mem.write_u16(REG_IF, mem.read_u16(REG_IF));
+        assert_eq!(mem.read_u16(REG_IF), 0);
+        assert!(!mem.interrupt_pending());
+    }
+
+    #[test]
+    fn test_undefined_instruction() {
+        let mut program = [0xE3A00001; 16];
+        program[4] = 0xE7F000F0; // Undefined
+        program[8] = 0x0000B100; // Undefined in thumb
+        let mut mem = Memory::new(bios_with(&program), vec![0; 0x100]);
+        let mut cpu = CPU::new();
+        cpu.set_mode(MODE_SYS);
+        cpu.set_irq_disable(false);
+        cpu.set_r(REGISTER_PC, 0x00000010);
+
+        let cpsr = cpu.get_cpsr();
+        cpu.cycle(&mut mem);
+        assert_eq!(cpu.get_mode(), MODE_UND);
+        assert_eq!(cpu.get_spsr(), cpsr);
+        assert!(cpu.get_irq_disable());
+        assert_eq!(cpu.get_r(REGISTER_LR), 0x00000014);
+        assert_eq!(cpu.get_r(REGISTER_PC), VECTOR_UNDEFINED);
+
+        // In thumb the return address is the next halfword
+        cpu.set_mode(MODE_SYS);
+        cpu.set_thumb_state(true);
+        cpu.set_r(REGISTER_PC, 0x00000020);
+        cpu.cycle(&mut mem);
+        assert_eq!(cpu.get_mode(), MODE_UND);
+        assert!(!cpu.get_thumb_state());
+        assert_eq!(cpu.get_r(REGISTER_LR), 0x00000022);
+    }
+
+    #[test]
+    fn test_format_banked() {
+        let mut cpu = CPU::new();
+        cpu.set_mode(MODE_SYS);
+        cpu.set_r(REGISTER_SP, 0x03_007_F00);
+        cpu.set_mode(MODE_SVC);
+        cpu.set_r(REGISTER_SP, 0x03_007_FE0);
+        cpu.set_spsr(0x1F);
+
+        let banked = cpu.format_banked();
+        let lines: Vec<&str> = banked.lines().collect();
+        assert_eq!(lines.len(), 6);
+        assert_eq!(lines[0], "USR: SP: 03007F00   LR: 00000000");
+        assert_eq!(lines[1], "SVC: SP: 03007FE0   LR: 00000000   SPSR: 0000001F");
+        assert!(lines[2].starts_with("IRQ: SP: 00000000"));
+    }
+
+    #[test]
+    fn test_aborts() {
+        let mut mem = Memory::new(bios_with(&[0xE5910000; 8]), vec![0; 0x100]); // LDR R0, [R1]
+        let mut cpu = CPU::new();
+        cpu.set_mode(MODE_SYS);
+        cpu.set_r(REGISTER_PC, 0x00000008);
+        cpu.set_r(1, 0x01_000_000);
+
+        // Faults are ignored unless they abort
+        cpu.cycle(&mut mem);
+        assert_eq!(cpu.get_mode(), MODE_SYS);
+
+        mem.set_abort_on_fault(true);
+        let cpsr = cpu.get_cpsr();
+        cpu.cycle(&mut mem);
+        assert_eq!(cpu.get_mode(), MODE_ABT);
+        assert_eq!(cpu.get_spsr(), cpsr);
+        assert!(cpu.get_irq_disable());
+        assert_eq!(cpu.get_r(REGISTER_LR), 0x0000000C + 8);
+        assert_eq!(cpu.get_r(REGISTER_PC), VECTOR_DATA_ABORT);
+
+        // Executing unmapped memory aborts before the instruction runs
+        cpu.set_mode(MODE_SYS);
+        cpu.set_r(REGISTER_PC, 0x01_000_000);
+        cpu.cycle(&mut mem);
+        assert_eq!(cpu.get_mode(), MODE_ABT);
+        assert_eq!(cpu.get_r(REGISTER_LR), 0x01_000_004);
+        assert_eq!(cpu.get_r(REGISTER_PC), VECTOR_PREFETCH_ABORT);
+
+        // The return address wraps at the end of the address space
+        for thumb in [false, true] {
+            cpu.set_mode(MODE_SYS);
+            cpu.set_thumb_state(thumb);
+            cpu.set_r(REGISTER_PC, if thumb { 0xFFFF_FFFE } else { 0xFFFF_FFFC });
+            cpu.cycle(&mut mem);
+            assert_eq!(cpu.get_mode(), MODE_ABT);
+            assert_eq!(cpu.get_r(REGISTER_LR), if thumb { 0x0000_0002 } else { 0x0000_0000 });
+            assert_eq!(cpu.get_r(REGISTER_PC), VECTOR_PREFETCH_ABORT);
+        }
+    }
+
+    #[test]
+    fn test_unconditional_instruction() {
+        let mut mem = Memory::new(bios_with(&[0xE3A00001, 0xE3A00001, 0xF0000000]), vec![0; 0x100]);
+        let mut cpu = CPU::new();
+        cpu.set_mode(MODE_SYS);
+        cpu.set_r(REGISTER_PC, 0x00000008);
+        cpu.cycle(&mut mem);
+        assert_eq!(cpu.get_mode(), MODE_UND);
+        assert_eq!(cpu.get_r(REGISTER_PC), 0x04);
+        assert_eq!(cpu.get_r(REGISTER_LR), 0x0000000C);
+    }
+
+    #[test]
+    #[should_panic(expected = "Tried to execute unknown arm instruction: F0000000")]
+    fn test_strict_unconditional_instruction() {
+        let mut mem = Memory::new(bios_with(&[0xF0000000]), vec![0; 0x100]);
+        let mut cpu = CPU::new();
+        cpu.set_strict(true);
+        cpu.set_r(REGISTER_PC, 0x00000000);
+        cpu.cycle(&mut mem);
+    }
+
+    #[test]
+    #[should_panic(expected = "Tried to execute unknown arm instruction: E7F000F0")]
+    fn test_strict_undefined_instruction() {
+        let mut mem = Memory::new(bios_with(&[0xE7F000F0]), vec![0; 0x100]);
+        let mut cpu = CPU::new();
+        cpu.set_strict(true);
+        cpu.set_r(REGISTER_PC, 0x00000000);
+        cpu.cycle(&mut mem);
+    }
+
+    #[test]
+    fn test_branch_trace() {
+        let mut mem = Memory::new(
+            bios_with(&[
+                0xEB000001, // 00000000: BL 0000000C
+                0xEAFFFFFE, // 00000004: B 00000004
+                0x00000000, 0xE2800001, // 0000000C: ADD R0, R0, #1
+                0xE12FFF1E, // 00000010: BX LR
+            ]),
+            vec![0; 0x100],
+        );
+        let mut cpu = CPU::new();
+        cpu.set_branch_trace(true);
+        for _ in 0..5 {
+            cpu.cycle(&mut mem);
+        }
+
+        let edges: Vec<_> = cpu.branch_trace().unwrap().edges().map(|(&edge, &count)| (edge, count)).collect();
+        assert_eq!(
+            edges,
+            [
+                (
+                    BranchEdge {
+                        source: 0x00,
+                        target: 0x0C,
+                        call: true
+                    },
+                    1
+                ),
+                (
+                    BranchEdge {
+                        source: 0x04,
+                        target: 0x04,
+                        call: false
+                    },
+                    2
+                ),
+                (
+                    BranchEdge {
+                        source: 0x10,
+                        target: 0x04,
+                        call: false
+                    },
+                    1
+                ),
+            ]
         );
+        assert_eq!(cpu.branch_trace().unwrap().export_dot(), "digraph calls {\n    \"entry\" -> \"0000000C\";\n}\n");
+
+        cpu.set_branch_trace(false);
+        assert!(cpu.branch_trace().is_none());
     }
 
-    pub fn print_next_instruction(&self, mem: &Memory) {
-        let pc = self.r[REGISTER_PC as usize];
-        if self.get_thumb_state() {
-            println!(
-                "Next thumb instruction at {:08X}: {}",
-                pc,
-                format_instruction_thumb(self.fetch_thumb(mem), self.fetch_next_thumb(mem), pc)
-            );
-        } else {
-            println!("Next arm instruction at {:08X}: {}", pc, format_instruction_arm(self.fetch_arm(mem), pc));
+    #[test]
+    fn test_instruction_cache() {
+        // ADD R0, R0, #1; B .-4
+        let mut mem = Memory::new(bios_with(&[0xE2800001, 0xEAFFFFFD]), vec![0; 0x100]);
+        let mut cpu = CPU::new();
+        for _ in 0..10_000 {
+            cpu.cycle(&mut mem);
         }
+        assert_eq!(cpu.get_r(0), 5_000);
+        // A loop is only decoded once
+        assert_eq!(cpu.instruction_cache.misses(), 2);
+
+        // Rewriting an instruction decodes it again: MOV R0, #0; B .-4
+        mem.write_u32(0x02_000_000, 0xE3A00000);
+        mem.write_u32(0x02_000_004, 0xEAFFFFFD);
+        cpu.set_r(REGISTER_PC, 0x02_000_000);
+        cpu.cycle(&mut mem);
+        mem.write_u32(0x02_000_000, 0xE3A00007); // MOV R0, #7
+        cpu.cycle(&mut mem);
+        cpu.cycle(&mut mem);
+        assert_eq!(cpu.get_r(0), 7);
+        assert_eq!(cpu.instruction_cache.misses(), 5);
     }
 }
@@ -3,17 +3,39 @@ use std::sync::{Arc, RwLock};
 use winit::{
     application::ApplicationHandler,
     dpi::Size,
-    event::WindowEvent,
+    event::{ElementState, WindowEvent},
     event_loop::{ActiveEventLoop, ControlFlow, EventLoop},
+    keyboard::{KeyCode, PhysicalKey},
     window::{Window, WindowAttributes, WindowButtons, WindowId},
 };
 
-use super::ppu::{Framebuffer, FRAMEBUFFER_HEIGHT, FRAMEBUFFER_WIDTH};
+use crate::frame_limiter::Unthrottled;
+
+use super::{
+    keypad::{Button, Keypad},
+    ppu::{copy_rgba, Framebuffer, FRAMEBUFFER_HEIGHT, FRAMEBUFFER_WIDTH},
+};
+
+/// How the framebuffer is fitted to the window
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scaling {
+    // Largest whole multiple that fits, centered with black borders
+    Integer,
+    // Fills the whole window, ignoring the aspect ratio
+    Stretch,
+}
 
 pub struct Display {
     window: Option<Arc<Window>>,
     pixels: Option<Pixels<'static>>,
     framebuffer: Arc<RwLock<Framebuffer>>,
+    keypad: Keypad,
+    key_map: KeyMap,
+    unthrottled: Unthrottled,
+    scaling: Scaling,
+    // Framebuffer column and row of each buffer pixel when stretching, computed on resize
+    source_columns: Vec<usize>,
+    source_rows: Vec<usize>,
 }
 
 #[derive(Debug)]
@@ -21,8 +43,120 @@ pub enum DisplayEvent {
     RedrawRequested,
 }
 
+/// Host key bound to each GBA button, indexed by the button's bit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyMap {
+    keys: [KeyCode; 10],
+}
+
+impl Default for KeyMap {
+    fn default() -> Self {
+        Self {
+            keys: [
+                KeyCode::KeyX,
+                KeyCode::KeyZ,
+                KeyCode::Backspace,
+                KeyCode::Enter,
+                KeyCode::ArrowRight,
+                KeyCode::ArrowLeft,
+                KeyCode::ArrowUp,
+                KeyCode::ArrowDown,
+                KeyCode::KeyS,
+                KeyCode::KeyA,
+            ],
+        }
+    }
+}
+
+impl KeyMap {
+    /// Binds `button` to `key`, unbinding whichever button had `key` before
+    pub fn rebind(&mut self, button: Button, key: KeyCode) {
+        let old_key = self.keys[button as usize];
+        if let Some(other) = self.keys.iter_mut().find(|bound| **bound == key) {
+            // Swap so the other button keeps a key instead of sharing one
+            *other = old_key;
+        }
+        self.keys[button as usize] = key;
+    }
+
+    pub fn button(&self, key: KeyCode) -> Option<Button> {
+        self.keys.iter().position(|&bound| bound == key).map(|i| Button::ALL[i])
+    }
+}
+
+/// Parses the name of a letter, digit, arrow or a few other keys, like "j", "5", "up" or "space"
+pub fn parse_key(name: &str) -> Result<KeyCode, String> {
+    const LETTERS: [KeyCode; 26] = [
+        KeyCode::KeyA,
+        KeyCode::KeyB,
+        KeyCode::KeyC,
+        KeyCode::KeyD,
+        KeyCode::KeyE,
+        KeyCode::KeyF,
+        KeyCode::KeyG,
+        KeyCode::KeyH,
+        KeyCode::KeyI,
+        KeyCode::KeyJ,
+        KeyCode::KeyK,
+        KeyCode::KeyL,
+        KeyCode::KeyM,
+        KeyCode::KeyN,
+        KeyCode::KeyO,
+        KeyCode::KeyP,
+        KeyCode::KeyQ,
+        KeyCode::KeyR,
+        KeyCode::KeyS,
+        KeyCode::KeyT,
+        KeyCode::KeyU,
+        KeyCode::KeyV,
+        KeyCode::KeyW,
+        KeyCode::KeyX,
+        KeyCode::KeyY,
+        KeyCode::KeyZ,
+    ];
+    const DIGITS: [KeyCode; 10] = [
+        KeyCode::Digit0,
+        KeyCode::Digit1,
+        KeyCode::Digit2,
+        KeyCode::Digit3,
+        KeyCode::Digit4,
+        KeyCode::Digit5,
+        KeyCode::Digit6,
+        KeyCode::Digit7,
+        KeyCode::Digit8,
+        KeyCode::Digit9,
+    ];
+
+    let name = name.to_ascii_lowercase();
+    if let [c] = name.as_bytes() {
+        match c {
+            b'a'..=b'z' => return Ok(LETTERS[(c - b'a') as usize]),
+            b'0'..=b'9' => return Ok(DIGITS[(c - b'0') as usize]),
+            _ => (),
+        }
+    }
+    match name.as_str() {
+        "up" => Ok(KeyCode::ArrowUp),
+        "down" => Ok(KeyCode::ArrowDown),
+        "left" => Ok(KeyCode::ArrowLeft),
+        "right" => Ok(KeyCode::ArrowRight),
+        "enter" => Ok(KeyCode::Enter),
+        "backspace" => Ok(KeyCode::Backspace),
+        "space" => Ok(KeyCode::Space),
+        "tab" => Ok(KeyCode::Tab),
+        "shift" => Ok(KeyCode::ShiftLeft),
+        "rshift" => Ok(KeyCode::ShiftRight),
+        _ => Err(format!("Unknown key: {}", name)),
+    }
+}
+
+/// Nearest neighbour source index for each of the `len` destination pixels
+fn nearest_indices(source_len: usize, len: usize) -> Vec<usize> {
+    (0..len).map(|i| i * source_len / len).collect()
+}
+
 impl Display {
-    pub fn new(framebuffer: Arc<RwLock<Framebuffer>>) -> (Self, EventLoop<DisplayEvent>) {
+    pub fn new(framebuffer: Arc<RwLock<Framebuffer>>, keypad: Keypad, key_map: KeyMap, unthrottled: Unthrottled, scaling: Scaling) -> (Self, EventLoop<DisplayEvent>) {
         let event_loop = EventLoop::<DisplayEvent>::with_user_event().build().expect("Failed to create event loop");
         event_loop.set_control_flow(ControlFlow::Poll);
 
@@ -31,10 +165,40 @@ impl Display {
                 window: None,
                 pixels: None,
                 framebuffer,
+                keypad,
+                key_map,
+                unthrottled,
+                scaling,
+                source_columns: Vec::new(),
+                source_rows: Vec::new(),
             },
             event_loop,
         )
     }
+
+    fn resize(&mut self) {
+        let (Some(window), Some(pixels)) = (self.window.as_ref(), self.pixels.as_mut()) else {
+            return;
+        };
+        let size = window.inner_size();
+        // Minimized windows have no size
+        if size.width == 0 || size.height == 0 {
+            return;
+        }
+
+        pixels.resize_surface(size.width, size.height).expect("Failed to resize surface");
+        match self.scaling {
+            // Pixels already scales its buffer by whole multiples and centers it
+            Scaling::Integer => pixels.resize_buffer(FRAMEBUFFER_WIDTH as u32, FRAMEBUFFER_HEIGHT as u32),
+            Scaling::Stretch => {
+                self.source_columns = nearest_indices(FRAMEBUFFER_WIDTH, size.width as usize);
+                self.source_rows = nearest_indices(FRAMEBUFFER_HEIGHT, size.height as usize);
+                pixels.resize_buffer(size.width, size.height)
+            }
+        }
+        .expect("Failed to resize buffer");
+        window.request_redraw();
+    }
 }
 
 impl ApplicationHandler<DisplayEvent> for Display {
@@ -53,9 +217,9 @@ impl ApplicationHandler<DisplayEvent> for Display {
         let surface_texture = SurfaceTexture::new(window_size.width, window_size.height, window.clone());
         let pixels = Pixels::new(FRAMEBUFFER_WIDTH as u32, FRAMEBUFFER_HEIGHT as u32, surface_texture).expect("Failed to create pixels buffer");
 
-        self.window = Some(window.clone());
+        self.window = Some(window);
         self.pixels = Some(pixels);
-        window.request_redraw();
+        self.resize();
     }
 
     fn user_event(&mut self, _event_loop: &ActiveEventLoop, event: DisplayEvent) {
@@ -69,30 +233,39 @@ impl ApplicationHandler<DisplayEvent> for Display {
     fn window_event(&mut self, event_loop: &ActiveEventLoop, _window_id: WindowId, event: WindowEvent) {
         match event {
             WindowEvent::CloseRequested => event_loop.exit(),
-            WindowEvent::Resized(_) => {
-                self.window.as_ref().unwrap().request_redraw();
+            WindowEvent::KeyboardInput { event, .. } => {
+                if let PhysicalKey::Code(key) = event.physical_key {
+                    if let Some(button) = self.key_map.button(key) {
+                        self.keypad.set_pressed(button, event.state == ElementState::Pressed);
+                    } else if key == KeyCode::Tab && event.state == ElementState::Pressed && !event.repeat {
+                        let unthrottled = self.unthrottled.toggle();
+                        println!("Turbo {}", if unthrottled { "on" } else { "off" });
+                    } else if key == KeyCode::KeyF && event.state == ElementState::Pressed && !event.repeat {
+                        self.scaling = match self.scaling {
+                            Scaling::Integer => Scaling::Stretch,
+                            Scaling::Stretch => Scaling::Integer,
+                        };
+                        self.resize();
+                    }
+                }
             }
+            WindowEvent::Resized(_) => self.resize(),
             WindowEvent::RedrawRequested => {
                 let pixels = self.pixels.as_mut().unwrap();
-                let window = self.window.as_ref().unwrap();
-
-                let (width, height) = {
-                    let size = window.inner_size();
-                    (size.width, size.height)
-                };
-
-                pixels.resize_surface(width, height).expect("Failed to resize surface");
-
                 let framebuffer = &self.framebuffer.read().unwrap();
                 let frame = pixels.frame_mut();
 
-                for (i, pixel) in frame.chunks_exact_mut(4).enumerate() {
-                    let x = (i % FRAMEBUFFER_WIDTH) as usize;
-                    let y = (i / FRAMEBUFFER_WIDTH) as usize;
-                    pixel[0] = framebuffer[y][x][0]; // R
-                    pixel[1] = framebuffer[y][x][1]; // G
-                    pixel[2] = framebuffer[y][x][2]; // B
-                    pixel[3] = 255; // A
+                match self.scaling {
+                    Scaling::Integer => copy_rgba(framebuffer, frame),
+                    Scaling::Stretch => {
+                        let mut pixels = frame.chunks_exact_mut(4);
+                        for &y in &self.source_rows {
+                            for (&x, pixel) in self.source_columns.iter().zip(&mut pixels) {
+                                pixel[..3].copy_from_slice(&framebuffer[y][x]);
+                                pixel[3] = 255;
+                            }
+                        }
+                    }
                 }
 
                 pixels.render().expect("Failed to render frame");
@@ -101,3 +274,43 @@ impl ApplicationHandler<DisplayEvent> for Display {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_map() {
+        let default = KeyMap::default();
+        assert_eq!(default.button(KeyCode::Enter), Some(Button::Start));
+        assert_eq!(default.button(KeyCode::KeyQ), None);
+
+        let mut key_map = KeyMap::default();
+        key_map.rebind(Button::A, KeyCode::KeyJ);
+        let keypad = Keypad::default();
+        keypad.set_pressed(key_map.button(KeyCode::KeyJ).unwrap(), true);
+        assert_eq!(keypad.keyinput() & 1 << Button::A as u16, 0);
+        assert_eq!(key_map.button(KeyCode::KeyX), None);
+
+        // Taking another button's key hands it the old one
+        key_map.rebind(Button::B, KeyCode::KeyJ);
+        assert_eq!(key_map.button(KeyCode::KeyJ), Some(Button::B));
+        assert_eq!(key_map.button(KeyCode::KeyZ), Some(Button::A));
+
+        assert_eq!(parse_key("J"), Ok(KeyCode::KeyJ));
+        assert_eq!(parse_key("5"), Ok(KeyCode::Digit5));
+        assert_eq!(parse_key("space"), Ok(KeyCode::Space));
+        assert!(parse_key("f13").is_err());
+    }
+
+    #[test]
+    fn test_nearest_indices() {
+        assert_eq!(nearest_indices(3, 6), [0, 0, 1, 1, 2, 2]);
+        assert_eq!(nearest_indices(4, 2), [0, 2]);
+        // Odd factors still cover the whole source
+        let columns = nearest_indices(FRAMEBUFFER_WIDTH, 1000);
+        assert_eq!(columns[0], 0);
+        assert_eq!(columns[999], FRAMEBUFFER_WIDTH - 1);
+        assert!(columns.windows(2).all(|pair| pair[1] - pair[0] <= 1));
+    }
+}
@@ -0,0 +1,290 @@
+use std::io;
+
+use super::{
+    interrupts::Interrupt,
+    memory::Memory,
+    state::{StateReader, StateWriter},
+};
+
+pub const REG_DMA0SAD: u32 = 0x04_000_0B0;
+pub const REG_DMA0CNT_H: u32 = 0x04_000_0BA;
+pub const REG_DMA3CNT_H: u32 = 0x04_000_0DE;
+// Each channel has SAD, DAD, CNT_L and CNT_H
+pub const DMA_CHANNEL_LEN: u32 = 12;
+
+// DMAxCNT_H bits
+const DMACNT_REPEAT: u16 = 1 << 9;
+const DMACNT_32BIT: u16 = 1 << 10;
+const DMACNT_IRQ: u16 = 1 << 14;
+pub const DMACNT_ENABLE: u16 = 1 << 15;
+
+// Address control values
+const ADDRESS_INCREMENT: u16 = 0;
+const ADDRESS_DECREMENT: u16 = 1;
+const ADDRESS_FIXED: u16 = 2;
+const ADDRESS_INCREMENT_RELOAD: u16 = 3;
+
+// Channel 0 can't access the game pak and only channel 3 can write to it
+const SOURCE_MASKS: [u32; 4] = [0x07_FFF_FFF, 0x0F_FFF_FFF, 0x0F_FFF_FFF, 0x0F_FFF_FFF];
+const DESTINATION_MASKS: [u32; 4] = [0x07_FFF_FFF, 0x07_FFF_FFF, 0x07_FFF_FFF, 0x0F_FFF_FFF];
+const MAX_COUNTS: [u32; 4] = [0x4000, 0x4000, 0x4000, 0x10000];
+const INTERRUPTS: [Interrupt; 4] = [Interrupt::Dma0, Interrupt::Dma1, Interrupt::Dma2, Interrupt::Dma3];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmaTiming {
+    Immediate = 0,
+    VBlank = 1,
+    HBlank = 2,
+    // Sound FIFO transfers on channels 1 and 2, video capture on channel 3 isn't supported yet
+    Special = 3,
+}
+
+impl DmaTiming {
+    fn from_control(control: u16) -> Self {
+        match (control >> 12) & 0b11 {
+            0 => DmaTiming::Immediate,
+            1 => DmaTiming::VBlank,
+            2 => DmaTiming::HBlank,
+            _ => DmaTiming::Special,
+        }
+    }
+}
+
+/// Internal registers of a channel, latched when it gets enabled
+#[derive(Debug, Clone, Copy, Default)]
+struct Channel {
+    source: u32,
+    destination: u32,
+    count: u32,
+}
+
+#[derive(Debug, Default)]
+pub struct Dma {
+    channels: [Channel; 4],
+}
+
+fn register(channel: usize, offset: u32) -> u32 {
+    REG_DMA0SAD + channel as u32 * DMA_CHANNEL_LEN + offset
+}
+
+fn control(channel: usize, mem: &Memory) -> u16 {
+    mem.io_u16(register(channel, 10))
+}
+
+fn word_count(channel: usize, mem: &Memory) -> u32 {
+    // A count of 0 transfers the maximum amount
+    match mem.io_u16(register(channel, 8)) as u32 & (MAX_COUNTS[channel] - 1) {
+        0 => MAX_COUNTS[channel],
+        count => count,
+    }
+}
+
+fn step_address(address: u32, address_control: u16, unit: u32) -> u32 {
+    match address_control {
+        // The reload setting is prohibited for the source and behaves like increment
+        ADDRESS_INCREMENT | ADDRESS_INCREMENT_RELOAD => address.wrapping_add(unit),
+        ADDRESS_DECREMENT => address.wrapping_sub(unit),
+        ADDRESS_FIXED => address,
+        _ => unreachable!(),
+    }
+}
+
+impl Dma {
+    pub fn save_state(&self, state: &mut StateWriter) {
+        for channel in &self.channels {
+            state.write_u32(channel.source);
+            state.write_u32(channel.destination);
+            state.write_u32(channel.count);
+        }
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        for channel in &mut self.channels {
+            channel.source = state.read_u32()?;
+            channel.destination = state.read_u32()?;
+            channel.count = state.read_u32()?;
+        }
+        Ok(())
+    }
+
+    /// Latches the registers of a channel that just got enabled and starts immediate transfers
+    pub fn enable(&mut self, channel: usize, mem: &mut Memory) {
+        self.channels[channel] = Channel {
+            source: mem.io_u32(register(channel, 0)) & SOURCE_MASKS[channel],
+            destination: mem.io_u32(register(channel, 4)) & DESTINATION_MASKS[channel],
+            count: word_count(channel, mem),
+        };
+
+        if DmaTiming::from_control(control(channel, mem)) == DmaTiming::Immediate {
+            self.transfer(channel, mem);
+        }
+    }
+
+    /// Starts the enabled channels waiting for `timing`
+    pub fn trigger(&mut self, timing: DmaTiming, mem: &mut Memory) {
+        for channel in 0..4 {
+            let control = control(channel, mem);
+            if control & DMACNT_ENABLE != 0 && DmaTiming::from_control(control) == timing {
+                self.transfer(channel, mem);
+            }
+        }
+    }
+
+    /// Refills the sound FIFO at `fifo_address` from the channels 1 and 2 set up to write to it
+    pub fn trigger_fifo(&mut self, fifo_address: u32, mem: &mut Memory) {
+        for channel in 1..=2 {
+            let control = control(channel, mem);
+            if control & DMACNT_ENABLE != 0 && DmaTiming::from_control(control) == DmaTiming::Special && self.channels[channel].destination == fifo_address {
+                self.transfer_fifo(channel, mem);
+            }
+        }
+    }
+
+    /// Sound DMA ignores the word count, the width and the destination control, it always writes 4 words to the FIFO and repeats
+    fn transfer_fifo(&mut self, channel: usize, mem: &mut Memory) {
+        let control = control(channel, mem);
+        let source_control = (control >> 7) & 0b11;
+        let state = &mut self.channels[channel];
+        for _ in 0..4 {
+            let value = mem.read_u32(state.source & !3);
+            mem.write_u32(state.destination, value);
+            state.source = step_address(state.source, source_control, 4);
+        }
+
+        if control & DMACNT_IRQ != 0 {
+            mem.request_interrupt(INTERRUPTS[channel]);
+        }
+    }
+
+    fn transfer(&mut self, channel: usize, mem: &mut Memory) {
+        let control = control(channel, mem);
+        let destination_control = (control >> 5) & 0b11;
+        let source_control = (control >> 7) & 0b11;
+        let unit = if control & DMACNT_32BIT != 0 { 4 } else { 2 };
+
+        let state = &mut self.channels[channel];
+        // The EEPROM size is only known from the length of its commands
+        mem.detect_eeprom_size(state.destination, state.count);
+        let len = state.count * unit;
+        let (source, destination) = (state.source & !(unit - 1), state.destination & !(unit - 1));
+        let increments = |address_control| matches!(address_control, ADDRESS_INCREMENT | ADDRESS_INCREMENT_RELOAD);
+        // A destination just after the source reads what was written earlier in the transfer, which copying in one go wouldn't
+        let overlaps = mem.overlaps_ahead(destination, source, len);
+        // Copies between plain memory are done in one go
+        if increments(source_control) && increments(destination_control) && !overlaps && mem.is_plain_memory(source, len) && mem.is_plain_memory(destination, len) && mem.is_writable(destination) {
+            // Can't fail, the destination is a single writable region
+            mem.copy_within(destination, source, len).unwrap();
+            state.source = state.source.wrapping_add(len);
+            state.destination = state.destination.wrapping_add(len);
+        } else {
+            for _ in 0..state.count {
+                if unit == 4 {
+                    let value = mem.read_u32(state.source & !3);
+                    mem.write_u32(state.destination & !3, value);
+                } else {
+                    let value = mem.read_u16(state.source & !1);
+                    mem.write_u16(state.destination & !1, value);
+                }
+                state.source = step_address(state.source, source_control, unit);
+                state.destination = step_address(state.destination, destination_control, unit);
+            }
+        }
+
+        if control & DMACNT_IRQ != 0 {
+            mem.request_interrupt(INTERRUPTS[channel]);
+        }
+
+        if control & DMACNT_REPEAT != 0 && DmaTiming::from_control(control) != DmaTiming::Immediate {
+            state.count = word_count(channel, mem);
+            if destination_control == ADDRESS_INCREMENT_RELOAD {
+                state.destination = mem.io_u32(register(channel, 4)) & DESTINATION_MASKS[channel];
+            }
+        } else {
+            mem.set_io_u16(register(channel, 10), control & !DMACNT_ENABLE);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::system::interrupts::REG_IF;
+
+    #[test]
+    fn test_immediate_transfer() {
+        let mut mem = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+        mem.write_u32(0x02_000_000, 0xDEADBEEF);
+
+        // Channel 3: fixed source, incrementing destination, 4 words
+        mem.write_u32(register(3, 0), 0x02_000_000);
+        mem.write_u32(register(3, 4), 0x03_000_000);
+        mem.write_u16(register(3, 8), 4);
+        mem.write_u16(register(3, 10), DMACNT_ENABLE | DMACNT_32BIT | ADDRESS_FIXED << 7 | ADDRESS_INCREMENT << 5);
+
+        for i in 0..4 {
+            assert_eq!(mem.read_u32(0x03_000_000 + i * 4), 0xDEADBEEF);
+        }
+        assert_eq!(mem.read_u32(0x03_000_010), 0);
+        assert_eq!(control(3, &mem) & DMACNT_ENABLE, 0);
+    }
+
+    #[test]
+    fn test_overlapping_transfer() {
+        let mut mem = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+        mem.write_u32(0x02_000_000, 0xDEADBEEF);
+        mem.write_u32(0x02_000_004, 0x12345678);
+
+        // Copying one word ahead of the source fills the range with the first word, unit by unit
+        mem.write_u32(register(3, 0), 0x02_000_000);
+        mem.write_u32(register(3, 4), 0x02_000_004);
+        mem.write_u16(register(3, 8), 4);
+        mem.write_u16(register(3, 10), DMACNT_ENABLE | DMACNT_32BIT);
+
+        for i in 0..5 {
+            assert_eq!(mem.read_u32(0x02_000_000 + i * 4), 0xDEADBEEF);
+        }
+        assert_eq!(mem.read_u32(0x02_000_014), 0);
+
+        // The same through the mirror of WRAM after it
+        mem.write_u32(0x02_000_100, 0x12345678);
+        mem.write_u32(register(3, 0), 0x02_000_100);
+        mem.write_u32(register(3, 4), 0x02_040_104);
+        mem.write_u16(register(3, 8), 4);
+        mem.write_u16(register(3, 10), DMACNT_ENABLE | DMACNT_32BIT);
+        for i in 0..5 {
+            assert_eq!(mem.read_u32(0x02_000_100 + i * 4), 0x12345678);
+        }
+        assert_eq!(mem.read_u32(0x02_000_114), 0);
+    }
+
+    #[test]
+    fn test_repeated_transfer() {
+        let mut mem = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+        for i in 0..8 {
+            mem.write_u16(0x02_000_000 + i * 2, i as u16 + 1);
+        }
+
+        // Channel 1: 2 halfwords each VBlank, reloading the destination
+        mem.write_u32(register(1, 0), 0x02_000_000);
+        mem.write_u32(register(1, 4), 0x03_000_000);
+        mem.write_u16(register(1, 8), 2);
+        mem.write_u16(
+            register(1, 10),
+            DMACNT_ENABLE | DMACNT_REPEAT | DMACNT_IRQ | (DmaTiming::VBlank as u16) << 12 | ADDRESS_INCREMENT_RELOAD << 5,
+        );
+        assert_eq!(mem.read_u32(0x03_000_000), 0);
+
+        mem.trigger_dma(DmaTiming::HBlank);
+        assert_eq!(mem.read_u32(0x03_000_000), 0);
+
+        mem.trigger_dma(DmaTiming::VBlank);
+        assert_eq!(mem.read_u32(0x03_000_000), 0x0002_0001);
+        assert_eq!(mem.read_u16(REG_IF), Interrupt::Dma1.mask());
+
+        // The source continues where it left off
+        mem.trigger_dma(DmaTiming::VBlank);
+        assert_eq!(mem.read_u32(0x03_000_000), 0x0004_0003);
+        assert_eq!(mem.read_u32(0x03_000_004), 0);
+        assert_ne!(control(1, &mem) & DMACNT_ENABLE, 0);
+    }
+}
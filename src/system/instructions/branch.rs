@@ -6,26 +6,29 @@ use crate::{
     },
 };
 
-use super::{Condition, DecodedInstruction};
+use super::{lut::UnknownInstruction, swi, Condition, DecodedInstruction, CYCLES_N, CYCLES_S};
 
 #[derive(Debug, Clone, Copy)]
-enum Opcode {
+pub enum Opcode {
     BOffset { l: bool, x: bool, offset: u32 },
     BRegister { l: bool, x: bool, m: u8 },
     BCondThumb { cond: Condition, offset: u32 },
     BLThumb { offset: u32 },
+    BLXThumb { offset: u32 },
 }
 
-pub fn decode_b_arm(instruction: u32) -> Box<dyn super::DecodedInstruction> {
-    let signed_immed_24 = get_bits32(instruction, 0, 24);
-    let offset = (sign_extend32(signed_immed_24, 24) << 2).wrapping_add(INSTRUCTION_LEN_ARM * 2);
-    Box::new(Opcode::BOffset { l: false, x: false, offset })
+pub fn decode_b_bl_arm(instruction: u32) -> Box<dyn super::DecodedInstruction> {
+    Box::new(decode_b_bl_arm_unboxed(instruction))
 }
 
-pub fn decode_bl_arm(instruction: u32) -> Box<dyn super::DecodedInstruction> {
+pub fn decode_b_bl_arm_unboxed(instruction: u32) -> Opcode {
     let signed_immed_24 = get_bits32(instruction, 0, 24);
     let offset = (sign_extend32(signed_immed_24, 24) << 2).wrapping_add(INSTRUCTION_LEN_ARM * 2);
-    Box::new(Opcode::BOffset { l: true, x: false, offset })
+    Opcode::BOffset {
+        l: get_bit(instruction, 24),
+        x: false,
+        offset,
+    }
 }
 
 pub fn decode_bx_arm(instruction: u32) -> Box<dyn super::DecodedInstruction> {
@@ -45,12 +48,8 @@ pub fn decode_blx_arm(instruction: u32) -> Box<dyn super::DecodedInstruction> {
 }
 
 pub fn decode_branch_exchange_thumb(instruction: u16, _next_instruction: u16) -> Box<dyn super::DecodedInstruction> {
-    let l = get_bit16(instruction, 7);
-    if l {
-        panic!("BLX (2) not implemented");
-    }
     Box::new(Opcode::BRegister {
-        l,
+        l: get_bit16(instruction, 7),
         x: true,
         m: get_bits16(instruction, 3, 4) as u8,
     })
@@ -58,7 +57,12 @@ pub fn decode_branch_exchange_thumb(instruction: u16, _next_instruction: u16) ->
 
 pub fn decode_bl_thumb(instruction: u16, next_instruction: u16) -> Box<dyn super::DecodedInstruction> {
     assert_eq!(get_bits16(instruction, 11, 2), 0b10);
-    assert_eq!(get_bits16(next_instruction, 11, 2), 0b11);
+    match get_bits16(next_instruction, 11, 2) {
+        0b01 => return decode_blx_suffix_thumb(instruction, next_instruction),
+        0b11 => {}
+        // Only prefixes followed by their suffix are supported
+        _ => return UnknownInstruction::decode_thumb(instruction, next_instruction),
+    }
 
     let hi = sign_extend32(get_bits16(instruction, 0, 11) as u32, 11) << 12;
     let lo = get_bits16(next_instruction, 0, 11) as u32 * 2;
@@ -68,7 +72,26 @@ pub fn decode_bl_thumb(instruction: u16, next_instruction: u16) -> Box<dyn super
     Box::new(Opcode::BLThumb { offset })
 }
 
-pub fn decode_conditional_branch_thumb(instruction: u16, _next_instruction: u16) -> Box<dyn super::DecodedInstruction> {
+pub fn decode_blx_suffix_thumb(instruction: u16, next_instruction: u16) -> Box<dyn super::DecodedInstruction> {
+    assert_eq!(get_bits16(instruction, 11, 2), 0b10);
+    assert_eq!(get_bits16(next_instruction, 11, 2), 0b01);
+
+    let hi = sign_extend32(get_bits16(instruction, 0, 11) as u32, 11) << 12;
+    let lo = get_bits16(next_instruction, 0, 11) as u32 * 2;
+
+    let offset = hi.wrapping_add(INSTRUCTION_LEN_THUMB * 2).wrapping_add(lo);
+
+    Box::new(Opcode::BLXThumb { offset })
+}
+
+pub fn decode_conditional_branch_thumb(instruction: u16, next_instruction: u16) -> Box<dyn super::DecodedInstruction> {
+    // Condition codes 1110 and 1111 are not branches but the undefined and software interrupt encodings
+    match get_bits16(instruction, 8, 4) {
+        0b1110 => return UnknownInstruction::decode_thumb(instruction, next_instruction),
+        0b1111 => return swi::decode_thumb(instruction, next_instruction),
+        _ => {}
+    }
+
     let signed_immed_8 = get_bits16(instruction, 0, 8);
     let offset = (sign_extend32(signed_immed_8 as u32, 8) << 1).wrapping_add(INSTRUCTION_LEN_THUMB * 2);
     Box::new(Opcode::BCondThumb {
@@ -85,6 +108,7 @@ pub fn decode_unconditional_branch_thumb(instruction: u16, _next_instruction: u1
 
 impl DecodedInstruction for Opcode {
     fn execute(&self, cpu: &mut CPU, _mem: &mut Memory) {
+        let source = cpu.curr_instruction_address_from_execution_stage();
         match *self {
             Opcode::BOffset { l, x, offset } => {
                 if l {
@@ -94,30 +118,57 @@ impl DecodedInstruction for Opcode {
                     cpu.set_thumb_state(true);
                 }
                 cpu.set_r(REGISTER_PC, cpu.curr_instruction_address_from_execution_stage().wrapping_add(offset));
+                cpu.record_branch(source, l);
             }
             Opcode::BRegister { l, x, m } => {
+                // The pc reads as the instruction address plus 8 (or plus 4 word aligned in thumb), with bit 0 clear,
+                // so BX PC always switches to or stays in ARM state
+                let r_m = if m == REGISTER_PC { cpu.pc_for_operand() } else { cpu.get_r(m) };
                 if l {
-                    cpu.set_r(REGISTER_LR, cpu.next_instruction_address_from_execution_stage());
+                    // Thumb return addresses have bit 0 set so a BX LR returns to Thumb state
+                    let return_address = cpu.next_instruction_address_from_execution_stage();
+                    cpu.set_r(REGISTER_LR, if cpu.get_thumb_state() { return_address | 1 } else { return_address });
                 }
-                let r_m = cpu.get_r(m);
                 if x {
                     cpu.set_thumb_state(get_bit(r_m, 0));
                 }
-                cpu.set_r(REGISTER_PC, r_m & 0xFFFFFFFE);
+                cpu.set_r(REGISTER_PC, if cpu.get_thumb_state() { r_m & !0b1 } else { r_m & !0b11 });
+                cpu.record_branch(source, l);
             }
             Opcode::BCondThumb { cond, offset } => {
                 if cond.check(cpu) {
                     cpu.set_r(REGISTER_PC, cpu.curr_instruction_address_from_execution_stage().wrapping_add(offset));
+                    cpu.record_branch(source, false);
                 }
             }
             Opcode::BLThumb { offset } => {
                 // Point LR to the next instruction after the suffix
-                cpu.set_r(REGISTER_LR, cpu.next_instruction_address_from_execution_stage() + cpu.instruction_len_in_bytes() | 1);
+                cpu.set_r(REGISTER_LR, (cpu.next_instruction_address_from_execution_stage() + cpu.instruction_len_in_bytes()) | 1);
                 cpu.set_r(REGISTER_PC, cpu.curr_instruction_address_from_execution_stage().wrapping_add(offset));
+                cpu.record_branch(source, true);
+            }
+            Opcode::BLXThumb { offset } => {
+                // Same as BL, but the target is word aligned ARM code
+                cpu.set_r(REGISTER_LR, (cpu.next_instruction_address_from_execution_stage() + cpu.instruction_len_in_bytes()) | 1);
+                let target = cpu.curr_instruction_address_from_execution_stage().wrapping_add(offset) & !0b11;
+                cpu.set_thumb_state(false);
+                cpu.set_r(REGISTER_PC, target);
+                cpu.record_branch(source, true);
             }
         }
     }
 
+    fn cycles(&self, cpu: &CPU) -> u32 {
+        match *self {
+            // 2S + 1N
+            Opcode::BOffset { .. } | Opcode::BRegister { .. } => 2 * CYCLES_S + CYCLES_N,
+            Opcode::BCondThumb { cond, .. } if cond.check(cpu) => 2 * CYCLES_S + CYCLES_N,
+            Opcode::BCondThumb { .. } => CYCLES_S,
+            // The prefix (1S) and suffix (2S + 1N) are executed together
+            Opcode::BLThumb { .. } | Opcode::BLXThumb { .. } => 3 * CYCLES_S + CYCLES_N,
+        }
+    }
+
     fn disassemble(&self, cond: Condition, base_address: u32) -> String {
         use Opcode::*;
         match *self {
@@ -125,6 +176,86 @@ impl DecodedInstruction for Opcode {
             BRegister { l, x, m } => format!("B{}{}{} R{}", if l { "L" } else { "" }, if x { "X" } else { "" }, cond, m),
             BCondThumb { cond, offset } => format!("B{} #{:08X}", cond, base_address.wrapping_add(offset)),
             BLThumb { offset } => format!("BL #{:08X}", base_address.wrapping_add(offset)),
+            BLXThumb { offset } => format!("BLX #{:08X}", base_address.wrapping_add(offset) & !0b11),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_conditional_branch_thumb() {
+        assert_eq!(decode_conditional_branch_thumb(0xD0FE, 0).disassemble(Condition::AL, 0x08000000), "BEQ #08000000");
+        assert_eq!(decode_conditional_branch_thumb(0xDF08, 0).disassemble(Condition::AL, 0), "SWI #8");
+        assert_eq!(decode_conditional_branch_thumb(0xDE00, 0).disassemble(Condition::AL, 0), "???: DE00");
+    }
+
+    #[test]
+    fn test_blx_register_thumb() {
+        let mut cpu = CPU::new();
+        let mut mem = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+        cpu.set_thumb_state(true);
+        cpu.set_r(2, 0x03000000);
+
+        // BLX R2 executed at 0x08000100
+        let blx = decode_branch_exchange_thumb(0x4790, 0);
+        assert_eq!(blx.disassemble(Condition::AL, 0), "BLX R2");
+        cpu.set_r(REGISTER_PC, 0x08000104);
+        blx.execute(&mut cpu, &mut mem);
+        assert_eq!(cpu.get_r(REGISTER_LR), 0x08000103);
+        assert_eq!(cpu.get_r(REGISTER_PC), 0x03000000);
+        assert!(!cpu.get_thumb_state());
+    }
+
+    #[test]
+    fn test_bx_pc_thumb() {
+        let mut cpu = CPU::new();
+        let mut mem = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+        cpu.set_thumb_state(true);
+
+        // BX PC executed at 0x08000102 switches to ARM at the next word
+        cpu.set_r(REGISTER_PC, 0x08000106);
+        decode_branch_exchange_thumb(0x4778, 0).execute(&mut cpu, &mut mem);
+        assert_eq!(cpu.get_r(REGISTER_PC), 0x08000104);
+        assert!(!cpu.get_thumb_state());
+    }
+
+    #[test]
+    fn test_bx_pc_arm() {
+        let mut cpu = CPU::new();
+        let mut mem = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+
+        // BX PC executed at 0x08000100 continues in ARM state two instructions later
+        let bx = decode_bx_arm(0xE12FFF1F);
+        assert_eq!(bx.disassemble(Condition::AL, 0), "BX R15");
+        cpu.set_r(REGISTER_PC, 0x08000108);
+        bx.execute(&mut cpu, &mut mem);
+        assert_eq!(cpu.get_r(REGISTER_PC), 0x08000108);
+        assert!(!cpu.get_thumb_state());
+
+        // BLX PC links to the next instruction
+        cpu.set_r(REGISTER_PC, 0x08000108);
+        decode_blx_arm(0xE12FFF3F).execute(&mut cpu, &mut mem);
+        assert_eq!(cpu.get_r(REGISTER_LR), 0x08000104);
+        assert_eq!(cpu.get_r(REGISTER_PC), 0x08000108);
+        assert!(!cpu.get_thumb_state());
+    }
+
+    #[test]
+    fn test_blx_immediate_thumb() {
+        let mut cpu = CPU::new();
+        let mut mem = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+        cpu.set_thumb_state(true);
+
+        // BLX pair at 0x08000100 targeting 0x08000100 + 4 + 6, word aligned
+        let blx = decode_bl_thumb(0xF000, 0xE803);
+        assert_eq!(blx.disassemble(Condition::AL, 0x08000100), "BLX #08000108");
+        cpu.set_r(REGISTER_PC, 0x08000104);
+        blx.execute(&mut cpu, &mut mem);
+        assert_eq!(cpu.get_r(REGISTER_LR), 0x08000105);
+        assert_eq!(cpu.get_r(REGISTER_PC), 0x08000108);
+        assert!(!cpu.get_thumb_state());
+    }
+}
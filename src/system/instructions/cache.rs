@@ -0,0 +1,189 @@
+use crate::system::{cpu::CPU, memory::Memory};
+
+use super::{branch, data_processing, load_store, lut::InstructionLut, Condition, DecodedInstruction};
+
+const CACHE_LEN: usize = 0x1000;
+
+/// A decoded instruction, the classes executed most often are stored unboxed so executing them needs no dynamic dispatch
+#[derive(Debug)]
+pub enum Decoded {
+    DataProcessing(data_processing::DataProcessing),
+    LoadStore(load_store::LoadStore),
+    Branch(branch::Opcode),
+    Other(Box<dyn DecodedInstruction>),
+}
+
+// Each arm calls the concrete implementation, only `Other` goes through the vtable
+macro_rules! dispatch {
+    ($decoded:expr, $instruction:ident => $call:expr) => {
+        match $decoded {
+            Decoded::DataProcessing($instruction) => $call,
+            Decoded::LoadStore($instruction) => $call,
+            Decoded::Branch($instruction) => $call,
+            Decoded::Other($instruction) => $call,
+        }
+    };
+}
+
+impl DecodedInstruction for Decoded {
+    fn execute(&self, cpu: &mut CPU, mem: &mut Memory) {
+        dispatch!(self, instruction => instruction.execute(cpu, mem))
+    }
+
+    fn disassemble(&self, cond: Condition, base_address: u32) -> String {
+        dispatch!(self, instruction => instruction.disassemble(cond, base_address))
+    }
+
+    fn cycles(&self, cpu: &CPU) -> u32 {
+        dispatch!(self, instruction => instruction.cycles(cpu))
+    }
+
+    fn wait_cycles(&self, cpu: &CPU, mem: &Memory) -> u32 {
+        dispatch!(self, instruction => instruction.wait_cycles(cpu, mem))
+    }
+}
+
+pub struct CachedInstruction {
+    address: u32,
+    // The ARM instruction, or the thumb instruction with the next halfword above it
+    raw: u32,
+    thumb: bool,
+    pub instruction: Decoded,
+}
+
+/// Decoded instructions by address, so code that runs repeatedly isn't decoded and allocated again every time.
+/// An entry is only used while memory still holds the same instruction, which keeps self-modifying code working.
+pub struct InstructionCache {
+    entries: Vec<Option<CachedInstruction>>,
+    // Instructions that had to be decoded
+    #[cfg(test)]
+    misses: u64,
+}
+
+impl Default for InstructionCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl InstructionCache {
+    pub fn new() -> Self {
+        Self {
+            entries: (0..CACHE_LEN).map(|_| None).collect(),
+            #[cfg(test)]
+            misses: 0,
+        }
+    }
+
+    fn index(address: u32) -> usize {
+        (address >> 1) as usize % CACHE_LEN
+    }
+
+    /// Takes the decoded instruction at `address` out of the cache, decoding `raw` if it isn't cached. Returned with `put` after executing.
+    pub fn take(&mut self, address: u32, raw: u32, thumb: bool) -> CachedInstruction {
+        match self.entries[Self::index(address)].take() {
+            Some(entry) if entry.address == address && entry.raw == raw && entry.thumb == thumb => entry,
+            _ => {
+                #[cfg(test)]
+                {
+                    self.misses += 1;
+                }
+                let instruction = if thumb {
+                    Decoded::Other(InstructionLut::decode_thumb(raw as u16, (raw >> 16) as u16))
+                } else {
+                    InstructionLut::decode_arm_hot(raw).unwrap_or_else(|| Decoded::Other(InstructionLut::decode_arm(raw)))
+                };
+                CachedInstruction { address, raw, thumb, instruction }
+            }
+        }
+    }
+
+    pub fn put(&mut self, entry: CachedInstruction) {
+        let index = Self::index(entry.address);
+        self.entries[index] = Some(entry);
+    }
+
+    #[cfg(test)]
+    pub fn misses(&self) -> u64 {
+        self.misses
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{bitutil::get_bits32, system::cpu::REGISTER_PC};
+    use std::{hint::black_box, time::Instant};
+
+    fn lcg(seed: &mut u32) -> u32 {
+        *seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+        *seed
+    }
+
+    #[test]
+    fn test_unboxed_matches_boxed() {
+        let mut seed = 0x1234_5678;
+        let mut instructions = Vec::new();
+        while instructions.len() < 2000 {
+            // Always executed, without the pc as an operand
+            let mut instruction = 0xE000_0000 | (lcg(&mut seed) & 0x0FFF_FFFF);
+            for shift in [0, 8, 12, 16] {
+                if get_bits32(instruction, shift, 4) == 0xF {
+                    instruction &= !(1 << shift);
+                }
+            }
+            // Byte stores could hit video memory, which doesn't support them
+            let byte_store = get_bits32(instruction, 26, 2) == 0b01 && get_bits32(instruction, 20, 3) == 0b100;
+            if !byte_store && InstructionLut::decode_arm_hot(instruction).is_some() {
+                instructions.push(instruction);
+            }
+        }
+
+        let mut mems = [Memory::new(vec![0; 0x4000], vec![0; 0x100]), Memory::new(vec![0; 0x4000], vec![0; 0x100])];
+        let mut cpus = [CPU::new(), CPU::new()];
+        for instruction in instructions {
+            let registers: Vec<u32> = (0..15).map(|_| 0x02_000_000 | (lcg(&mut seed) & 0xFFFC)).collect();
+            let decoded = [InstructionLut::decode_arm_hot(instruction).unwrap(), Decoded::Other(InstructionLut::decode_arm(instruction))];
+            let mut cycles = [0; 2];
+            for i in 0..2 {
+                for (r, &value) in registers.iter().enumerate() {
+                    cpus[i].set_r(r as u8, value);
+                }
+                cpus[i].set_r(REGISTER_PC, 0x02_001_008);
+                cycles[i] = decoded[i].cycles(&cpus[i]) + decoded[i].wait_cycles(&cpus[i], &mems[i]);
+                decoded[i].execute(&mut cpus[i], &mut mems[i]);
+            }
+            assert_eq!(cycles[0], cycles[1], "{:08X}", instruction);
+            assert_eq!(cpus[0].get_cpsr(), cpus[1].get_cpsr(), "{:08X}", instruction);
+            for r in 0..16 {
+                assert_eq!(cpus[0].get_r(r), cpus[1].get_r(r), "{:08X}", instruction);
+            }
+        }
+        for address in (0x02_000_000..0x02_012_000).step_by(4) {
+            assert_eq!(mems[0].read_u32(address), mems[1].read_u32(address));
+        }
+    }
+
+    // Compares the unboxed and boxed paths, run with `cargo test --release bench_decoded -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn bench_decoded() {
+        // ADD R0, R0, #1; EOR R1, R1, R0; MOV R2, R1, LSL #3; SUBS R3, R2, R0; LDR R4, [R5]; STR R4, [R5, #4]
+        let program = [0xE2800001, 0xE0211000, 0xE1A02181, 0xE0523000, 0xE5954000, 0xE5854004];
+        let mut mem = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+        let mut cpu = CPU::new();
+        cpu.set_r(5, 0x02_000_000);
+
+        let unboxed: Vec<Decoded> = program.iter().map(|&instruction| InstructionLut::decode_arm_hot(instruction).unwrap()).collect();
+        let boxed: Vec<Decoded> = program.iter().map(|&instruction| Decoded::Other(InstructionLut::decode_arm(instruction))).collect();
+        for (name, decoded) in [("unboxed", unboxed), ("boxed", boxed)] {
+            let start = Instant::now();
+            for _ in 0..1_000_000 {
+                for instruction in black_box(&decoded) {
+                    instruction.execute(&mut cpu, &mut mem);
+                }
+            }
+            println!("{}: {:?} per instruction", name, start.elapsed() / (1_000_000 * program.len() as u32));
+        }
+    }
+}
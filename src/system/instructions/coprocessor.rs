@@ -0,0 +1,87 @@
+use crate::{
+    bitutil::{get_bit, get_bits32},
+    system::{
+        cpu::{CPU, REGISTER_PC},
+        memory::Memory,
+    },
+};
+
+use super::{Condition, DecodedInstruction};
+
+/// MRC/MCR, the GBA has no coprocessors so these only log the access
+#[derive(Debug)]
+struct RegisterTransfer {
+    opcode: Opcode,
+    cp_num: u8,
+    opcode_1: u8,
+    d: u8,
+    c_n: u8,
+    c_m: u8,
+    opcode_2: u8,
+}
+
+#[derive(Debug)]
+enum Opcode {
+    Mrc,
+    Mcr,
+}
+
+pub fn decode_register_transfer_arm(instruction: u32) -> Box<dyn DecodedInstruction> {
+    Box::new(RegisterTransfer {
+        opcode: if get_bit(instruction, 20) { Opcode::Mrc } else { Opcode::Mcr },
+        cp_num: get_bits32(instruction, 8, 4) as u8,
+        opcode_1: get_bits32(instruction, 21, 3) as u8,
+        d: get_bits32(instruction, 12, 4) as u8,
+        c_n: get_bits32(instruction, 16, 4) as u8,
+        c_m: get_bits32(instruction, 0, 4) as u8,
+        opcode_2: get_bits32(instruction, 5, 3) as u8,
+    })
+}
+
+impl DecodedInstruction for RegisterTransfer {
+    fn execute(&self, cpu: &mut CPU, _mem: &mut Memory) {
+        warn!("Ignored coprocessor access: {}", self.disassemble(Condition::AL, 0));
+        if let Opcode::Mrc = self.opcode {
+            // Reads as 0, with the pc as destination the top bits go to the flags instead
+            if self.d == REGISTER_PC {
+                cpu.cpsr &= 0x0FFF_FFFF;
+            } else {
+                cpu.set_r(self.d, 0);
+            }
+        }
+    }
+
+    fn disassemble(&self, cond: Condition, _base_address: u32) -> String {
+        // {MRC|MCR}{<cond>} p<cp_num>, <opcode_1>, Rd, CRn, CRm, <opcode_2>
+        let opcode = match self.opcode {
+            Opcode::Mrc => "MRC",
+            Opcode::Mcr => "MCR",
+        };
+        format!("{}{} p{}, {}, R{}, c{}, c{}, {}", opcode, cond, self.cp_num, self.opcode_1, self.d, self.c_n, self.c_m, self.opcode_2)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mrc() {
+        let mut cpu = CPU::new();
+        let mut mem = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+        cpu.set_r(3, 0x1234);
+
+        // MRC p15, 0, R3, c1, c0, 0
+        let mrc = decode_register_transfer_arm(0xEE113F10);
+        assert_eq!(mrc.disassemble(Condition::AL, 0), "MRC p15, 0, R3, c1, c0, 0");
+        mrc.execute(&mut cpu, &mut mem);
+        assert_eq!(cpu.get_r(3), 0);
+
+        // MCR p14, 1, R3, c2, c4, 5 leaves the registers alone
+        cpu.set_r(3, 0x1234);
+        let mcr = decode_register_transfer_arm(0xEE223EB4);
+        assert_eq!(mcr.disassemble(Condition::NE, 0), "MCRNE p14, 1, R3, c2, c4, 5");
+        mcr.execute(&mut cpu, &mut mem);
+        assert_eq!(cpu.get_r(3), 0x1234);
+    }
+}
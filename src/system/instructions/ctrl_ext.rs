@@ -39,9 +39,9 @@ pub mod mrs {
 
 pub mod msr {
     use crate::{
-        bitutil::{get_bit, get_bits32},
+        bitutil::{get_bit, get_bits32, set_bits32},
         system::{
-            cpu::CPU,
+            cpu::{is_valid_mode, CPU},
             instructions::{Condition, DecodedInstruction},
             memory::Memory,
         },
@@ -50,7 +50,8 @@ pub mod msr {
     // Masks for processor ARM7TDMI
     const UNALLOC_MASK: u32 = 0x0FFFFF00;
     const USER_MASK: u32 = 0xF0000000;
-    const PRIV_MASK: u32 = 0x0000000F;
+    // The interrupt disable bits and the mode, the state bit is only written through SPSR
+    const PRIV_MASK: u32 = 0x000000DF;
     const STATE_MASK: u32 = 0x00000020;
 
     #[derive(Debug)]
@@ -87,9 +88,8 @@ pub mod msr {
                 MsrOperand::Register(m) => cpu.get_r(m),
             };
 
-            if operand & UNALLOC_MASK != 0 {
-                panic!("Attempt to set reserved bits");
-            }
+            // Writes to the unallocated bits are ignored
+            let operand = operand & !UNALLOC_MASK;
 
             let mut mask = 0u32;
             for i in 0..4 {
@@ -108,7 +108,13 @@ pub mod msr {
                 } else {
                     mask &= USER_MASK;
                 }
-                cpu.cpsr = (cpu.cpsr & !mask) | (operand & mask);
+                let mut cpsr = (cpu.cpsr & !mask) | (operand & mask);
+                // The cpu would be left without registers in an invalid mode, so it stays in the current one
+                if !is_valid_mode(get_bits32(cpsr, 0, 5) as u8) {
+                    warn!("MSR to invalid mode {:05b} ignored", get_bits32(cpsr, 0, 5));
+                    cpsr = set_bits32(cpsr, 0, 5, cpu.get_mode() as u32);
+                }
+                cpu.cpsr = cpsr;
             } else {
                 if cpu.current_mode_has_spsr() {
                     mask &= USER_MASK | PRIV_MASK | STATE_MASK;
@@ -136,4 +142,150 @@ pub mod msr {
             )
         }
     }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::system::cpu::{MODE_IRQ, MODE_SVC, MODE_SYS};
+
+        fn execute(cpu: &mut CPU, instruction: u32, operand: u32) {
+            let mut mem = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+            cpu.set_r(0, operand);
+            decode_arm(instruction).execute(cpu, &mut mem);
+        }
+
+        #[test]
+        fn test_msr() {
+            let mut cpu = CPU::new();
+            // MSR CPSR_cf, R0 with reserved bits set
+            let msr = 0xE129F000;
+            assert_eq!(decode_arm(msr).disassemble(Condition::AL, 0), "MSR CPSR_cf, R0");
+            execute(&mut cpu, msr, 0x6FFF_FF00 | 0xC0 | MODE_SYS as u32);
+            assert_eq!(cpu.cpsr, 0x6000_0000 | 0xC0 | MODE_SYS as u32);
+
+            // Interrupts are enabled again and the mode switched
+            execute(&mut cpu, msr, MODE_IRQ as u32);
+            assert_eq!(cpu.cpsr, MODE_IRQ as u32);
+
+            // An invalid mode is rejected, the other bits are still written
+            execute(&mut cpu, msr, 0x8000_0080 | 0b10100);
+            assert_eq!(cpu.get_mode(), MODE_IRQ);
+            assert_eq!(cpu.cpsr, 0x8000_0080 | MODE_IRQ as u32);
+            execute(&mut cpu, msr, 0x03);
+            assert_eq!(cpu.get_mode(), MODE_IRQ);
+
+            // MSR SPSR_cxsf, R0 ignores the reserved bits too
+            cpu.set_mode(MODE_SVC);
+            execute(&mut cpu, 0xE16FF000, 0x0FFF_FF00 | 0x20 | MODE_SYS as u32);
+            assert_eq!(cpu.get_spsr(), 0x20 | MODE_SYS as u32);
+        }
+    }
+}
+
+pub mod clz {
+    use crate::{
+        bitutil::get_bits32,
+        system::{
+            cpu::CPU,
+            instructions::{Condition, DecodedInstruction},
+            memory::Memory,
+        },
+    };
+
+    #[derive(Debug)]
+    struct Clz {
+        d: u8,
+        m: u8,
+    }
+
+    pub fn decode_arm(instruction: u32) -> Box<dyn DecodedInstruction> {
+        Box::new(Clz {
+            d: get_bits32(instruction, 12, 4) as u8,
+            m: get_bits32(instruction, 0, 4) as u8,
+        })
+    }
+
+    impl DecodedInstruction for Clz {
+        fn execute(&self, cpu: &mut CPU, _mem: &mut Memory) {
+            cpu.set_r(self.d, cpu.get_r(self.m).leading_zeros());
+        }
+
+        fn disassemble(&self, cond: Condition, _base_address: u32) -> String {
+            // CLZ{<cond>} <Rd>, <Rm>
+            format!("CLZ{} R{}, R{}", cond, self.d, self.m)
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_clz() {
+            let mut cpu = CPU::new();
+            let mut mem = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+            let clz = decode_arm(0xE16F0F11);
+            assert_eq!(clz.disassemble(Condition::AL, 0), "CLZ R0, R1");
+
+            cpu.set_r(1, 0);
+            clz.execute(&mut cpu, &mut mem);
+            assert_eq!(cpu.get_r(0), 32);
+
+            cpu.set_r(1, 0x00FF0000);
+            clz.execute(&mut cpu, &mut mem);
+            assert_eq!(cpu.get_r(0), 8);
+        }
+    }
+}
+
+pub mod bkpt {
+    use crate::{
+        bitutil::get_bits32,
+        system::{
+            cpu::CPU,
+            instructions::{Condition, DecodedInstruction},
+            memory::Memory,
+        },
+    };
+
+    #[derive(Debug)]
+    struct Bkpt {
+        immediate: u16,
+    }
+
+    pub fn decode_arm(instruction: u32) -> Box<dyn DecodedInstruction> {
+        Box::new(Bkpt {
+            immediate: (get_bits32(instruction, 8, 12) << 4 | get_bits32(instruction, 0, 4)) as u16,
+        })
+    }
+
+    impl DecodedInstruction for Bkpt {
+        fn execute(&self, cpu: &mut CPU, _mem: &mut Memory) {
+            // Stops in the debugger instead of taking the prefetch abort
+            cpu.request_debug_halt(self.immediate);
+        }
+
+        fn disassemble(&self, _cond: Condition, _base_address: u32) -> String {
+            // BKPT <immed_16>, it can't be conditional
+            format!("BKPT #{:X}", self.immediate)
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_bkpt() {
+            let mut cpu = CPU::new();
+            let mut mem = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+            let bkpt = decode_arm(0xE1212374);
+            assert_eq!(bkpt.disassemble(Condition::AL, 0), "BKPT #1234");
+
+            assert_eq!(cpu.take_debug_halt(), None);
+            bkpt.execute(&mut cpu, &mut mem);
+            assert_eq!(cpu.take_debug_halt(), Some(0x1234));
+            assert_eq!(cpu.take_debug_halt(), None);
+        }
+    }
 }
@@ -3,17 +3,21 @@ use std::fmt::Display;
 use crate::{
     bitutil::{self, arithmetic_shift_right, get_bit, get_bit16, get_bits16, get_bits32, rotate_right_with_extend},
     system::{
-        cpu::{CPU, REGISTER_SP},
+        cpu::{CPU, REGISTER_PC, REGISTER_SP},
         memory::Memory,
     },
 };
 
-use super::{Condition, DecodedInstruction};
+use super::{branch, multiply, Condition, DecodedInstruction, CYCLES_I, CYCLES_N, CYCLES_S};
 
 pub fn decode_arm(instruction: u32) -> Box<dyn DecodedInstruction> {
+    Box::new(decode_arm_unboxed(instruction))
+}
+
+pub fn decode_arm_unboxed(instruction: u32) -> DataProcessing {
     let d = get_bits32(instruction, 12, 4) as u8;
     let n = get_bits32(instruction, 16, 4) as u8;
-    Box::new(DataProcessing {
+    DataProcessing {
         opcode: match get_bits32(instruction, 21, 4) {
             0b0000 => Opcode::AND { d, n },
             0b0001 => Opcode::EOR { d, n },
@@ -36,7 +40,7 @@ pub fn decode_arm(instruction: u32) -> Box<dyn DecodedInstruction> {
         set_flags: get_bit(instruction, 20),
 
         shifter_operand: ShifterOperand::decode_arm(instruction),
-    })
+    }
 }
 
 pub fn decode_shift_imm_thumb(instruction: u16, _next_instruction: u16) -> Box<dyn DecodedInstruction> {
@@ -56,7 +60,7 @@ pub fn decode_shift_imm_thumb(instruction: u16, _next_instruction: u16) -> Box<d
     })
 }
 
-pub fn decode_register_thumb(instruction: u16, _next_instruction: u16) -> Box<dyn DecodedInstruction> {
+pub fn decode_register_thumb(instruction: u16, next_instruction: u16) -> Box<dyn DecodedInstruction> {
     let d = get_bits16(instruction, 0, 3) as u8;
     let s = get_bits16(instruction, 3, 3) as u8;
     let (opcode, shifter_operand) = match get_bits16(instruction, 6, 4) {
@@ -69,14 +73,15 @@ pub fn decode_register_thumb(instruction: u16, _next_instruction: u16) -> Box<dy
         0b0110 => (Opcode::SBC { d, n: d }, ShifterOperand::Register { m: s }),
         0b0111 => (Opcode::MOV { d }, ShifterOperand::RotateRightRegister { m: d, s }),
         0b1000 => (Opcode::TST { n: d }, ShifterOperand::Register { m: s }),
-        0b1001 => (Opcode::RSB { d, n: d }, ShifterOperand::Register { m: s }),
+        // NEG Rd, Rm is RSB Rd, Rm, #0
+        0b1001 => (Opcode::RSB { d, n: s }, ShifterOperand::Immediate { immed: 0, rotate_imm: 0 }),
         0b1010 => (Opcode::CMP { n: d }, ShifterOperand::Register { m: s }),
         0b1011 => (Opcode::CMN { n: d }, ShifterOperand::Register { m: s }),
         0b1100 => (Opcode::ORR { d, n: d }, ShifterOperand::Register { m: s }),
-        0b1101 => panic!("decode_register_thumb: MUL is not supported"),
+        0b1101 => return multiply::decode_thumb(instruction, next_instruction),
         0b1110 => (Opcode::BIC { d, n: d }, ShifterOperand::Register { m: s }),
         0b1111 => (Opcode::MVN { d }, ShifterOperand::Register { m: s }),
-        x => todo!("Thumb opcode {:#04b}", x),
+        _ => unreachable!(),
     };
     Box::new(DataProcessing {
         opcode,
@@ -85,14 +90,15 @@ pub fn decode_register_thumb(instruction: u16, _next_instruction: u16) -> Box<dy
     })
 }
 
-pub fn decode_special_thumb(instruction: u16, _next_instruction: u16) -> Box<dyn DecodedInstruction> {
-    let d = get_bits16(instruction, 0, 3) as u8 | (get_bit16(instruction, 7) as u8) << 4;
+pub fn decode_special_thumb(instruction: u16, next_instruction: u16) -> Box<dyn DecodedInstruction> {
+    // H1 and H2 extend Rd and Rm to reach the high registers
+    let d = get_bits16(instruction, 0, 3) as u8 | (get_bit16(instruction, 7) as u8) << 3;
     let s = get_bits16(instruction, 3, 4) as u8;
     let (opcode, set_flags) = match get_bits16(instruction, 8, 2) {
         0b00 => (Opcode::ADD { d, n: d }, false),
         0b01 => (Opcode::CMP { n: d }, true),
         0b10 => (Opcode::MOV { d }, false),
-        0b11 => panic!("decode_special_thumb: Should use branch decoder"),
+        0b11 => return branch::decode_branch_exchange_thumb(instruction, next_instruction),
         _ => unreachable!(),
     };
     Box::new(DataProcessing {
@@ -158,8 +164,23 @@ pub fn decode_adjust_sp_thumb(instruction: u16, _next_instruction: u16) -> Box<d
     })
 }
 
+pub fn decode_add_sp_pc_thumb(instruction: u16, _next_instruction: u16) -> Box<dyn DecodedInstruction> {
+    let is_sp = get_bit16(instruction, 11);
+    Box::new(DataProcessing {
+        opcode: Opcode::ADD {
+            d: get_bits16(instruction, 8, 3) as u8,
+            n: if is_sp { REGISTER_SP } else { REGISTER_PC },
+        },
+        set_flags: false,
+        shifter_operand: ShifterOperand::Immediate {
+            immed: get_bits16(instruction, 0, 8) << 2,
+            rotate_imm: 0,
+        },
+    })
+}
+
 #[derive(Debug)]
-struct DataProcessing {
+pub struct DataProcessing {
     opcode: Opcode,
     set_flags: bool,
     shifter_operand: ShifterOperand,
@@ -200,16 +221,37 @@ enum ShifterOperand {
     RotateRightWithExtend { m: u8 },
 }
 
+impl DataProcessing {
+    fn get_r_n(&self, cpu: &CPU, n: u8) -> u32 {
+        // pc relative immediate arithmetic (Thumb ADD Rd, PC, #imm) uses the word aligned pc
+        if n == REGISTER_PC && matches!(self.shifter_operand, ShifterOperand::Immediate { .. }) {
+            cpu.pc_for_operand()
+        } else {
+            cpu.get_r(n)
+        }
+    }
+}
+
 impl DecodedInstruction for DataProcessing {
     fn execute(&self, cpu: &mut CPU, _mem: &mut Memory) {
         use Opcode::*;
 
         let process_result = |cpu: &mut CPU, d: Option<u8>, result: u32, carry: bool, overflow: Option<bool>| {
             if let Some(d) = d {
-                if self.set_flags && d == 15 {
-                    todo!("d == 15");
+                if self.set_flags && d == REGISTER_PC {
+                    // Exception return: restore the cpsr from the current mode's spsr instead of updating the flags
+                    if !cpu.current_mode_has_spsr() {
+                        panic!("Tried to restore CPSR from SPSR in user or system mode");
+                    }
+                    cpu.cpsr = cpu.get_spsr();
+                    cpu.set_r(d, result);
+                    return;
+                }
+                if d == REGISTER_PC && cpu.get_thumb_state() {
+                    cpu.set_r(d, result & !0b1);
+                } else {
+                    cpu.set_r(d, result);
                 }
-                cpu.set_r(d, result);
             }
             if self.set_flags {
                 cpu.set_negative_flag(get_bit(result, 31));
@@ -223,49 +265,65 @@ impl DecodedInstruction for DataProcessing {
 
         let (shifter_operand, shifter_carry) = self.shifter_operand.eval(cpu);
         match self.opcode {
-            AND { d, n } => process_result(cpu, Some(d), cpu.get_r(n) & shifter_operand, shifter_carry, None),
-            EOR { d, n } => process_result(cpu, Some(d), cpu.get_r(n) ^ shifter_operand, shifter_carry, None),
+            AND { d, n } => process_result(cpu, Some(d), self.get_r_n(cpu, n) & shifter_operand, shifter_carry, None),
+            EOR { d, n } => process_result(cpu, Some(d), self.get_r_n(cpu, n) ^ shifter_operand, shifter_carry, None),
             SUB { d, n } => {
-                let (result, borrow, overflow) = bitutil::sub_with_flags(cpu.get_r(n), shifter_operand);
+                let (result, borrow, overflow) = bitutil::sub_with_flags(self.get_r_n(cpu, n), shifter_operand);
                 process_result(cpu, Some(d), result, !borrow, Some(overflow));
             }
             RSB { d, n } => {
-                let (result, borrow, overflow) = bitutil::sub_with_flags(shifter_operand, cpu.get_r(n));
+                let (result, borrow, overflow) = bitutil::sub_with_flags(shifter_operand, self.get_r_n(cpu, n));
                 process_result(cpu, Some(d), result, !borrow, Some(overflow))
             }
             ADD { d, n } => {
-                let (result, carry, overflow) = bitutil::add_with_flags(cpu.get_r(n), shifter_operand);
+                let (result, carry, overflow) = bitutil::add_with_flags(self.get_r_n(cpu, n), shifter_operand);
                 process_result(cpu, Some(d), result, carry, Some(overflow))
             }
             ADC { d, n } => {
-                let (result, carry, overflow) = bitutil::add_with_flags_carry(cpu.get_r(n), shifter_operand, cpu.get_carry_flag());
+                let (result, carry, overflow) = bitutil::add_with_flags_carry(self.get_r_n(cpu, n), shifter_operand, cpu.get_carry_flag());
                 process_result(cpu, Some(d), result, carry, Some(overflow))
             }
             SBC { d, n } => {
-                let (result, borrow, overflow) = bitutil::sub_with_flags_carry(cpu.get_r(n), shifter_operand, !cpu.get_carry_flag());
+                let (result, borrow, overflow) = bitutil::sub_with_flags_carry(self.get_r_n(cpu, n), shifter_operand, !cpu.get_carry_flag());
                 process_result(cpu, Some(d), result, !borrow, Some(overflow))
             }
             RSC { d, n } => {
-                let (result, borrow, overflow) = bitutil::sub_with_flags_carry(shifter_operand, cpu.get_r(n), !cpu.get_carry_flag());
+                let (result, borrow, overflow) = bitutil::sub_with_flags_carry(shifter_operand, self.get_r_n(cpu, n), !cpu.get_carry_flag());
                 process_result(cpu, Some(d), result, !borrow, Some(overflow))
             }
-            TST { n } => process_result(cpu, None, cpu.get_r(n) & shifter_operand, shifter_carry, None),
-            TEQ { n } => process_result(cpu, None, cpu.get_r(n) ^ shifter_operand, shifter_carry, None),
+            TST { n } => process_result(cpu, None, self.get_r_n(cpu, n) & shifter_operand, shifter_carry, None),
+            TEQ { n } => process_result(cpu, None, self.get_r_n(cpu, n) ^ shifter_operand, shifter_carry, None),
             CMP { n } => {
-                let (result, borrow, overflow) = bitutil::sub_with_flags(cpu.get_r(n), shifter_operand);
+                let (result, borrow, overflow) = bitutil::sub_with_flags(self.get_r_n(cpu, n), shifter_operand);
                 process_result(cpu, None, result, !borrow, Some(overflow));
             }
             CMN { n } => {
-                let (result, add_carry, overflow) = bitutil::add_with_flags(cpu.get_r(n), shifter_operand);
+                let (result, add_carry, overflow) = bitutil::add_with_flags(self.get_r_n(cpu, n), shifter_operand);
                 process_result(cpu, None, result, add_carry, Some(overflow));
             }
-            ORR { d, n } => process_result(cpu, Some(d), cpu.get_r(n) | shifter_operand, shifter_carry, None),
+            ORR { d, n } => process_result(cpu, Some(d), self.get_r_n(cpu, n) | shifter_operand, shifter_carry, None),
             MOV { d } => process_result(cpu, Some(d), shifter_operand, shifter_carry, None),
-            BIC { d, n } => process_result(cpu, Some(d), cpu.get_r(n) & !shifter_operand, shifter_carry, None),
+            BIC { d, n } => process_result(cpu, Some(d), self.get_r_n(cpu, n) & !shifter_operand, shifter_carry, None),
             MVN { d } => process_result(cpu, Some(d), !shifter_operand, shifter_carry, None),
         }
     }
 
+    fn cycles(&self, _cpu: &CPU) -> u32 {
+        use Opcode::*;
+        let mut cycles = CYCLES_S;
+        if self.shifter_operand.is_register_shift() {
+            cycles += CYCLES_I;
+        }
+        match self.opcode {
+            AND { d, .. } | EOR { d, .. } | SUB { d, .. } | RSB { d, .. } | ADD { d, .. } | ADC { d, .. } | SBC { d, .. } | RSC { d, .. } | ORR { d, .. } | BIC { d, .. } | MOV { d } | MVN { d }
+                if d == REGISTER_PC =>
+            {
+                cycles + CYCLES_S + CYCLES_N
+            }
+            _ => cycles,
+        }
+    }
+
     fn disassemble(&self, cond: Condition, _base_address: u32) -> String {
         use Opcode::*;
         let (d, n) = match self.opcode {
@@ -289,15 +347,15 @@ impl DecodedInstruction for DataProcessing {
 impl Display for ShifterOperand {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match *self {
-            ShifterOperand::Immediate { immed, rotate_imm } => write!(f, "#{:08X}", ShifterOperand::calc_immediate(immed, rotate_imm)),
+            ShifterOperand::Immediate { immed, rotate_imm } => write!(f, "#0x{:X}", ShifterOperand::calc_immediate(immed, rotate_imm)),
             ShifterOperand::Register { m } => write!(f, "R{}", m),
-            ShifterOperand::LogicalShiftLeftImmediate { m, shift_imm } => write!(f, "R{}, LSL #{:08X}", m, shift_imm),
+            ShifterOperand::LogicalShiftLeftImmediate { m, shift_imm } => write!(f, "R{}, LSL #0x{:X}", m, shift_imm),
             ShifterOperand::LogicalShiftLeftRegister { m, s } => write!(f, "R{}, LSL R{}", m, s),
-            ShifterOperand::LogicalShiftRightImmediate { m, shift_imm } => write!(f, "R{}, LSR #{:08X}", m, shift_imm),
+            ShifterOperand::LogicalShiftRightImmediate { m, shift_imm } => write!(f, "R{}, LSR #0x{:X}", m, shift_imm),
             ShifterOperand::LogicalShiftRightRegister { m, s } => write!(f, "R{}, LSR R{}", m, s),
-            ShifterOperand::ArithmeticShiftRightImmediate { m, shift_imm } => write!(f, "R{}, ASR #{:08X}", m, shift_imm),
+            ShifterOperand::ArithmeticShiftRightImmediate { m, shift_imm } => write!(f, "R{}, ASR #0x{:X}", m, shift_imm),
             ShifterOperand::ArithmeticShiftRightRegister { m, s } => write!(f, "R{}, ASR R{}", m, s),
-            ShifterOperand::RotateRightImmediate { m, s } => write!(f, "R{}, ROR #{:08X}", m, s),
+            ShifterOperand::RotateRightImmediate { m, s } => write!(f, "R{}, ROR #0x{:X}", m, s),
             ShifterOperand::RotateRightRegister { m, s } => write!(f, "R{}, ROR R{}", m, s),
             ShifterOperand::RotateRightWithExtend { m } => write!(f, "R{}, RRX", m),
         }
@@ -328,6 +386,16 @@ impl Display for Opcode {
 }
 
 impl ShifterOperand {
+    const fn is_register_shift(&self) -> bool {
+        matches!(
+            self,
+            ShifterOperand::LogicalShiftLeftRegister { .. }
+                | ShifterOperand::LogicalShiftRightRegister { .. }
+                | ShifterOperand::ArithmeticShiftRightRegister { .. }
+                | ShifterOperand::RotateRightRegister { .. }
+        )
+    }
+
     const fn calc_immediate(immed: u16, rotate_imm: u8) -> u32 {
         (immed as u32).rotate_right(rotate_imm as u32 * 2)
     }
@@ -503,4 +571,106 @@ mod tests {
         let inst = decode_arm(instruction);
         assert_eq!("ADD R1, R2, #0x208", format!("{}", inst.disassemble(Condition::AL, 0)));
     }
+
+    #[test]
+    fn test_register_thumb() {
+        assert_eq!(decode_register_thumb(0x4008, 0).disassemble(Condition::AL, 0), "ANDS R0, R0, R1");
+        assert_eq!(decode_register_thumb(0x4088, 0).disassemble(Condition::AL, 0), "MOVS R0, R0, LSL R1");
+        assert_eq!(decode_register_thumb(0x4248, 0).disassemble(Condition::AL, 0), "RSBS R0, R1, #0x0");
+        assert_eq!(decode_register_thumb(0x4348, 0).disassemble(Condition::AL, 0), "MULS R0, R1, R0");
+    }
+
+    #[test]
+    fn test_neg_thumb_flags() {
+        let mut cpu = CPU::new();
+        let mut mem = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+        // NEG R0, R1 subtracts from zero, its flags aren't those of a logical operation
+        let neg = decode_register_thumb(0x4248, 0);
+
+        cpu.set_r(1, 0x80000000);
+        neg.execute(&mut cpu, &mut mem);
+        assert_eq!(cpu.get_r(0), 0x80000000);
+        assert!(cpu.get_overflow_flag());
+        assert!(cpu.get_negative_flag());
+        assert!(!cpu.get_carry_flag());
+
+        // No borrow, so the carry is set
+        cpu.set_r(1, 0);
+        neg.execute(&mut cpu, &mut mem);
+        assert_eq!(cpu.get_r(0), 0);
+        assert!(cpu.get_carry_flag());
+        assert!(cpu.get_zero_flag());
+        assert!(!cpu.get_overflow_flag());
+
+        cpu.set_r(1, 1);
+        neg.execute(&mut cpu, &mut mem);
+        assert_eq!(cpu.get_r(0), 0xFFFFFFFF);
+        assert!(!cpu.get_carry_flag());
+        assert!(!cpu.get_overflow_flag());
+    }
+
+    #[test]
+    fn test_special_thumb() {
+        assert_eq!(decode_special_thumb(0x4688, 0).disassemble(Condition::AL, 0), "MOV R8, R1");
+        assert_eq!(decode_special_thumb(0x448C, 0).disassemble(Condition::AL, 0), "ADD R12, R12, R1");
+        assert_eq!(decode_special_thumb(0x459A, 0).disassemble(Condition::AL, 0), "CMP R10, R3");
+    }
+
+    #[test]
+    fn test_special_thumb_mov_pc() {
+        let mut cpu = CPU::new();
+        let mut mem = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+        cpu.set_thumb_state(true);
+        cpu.set_r(1, 0x08000301);
+
+        // MOV PC, R1
+        decode_special_thumb(0x468F, 0).execute(&mut cpu, &mut mem);
+        assert_eq!(cpu.get_r(REGISTER_PC), 0x08000300);
+        assert!(cpu.get_thumb_state());
+    }
+
+    #[test]
+    fn test_add_sp_pc_thumb() {
+        assert_eq!(decode_add_sp_pc_thumb(0xAA04, 0).disassemble(Condition::AL, 0), "ADD R2, R13, #0x10");
+
+        let mut cpu = CPU::new();
+        let mut mem = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+        cpu.set_thumb_state(true);
+
+        // ADD R0, PC, #8 executed at 0x08000102
+        cpu.set_r(REGISTER_PC, 0x08000106);
+        decode_add_sp_pc_thumb(0xA002, 0).execute(&mut cpu, &mut mem);
+        assert_eq!(cpu.get_r(0), 0x0800010C);
+    }
+
+    #[test]
+    fn test_subs_pc_restores_cpsr() {
+        use crate::system::cpu::{MODE_IRQ, MODE_SYS, REGISTER_LR};
+
+        let mut cpu = CPU::new();
+        let mut mem = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+        cpu.set_mode(MODE_SYS);
+        cpu.set_thumb_state(true);
+        let interrupted_cpsr = cpu.get_cpsr();
+        cpu.raise_exception(MODE_IRQ, 0x18, 0x08000124);
+
+        // SUBS PC, LR, #4
+        decode_arm(0xE25EF004).execute(&mut cpu, &mut mem);
+        assert_eq!(cpu.get_cpsr(), interrupted_cpsr);
+        assert_eq!(cpu.get_r(REGISTER_PC), 0x08000120);
+        assert_eq!(cpu.get_r_in_mode(REGISTER_LR, MODE_IRQ), 0x08000124);
+    }
+
+    #[test]
+    fn test_mov_pc_without_s_is_plain_branch() {
+        let mut cpu = CPU::new();
+        let mut mem = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+        let cpsr = cpu.get_cpsr();
+        cpu.set_r(0, 0x03000000);
+
+        // MOV PC, R0
+        decode_arm(0xE1A0F000).execute(&mut cpu, &mut mem);
+        assert_eq!(cpu.get_r(REGISTER_PC), 0x03000000);
+        assert_eq!(cpu.get_cpsr(), cpsr);
+    }
 }
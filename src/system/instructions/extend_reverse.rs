@@ -0,0 +1,155 @@
+use crate::{
+    bitutil::get_bits16,
+    system::{cpu::CPU, memory::Memory},
+};
+
+use super::{lut::UnknownInstruction, Condition, DecodedInstruction};
+
+/// ARMv6 sign and zero extension of the low byte or halfword
+#[derive(Debug)]
+struct Extend {
+    opcode: ExtendOpcode,
+    d: u8,
+    m: u8,
+}
+
+#[derive(Debug)]
+enum ExtendOpcode {
+    Sxth,
+    Sxtb,
+    Uxth,
+    Uxtb,
+}
+
+/// ARMv6 byte reversal, for converting between little and big endian
+#[derive(Debug)]
+struct Reverse {
+    opcode: ReverseOpcode,
+    d: u8,
+    m: u8,
+}
+
+#[derive(Debug)]
+enum ReverseOpcode {
+    Rev,
+    Rev16,
+    Revsh,
+}
+
+pub fn decode_extend_thumb(instruction: u16, _next_instruction: u16) -> Box<dyn DecodedInstruction> {
+    Box::new(Extend {
+        opcode: match get_bits16(instruction, 6, 2) {
+            0b00 => ExtendOpcode::Sxth,
+            0b01 => ExtendOpcode::Sxtb,
+            0b10 => ExtendOpcode::Uxth,
+            0b11 => ExtendOpcode::Uxtb,
+            _ => unreachable!(),
+        },
+        d: get_bits16(instruction, 0, 3) as u8,
+        m: get_bits16(instruction, 3, 3) as u8,
+    })
+}
+
+pub fn decode_reverse_thumb(instruction: u16, next_instruction: u16) -> Box<dyn DecodedInstruction> {
+    let opcode = match get_bits16(instruction, 6, 2) {
+        0b00 => ReverseOpcode::Rev,
+        0b01 => ReverseOpcode::Rev16,
+        0b11 => ReverseOpcode::Revsh,
+        _ => return UnknownInstruction::decode_thumb(instruction, next_instruction),
+    };
+    Box::new(Reverse {
+        opcode,
+        d: get_bits16(instruction, 0, 3) as u8,
+        m: get_bits16(instruction, 3, 3) as u8,
+    })
+}
+
+impl DecodedInstruction for Extend {
+    fn execute(&self, cpu: &mut CPU, _mem: &mut Memory) {
+        let m = cpu.get_r(self.m);
+        let result = match self.opcode {
+            ExtendOpcode::Sxth => m as i16 as u32,
+            ExtendOpcode::Sxtb => m as i8 as u32,
+            ExtendOpcode::Uxth => m as u16 as u32,
+            ExtendOpcode::Uxtb => m as u8 as u32,
+        };
+        cpu.set_r(self.d, result);
+    }
+
+    fn disassemble(&self, cond: Condition, _base_address: u32) -> String {
+        // {SXTH|SXTB|UXTH|UXTB}{<cond>} <Rd>, <Rm>
+        let opcode = match self.opcode {
+            ExtendOpcode::Sxth => "SXTH",
+            ExtendOpcode::Sxtb => "SXTB",
+            ExtendOpcode::Uxth => "UXTH",
+            ExtendOpcode::Uxtb => "UXTB",
+        };
+        format!("{}{} R{}, R{}", opcode, cond, self.d, self.m)
+    }
+}
+
+impl DecodedInstruction for Reverse {
+    fn execute(&self, cpu: &mut CPU, _mem: &mut Memory) {
+        let m = cpu.get_r(self.m);
+        let result = match self.opcode {
+            ReverseOpcode::Rev => m.swap_bytes(),
+            // Swaps the bytes within each halfword
+            ReverseOpcode::Rev16 => (m & 0xFF00_FF00) >> 8 | (m & 0x00FF_00FF) << 8,
+            // Swaps the bytes of the low halfword and sign extends it
+            ReverseOpcode::Revsh => (m as u16).swap_bytes() as i16 as u32,
+        };
+        cpu.set_r(self.d, result);
+    }
+
+    fn disassemble(&self, cond: Condition, _base_address: u32) -> String {
+        // {REV|REV16|REVSH}{<cond>} <Rd>, <Rm>
+        let opcode = match self.opcode {
+            ReverseOpcode::Rev => "REV",
+            ReverseOpcode::Rev16 => "REV16",
+            ReverseOpcode::Revsh => "REVSH",
+        };
+        format!("{}{} R{}, R{}", opcode, cond, self.d, self.m)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::system::instructions::lut::InstructionLut;
+
+    fn execute(instruction: u16, m: u32) -> u32 {
+        let mut cpu = CPU::new();
+        let mut mem = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+        cpu.set_r(1, m);
+        InstructionLut::decode_thumb(instruction, 0).execute(&mut cpu, &mut mem);
+        cpu.get_r(0)
+    }
+
+    #[test]
+    fn test_extend() {
+        // SXTB R0, R1
+        assert_eq!(InstructionLut::decode_thumb(0xB248, 0).disassemble(Condition::AL, 0), "SXTB R0, R1");
+        assert_eq!(execute(0xB248, 0x1234_5680), 0xFFFF_FF80);
+        assert_eq!(execute(0xB248, 0x1234_567F), 0x0000_007F);
+        // SXTH R0, R1
+        assert_eq!(execute(0xB208, 0x1234_8765), 0xFFFF_8765);
+        // UXTB R0, R1
+        assert_eq!(InstructionLut::decode_thumb(0xB2C8, 0).disassemble(Condition::AL, 0), "UXTB R0, R1");
+        assert_eq!(execute(0xB2C8, 0x1234_5680), 0x0000_0080);
+        // UXTH R0, R1
+        assert_eq!(execute(0xB288, 0x1234_8765), 0x0000_8765);
+    }
+
+    #[test]
+    fn test_reverse() {
+        // REV R0, R1
+        assert_eq!(InstructionLut::decode_thumb(0xBA08, 0).disassemble(Condition::AL, 0), "REV R0, R1");
+        assert_eq!(execute(0xBA08, 0x1234_5678), 0x7856_3412);
+        // REV16 R0, R1
+        assert_eq!(InstructionLut::decode_thumb(0xBA48, 0).disassemble(Condition::AL, 0), "REV16 R0, R1");
+        assert_eq!(execute(0xBA48, 0x1234_5678), 0x3412_7856);
+        // REVSH R0, R1
+        assert_eq!(execute(0xBAC8, 0x1234_5680), 0xFFFF_8056);
+        assert_eq!(execute(0xBAC8, 0x1234_807F), 0x0000_7F80);
+    }
+}
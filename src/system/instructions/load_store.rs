@@ -3,23 +3,27 @@ use std::fmt::Display;
 use crate::{
     bitutil::{arithmetic_shift_right, get_bit, get_bit16, get_bits16, get_bits32, rotate_right_with_extend, sign_extend32},
     system::{
-        cpu::{CPU, REGISTER_SP},
+        cpu::{CPU, INSTRUCTION_LEN_ARM, MODE_ABT, REGISTER_PC, REGISTER_SP, VECTOR_DATA_ABORT},
         memory::Memory,
     },
 };
 
-use super::{Condition, DecodedInstruction};
+use super::{lut::UnknownInstruction, Condition, DecodedInstruction, CYCLES_I, CYCLES_N, CYCLES_S};
 
 pub fn decode_arm(instruction: u32) -> Box<dyn DecodedInstruction> {
+    Box::new(decode_arm_unboxed(instruction))
+}
+
+pub fn decode_arm_unboxed(instruction: u32) -> LoadStore {
     let d = get_bits32(instruction, 12, 4) as u8;
     let b = get_bit(instruction, 22);
-    Box::new(LoadStore {
+    LoadStore {
         opcode: if get_bit(instruction, 20) { Opcode::LDR } else { Opcode::STR },
         length: if b { Length::Byte } else { Length::Word },
         sign_extend: false,
         d,
         adressing_mode: AddressingMode::decode_arm(instruction),
-    })
+    }
 }
 
 pub fn decode_extra_arm(instruction: u32) -> Box<dyn DecodedInstruction> {
@@ -36,6 +40,10 @@ pub fn decode_extra_arm(instruction: u32) -> Box<dyn DecodedInstruction> {
         (true, true, true) => (Opcode::LDR, true, Length::Halfword),
         _ => panic!("Invalid extra arm instruction: {:#08X}", instruction),
     };
+    // Doublewords are transferred to an even register and the one after it
+    if matches!(length, Length::Doubleword) && !d.is_multiple_of(2) {
+        return UnknownInstruction::decode_arm(instruction);
+    }
     Box::new(LoadStore {
         opcode,
         length,
@@ -64,9 +72,10 @@ pub fn decode_halfword_thumb(instruction: u16, _next_instruction: u16) -> Box<dy
 pub fn decode_word_byte_thumb(instruction: u16, _next_instruction: u16) -> Box<dyn DecodedInstruction> {
     let d = get_bits16(instruction, 0, 3) as u8;
     let b = get_bits16(instruction, 3, 3) as u8;
-    let offset = get_bits16(instruction, 6, 5);
     let is_load = get_bit16(instruction, 11);
     let is_byte = get_bit16(instruction, 12);
+    // Byte offsets are unscaled, word offsets are in units of 4 bytes
+    let offset = if is_byte { get_bits16(instruction, 6, 5) } else { get_bits16(instruction, 6, 5) * 4 };
     Box::new(LoadStore {
         opcode: if is_load { Opcode::LDR } else { Opcode::STR },
         length: if is_byte { Length::Byte } else { Length::Word },
@@ -105,7 +114,7 @@ pub fn decode_load_from_literal_pool_thumb(instruction: u16, _next_instruction:
         d: get_bits16(instruction, 8, 3) as u8,
         adressing_mode: AddressingMode {
             u_is_add: true,
-            n: 15,
+            n: REGISTER_PC,
             mode: AddressingModeType::Immediate(get_bits16(instruction, 0, 8) as u16 * 4),
             indexing_mode: IndexingMode::Offset,
         },
@@ -132,7 +141,7 @@ pub fn decode_register_offset_thumb(instruction: u16, _next_instruction: u16) ->
 }
 
 #[derive(Debug)]
-struct LoadStore {
+pub struct LoadStore {
     opcode: Opcode,
     length: Length,
     sign_extend: bool,
@@ -180,21 +189,49 @@ enum IndexingMode {
     PostIndexed { t: bool },
 }
 
+/// Reads the aligned word containing `address`, rotated so the addressed byte is the low byte
+fn read_word_rotated(mem: &Memory, address: u32) -> u32 {
+    mem.read_u32(address & !0b11).rotate_right(8 * (address & 0b11))
+}
+
 impl DecodedInstruction for LoadStore {
     fn execute(&self, cpu: &mut CPU, mem: &mut Memory) {
-        if self.d == 15 {
-            todo!("d == 15");
+        // Doublewords have to be 8 byte aligned, otherwise the access aborts before the base is written back
+        let (address, _) = self.adressing_mode.address(cpu);
+        if matches!(self.length, Length::Doubleword) && address & 0b111 != 0 {
+            let pc = cpu.curr_instruction_address_from_execution_stage();
+            warn!("Misaligned doubleword access at {:08X}: {:08X}", pc, address);
+            cpu.raise_exception(MODE_ABT, VECTOR_DATA_ABORT, pc.wrapping_add(8));
+            return;
         }
-
         let address = self.adressing_mode.execute(cpu);
 
         match self.opcode {
+            Opcode::LDR if self.d == REGISTER_PC && matches!(self.length, Length::Word) => {
+                // Bit 0 of the loaded value selects the instruction set (interworking)
+                let value = read_word_rotated(mem, address);
+                let thumb = get_bit(value, 0);
+                cpu.set_thumb_state(thumb);
+                cpu.set_r(REGISTER_PC, if thumb { value & !0b1 } else { value & !0b11 });
+            }
+            Opcode::STR if self.d == REGISTER_PC => {
+                // A stored pc is the address of the instruction plus 12
+                let value = cpu.get_r(REGISTER_PC).wrapping_add(INSTRUCTION_LEN_ARM);
+                match self.length {
+                    Length::Byte => mem.write_u8(address, value as u8),
+                    Length::Halfword => mem.write_u16(address, value as u16),
+                    _ => mem.write_u32(address, value),
+                }
+            }
             Opcode::LDR => match self.length {
                 Length::Byte if self.sign_extend => cpu.set_r(self.d, sign_extend32(mem.read_u8(address) as u32, 8)),
                 Length::Byte => cpu.set_r(self.d, mem.read_u8(address) as u32),
+                // A misaligned signed halfword load only loads the addressed byte
+                Length::Halfword if self.sign_extend && address & 1 != 0 => cpu.set_r(self.d, sign_extend32(mem.read_u8(address) as u32, 8)),
                 Length::Halfword if self.sign_extend => cpu.set_r(self.d, sign_extend32(mem.read_u16(address) as u32, 16)),
-                Length::Halfword => cpu.set_r(self.d, mem.read_u16(address) as u32),
-                Length::Word => cpu.set_r(self.d, mem.read_u32(address)),
+                // Misaligned halfword loads rotate the addressed byte into the low byte
+                Length::Halfword => cpu.set_r(self.d, (mem.read_u16(address & !1) as u32).rotate_right(8 * (address & 1))),
+                Length::Word => cpu.set_r(self.d, read_word_rotated(mem, address)),
                 Length::Doubleword => {
                     cpu.set_r(self.d, mem.read_u32(address));
                     cpu.set_r(self.d + 1, mem.read_u32(address + 4));
@@ -212,6 +249,37 @@ impl DecodedInstruction for LoadStore {
         }
     }
 
+    fn cycles(&self, _cpu: &CPU) -> u32 {
+        match self.opcode {
+            // 1S + 1N + 1I, plus refilling the pipeline when loading the pc
+            Opcode::LDR => {
+                let mut cycles = CYCLES_S + CYCLES_N + CYCLES_I;
+                if matches!(self.length, Length::Doubleword) {
+                    cycles += CYCLES_S;
+                }
+                if self.d == REGISTER_PC {
+                    cycles += CYCLES_S + CYCLES_N;
+                }
+                cycles
+            }
+            // 2N
+            Opcode::STR => match self.length {
+                Length::Doubleword => 2 * CYCLES_N + CYCLES_S,
+                _ => 2 * CYCLES_N,
+            },
+        }
+    }
+
+    fn wait_cycles(&self, cpu: &CPU, mem: &Memory) -> u32 {
+        let (address, _) = self.adressing_mode.address(cpu);
+        match self.length {
+            Length::Byte => mem.access_cycles(address, 1) - 1,
+            Length::Halfword => mem.access_cycles(address, 2) - 1,
+            Length::Word => mem.access_cycles(address, 4) - 1,
+            Length::Doubleword => mem.access_cycles(address, 4) + mem.access_cycles(address.wrapping_add(4), 4) - 2,
+        }
+    }
+
     fn disassemble(&self, cond: Condition, _base_address: u32) -> String {
         let t = match self.adressing_mode.indexing_mode {
             IndexingMode::PostIndexed { t } => t,
@@ -306,6 +374,15 @@ impl AddressingMode {
     }
 
     fn execute(&self, cpu: &mut CPU) -> u32 {
+        let (address, writeback) = self.address(cpu);
+        if let Some(base) = writeback {
+            cpu.set_r(self.n, base);
+        }
+        address
+    }
+
+    /// Returns the address to access and the value to write back to the base register, if any
+    fn address(&self, cpu: &CPU) -> (u32, Option<u32>) {
         use AddressingModeType::*;
         let offset = match self.mode {
             Immediate(imm) => imm as u32,
@@ -333,20 +410,13 @@ impl AddressingMode {
             RotateRightWithExtend { m } => rotate_right_with_extend(cpu.get_carry_flag(), cpu.get_r(m)),
         };
 
-        // If n == 15, we need to mask the bottom two bits of the PC for Thumb mode
-        let r_n = if self.n == 15 { cpu.get_r(self.n) & !0b11u32 } else { cpu.get_r(self.n) };
+        let r_n = if self.n == REGISTER_PC { cpu.pc_for_operand() } else { cpu.get_r(self.n) };
         let r_n_offset = if self.u_is_add { r_n.wrapping_add(offset) } else { r_n.wrapping_sub(offset) };
 
         match self.indexing_mode {
-            IndexingMode::Offset => r_n_offset,
-            IndexingMode::PreIndexed => {
-                cpu.set_r(self.n, r_n_offset);
-                r_n_offset
-            }
-            IndexingMode::PostIndexed { .. } => {
-                cpu.set_r(self.n, r_n_offset);
-                r_n
-            }
+            IndexingMode::Offset => (r_n_offset, None),
+            IndexingMode::PreIndexed => (r_n_offset, Some(r_n_offset)),
+            IndexingMode::PostIndexed { .. } => (r_n, Some(r_n_offset)),
         }
     }
 }
@@ -354,21 +424,23 @@ impl AddressingMode {
 impl Display for AddressingMode {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         use AddressingModeType::*;
+        // Immediates always carry their sign, register offsets only when subtracted
+        let sign = if self.u_is_add { "" } else { "-" };
         let rhs = match self.mode {
-            Immediate(imm) => format!("#{}{:X}", if self.u_is_add { "+" } else { "-" }, imm),
-            Register { m } => format!("R{}", m),
-            LogicalShiftLeft { m, shift_imm } => format!("R{}, LSL #{:X}", m, shift_imm),
-            LogicalShiftRight { m, shift_imm } => format!("R{}, LSR #{:X}", m, shift_imm),
-            ArithmeticShiftRight { m, shift_imm } => format!("R{}, ASR #{:X}", m, shift_imm),
-            RotateRight { m, shift_imm } => format!("R{}, ROR #{:X}", m, shift_imm),
-            RotateRightWithExtend { m } => format!("R{}, RRX", m),
+            Immediate(imm) => format!("#{}0x{:X}", if self.u_is_add { "+" } else { "-" }, imm),
+            Register { m } => format!("{}R{}", sign, m),
+            LogicalShiftLeft { m, shift_imm } => format!("{}R{}, LSL #0x{:X}", sign, m, shift_imm),
+            LogicalShiftRight { m, shift_imm } => format!("{}R{}, LSR #0x{:X}", sign, m, shift_imm),
+            ArithmeticShiftRight { m, shift_imm } => format!("{}R{}, ASR #0x{:X}", sign, m, shift_imm),
+            RotateRight { m, shift_imm } => format!("{}R{}, ROR #0x{:X}", sign, m, shift_imm),
+            RotateRightWithExtend { m } => format!("{}R{}, RRX", sign, m),
         };
 
         let n = self.n;
         match self.indexing_mode {
             IndexingMode::Offset => write!(f, "[R{}, {}]", n, rhs),
             IndexingMode::PreIndexed => write!(f, "[R{}, {}]!", n, rhs),
-            IndexingMode::PostIndexed { .. } => write!(f, "[R{}], {}", rhs, n),
+            IndexingMode::PostIndexed { .. } => write!(f, "[R{}], {}", n, rhs),
         }
     }
 }
@@ -394,4 +466,124 @@ mod tests {
         let instruction = decode_halfword_thumb(0x8021, 0);
         assert_eq!(format!("{}", instruction.disassemble(Condition::AL, 0)), "STRH R1, [R4, #+0x0]");
     }
+
+    #[test]
+    fn test_post_indexed() {
+        assert_eq!(decode_arm(0xE4910004).disassemble(Condition::AL, 0), "LDR R0, [R1], #+0x4");
+        assert_eq!(decode_arm(0xE6821103).disassemble(Condition::AL, 0), "STR R1, [R2], R3, LSL #0x2");
+    }
+
+    #[test]
+    fn test_pre_indexed() {
+        assert_eq!(decode_arm(0xE5310004).disassemble(Condition::AL, 0), "LDR R0, [R1, #-0x4]!");
+        assert_eq!(decode_arm(0xE7221103).disassemble(Condition::AL, 0), "STR R1, [R2, -R3, LSL #0x2]!");
+        assert_eq!(decode_arm(0xE7B21003).disassemble(Condition::AL, 0), "LDR R1, [R2, R3]!");
+        assert_eq!(decode_arm(0xE5110008).disassemble(Condition::AL, 0), "LDR R0, [R1, #-0x8]");
+    }
+
+    #[test]
+    fn test_word_byte_thumb() {
+        assert_eq!(decode_word_byte_thumb(0x6848, 0).disassemble(Condition::AL, 0), "LDR R0, [R1, #+0x4]");
+        assert_eq!(decode_word_byte_thumb(0x715A, 0).disassemble(Condition::AL, 0), "STRB R2, [R3, #+0x5]");
+    }
+
+    #[test]
+    fn test_ldr_pc_interworking() {
+        let mut cpu = CPU::new();
+        let mut mem = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+        mem.write_u32(0x03000000, 0x08000101);
+        cpu.set_r(0, 0x03000000);
+
+        // LDR PC, [R0]
+        decode_arm(0xE590F000).execute(&mut cpu, &mut mem);
+        assert_eq!(cpu.get_r(REGISTER_PC), 0x08000100);
+        assert!(cpu.get_thumb_state());
+    }
+
+    #[test]
+    fn test_misaligned_loads() {
+        let mut cpu = CPU::new();
+        let mut mem = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+        mem.write_u32(0x03000000, 0x44332211);
+        mem.write_u32(0x03000004, 0x88776655);
+
+        // LDR R1, [R0]
+        cpu.set_r(0, 0x03000001);
+        decode_arm(0xE5901000).execute(&mut cpu, &mut mem);
+        assert_eq!(cpu.get_r(1), 0x11443322);
+        cpu.set_r(0, 0x03000003);
+        decode_arm(0xE5901000).execute(&mut cpu, &mut mem);
+        assert_eq!(cpu.get_r(1), 0x33221144);
+
+        // LDRH R1, [R0]
+        decode_extra_arm(0xE1D010B0).execute(&mut cpu, &mut mem);
+        assert_eq!(cpu.get_r(1), 0x33000044);
+        // LDRSH R1, [R0]
+        decode_extra_arm(0xE1D010F0).execute(&mut cpu, &mut mem);
+        assert_eq!(cpu.get_r(1), 0x00000044);
+        cpu.set_r(0, 0x03000007);
+        decode_extra_arm(0xE1D010F0).execute(&mut cpu, &mut mem);
+        assert_eq!(cpu.get_r(1), 0xFFFFFF88);
+    }
+
+    #[test]
+    fn test_doubleword() {
+        use crate::system::cpu::REGISTER_LR;
+
+        let mut cpu = CPU::new();
+        let mut mem = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+        mem.write_u32(0x03000008, 0x44332211);
+        mem.write_u32(0x0300000C, 0x88776655);
+
+        // LDRD R0, [R2, #8]
+        let ldrd = decode_extra_arm(0xE1C200D8);
+        assert_eq!(ldrd.disassemble(Condition::AL, 0), "LDRD R0, [R2, #+0x8]");
+        cpu.set_r(2, 0x03000000);
+        ldrd.execute(&mut cpu, &mut mem);
+        assert_eq!(cpu.get_r(0), 0x44332211);
+        assert_eq!(cpu.get_r(1), 0x88776655);
+
+        // Misaligned doublewords abort instead of loading
+        cpu.set_r(0, 0);
+        cpu.set_r(2, 0x03000004);
+        cpu.set_r(REGISTER_PC, 0x00000108);
+        ldrd.execute(&mut cpu, &mut mem);
+        assert_eq!(cpu.get_r(0), 0);
+        assert_eq!(cpu.get_mode(), MODE_ABT);
+        assert_eq!(cpu.get_r(REGISTER_PC), VECTOR_DATA_ABORT);
+        assert_eq!(cpu.get_r(REGISTER_LR), 0x00000108);
+
+        // LDRD R1, [R2] needs an even register
+        assert_eq!(decode_extra_arm(0xE1C210D0).disassemble(Condition::AL, 0), "???: E1C210D0");
+    }
+
+    #[test]
+    fn test_literal_pool_thumb() {
+        let mut cpu = CPU::new();
+        let mut mem = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+        mem.write_u16(0x03000000, 0x4801); // LDR R0, [PC, #4]
+        mem.write_u16(0x03000002, 0x4901); // LDR R1, [PC, #4]
+        mem.write_u32(0x03000004, 0x11111111);
+        mem.write_u32(0x03000008, 0x22222222);
+        cpu.set_thumb_state(true);
+        cpu.set_r(REGISTER_PC, 0x03000000);
+
+        // The pc is 4 ahead and word aligned, so both load the same literal
+        cpu.cycle(&mut mem);
+        cpu.cycle(&mut mem);
+        assert_eq!(cpu.get_r(0), 0x22222222);
+        assert_eq!(cpu.get_r(1), 0x22222222);
+    }
+
+    #[test]
+    fn test_str_pc() {
+        let mut cpu = CPU::new();
+        let mut mem = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+        cpu.set_r(0, 0x03000000);
+
+        // STR PC, [R0] executed at 0x00000100
+        cpu.set_r(REGISTER_PC, 0x00000108);
+        decode_arm(0xE580F000).execute(&mut cpu, &mut mem);
+        assert_eq!(mem.read_u32(0x03000000), 0x0000010C);
+    }
 }
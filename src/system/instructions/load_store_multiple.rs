@@ -1,14 +1,14 @@
 use std::fmt::Display;
 
 use crate::{
-    bitutil::{get_bit, get_bits16, get_bits32},
+    bitutil::{get_bit, get_bit16, get_bits16, get_bits32},
     system::{
         cpu::{self, CPU, REGISTER_LR, REGISTER_PC, REGISTER_SP},
         memory::Memory,
     },
 };
 
-use super::{Condition, DecodedInstruction};
+use super::{Condition, DecodedInstruction, CYCLES_I, CYCLES_N, CYCLES_S};
 
 #[derive(Debug)]
 struct LoadStoreMultiple {
@@ -98,35 +98,101 @@ pub fn decode_pop_thumb(instruction: u16, _next_instruction: u16) -> Box<dyn sup
     })
 }
 
+pub fn decode_ldm_stm_thumb(instruction: u16, _next_instruction: u16) -> Box<dyn super::DecodedInstruction> {
+    let registers = get_bits16(instruction, 0, 8);
+    let n = get_bits16(instruction, 8, 3) as u8;
+    let l = get_bit16(instruction, 11);
+    Box::new(LoadStoreMultiple {
+        opcode: if l { Opcode::LDM } else { Opcode::STM },
+        addressing_mode: AddressingMode {
+            n,
+            // A base register in the load list is overwritten by the loaded value instead
+            w: !(l && get_bit16(registers, n)),
+            registers,
+            typ: AddressingModeType::IncrementAfter,
+        },
+        s: false,
+    })
+}
+
 impl DecodedInstruction for LoadStoreMultiple {
     fn execute(&self, cpu: &mut CPU, mem: &mut Memory) {
-        let registers = self.addressing_mode.registers as u32;
-        let (start_address, end_address) = self.addressing_mode.execute(cpu);
+        // An empty list transfers just the pc
+        let registers = if self.addressing_mode.registers == 0 {
+            1 << REGISTER_PC
+        } else {
+            self.addressing_mode.registers as u32
+        };
+        let (start_address, mut writeback) = self.addressing_mode.execute(cpu);
 
         let mut address = start_address;
-        let cpu_mode = if self.s { cpu::MODE_USR } else { cpu.get_mode() };
+        // With the S bit the user bank is transferred, except by an LDM with the pc, which is an exception return instead
+        let exception_return = self.s && matches!(self.opcode, Opcode::LDM) && get_bit(registers, REGISTER_PC);
+        let cpu_mode = if self.s && !exception_return { cpu::MODE_USR } else { cpu.get_mode() };
         match self.opcode {
             Opcode::LDM => {
-                if get_bit(registers, 15) {
-                    todo!("ldm with destination register 15 not implemented");
-                }
-                for i in 0..=15 {
+                for i in 0..=14 {
                     if get_bit(registers, i) {
                         cpu.set_r_in_mode(i, cpu_mode, mem.read_u32(address));
-                        address += 4;
+                        address = address.wrapping_add(4);
                     }
                 }
+                // A loaded base register wins over the writeback, which has to happen before the cpsr is restored
+                if let Some(base) = writeback {
+                    if !get_bit(registers, self.addressing_mode.n) {
+                        cpu.set_r(self.addressing_mode.n, base);
+                    }
+                }
+                if get_bit(registers, REGISTER_PC) {
+                    let value = mem.read_u32(address);
+                    if exception_return {
+                        // The restored cpsr decides the instruction set
+                        if !cpu.current_mode_has_spsr() {
+                            panic!("Tried to restore CPSR from SPSR in user or system mode");
+                        }
+                        cpu.cpsr = cpu.get_spsr();
+                    } else {
+                        cpu.set_thumb_state(get_bit(value, 0));
+                    }
+                    cpu.set_r(REGISTER_PC, if cpu.get_thumb_state() { value & !0b1 } else { value & !0b11 });
+                }
             }
             Opcode::STM => {
                 for i in 0..=15 {
                     if get_bit(registers, i) {
                         mem.write_u32(address, cpu.get_r_in_mode(i, cpu_mode));
-                        address += 4;
+                        address = address.wrapping_add(4);
+                        // The base is written back after the first store, so only a base first in the list is stored unchanged
+                        if let Some(base) = writeback.take() {
+                            cpu.set_r(self.addressing_mode.n, base);
+                        }
                     }
                 }
             }
         }
-        assert_eq!(end_address, address - 4);
+    }
+
+    fn cycles(&self, _cpu: &CPU) -> u32 {
+        let count = self.addressing_mode.registers.count_ones();
+        match self.opcode {
+            // nS + 1N + 1I, plus refilling the pipeline when loading the pc
+            Opcode::LDM => {
+                let cycles = count * CYCLES_S + CYCLES_N + CYCLES_I;
+                if get_bit(self.addressing_mode.registers as u32, REGISTER_PC) {
+                    cycles + CYCLES_S + CYCLES_N
+                } else {
+                    cycles
+                }
+            }
+            // (n-1)S + 2N
+            Opcode::STM => count.saturating_sub(1) * CYCLES_S + 2 * CYCLES_N,
+        }
+    }
+
+    fn wait_cycles(&self, cpu: &CPU, mem: &Memory) -> u32 {
+        let (start_address, _) = self.addressing_mode.execute(cpu);
+        let count = self.addressing_mode.registers.count_ones().max(1);
+        (0..count).map(|i| mem.access_cycles(start_address.wrapping_add(i * 4), 4) - 1).sum()
     }
 
     fn disassemble(&self, cond: Condition, _base_address: u32) -> String {
@@ -167,35 +233,250 @@ impl Display for AddressingMode {
 }
 
 impl AddressingMode {
-    pub fn execute(&self, cpu: &mut CPU) -> (u32, u32) {
+    /// Returns the start address and the value to write back to the base register, if any
+    pub fn execute(&self, cpu: &CPU) -> (u32, Option<u32>) {
         let r_n = cpu.get_r(self.n);
-        let registers_count = self.registers.count_ones();
+        // An empty list moves the base as if all 16 registers were transferred
+        let len = if self.registers == 0 { 0x40 } else { self.registers.count_ones() * 4 };
         let start_address = match self.typ {
-            AddressingModeType::DecrementAfter => r_n - registers_count * 4 + 4,
+            AddressingModeType::DecrementAfter => r_n.wrapping_sub(len).wrapping_add(4),
             AddressingModeType::IncrementAfter => r_n,
-            AddressingModeType::DecrementBefore => r_n - registers_count * 4,
-            AddressingModeType::IncrementBefore => r_n + 4,
+            AddressingModeType::DecrementBefore => r_n.wrapping_sub(len),
+            AddressingModeType::IncrementBefore => r_n.wrapping_add(4),
         };
 
-        let end_address = match self.typ {
-            AddressingModeType::DecrementAfter => r_n,
-            AddressingModeType::IncrementAfter => r_n + registers_count * 4 - 4,
-            AddressingModeType::DecrementBefore => r_n - 4,
-            AddressingModeType::IncrementBefore => r_n + registers_count * 4,
-        };
+        let writeback = self.w.then(|| match self.typ {
+            AddressingModeType::DecrementAfter | AddressingModeType::DecrementBefore => r_n.wrapping_sub(len),
+            AddressingModeType::IncrementAfter | AddressingModeType::IncrementBefore => r_n.wrapping_add(len),
+        });
 
-        if self.w {
-            cpu.set_r(
-                self.n,
-                match self.typ {
-                    AddressingModeType::DecrementAfter => r_n - registers_count * 4,
-                    AddressingModeType::IncrementAfter => r_n + registers_count * 4,
-                    AddressingModeType::DecrementBefore => r_n - registers_count * 4,
-                    AddressingModeType::IncrementBefore => r_n + registers_count * 4,
-                },
-            );
-        };
+        (start_address, writeback)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pop_pc() {
+        let mut cpu = CPU::new();
+        let mut mem = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+        let mode = cpu.get_mode();
+        cpu.set_thumb_state(true);
+        cpu.set_r(REGISTER_SP, 0x03000100);
+        mem.write_u32(0x03000100, 0x12345678);
+        mem.write_u32(0x03000104, 0x08000201);
+
+        // POP {R0, PC}
+        decode_pop_thumb(0xBD01, 0).execute(&mut cpu, &mut mem);
+        assert_eq!(cpu.get_r(0), 0x12345678);
+        assert_eq!(cpu.get_r(REGISTER_PC), 0x08000200);
+        assert_eq!(cpu.get_r(REGISTER_SP), 0x03000108);
+        assert!(cpu.get_thumb_state());
+        assert_eq!(cpu.get_mode(), mode);
+    }
+
+    #[test]
+    fn test_push_pop_round_trip() {
+        let mut cpu = CPU::new();
+        let mut mem = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+        cpu.set_thumb_state(true);
+        cpu.set_r(REGISTER_SP, 0x03000100);
+        for r in 4..=7 {
+            cpu.set_r(r, r as u32 * 0x11111111);
+        }
+        cpu.set_r(REGISTER_LR, 0x08000123);
+
+        // PUSH {R4-R7, LR} stores the lowest register at the lowest address
+        decode_push_thumb(0xB5F0, 0).execute(&mut cpu, &mut mem);
+        assert_eq!(cpu.get_r(REGISTER_SP), 0x030000EC);
+        assert_eq!(mem.read_u32(0x030000EC), 0x44444444);
+        assert_eq!(mem.read_u32(0x030000F8), 0x77777777);
+        assert_eq!(mem.read_u32(0x030000FC), 0x08000123);
+
+        for r in 4..=7 {
+            cpu.set_r(r, 0);
+        }
+        // POP {R4-R7, PC} restores them and returns in thumb state
+        decode_pop_thumb(0xBDF0, 0).execute(&mut cpu, &mut mem);
+        assert_eq!(cpu.get_r(REGISTER_SP), 0x03000100);
+        assert_eq!(cpu.get_r(4), 0x44444444);
+        assert_eq!(cpu.get_r(7), 0x77777777);
+        assert_eq!(cpu.get_r(REGISTER_PC), 0x08000122);
+        assert!(cpu.get_thumb_state());
+
+        // Popping an even address switches to ARM
+        decode_push_thumb(0xB500, 0).execute(&mut cpu, &mut mem);
+        mem.write_u32(0x030000FC, 0x08000200);
+        decode_pop_thumb(0xBD00, 0).execute(&mut cpu, &mut mem);
+        assert_eq!(cpu.get_r(REGISTER_PC), 0x08000200);
+        assert!(!cpu.get_thumb_state());
+    }
+
+    #[test]
+    fn test_base_in_list() {
+        let mut cpu = CPU::new();
+        let mut mem = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+        mem.write_u32(0x03000100, 0x11111111);
+        mem.write_u32(0x03000104, 0x22222222);
+
+        // LDMIA R0!, {R0, R1}: the loaded value wins
+        cpu.set_r(0, 0x03000100);
+        decode_arm(0xE8B00003).execute(&mut cpu, &mut mem);
+        assert_eq!(cpu.get_r(0), 0x11111111);
+        assert_eq!(cpu.get_r(1), 0x22222222);
+
+        // LDMIA R2!, {R0, R1}
+        cpu.set_r(2, 0x03000100);
+        decode_arm(0xE8B20003).execute(&mut cpu, &mut mem);
+        assert_eq!(cpu.get_r(2), 0x03000108);
+
+        // STMIA R0!, {R0, R1}: the base is first, so the old base is stored
+        cpu.set_r(0, 0x03000200);
+        decode_arm(0xE8A00003).execute(&mut cpu, &mut mem);
+        assert_eq!(mem.read_u32(0x03000200), 0x03000200);
+        assert_eq!(cpu.get_r(0), 0x03000208);
+
+        // STMIA R1!, {R0, R1}: the base is not first, so the new base is stored
+        cpu.set_r(1, 0x03000300);
+        decode_arm(0xE8A10003).execute(&mut cpu, &mut mem);
+        assert_eq!(mem.read_u32(0x03000304), 0x03000308);
+        assert_eq!(cpu.get_r(1), 0x03000308);
+
+        // STMDB R1!, {R0, R1} stores the new base as well
+        cpu.set_r(1, 0x03000400);
+        decode_arm(0xE9210003).execute(&mut cpu, &mut mem);
+        assert_eq!(mem.read_u32(0x030003FC), 0x030003F8);
+        assert_eq!(cpu.get_r(1), 0x030003F8);
+    }
+
+    #[test]
+    fn test_base_wraps() {
+        let mut cpu = CPU::new();
+        let mut mem = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+
+        // STMDB R0!, {R1, R2} below address 0
+        cpu.set_r(0, 4);
+        decode_arm(0xE9200006).execute(&mut cpu, &mut mem);
+        assert_eq!(cpu.get_r(0), 0xFFFF_FFFC);
+
+        // LDMIA R0!, {R1, R2} past the end of the address space
+        decode_arm(0xE8B00006).execute(&mut cpu, &mut mem);
+        assert_eq!(cpu.get_r(0), 4);
+
+        // LDMDA R0!, {R1-R3}
+        cpu.set_r(0, 4);
+        decode_arm(0xE830000E).execute(&mut cpu, &mut mem);
+        assert_eq!(cpu.get_r(0), 0xFFFF_FFF8);
+    }
+
+    #[test]
+    fn test_empty_list() {
+        let mut cpu = CPU::new();
+        let mut mem = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+        cpu.set_r(REGISTER_PC, 0x08000010);
+
+        // STMIA R0!, {} stores the pc and moves the base by 0x40
+        cpu.set_r(0, 0x03000100);
+        decode_arm(0xE8A00000).execute(&mut cpu, &mut mem);
+        assert_eq!(mem.read_u32(0x03000100), 0x08000010);
+        assert_eq!(cpu.get_r(0), 0x03000140);
+
+        // STMDB R0!, {} stores at the lowest address of the 0x40 bytes
+        decode_arm(0xE9200000).execute(&mut cpu, &mut mem);
+        assert_eq!(mem.read_u32(0x03000100), 0x08000010);
+        assert_eq!(cpu.get_r(0), 0x03000100);
+
+        // LDMIA R0!, {} loads the pc
+        mem.write_u32(0x03000100, 0x08000200);
+        decode_arm(0xE8B00000).execute(&mut cpu, &mut mem);
+        assert_eq!(cpu.get_r(REGISTER_PC), 0x08000200);
+        assert_eq!(cpu.get_r(0), 0x03000140);
+    }
+
+    #[test]
+    fn test_user_bank_transfer() {
+        let mut cpu = CPU::new();
+        let mut mem = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+        for r in 8..=14 {
+            cpu.set_r_in_mode(r, cpu::MODE_USR, r as u32);
+            cpu.set_r_in_mode(r, cpu::MODE_FIQ, 0xF0 + r as u32);
+        }
+        cpu.set_mode(cpu::MODE_FIQ);
+        cpu.set_r(REGISTER_SP, 0x03000100);
+
+        // STMFD SP, {R8-R14}^
+        let stmfd = decode_arm(0xE94D7F00);
+        assert_eq!(stmfd.disassemble(Condition::AL, 0), "STMDB R13, {r8, r9, r10, r11, r12, r13, r14}^");
+        stmfd.execute(&mut cpu, &mut mem);
+        for r in 8..=14 {
+            assert_eq!(mem.read_u32(0x03000100 - 0x1C + (r - 8) * 4), r);
+        }
+
+        // LDMFD SP, {R8-R14}^ loads into the user bank
+        for r in 8..=14 {
+            mem.write_u32(0x03000100 - 0x1C + (r - 8) * 4, 0x100 + r);
+        }
+        cpu.set_r(REGISTER_SP, 0x03000100 - 0x1C);
+        decode_arm(0xE8DD7F00).execute(&mut cpu, &mut mem);
+        for r in 8..=14 {
+            assert_eq!(cpu.get_r_in_mode(r as u8, cpu::MODE_USR), 0x100 + r);
+        }
+        assert_eq!(cpu.get_r(8), 0xF8);
+        assert_eq!(cpu.get_r(REGISTER_SP), 0x03000100 - 0x1C);
+    }
+
+    #[test]
+    fn test_exception_return() {
+        let mut cpu = CPU::new();
+        let mut mem = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+        cpu.set_mode(cpu::MODE_IRQ);
+        cpu.set_spsr(cpu::MODE_SYS as u32 | 1 << 5);
+        cpu.set_r(REGISTER_SP, 0x03000100);
+        mem.write_u32(0x03000100, 0x12345678);
+        mem.write_u32(0x03000104, 0x08000201);
+
+        // LDMFD SP!, {R0, PC}^ uses the IRQ bank and restores the cpsr
+        decode_arm(0xE8FD8001).execute(&mut cpu, &mut mem);
+        assert_eq!(cpu.get_mode(), cpu::MODE_SYS);
+        assert!(cpu.get_thumb_state());
+        assert_eq!(cpu.get_r(0), 0x12345678);
+        assert_eq!(cpu.get_r(REGISTER_PC), 0x08000200);
+        assert_eq!(cpu.get_r_in_mode(REGISTER_SP, cpu::MODE_IRQ), 0x03000108);
+        assert_eq!(cpu.get_r(REGISTER_SP), 0);
+    }
+
+    #[test]
+    fn test_ldmia_stmia_thumb() {
+        let mut cpu = CPU::new();
+        let mut mem = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+        cpu.set_thumb_state(true);
+        cpu.set_r(0, 0x03000010);
+        cpu.set_r(1, 0x11111111);
+        cpu.set_r(2, 0x22222222);
+        cpu.set_r(3, 0x33333333);
+
+        // STMIA R0!, {R1-R3}
+        let stmia = decode_ldm_stm_thumb(0xC00E, 0);
+        assert_eq!(stmia.disassemble(Condition::AL, 0), "STMIA R0!, {r1, r2, r3}");
+        stmia.execute(&mut cpu, &mut mem);
+        assert_eq!(cpu.get_r(0), 0x0300001C);
+        assert_eq!(mem.read_u32(0x03000010), 0x11111111);
+        assert_eq!(mem.read_u32(0x03000018), 0x33333333);
+
+        // LDMIA R0!, {R4-R6}
+        cpu.set_r(0, 0x03000010);
+        decode_ldm_stm_thumb(0xC870, 0).execute(&mut cpu, &mut mem);
+        assert_eq!(cpu.get_r(0), 0x0300001C);
+        assert_eq!((cpu.get_r(4), cpu.get_r(5), cpu.get_r(6)), (0x11111111, 0x22222222, 0x33333333));
 
-        (start_address, end_address)
+        // LDMIA R0, {R0, R1} leaves the loaded base in place
+        cpu.set_r(0, 0x03000010);
+        let ldmia = decode_ldm_stm_thumb(0xC803, 0);
+        assert_eq!(ldmia.disassemble(Condition::AL, 0), "LDMIA R0, {r0, r1}");
+        ldmia.execute(&mut cpu, &mut mem);
+        assert_eq!(cpu.get_r(0), 0x11111111);
+        assert_eq!(cpu.get_r(1), 0x22222222);
     }
 }
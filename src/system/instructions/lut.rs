@@ -1,57 +1,66 @@
 use crate::system::instructions::{branch, data_processing, load_store};
 use crate::system::memory::Memory;
-use crate::{bitutil::get_bits32, system::cpu::CPU};
+use crate::{
+    bitutil::get_bits32,
+    system::cpu::{CPU, MODE_UND, VECTOR_UNDEFINED},
+};
+use std::sync::LazyLock;
 
-use super::{ctrl_ext, load_store_multiple, Condition, DecodedInstruction};
+use super::{cache::Decoded, coprocessor, ctrl_ext, extend_reverse, load_store_multiple, multiply, qarith, signed_multiply, swap, swi, Condition, DecodedInstruction, CYCLES_I, CYCLES_N, CYCLES_S};
 
 const LUT_ARM_SIZE: usize = 1 << 12;
 const LUT_THUMB_SIZE: usize = 1 << 8;
 
-static mut INSTRUCTION_LUT: Option<InstructionLut> = None;
+// Built on the first decode and shared by all threads
+static INSTRUCTION_LUT: LazyLock<InstructionLut> = LazyLock::new(InstructionLut::new);
 
 type DecoderArmFn = fn(u32) -> Box<dyn DecodedInstruction>;
 type DecoderThumbFn = fn(u16, u16) -> Box<dyn DecodedInstruction>;
+type HotDecoderArmFn = fn(u32) -> Decoded;
 
+#[derive(Clone, Copy)]
 enum DecoderFn {
     Arm(DecoderArmFn),
+    // An instruction class that also decodes without a box, for the classes executed most often
+    HotArm(DecoderArmFn, HotDecoderArmFn),
     Thumb(DecoderThumbFn),
 }
 
 pub struct InstructionLut {
     decoders_arm: [DecoderArmFn; LUT_ARM_SIZE],
+    hot_decoders_arm: [Option<HotDecoderArmFn>; LUT_ARM_SIZE],
     decoders_thumb: [DecoderThumbFn; LUT_THUMB_SIZE],
 }
 
 impl InstructionLut {
-    pub fn initialize() {
+    fn new() -> Self {
         let mut lut = Self {
             decoders_arm: [UnknownInstruction::decode_arm; LUT_ARM_SIZE],
+            hot_decoders_arm: [None; LUT_ARM_SIZE],
             decoders_thumb: [UnknownInstruction::decode_thumb; LUT_THUMB_SIZE],
         };
         lut.setup_patterns();
-        unsafe {
-            INSTRUCTION_LUT = Some(lut);
-        }
+        lut
     }
 
+    /// Decodes an ARM instruction, the unconditional instruction space (condition 1111) isn't part of ARMv4T and is undefined
     pub fn decode_arm(instruction: u32) -> Box<dyn DecodedInstruction> {
-        unsafe {
-            if let Some(ref lut) = INSTRUCTION_LUT {
-                (lut.decoders_arm[Self::index_arm(instruction)])(instruction)
-            } else {
-                panic!("Instruction LUT not initialized!");
-            }
+        if is_unconditional(instruction) {
+            return UnknownInstruction::decode_arm(instruction);
         }
+        (INSTRUCTION_LUT.decoders_arm[Self::index_arm(instruction)])(instruction)
     }
 
     pub fn decode_thumb(instruction: u16, next_instruction: u16) -> Box<dyn DecodedInstruction> {
-        unsafe {
-            if let Some(ref lut) = INSTRUCTION_LUT {
-                (lut.decoders_thumb[Self::index_thumb(instruction)])(instruction, next_instruction)
-            } else {
-                panic!("Instruction LUT not initialized!");
-            }
+        (INSTRUCTION_LUT.decoders_thumb[Self::index_thumb(instruction)])(instruction, next_instruction)
+    }
+
+    /// Decodes data processing, load/store and branch instructions into a `Decoded` that executes them without dynamic dispatch, None for other instructions
+    pub fn decode_arm_hot(instruction: u32) -> Option<Decoded> {
+        if is_unconditional(instruction) {
+            return None;
         }
+        INSTRUCTION_LUT.hot_decoders_arm[Self::index_arm(instruction)].map(|decoder| decoder(instruction))
     }
 
     fn index_arm(instruction: u32) -> usize {
@@ -67,31 +76,43 @@ impl InstructionLut {
 
     fn setup_patterns(&mut self) {
         use DecoderFn::*;
+        let data_processing = HotArm(data_processing::decode_arm, |instruction| Decoded::DataProcessing(data_processing::decode_arm_unboxed(instruction)));
+        let load_store = HotArm(load_store::decode_arm, |instruction| Decoded::LoadStore(load_store::decode_arm_unboxed(instruction)));
+        let branch = HotArm(branch::decode_b_bl_arm, |instruction| Decoded::Branch(branch::decode_b_bl_arm_unboxed(instruction)));
+
         // arm
         // data processing immediate shift
-        self.add_pattern("000xxxxx xxx0", Arm(data_processing::decode_arm));
+        self.add_pattern("000xxxxx xxx0", data_processing);
         // misc
         self.add_pattern("00010xx0 xxx0", Arm(UnknownInstruction::decode_arm));
         self.add_pattern("00010x00 0000", Arm(ctrl_ext::mrs::decode_arm));
         self.add_pattern("00010x10 0000", Arm(ctrl_ext::msr::decode_arm));
         // data processing register shift
-        self.add_pattern("000xxxxx 0xx1", Arm(data_processing::decode_arm));
+        self.add_pattern("000xxxxx 0xx1", data_processing);
         // misc
         self.add_pattern("00010xx0 xxx1", Arm(UnknownInstruction::decode_arm));
         self.add_pattern("00010010 0001", Arm(branch::decode_bx_arm));
         self.add_pattern("00010010 0011", Arm(branch::decode_blx_arm));
+        self.add_pattern("00010110 0001", Arm(ctrl_ext::clz::decode_arm));
+        self.add_pattern("00010010 0111", Arm(ctrl_ext::bkpt::decode_arm));
+        self.add_pattern("00010xx0 0101", Arm(qarith::decode_arm));
+        self.add_pattern("00010xx0 1xx0", Arm(signed_multiply::decode_arm));
         // multiplies, extra load/stores
         self.add_pattern("000xxxxx 1xx1", Arm(load_store::decode_extra_arm));
+        self.add_pattern("0000000x 1001", Arm(multiply::decode_arm));
+        self.add_pattern("0000001x 1001", Arm(multiply::decode_arm));
+        self.add_pattern("00001xxx 1001", Arm(multiply::decode_long_arm));
+        self.add_pattern("00010x00 1001", Arm(swap::decode_arm));
         // data processing immediate
-        self.add_pattern("001xxxxx xxxx", Arm(data_processing::decode_arm));
+        self.add_pattern("001xxxxx xxxx", data_processing);
         // undefined
         self.add_pattern("00110x00 1xx1", Arm(UnknownInstruction::decode_arm));
         // move immediate to status register
         self.add_pattern("00110x10 xxxx", Arm(UnknownInstruction::decode_arm));
         // load/store immediate offset
-        self.add_pattern("010xxxxx xxxx", Arm(load_store::decode_arm));
+        self.add_pattern("010xxxxx xxxx", load_store);
         // load/store register offset
-        self.add_pattern("011xxxxx xxx0", Arm(load_store::decode_arm));
+        self.add_pattern("011xxxxx xxx0", load_store);
         // media instructions
         self.add_pattern("011xxxxx xxx1", Arm(load_store::decode_arm));
         // undefined
@@ -99,16 +120,15 @@ impl InstructionLut {
         // load store multiple
         self.add_pattern("100xxxxx xxxx", Arm(load_store_multiple::decode_arm));
         // branch
-        self.add_pattern("1010xxxx xxxx", Arm(branch::decode_b_arm));
-        self.add_pattern("1011xxxx xxxx", Arm(branch::decode_bl_arm));
+        self.add_pattern("101xxxxx xxxx", branch);
         // coprocessor load/store and double register transfers
         self.add_pattern("110xxxxx xxxx", Arm(UnknownInstruction::decode_arm));
         // coprocessor data processing
         self.add_pattern("1110xxxx xxx0", Arm(UnknownInstruction::decode_arm));
         // coprocessor register transfers
-        self.add_pattern("1110xxxx xxx1", Arm(UnknownInstruction::decode_arm));
+        self.add_pattern("1110xxxx xxx1", Arm(coprocessor::decode_register_transfer_arm));
         // software interrupt
-        self.add_pattern("1111xxxx xxxx", Arm(UnknownInstruction::decode_arm));
+        self.add_pattern("1111xxxx xxxx", Arm(swi::decode_arm));
 
         // thumb
         // shift by immediate
@@ -136,20 +156,18 @@ impl InstructionLut {
         // load/store to/from stack
         self.add_pattern("1001 xxxx", Thumb(load_store::decode_stack_thumb));
         // add sp or pc
-        self.add_pattern("1010 xxxx", Thumb(UnknownInstruction::decode_thumb));
+        self.add_pattern("1010 xxxx", Thumb(data_processing::decode_add_sp_pc_thumb));
         // misc
         self.add_pattern("1011 xxxx", Thumb(UnknownInstruction::decode_thumb));
         self.add_pattern("1011 0000", Thumb(data_processing::decode_adjust_sp_thumb));
+        self.add_pattern("1011 0010", Thumb(extend_reverse::decode_extend_thumb));
+        self.add_pattern("1011 1010", Thumb(extend_reverse::decode_reverse_thumb));
         self.add_pattern("1011 010x", Thumb(load_store_multiple::decode_push_thumb));
         self.add_pattern("1011 110x", Thumb(load_store_multiple::decode_pop_thumb));
         // load/store multiple
-        self.add_pattern("1100 xxxx", Thumb(UnknownInstruction::decode_thumb));
-        // conditional branch
+        self.add_pattern("1100 xxxx", Thumb(load_store_multiple::decode_ldm_stm_thumb));
+        // conditional branch, undefined and software interrupt
         self.add_pattern("1101 xxxx", Thumb(branch::decode_conditional_branch_thumb));
-        // undefined
-        self.add_pattern("1101 1110", Thumb(UnknownInstruction::decode_thumb));
-        // software interrupt
-        self.add_pattern("1101 1111", Thumb(UnknownInstruction::decode_thumb));
         // unconditional branch
         self.add_pattern("11100 xxx", Thumb(branch::decode_unconditional_branch_thumb));
         // bl
@@ -161,7 +179,7 @@ impl InstructionLut {
 
         let pattern = pattern.to_string().replace(" ", "");
         let pattern_len = match decoder {
-            Arm(_) => 12,
+            Arm(_) | HotArm(..) => 12,
             Thumb(_) => 8,
         };
 
@@ -197,32 +215,57 @@ impl InstructionLut {
             }
 
             match decoder {
-                Arm(decoder) => self.decoders_arm[index] = decoder,
+                Arm(decoder) => {
+                    self.decoders_arm[index] = decoder;
+                    self.hot_decoders_arm[index] = None;
+                }
+                HotArm(decoder, hot_decoder) => {
+                    self.decoders_arm[index] = decoder;
+                    self.hot_decoders_arm[index] = Some(hot_decoder);
+                }
                 Thumb(decoder) => self.decoders_thumb[index] = decoder,
             };
         }
     }
 }
 
+/// Whether an ARM instruction has condition 1111, which has no `Condition`
+pub fn is_unconditional(instruction: u32) -> bool {
+    get_bits32(instruction, 28, 4) == 0b1111
+}
+
 #[derive(Debug)]
-enum UnknownInstruction {
+pub enum UnknownInstruction {
     Arm(u32),
     Thumb(u16, u16),
 }
 impl UnknownInstruction {
-    fn decode_arm(instruction: u32) -> Box<dyn DecodedInstruction> {
+    pub fn decode_arm(instruction: u32) -> Box<dyn DecodedInstruction> {
         Box::new(UnknownInstruction::Arm(instruction))
     }
-    fn decode_thumb(instruction: u16, next_instruction: u16) -> Box<dyn DecodedInstruction> {
+    pub fn decode_thumb(instruction: u16, next_instruction: u16) -> Box<dyn DecodedInstruction> {
         Box::new(UnknownInstruction::Thumb(instruction, next_instruction))
     }
 }
 impl DecodedInstruction for UnknownInstruction {
-    fn execute(&self, _cpu: &mut CPU, _mem: &mut Memory) {
-        match self {
-            UnknownInstruction::Arm(instruction) => panic!("Tried to execute unknown arm instruction: {:08X}", instruction),
-            UnknownInstruction::Thumb(instruction, next_instruction) => panic!("Tried to execute unknown thumb instruction: {:04X}, next: {:04X}", instruction, next_instruction),
+    fn execute(&self, cpu: &mut CPU, _mem: &mut Memory) {
+        if cpu.is_strict() {
+            match self {
+                UnknownInstruction::Arm(instruction) => panic!("Tried to execute unknown arm instruction: {:08X}", instruction),
+                UnknownInstruction::Thumb(instruction, next_instruction) => panic!("Tried to execute unknown thumb instruction: {:04X}, next: {:04X}", instruction, next_instruction),
+            }
         }
+        warn!(
+            "Undefined instruction at {:08X}: {}",
+            cpu.curr_instruction_address_from_execution_stage(),
+            self.disassemble(Condition::AL, 0)
+        );
+        // The handler returns to the instruction after the undefined one
+        cpu.raise_exception(MODE_UND, VECTOR_UNDEFINED, cpu.next_instruction_address_from_execution_stage());
+    }
+
+    fn cycles(&self, _cpu: &CPU) -> u32 {
+        2 * CYCLES_S + CYCLES_I + CYCLES_N
     }
 
     fn disassemble(&self, _cond: Condition, _base_address: u32) -> String {
@@ -232,3 +275,27 @@ impl DecodedInstruction for UnknownInstruction {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_concurrent_decode() {
+        // The table is built once, whichever thread decodes first
+        let threads: Vec<_> = (0..4)
+            .map(|i| {
+                std::thread::spawn(move || {
+                    let add = InstructionLut::decode_arm(0xE0800001 + (i << 12)).disassemble(Condition::AL, 0);
+                    let shift = InstructionLut::decode_thumb(0x0088 + i as u16, 0).disassemble(Condition::AL, 0);
+                    (add, shift)
+                })
+            })
+            .collect();
+        for (i, thread) in threads.into_iter().enumerate() {
+            let (add, shift) = thread.join().unwrap();
+            assert_eq!(add, format!("ADD R{}, R0, R1", i));
+            assert_eq!(shift, format!("MOVS R{}, R1, LSL #0x2", i));
+        }
+    }
+}
@@ -1,63 +1,22 @@
 use std::fmt::{Debug, Display};
 
 use super::{cpu::CPU, memory::Memory};
-use crate::bitutil::{get_bit, get_bits32};
+use crate::bitutil::get_bits32;
 
 mod branch;
+pub mod cache;
+mod coprocessor;
 mod ctrl_ext;
 mod data_processing;
+mod extend_reverse;
 mod load_store;
 mod load_store_multiple;
 pub mod lut;
-
-pub fn format_instruction_arm(instruction: u32, base_address: u32) -> String {
-    format!(
-        "{} ({:08X})\n\
-            Bit Index:   27 26 25 24 23 22 21 20   07 06 05 04\n\
-            Values:      {:<2} {:<2} {:<2} {:<2} {:<2} {:<2} {:<2} {:<4} {:<2} {:<2} {:<2} {:<2}",
-        lut::InstructionLut::decode_arm(instruction).disassemble(Condition::decode_arm(instruction), base_address),
-        instruction,
-        get_bit(instruction, 27) as u32,
-        get_bit(instruction, 26) as u32,
-        get_bit(instruction, 25) as u32,
-        get_bit(instruction, 24) as u32,
-        get_bit(instruction, 23) as u32,
-        get_bit(instruction, 22) as u32,
-        get_bit(instruction, 21) as u32,
-        get_bit(instruction, 20) as u32,
-        get_bit(instruction, 7) as u32,
-        get_bit(instruction, 6) as u32,
-        get_bit(instruction, 5) as u32,
-        get_bit(instruction, 4) as u32,
-    )
-}
-
-pub fn format_instruction_thumb(instruction: u16, next_instruction: u16, base_address: u32) -> String {
-    format!(
-        "{} ({:04X}, next: {:04X})\n\
-            Bit Index:   15 14 13 12 11 10 09 08 07 06 05 04 03 02 01 00\n\
-            Values:      {:<2} {:<2} {:<2} {:<2} {:<2} {:<2} {:<2} {:<2} {:<2} {:<2} {:<2} {:<2} {:<2} {:<2} {:<2} {:<2}",
-        lut::InstructionLut::decode_thumb(instruction, next_instruction).disassemble(Condition::AL, base_address),
-        instruction,
-        next_instruction,
-        get_bit(instruction as u32, 15) as u32,
-        get_bit(instruction as u32, 14) as u32,
-        get_bit(instruction as u32, 13) as u32,
-        get_bit(instruction as u32, 12) as u32,
-        get_bit(instruction as u32, 11) as u32,
-        get_bit(instruction as u32, 10) as u32,
-        get_bit(instruction as u32, 9) as u32,
-        get_bit(instruction as u32, 8) as u32,
-        get_bit(instruction as u32, 7) as u32,
-        get_bit(instruction as u32, 6) as u32,
-        get_bit(instruction as u32, 5) as u32,
-        get_bit(instruction as u32, 4) as u32,
-        get_bit(instruction as u32, 3) as u32,
-        get_bit(instruction as u32, 2) as u32,
-        get_bit(instruction as u32, 1) as u32,
-        get_bit(instruction as u32, 0) as u32,
-    )
-}
+mod multiply;
+mod qarith;
+mod signed_multiply;
+mod swap;
+mod swi;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Condition {
@@ -134,9 +93,25 @@ impl Display for Condition {
     }
 }
 
-pub trait DecodedInstruction: Debug {
+// Cycle costs of the ARM7TDMI bus cycle types, excluding memory wait states
+pub const CYCLES_S: u32 = 1; // sequential
+pub const CYCLES_N: u32 = 1; // non-sequential
+pub const CYCLES_I: u32 = 1; // internal
+
+// Send so a system can be built before it is moved to the emulator thread
+pub trait DecodedInstruction: Debug + Send {
     fn execute(&self, cpu: &mut CPU, mem: &mut Memory);
     fn disassemble(&self, cond: Condition, base_address: u32) -> String;
+
+    /// Number of cycles the instruction takes, evaluated before it is executed
+    fn cycles(&self, _cpu: &CPU) -> u32 {
+        CYCLES_S
+    }
+
+    /// Wait states of the data accesses on top of `cycles`, evaluated before it is executed
+    fn wait_cycles(&self, _cpu: &CPU, _mem: &Memory) -> u32 {
+        0
+    }
 }
 
 #[cfg(test)]
@@ -0,0 +1,219 @@
+use crate::{
+    bitutil::{get_bit, get_bits16, get_bits32},
+    system::{cpu::CPU, memory::Memory},
+};
+
+use super::{Condition, DecodedInstruction, CYCLES_I, CYCLES_S};
+
+/// Number of internal cycles the multiplier array needs for the operand in Rs
+fn multiplier_cycles(r_s: u32, signed: bool) -> u32 {
+    let leading = if signed && get_bit(r_s, 31) { r_s.leading_ones() } else { r_s.leading_zeros() };
+    match leading {
+        24.. => 1,
+        16.. => 2,
+        8.. => 3,
+        _ => 4,
+    }
+}
+
+#[derive(Debug)]
+struct Multiply {
+    d: u8,
+    n: u8,
+    s: u8,
+    m: u8,
+    accumulate: bool,
+    set_flags: bool,
+}
+
+#[derive(Debug)]
+struct MultiplyLong {
+    d_hi: u8,
+    d_lo: u8,
+    s: u8,
+    m: u8,
+    signed: bool,
+    accumulate: bool,
+    set_flags: bool,
+}
+
+pub fn decode_arm(instruction: u32) -> Box<dyn DecodedInstruction> {
+    Box::new(Multiply {
+        d: get_bits32(instruction, 16, 4) as u8,
+        n: get_bits32(instruction, 12, 4) as u8,
+        s: get_bits32(instruction, 8, 4) as u8,
+        m: get_bits32(instruction, 0, 4) as u8,
+        accumulate: get_bit(instruction, 21),
+        set_flags: get_bit(instruction, 20),
+    })
+}
+
+pub fn decode_thumb(instruction: u16, _next_instruction: u16) -> Box<dyn DecodedInstruction> {
+    // MUL Rd, Rm computes Rd = Rm * Rd
+    let d = get_bits16(instruction, 0, 3) as u8;
+    Box::new(Multiply {
+        d,
+        n: 0,
+        s: d,
+        m: get_bits16(instruction, 3, 3) as u8,
+        accumulate: false,
+        set_flags: true,
+    })
+}
+
+pub fn decode_long_arm(instruction: u32) -> Box<dyn DecodedInstruction> {
+    Box::new(MultiplyLong {
+        d_hi: get_bits32(instruction, 16, 4) as u8,
+        d_lo: get_bits32(instruction, 12, 4) as u8,
+        s: get_bits32(instruction, 8, 4) as u8,
+        m: get_bits32(instruction, 0, 4) as u8,
+        signed: get_bit(instruction, 22),
+        accumulate: get_bit(instruction, 21),
+        set_flags: get_bit(instruction, 20),
+    })
+}
+
+impl DecodedInstruction for Multiply {
+    fn execute(&self, cpu: &mut CPU, _mem: &mut Memory) {
+        let mut result = cpu.get_r(self.m).wrapping_mul(cpu.get_r(self.s));
+        if self.accumulate {
+            result = result.wrapping_add(cpu.get_r(self.n));
+        }
+        cpu.set_r(self.d, result);
+
+        if self.set_flags {
+            cpu.set_negative_flag(get_bit(result, 31));
+            cpu.set_zero_flag(result == 0);
+        }
+    }
+
+    fn cycles(&self, cpu: &CPU) -> u32 {
+        // 1S + mI, plus 1I for the accumulate
+        CYCLES_S + (multiplier_cycles(cpu.get_r(self.s), true) + self.accumulate as u32) * CYCLES_I
+    }
+
+    fn disassemble(&self, cond: Condition, _base_address: u32) -> String {
+        // MUL{<cond>}{S} <Rd>, <Rm>, <Rs>
+        // MLA{<cond>}{S} <Rd>, <Rm>, <Rs>, <Rn>
+        format!(
+            "{}{}{} R{}, R{}, R{}{}",
+            if self.accumulate { "MLA" } else { "MUL" },
+            cond,
+            if self.set_flags { "S" } else { "" },
+            self.d,
+            self.m,
+            self.s,
+            if self.accumulate { format!(", R{}", self.n) } else { String::new() }
+        )
+    }
+}
+
+impl DecodedInstruction for MultiplyLong {
+    fn execute(&self, cpu: &mut CPU, _mem: &mut Memory) {
+        let r_m = cpu.get_r(self.m);
+        let r_s = cpu.get_r(self.s);
+        let mut result = if self.signed {
+            (r_m as i32 as i64).wrapping_mul(r_s as i32 as i64) as u64
+        } else {
+            (r_m as u64).wrapping_mul(r_s as u64)
+        };
+        if self.accumulate {
+            let accumulator = (cpu.get_r(self.d_hi) as u64) << 32 | cpu.get_r(self.d_lo) as u64;
+            result = result.wrapping_add(accumulator);
+        }
+        cpu.set_r(self.d_lo, result as u32);
+        cpu.set_r(self.d_hi, (result >> 32) as u32);
+
+        if self.set_flags {
+            cpu.set_negative_flag(result >> 63 != 0);
+            cpu.set_zero_flag(result == 0);
+        }
+    }
+
+    fn cycles(&self, cpu: &CPU) -> u32 {
+        // 1S + (m+1)I, plus 1I for the accumulate
+        CYCLES_S + (multiplier_cycles(cpu.get_r(self.s), self.signed) + 1 + self.accumulate as u32) * CYCLES_I
+    }
+
+    fn disassemble(&self, cond: Condition, _base_address: u32) -> String {
+        // {U|S}{MULL|MLAL}{<cond>}{S} <RdLo>, <RdHi>, <Rm>, <Rs>
+        format!(
+            "{}{}{}{} R{}, R{}, R{}, R{}",
+            if self.signed { "S" } else { "U" },
+            if self.accumulate { "MLAL" } else { "MULL" },
+            cond,
+            if self.set_flags { "S" } else { "" },
+            self.d_lo,
+            self.d_hi,
+            self.m,
+            self.s
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mul() {
+        assert_eq!(decode_arm(0xE0000291).disassemble(Condition::AL, 0), "MUL R0, R1, R2");
+        assert_eq!(decode_arm(0xE0334392).disassemble(Condition::AL, 0), "MLAS R3, R2, R3, R4");
+    }
+
+    #[test]
+    fn test_mla_execute() {
+        let mut cpu = CPU::new();
+        let mut mem = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+        cpu.set_r(1, 0xFFFFFFFF);
+        cpu.set_r(2, 3);
+        cpu.set_r(4, 1);
+
+        // MLAS R0, R1, R2, R4
+        decode_arm(0xE0304291).execute(&mut cpu, &mut mem);
+        assert_eq!(cpu.get_r(0), 0xFFFFFFFE);
+        assert!(cpu.get_negative_flag());
+        assert!(!cpu.get_zero_flag());
+    }
+
+    #[test]
+    fn test_multiplier_cycles() {
+        assert_eq!(multiplier_cycles(0x000000FF, true), 1);
+        assert_eq!(multiplier_cycles(0xFFFFFF00, true), 1);
+        assert_eq!(multiplier_cycles(0xFFFFFF00, false), 4);
+        assert_eq!(multiplier_cycles(0x0000FFFF, false), 2);
+        assert_eq!(multiplier_cycles(0x00FFFFFF, false), 3);
+    }
+
+    #[test]
+    fn test_multiply_long() {
+        assert_eq!(decode_long_arm(0xE0E10392).disassemble(Condition::AL, 0), "SMLAL R0, R1, R2, R3");
+        assert_eq!(decode_long_arm(0xE0810392).disassemble(Condition::AL, 0), "UMULL R0, R1, R2, R3");
+    }
+
+    #[test]
+    fn test_multiply_long_sign_extension() {
+        let mut cpu = CPU::new();
+        let mut mem = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+        cpu.set_r(2, -3i32 as u32);
+        cpu.set_r(3, 5);
+
+        // SMULLS R0, R1, R2, R3
+        decode_long_arm(0xE0D10392).execute(&mut cpu, &mut mem);
+        assert_eq!(cpu.get_r(0), -15i32 as u32);
+        assert_eq!(cpu.get_r(1), 0xFFFFFFFF);
+        assert!(cpu.get_negative_flag());
+
+        // UMULL R0, R1, R2, R3 treats the multiplicand as unsigned
+        decode_long_arm(0xE0810392).execute(&mut cpu, &mut mem);
+        assert_eq!(cpu.get_r(0), -15i32 as u32);
+        assert_eq!(cpu.get_r(1), 4);
+
+        // SMLAL R0, R1, R2, R3 accumulates onto R1:R0
+        cpu.set_r(0, 20);
+        cpu.set_r(1, 0);
+        decode_long_arm(0xE0E10392).execute(&mut cpu, &mut mem);
+        assert_eq!(cpu.get_r(0), 5);
+        assert_eq!(cpu.get_r(1), 0);
+    }
+}
@@ -0,0 +1,138 @@
+use crate::{
+    bitutil::get_bits32,
+    system::{cpu::CPU, memory::Memory},
+};
+
+use super::{Condition, DecodedInstruction};
+
+/// ARMv5 saturating add and subtract, which set the sticky Q flag instead of overflowing
+#[derive(Debug)]
+struct SaturatingArithmetic {
+    opcode: Opcode,
+    n: u8,
+    d: u8,
+    m: u8,
+}
+
+#[derive(Debug)]
+enum Opcode {
+    Qadd,
+    Qsub,
+    Qdadd,
+    Qdsub,
+}
+
+pub fn decode_arm(instruction: u32) -> Box<dyn DecodedInstruction> {
+    Box::new(SaturatingArithmetic {
+        opcode: match get_bits32(instruction, 21, 2) {
+            0b00 => Opcode::Qadd,
+            0b01 => Opcode::Qsub,
+            0b10 => Opcode::Qdadd,
+            0b11 => Opcode::Qdsub,
+            _ => unreachable!(),
+        },
+        n: get_bits32(instruction, 16, 4) as u8,
+        d: get_bits32(instruction, 12, 4) as u8,
+        m: get_bits32(instruction, 0, 4) as u8,
+    })
+}
+
+/// Returns the clamped result and whether it saturated
+fn saturate(value: Option<i32>, unbounded: i64) -> (i32, bool) {
+    match value {
+        Some(value) => (value, false),
+        None if unbounded < 0 => (i32::MIN, true),
+        None => (i32::MAX, true),
+    }
+}
+
+impl DecodedInstruction for SaturatingArithmetic {
+    fn execute(&self, cpu: &mut CPU, _mem: &mut Memory) {
+        let m = cpu.get_r(self.m) as i32;
+        let n = cpu.get_r(self.n) as i32;
+        // The doubling variants saturate 2 * Rn first
+        let (n, doubling_saturated) = match self.opcode {
+            Opcode::Qdadd | Opcode::Qdsub => saturate(n.checked_mul(2), n as i64),
+            Opcode::Qadd | Opcode::Qsub => (n, false),
+        };
+        let (result, saturated) = match self.opcode {
+            Opcode::Qadd | Opcode::Qdadd => saturate(m.checked_add(n), m as i64 + n as i64),
+            Opcode::Qsub | Opcode::Qdsub => saturate(m.checked_sub(n), m as i64 - n as i64),
+        };
+
+        cpu.set_r(self.d, result as u32);
+        if saturated || doubling_saturated {
+            cpu.set_saturation_flag(true);
+        }
+    }
+
+    fn disassemble(&self, cond: Condition, _base_address: u32) -> String {
+        // {QADD|QSUB|QDADD|QDSUB}{<cond>} <Rd>, <Rm>, <Rn>
+        let opcode = match self.opcode {
+            Opcode::Qadd => "QADD",
+            Opcode::Qsub => "QSUB",
+            Opcode::Qdadd => "QDADD",
+            Opcode::Qdsub => "QDSUB",
+        };
+        format!("{}{} R{}, R{}, R{}", opcode, cond, self.d, self.m, self.n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn execute(instruction: u32, m: i32, n: i32) -> (i32, bool) {
+        let mut cpu = CPU::new();
+        let mut mem = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+        cpu.set_r(1, m as u32);
+        cpu.set_r(2, n as u32);
+        decode_arm(instruction).execute(&mut cpu, &mut mem);
+        (cpu.get_r(0) as i32, cpu.get_saturation_flag())
+    }
+
+    #[test]
+    fn test_qadd_qsub() {
+        // QADD R0, R1, R2
+        assert_eq!(decode_arm(0xE1020051).disassemble(Condition::AL, 0), "QADD R0, R1, R2");
+        assert_eq!(execute(0xE1020051, 5, -7), (-2, false));
+        assert_eq!(execute(0xE1020051, i32::MAX, 1), (i32::MAX, true));
+        assert_eq!(execute(0xE1020051, i32::MIN, -1), (i32::MIN, true));
+
+        // QSUB R0, R1, R2
+        assert_eq!(decode_arm(0xE1220051).disassemble(Condition::NE, 0), "QSUBNE R0, R1, R2");
+        assert_eq!(execute(0xE1220051, 5, 7), (-2, false));
+        assert_eq!(execute(0xE1220051, i32::MIN, 1), (i32::MIN, true));
+        assert_eq!(execute(0xE1220051, 0, i32::MIN), (i32::MAX, true));
+    }
+
+    #[test]
+    fn test_qdadd_qdsub() {
+        // QDADD R0, R1, R2
+        assert_eq!(decode_arm(0xE1420051).disassemble(Condition::AL, 0), "QDADD R0, R1, R2");
+        assert_eq!(execute(0xE1420051, 1, 3), (7, false));
+        // Doubling saturates even if the sum would be in range again
+        assert_eq!(execute(0xE1420051, -0x7000_0000, 0x4000_0000), (0x0FFF_FFFF, true));
+
+        // QDSUB R0, R1, R2
+        assert_eq!(decode_arm(0xE1620051).disassemble(Condition::AL, 0), "QDSUB R0, R1, R2");
+        assert_eq!(execute(0xE1620051, 1, 3), (-5, false));
+        assert_eq!(execute(0xE1620051, -2, 0x4000_0000), (i32::MIN, true));
+    }
+
+    #[test]
+    fn test_sticky_saturation_flag() {
+        let mut cpu = CPU::new();
+        let mut mem = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+        cpu.set_r(1, i32::MAX as u32);
+        cpu.set_r(2, 1);
+        decode_arm(0xE1020051).execute(&mut cpu, &mut mem);
+        assert!(cpu.get_saturation_flag());
+
+        // Only cleared by writing the cpsr
+        cpu.set_r(1, 0);
+        decode_arm(0xE1020051).execute(&mut cpu, &mut mem);
+        assert_eq!(cpu.get_r(0), 1);
+        assert!(cpu.get_saturation_flag());
+    }
+}
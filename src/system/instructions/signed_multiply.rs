@@ -0,0 +1,183 @@
+use crate::{
+    bitutil::{get_bit, get_bits32},
+    system::{cpu::CPU, memory::Memory},
+};
+
+use super::{Condition, DecodedInstruction};
+
+/// ARMv5 signed multiplies of halfwords, selected by the x and y bits
+#[derive(Debug)]
+struct SignedMultiply {
+    opcode: Opcode,
+    // Rd, or RdHi for SMLAL
+    d: u8,
+    // Rn, or RdLo for SMLAL
+    n: u8,
+    s: u8,
+    m: u8,
+    // Use the top halfword of Rm and Rs
+    x: bool,
+    y: bool,
+}
+
+#[derive(Debug)]
+enum Opcode {
+    Smla,
+    // SMLAW and SMULW multiply all of Rm, which is why they use x to tell them apart
+    Smlaw,
+    Smulw,
+    Smlal,
+    Smul,
+}
+
+pub fn decode_arm(instruction: u32) -> Box<dyn DecodedInstruction> {
+    let x = get_bit(instruction, 5);
+    Box::new(SignedMultiply {
+        opcode: match get_bits32(instruction, 21, 2) {
+            0b00 => Opcode::Smla,
+            0b01 if x => Opcode::Smulw,
+            0b01 => Opcode::Smlaw,
+            0b10 => Opcode::Smlal,
+            0b11 => Opcode::Smul,
+            _ => unreachable!(),
+        },
+        d: get_bits32(instruction, 16, 4) as u8,
+        n: get_bits32(instruction, 12, 4) as u8,
+        s: get_bits32(instruction, 8, 4) as u8,
+        m: get_bits32(instruction, 0, 4) as u8,
+        x,
+        y: get_bit(instruction, 6),
+    })
+}
+
+fn halfword(value: u32, top: bool) -> i32 {
+    if top {
+        (value >> 16) as i16 as i32
+    } else {
+        value as i16 as i32
+    }
+}
+
+fn half_name(top: bool) -> char {
+    if top {
+        'T'
+    } else {
+        'B'
+    }
+}
+
+impl DecodedInstruction for SignedMultiply {
+    fn execute(&self, cpu: &mut CPU, _mem: &mut Memory) {
+        let r_m = cpu.get_r(self.m);
+        let r_s = cpu.get_r(self.s);
+        let y = halfword(r_s, self.y);
+        let product = match self.opcode {
+            Opcode::Smul | Opcode::Smla => halfword(r_m, self.x) * y,
+            // The top 32 bits of the 48 bit product
+            Opcode::Smulw | Opcode::Smlaw => ((r_m as i32 as i64 * y as i64) >> 16) as i32,
+            Opcode::Smlal => {
+                let accumulator = ((cpu.get_r(self.d) as u64) << 32 | cpu.get_r(self.n) as u64) as i64;
+                let result = accumulator.wrapping_add((halfword(r_m, self.x) * y) as i64);
+                cpu.set_r(self.n, result as u32);
+                cpu.set_r(self.d, (result >> 32) as u32);
+                return;
+            }
+        };
+
+        let result = if matches!(self.opcode, Opcode::Smla | Opcode::Smlaw) {
+            // Only the accumulate can overflow, which sets Q without saturating
+            let (result, overflow) = product.overflowing_add(cpu.get_r(self.n) as i32);
+            if overflow {
+                cpu.set_saturation_flag(true);
+            }
+            result
+        } else {
+            product
+        };
+        cpu.set_r(self.d, result as u32);
+    }
+
+    fn disassemble(&self, cond: Condition, _base_address: u32) -> String {
+        let (x, y) = (half_name(self.x), half_name(self.y));
+        match self.opcode {
+            // SMUL<x><y>{<cond>} <Rd>, <Rm>, <Rs>
+            Opcode::Smul => format!("SMUL{}{}{} R{}, R{}, R{}", x, y, cond, self.d, self.m, self.s),
+            // SMLA<x><y>{<cond>} <Rd>, <Rm>, <Rs>, <Rn>
+            Opcode::Smla => format!("SMLA{}{}{} R{}, R{}, R{}, R{}", x, y, cond, self.d, self.m, self.s, self.n),
+            // SMULW<y>{<cond>} <Rd>, <Rm>, <Rs>
+            Opcode::Smulw => format!("SMULW{}{} R{}, R{}, R{}", y, cond, self.d, self.m, self.s),
+            // SMLAW<y>{<cond>} <Rd>, <Rm>, <Rs>, <Rn>
+            Opcode::Smlaw => format!("SMLAW{}{} R{}, R{}, R{}, R{}", y, cond, self.d, self.m, self.s, self.n),
+            // SMLAL<x><y>{<cond>} <RdLo>, <RdHi>, <Rm>, <Rs>
+            Opcode::Smlal => format!("SMLAL{}{}{} R{}, R{}, R{}, R{}", x, y, cond, self.n, self.d, self.m, self.s),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setup() -> (CPU, Memory) {
+        let mut cpu = CPU::new();
+        cpu.set_r(1, 0x0003_FFFE); // -2 in the bottom half, 3 in the top
+        cpu.set_r(2, 0xFFF9_0005); // 5 in the bottom half, -7 in the top
+        (cpu, Memory::new(vec![0; 0x4000], vec![0; 0x100]))
+    }
+
+    #[test]
+    fn test_smulbb() {
+        let (mut cpu, mut mem) = setup();
+        // SMULBB R0, R1, R2
+        let smulbb = decode_arm(0xE1600281);
+        assert_eq!(smulbb.disassemble(Condition::AL, 0), "SMULBB R0, R1, R2");
+        smulbb.execute(&mut cpu, &mut mem);
+        assert_eq!(cpu.get_r(0) as i32, -10);
+
+        // SMULTT R0, R1, R2
+        let smultt = decode_arm(0xE16002E1);
+        assert_eq!(smultt.disassemble(Condition::AL, 0), "SMULTT R0, R1, R2");
+        smultt.execute(&mut cpu, &mut mem);
+        assert_eq!(cpu.get_r(0) as i32, -21);
+    }
+
+    #[test]
+    fn test_smlabb() {
+        let (mut cpu, mut mem) = setup();
+        cpu.set_r(3, -5i32 as u32);
+        // SMLABB R0, R1, R2, R3
+        let smlabb = decode_arm(0xE1003281);
+        assert_eq!(smlabb.disassemble(Condition::AL, 0), "SMLABB R0, R1, R2, R3");
+        smlabb.execute(&mut cpu, &mut mem);
+        assert_eq!(cpu.get_r(0) as i32, -15);
+        assert!(!cpu.get_saturation_flag());
+
+        // The accumulate overflowing sets Q, the result wraps
+        cpu.set_r(3, i32::MIN as u32);
+        smlabb.execute(&mut cpu, &mut mem);
+        assert_eq!(cpu.get_r(0), 0x7FFF_FFF6);
+        assert!(cpu.get_saturation_flag());
+
+        // SMLATB R0, R1, R2, R3
+        assert_eq!(decode_arm(0xE10032A1).disassemble(Condition::EQ, 0), "SMLATBEQ R0, R1, R2, R3");
+    }
+
+    #[test]
+    fn test_smulw_smlal() {
+        let (mut cpu, mut mem) = setup();
+        // SMULWT R0, R1, R2: 0x0003FFFE * -7 >> 16
+        let smulwt = decode_arm(0xE12002E1);
+        assert_eq!(smulwt.disassemble(Condition::AL, 0), "SMULWT R0, R1, R2");
+        smulwt.execute(&mut cpu, &mut mem);
+        assert_eq!(cpu.get_r(0) as i32, ((0x0003_FFFEi64 * -7) >> 16) as i32);
+
+        // SMLALBB R3, R4, R1, R2
+        cpu.set_r(3, 5);
+        cpu.set_r(4, 0);
+        let smlalbb = decode_arm(0xE1443281);
+        assert_eq!(smlalbb.disassemble(Condition::AL, 0), "SMLALBB R3, R4, R1, R2");
+        smlalbb.execute(&mut cpu, &mut mem);
+        assert_eq!(cpu.get_r(3) as i32, -5);
+        assert_eq!(cpu.get_r(4), 0xFFFF_FFFF);
+    }
+}
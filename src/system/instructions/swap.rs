@@ -0,0 +1,88 @@
+use crate::{
+    bitutil::{get_bit, get_bits32},
+    system::{cpu::CPU, memory::Memory},
+};
+
+use super::{Condition, DecodedInstruction, CYCLES_I, CYCLES_N, CYCLES_S};
+
+#[derive(Debug)]
+struct Swap {
+    b: bool,
+    n: u8,
+    d: u8,
+    m: u8,
+}
+
+pub fn decode_arm(instruction: u32) -> Box<dyn DecodedInstruction> {
+    Box::new(Swap {
+        b: get_bit(instruction, 22),
+        n: get_bits32(instruction, 16, 4) as u8,
+        d: get_bits32(instruction, 12, 4) as u8,
+        m: get_bits32(instruction, 0, 4) as u8,
+    })
+}
+
+impl DecodedInstruction for Swap {
+    fn execute(&self, cpu: &mut CPU, mem: &mut Memory) {
+        let address = cpu.get_r(self.n);
+        let r_m = cpu.get_r(self.m);
+        let temp = if self.b {
+            let temp = mem.read_u8(address) as u32;
+            mem.write_u8(address, r_m as u8);
+            temp
+        } else {
+            let temp = mem.read_u32(address);
+            mem.write_u32(address, r_m);
+            temp
+        };
+        cpu.set_r(self.d, temp);
+    }
+
+    fn cycles(&self, _cpu: &CPU) -> u32 {
+        CYCLES_S + 2 * CYCLES_N + CYCLES_I
+    }
+
+    fn wait_cycles(&self, cpu: &CPU, mem: &Memory) -> u32 {
+        let width = if self.b { 1 } else { 4 };
+        2 * (mem.access_cycles(cpu.get_r(self.n), width) - 1)
+    }
+
+    fn disassemble(&self, cond: Condition, _base_address: u32) -> String {
+        // SWP{<cond>}{B} <Rd>, <Rm>, [<Rn>]
+        format!("SWP{}{} R{}, R{}, [R{}]", cond, if self.b { "B" } else { "" }, self.d, self.m, self.n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_swpb() {
+        let mut cpu = CPU::new();
+        let mut mem = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+        mem.write_u32(0x03000000, 0x11223344);
+        cpu.set_r(1, 0xAABBCCDD);
+        cpu.set_r(2, 0x03000000);
+
+        let swpb = decode_arm(0xE1420091);
+        assert_eq!(swpb.disassemble(Condition::AL, 0), "SWPB R0, R1, [R2]");
+        swpb.execute(&mut cpu, &mut mem);
+        assert_eq!(cpu.get_r(0), 0x44);
+        assert_eq!(mem.read_u32(0x03000000), 0x112233DD);
+    }
+
+    #[test]
+    fn test_swp_same_register() {
+        let mut cpu = CPU::new();
+        let mut mem = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+        mem.write_u32(0x03000000, 0x11223344);
+        cpu.set_r(0, 0xAABBCCDD);
+        cpu.set_r(2, 0x03000000);
+
+        // SWP R0, R0, [R2]
+        decode_arm(0xE1020090).execute(&mut cpu, &mut mem);
+        assert_eq!(cpu.get_r(0), 0x11223344);
+        assert_eq!(mem.read_u32(0x03000000), 0xAABBCCDD);
+    }
+}
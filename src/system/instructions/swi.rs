@@ -0,0 +1,99 @@
+use crate::{
+    bitutil::{get_bits16, get_bits32},
+    system::{
+        cpu::{CPU, MODE_SVC, VECTOR_SWI},
+        memory::Memory,
+    },
+};
+
+use super::{Condition, DecodedInstruction, CYCLES_N, CYCLES_S};
+
+#[derive(Debug)]
+struct Swi {
+    comment: u32,
+    // The BIOS reads the function number from the top byte of the ARM comment
+    function: u8,
+}
+
+pub fn decode_arm(instruction: u32) -> Box<dyn DecodedInstruction> {
+    Box::new(Swi {
+        comment: get_bits32(instruction, 0, 24),
+        function: get_bits32(instruction, 16, 8) as u8,
+    })
+}
+
+pub fn decode_thumb(instruction: u16, _next_instruction: u16) -> Box<dyn DecodedInstruction> {
+    Box::new(Swi {
+        comment: get_bits16(instruction, 0, 8) as u32,
+        function: get_bits16(instruction, 0, 8) as u8,
+    })
+}
+
+impl DecodedInstruction for Swi {
+    fn execute(&self, cpu: &mut CPU, mem: &mut Memory) {
+        if let Some(mut bios) = cpu.hle_bios {
+            bios.call(self.function, cpu, mem);
+            cpu.hle_bios = Some(bios);
+            return;
+        }
+        cpu.raise_exception(MODE_SVC, VECTOR_SWI, cpu.next_instruction_address_from_execution_stage());
+    }
+
+    fn cycles(&self, _cpu: &CPU) -> u32 {
+        2 * CYCLES_S + CYCLES_N
+    }
+
+    fn disassemble(&self, cond: Condition, _base_address: u32) -> String {
+        // SWI{<cond>} <immed>
+        format!("SWI{} #{:X}", cond, self.comment)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::system::cpu::{MODE_SYS, REGISTER_LR, REGISTER_PC};
+
+    #[test]
+    fn test_swi_disassemble() {
+        assert_eq!(decode_arm(0xEF000006).disassemble(Condition::AL, 0), "SWI #6");
+        assert_eq!(decode_thumb(0xDF0B, 0).disassemble(Condition::AL, 0), "SWI #B");
+    }
+
+    #[test]
+    fn test_swi_execute() {
+        let mut cpu = CPU::new();
+        let mut mem = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+        cpu.set_mode(MODE_SYS);
+        cpu.set_irq_disable(false);
+        cpu.set_thumb_state(true);
+        let cpsr = cpu.get_cpsr();
+
+        // Pipeline state while executing a thumb instruction at 0x08000100
+        cpu.set_r(REGISTER_PC, 0x08000104);
+        decode_thumb(0xDF05, 0).execute(&mut cpu, &mut mem);
+
+        assert_eq!(cpu.get_mode(), MODE_SVC);
+        assert_eq!(cpu.get_spsr(), cpsr);
+        assert!(cpu.get_irq_disable());
+        assert!(!cpu.get_thumb_state());
+        assert_eq!(cpu.get_r(REGISTER_LR), 0x08000102);
+        assert_eq!(cpu.get_r(REGISTER_PC), VECTOR_SWI);
+    }
+
+    #[test]
+    fn test_swi_hle() {
+        let mut cpu = CPU::new();
+        let mut mem = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+        cpu.skip_bios();
+        cpu.set_r(0, 100);
+        cpu.set_r(1, 7);
+
+        // SWI 0x060000 (Div) is emulated without entering SVC mode
+        cpu.set_r(REGISTER_PC, 0x08000108);
+        decode_arm(0xEF060000).execute(&mut cpu, &mut mem);
+        assert_eq!(cpu.get_mode(), MODE_SYS);
+        assert_eq!((cpu.get_r(0), cpu.get_r(1)), (14, 2));
+        assert_eq!(cpu.get_r(REGISTER_PC), 0x08000108);
+    }
+}
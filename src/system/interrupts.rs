@@ -0,0 +1,26 @@
+pub const REG_IE: u32 = 0x04_000_200;
+pub const REG_IF: u32 = 0x04_000_202;
+pub const REG_IME: u32 = 0x04_000_208;
+
+/// Interrupt sources, by their bit in IE/IF
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interrupt {
+    VBlank = 0,
+    HBlank = 1,
+    VCount = 2,
+    Timer0 = 3,
+    Timer1 = 4,
+    Timer2 = 5,
+    Timer3 = 6,
+    Dma0 = 8,
+    Dma1 = 9,
+    Dma2 = 10,
+    Dma3 = 11,
+    Keypad = 12,
+}
+
+impl Interrupt {
+    pub const fn mask(self) -> u16 {
+        1 << self as u16
+    }
+}
@@ -0,0 +1,178 @@
+use super::{
+    apu::{REG_SOUNDCNT_H, REG_SOUNDCNT_X},
+    dma::{DMA_CHANNEL_LEN, REG_DMA0CNT_H, REG_DMA0SAD, REG_DMA3CNT_H},
+    interrupts::{REG_IE, REG_IF, REG_IME},
+    ppu::{REG_BG0CNT, REG_BG0HOFS, REG_DISPSTAT, REG_VCOUNT, REG_WIN1V, REG_WININ, REG_WINOUT},
+    psg::{REG_SOUND1CNT_H, REG_SOUND1CNT_L, REG_SOUND1CNT_X, REG_SOUND2CNT_H, REG_SOUND2CNT_L, REG_SOUND3CNT_H, REG_SOUND3CNT_L, REG_SOUND3CNT_X, REG_SOUND4CNT_H, REG_SOUND4CNT_L, REG_SOUNDCNT_L},
+};
+
+pub const IO_START: u32 = 0x04_000_000;
+pub const IO_END: u32 = 0x04_000_3FF;
+const IO_REGISTERS_LEN: usize = (IO_END - IO_START + 1) as usize;
+
+const REG_BG1CNT: u32 = REG_BG0CNT + 2;
+const REG_MOSAIC: u32 = 0x04_000_04C;
+const REG_BLDCNT: u32 = 0x04_000_050;
+const REG_BLDALPHA: u32 = 0x04_000_052;
+const REG_BLDY: u32 = 0x04_000_054;
+
+/// The bits of the halfword register at `address` the cpu can read and write
+fn access_masks(address: u32) -> (u16, u16) {
+    let register = address & !1;
+    match register {
+        // The blank and vcount match flags are status bits
+        REG_DISPSTAT => (0xFF3F, 0xFF38),
+        REG_VCOUNT => (0x00FF, 0),
+        // Only BG2 and BG3 can wrap around
+        REG_BG0CNT | REG_BG1CNT => (0xDFFF, 0xDFFF),
+        // Scrolling, rotation, window and mosaic registers are write-only
+        REG_BG0HOFS..=REG_WIN1V | REG_MOSAIC | REG_BLDY => (0, 0xFFFF),
+        REG_WININ | REG_WINOUT => (0x3F3F, 0x3F3F),
+        REG_BLDCNT => (0x3FFF, 0x3FFF),
+        REG_BLDALPHA => (0x1F1F, 0x1F1F),
+        // The PSG lengths, frequencies and restart bits are write-only
+        REG_SOUND1CNT_L => (0x007F, 0x007F),
+        REG_SOUND1CNT_H | REG_SOUND2CNT_L | REG_SOUND4CNT_L => (0xFFC0, 0xFFFF),
+        REG_SOUND1CNT_X | REG_SOUND2CNT_H | REG_SOUND3CNT_X => (0x4000, 0xC7FF),
+        REG_SOUND3CNT_L => (0x00E0, 0x00E0),
+        REG_SOUND3CNT_H => (0xE000, 0xE0FF),
+        REG_SOUND4CNT_H => (0x40FF, 0xC0FF),
+        REG_SOUNDCNT_L => (0xFF77, 0xFF77),
+        // The FIFO reset bits are write-only
+        REG_SOUNDCNT_H => (0x770F, 0xFF0F),
+        // The channel status bits are read from the PSG
+        REG_SOUNDCNT_X => (0x0080, 0x0080),
+        // Only the control of the DMA channels is readable, without the unused low bits
+        REG_DMA3CNT_H => (0xFFE0, 0xFFE0),
+        REG_DMA0SAD..=REG_DMA3CNT_H if (register - REG_DMA0SAD) % DMA_CHANNEL_LEN == REG_DMA0CNT_H - REG_DMA0SAD => (0xF7E0, 0xF7E0),
+        REG_DMA0SAD..=REG_DMA3CNT_H => (0, 0xFFFF),
+        REG_IE | REG_IF => (0x3FFF, 0x3FFF),
+        REG_IME => (0x0001, 0x0001),
+        _ => (0xFFFF, 0xFFFF),
+    }
+}
+
+/// The mask of the byte at `address` out of the masks of its halfword
+fn byte_mask(address: u32, mask: u16) -> u8 {
+    (mask >> (8 * (address & 1))) as u8
+}
+
+/// The I/O registers at 0x04000000, with the read-only, write-only and unused bits the cpu sees
+pub struct IoRegisters {
+    data: Vec<u8>,
+}
+
+impl Default for IoRegisters {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IoRegisters {
+    pub fn new() -> Self {
+        Self { data: vec![0; IO_REGISTERS_LEN] }
+    }
+
+    /// Reads a byte as the cpu sees it, write-only and unused bits read as 0
+    pub fn read(&self, address: u32) -> u8 {
+        let (readable, _) = access_masks(address);
+        self.get(address) & byte_mask(address, readable)
+    }
+
+    /// Writes a byte from the cpu, bits it can't write keep their value
+    pub fn write(&mut self, address: u32, value: u8) {
+        let (_, writable) = access_masks(address);
+        let mask = byte_mask(address, writable);
+        let old = self.get(address);
+        let new = if address & !1 == REG_IF {
+            // Writing 1 to a bit of IF acknowledges that interrupt
+            old & !(value & mask)
+        } else {
+            old & !mask | value & mask
+        };
+        self.set(address, new);
+    }
+
+    /// Reads a byte as the hardware sees it
+    pub fn get(&self, address: u32) -> u8 {
+        self.data[(address - IO_START) as usize]
+    }
+
+    /// Sets a byte from the hardware side, bypassing the behavior of cpu writes
+    pub fn set(&mut self, address: u32, value: u8) {
+        self.data[(address - IO_START) as usize] = value;
+    }
+
+    pub fn get_u16(&self, address: u32) -> u16 {
+        u16::from_le_bytes([self.get(address), self.get(address + 1)])
+    }
+
+    pub fn set_u16(&mut self, address: u32, value: u16) {
+        let [low, high] = value.to_le_bytes();
+        self.set(address, low);
+        self.set(address + 1, high);
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_u16(io: &mut IoRegisters, address: u32, value: u16) {
+        io.write(address, value as u8);
+        io.write(address + 1, (value >> 8) as u8);
+    }
+
+    fn read_u16(io: &IoRegisters, address: u32) -> u16 {
+        u16::from_le_bytes([io.read(address), io.read(address + 1)])
+    }
+
+    #[test]
+    fn test_vcount_read_only() {
+        let mut io = IoRegisters::new();
+        io.set_u16(REG_VCOUNT, 100);
+        write_u16(&mut io, REG_VCOUNT, 5);
+        assert_eq!(read_u16(&io, REG_VCOUNT), 100);
+
+        // The status bits of DISPSTAT are kept, the rest is written
+        io.set_u16(REG_DISPSTAT, 0b101);
+        write_u16(&mut io, REG_DISPSTAT, 0x1238);
+        assert_eq!(read_u16(&io, REG_DISPSTAT), 0x123D);
+    }
+
+    #[test]
+    fn test_if_write_one_to_clear() {
+        let mut io = IoRegisters::new();
+        io.set_u16(REG_IF, 0b1011);
+        write_u16(&mut io, REG_IF, 0b0010);
+        assert_eq!(read_u16(&io, REG_IF), 0b1001);
+        write_u16(&mut io, REG_IF, 0);
+        assert_eq!(read_u16(&io, REG_IF), 0b1001);
+        write_u16(&mut io, REG_IF, 0xFFFF);
+        assert_eq!(read_u16(&io, REG_IF), 0);
+    }
+
+    #[test]
+    fn test_access_masks() {
+        let mut io = IoRegisters::new();
+        // Scroll registers are write-only, but the ppu still sees them
+        write_u16(&mut io, REG_BG0HOFS, 0x1FF);
+        assert_eq!(read_u16(&io, REG_BG0HOFS), 0);
+        assert_eq!(io.get_u16(REG_BG0HOFS), 0x1FF);
+
+        write_u16(&mut io, REG_IME, 0xFFFF);
+        assert_eq!(read_u16(&io, REG_IME), 1);
+        write_u16(&mut io, REG_DMA0CNT_H, 0xFFFF);
+        assert_eq!(read_u16(&io, REG_DMA0CNT_H), 0xF7E0);
+        write_u16(&mut io, REG_DMA3CNT_H, 0xFFFF);
+        assert_eq!(read_u16(&io, REG_DMA3CNT_H), 0xFFE0);
+    }
+}
@@ -0,0 +1,136 @@
+use std::{
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        Arc,
+    },
+};
+
+pub const REG_KEYINPUT: u32 = 0x04_000_130;
+pub const REG_KEYCNT: u32 = 0x04_000_132;
+
+// KEYCNT bits
+const KEYCNT_BUTTONS: u16 = 0x3FF;
+const KEYCNT_IRQ: u16 = 1 << 14;
+const KEYCNT_IRQ_AND: u16 = 1 << 15;
+
+/// GBA buttons, by their bit in KEYINPUT/KEYCNT
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Button {
+    A = 0,
+    B = 1,
+    Select = 2,
+    Start = 3,
+    Right = 4,
+    Left = 5,
+    Up = 6,
+    Down = 7,
+    R = 8,
+    L = 9,
+}
+
+impl Button {
+    pub const ALL: [Button; 10] = [
+        Button::A,
+        Button::B,
+        Button::Select,
+        Button::Start,
+        Button::Right,
+        Button::Left,
+        Button::Up,
+        Button::Down,
+        Button::R,
+        Button::L,
+    ];
+}
+
+impl FromStr for Button {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "a" => Ok(Button::A),
+            "b" => Ok(Button::B),
+            "select" => Ok(Button::Select),
+            "start" => Ok(Button::Start),
+            "right" => Ok(Button::Right),
+            "left" => Ok(Button::Left),
+            "up" => Ok(Button::Up),
+            "down" => Ok(Button::Down),
+            "r" => Ok(Button::R),
+            "l" => Ok(Button::L),
+            _ => Err(format!("Unknown button: {}", s)),
+        }
+    }
+}
+
+/// Button state shared between the display thread and the emulator
+#[derive(Debug, Clone, Default)]
+pub struct Keypad {
+    pressed: Arc<AtomicU16>,
+}
+
+impl Keypad {
+    pub fn set_pressed(&self, button: Button, pressed: bool) {
+        let mask = 1 << button as u16;
+        if pressed {
+            self.pressed.fetch_or(mask, Ordering::Relaxed);
+        } else {
+            self.pressed.fetch_and(!mask, Ordering::Relaxed);
+        }
+    }
+
+    /// Value of KEYINPUT, where pressed buttons read as 0
+    pub fn keyinput(&self) -> u16 {
+        !self.pressed.load(Ordering::Relaxed) & KEYCNT_BUTTONS
+    }
+
+    /// Whether the buttons selected in KEYCNT fulfill its interrupt condition
+    pub fn interrupt_condition(&self, keycnt: u16) -> bool {
+        if keycnt & KEYCNT_IRQ == 0 {
+            return false;
+        }
+
+        let selected = keycnt & KEYCNT_BUTTONS;
+        let pressed = self.pressed.load(Ordering::Relaxed) & selected;
+        if keycnt & KEYCNT_IRQ_AND != 0 {
+            selected != 0 && pressed == selected
+        } else {
+            pressed != 0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keyinput() {
+        let keypad = Keypad::default();
+        assert_eq!(keypad.keyinput(), 0x3FF);
+
+        keypad.set_pressed(Button::A, true);
+        assert_eq!(keypad.keyinput(), 0x3FE);
+        keypad.clone().set_pressed(Button::L, true);
+        assert_eq!(keypad.keyinput(), 0x1FE);
+
+        keypad.set_pressed(Button::A, false);
+        assert_eq!(keypad.keyinput(), 0x1FF);
+    }
+
+    #[test]
+    fn test_interrupt_condition() {
+        let keypad = Keypad::default();
+        let a_or_b = KEYCNT_IRQ | 1 << Button::A as u16 | 1 << Button::B as u16;
+        let a_and_b = a_or_b | KEYCNT_IRQ_AND;
+
+        keypad.set_pressed(Button::A, true);
+        assert!(keypad.interrupt_condition(a_or_b));
+        assert!(!keypad.interrupt_condition(a_or_b & !KEYCNT_IRQ));
+        assert!(!keypad.interrupt_condition(a_and_b));
+
+        keypad.set_pressed(Button::B, true);
+        assert!(keypad.interrupt_condition(a_and_b));
+    }
+}
@@ -20,21 +20,53 @@ External Memory (Game Pak)
   08_000_000-09_FFF_FFF   Game Pak ROM/FlashROM (max 32MB) - Wait State 0
   0A_000_000-0B_FFF_FFF   Game Pak ROM/FlashROM (max 32MB) - Wait State 1
   0C_000_000-0D_FFF_FFF   Game Pak ROM/FlashROM (max 32MB) - Wait State 2
-  0E_000_000-0E_00F_FFF   Game Pak SRAM    (max 64 KBytes) - 8bit Bus width
+  0E_000_000-0E_00F_FFF   Game Pak SRAM/Flash (max 64 KBytes, banked for Flash) - 8bit Bus width
   0E_010_000-0F_FFF_FFF   Not used
 Unused Memory Area
   10_000_000-FF_FFF_FFF   Not used (upper 4bits of address bus unused)
 */
 
+use std::{cell::Cell, fmt, fs, io, ops::RangeInclusive, path::PathBuf};
+
+use super::{
+    apu::{Apu, SampleBuffer, FIFO_ADDRESSES, REG_FIFO_A, REG_SOUNDCNT_H, REG_SOUNDCNT_X, SOUNDCNT_X_ENABLE},
+    dma::{Dma, DmaTiming, DMACNT_ENABLE, DMA_CHANNEL_LEN, REG_DMA0CNT_H, REG_DMA3CNT_H},
+    interrupts::{Interrupt, REG_IE, REG_IF, REG_IME},
+    io_registers::{IoRegisters, IO_END, IO_START},
+    keypad::{Keypad, REG_KEYCNT, REG_KEYINPUT},
+    ppu::{AFFINE_BG_STRIDE, REG_BG2X, REG_BG3Y},
+    psg::{PSG_REGISTERS_END, REG_SOUND1CNT_L, REG_WAVE_RAM, WAVE_RAM_END},
+    rng::Rng,
+    save::{eeprom::Eeprom, SaveMemory, SaveType},
+    state::{StateReader, StateWriter},
+    timers::{Timers, REG_TM0CNT_L, REG_TM3CNT_H, TIMER_LEN},
+};
+
 macro_rules! gen_memory {
-    ($($start:literal..=$end:literal => ($region:ident, $index_fn:expr, $writable:expr)),* $(,)?) => {
+    ($($start:literal..=$end:literal => ($region:ident, $index_fn:expr, $writable:expr)),* $(,)?; $($field:ident: $field_type:ty),* $(,)?) => {
         pub struct Memory {
             $(
                 $region: Vec<u8>,
             )*
+            $(
+                $field: $field_type,
+            )*
         }
 
         impl Memory {
+            fn _is_mapped(&self, address: u32) -> bool {
+                matches!(address, $($start..=$end)|*)
+            }
+
+            fn _is_writable(&self, address: u32) -> bool {
+                match address {
+                    $(
+                        $start..=$end => $writable,
+                    )*
+                    _ => false,
+                }
+            }
+
             fn _read_u8(&self, address: u32) -> u8 {
                 match address {
                     $(
@@ -42,7 +74,7 @@ macro_rules! gen_memory {
                             self.$region[$index_fn(address, $start)]
                         }
                     )*
-                    _ => panic!("Read from unmapped address: {:#08X}", address),
+                    _ => self.unmapped_read(address),
                 }
             }
 
@@ -51,22 +83,124 @@ macro_rules! gen_memory {
                     $(
                         $start..=$end => {
                             if $writable { self.$region[$index_fn(address, $start)] = value }
-                            else { panic!("Write to read-only address: {:#08X}", address) }
+                            else { self.fault(MemoryFault::ReadOnlyWrite(address)) }
                         }
                     ,)*
-                    _ => panic!("Write to unmapped address: {:#08X}", address),
+                    _ => self.fault(MemoryFault::UnmappedWrite(address)),
+                }
+            }
+
+            /// The backing memory of the `len` bytes at `address`, if they are contiguous in a single region.
+            /// The EEPROM window isn't, reads there go to the EEPROM instead of the ROM.
+            fn region(&self, address: u32, len: u32) -> Option<&[u8]> {
+                let last = address.checked_add(len.checked_sub(1)?)?;
+                if self.eeprom_window().is_some_and(|window| address <= *window.end() && last >= *window.start()) {
+                    return None;
+                }
+                match address {
+                    $(
+                        $start..=$end if last <= $end => {
+                            let index = $index_fn(address, $start);
+                            if $index_fn(last, $start) != index + len as usize - 1 {
+                                return None;
+                            }
+                            self.$region.get(index..index + len as usize)
+                        }
+                    )*
+                    _ => None,
+                }
+            }
+
+            fn region_mut(&mut self, address: u32, len: u32) -> Option<&mut [u8]> {
+                let last = address.checked_add(len.checked_sub(1)?)?;
+                match address {
+                    $(
+                        $start..=$end if $writable && last <= $end => {
+                            let index = $index_fn(address, $start);
+                            if $index_fn(last, $start) != index + len as usize - 1 {
+                                return None;
+                            }
+                            self.$region.get_mut(index..index + len as usize)
+                        }
+                    )*
+                    _ => None,
                 }
             }
         }
     };
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+}
+
+/// An access the bus can't serve
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryFault {
+    UnmappedRead(u32),
+    UnmappedWrite(u32),
+    ReadOnlyWrite(u32),
+}
+
+impl fmt::Display for MemoryFault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MemoryFault::UnmappedRead(address) => write!(f, "Read from unmapped address: {:#08X}", address),
+            MemoryFault::UnmappedWrite(address) => write!(f, "Write to unmapped address: {:#08X}", address),
+            MemoryFault::ReadOnlyWrite(address) => write!(f, "Write to read-only address: {:#08X}", address),
+        }
+    }
+}
+
+/// A watched byte that was accessed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchHit {
+    pub kind: WatchKind,
+    pub address: u32,
+    pub value: u8,
+}
+
 const WRAM1_LEN: u32 = 0x40_000;
-const WRAM2_LEN: u32 = 0x800;
-const IO_REGISTERS_LEN: u32 = 0x3FF;
+const WRAM2_LEN: u32 = 0x8_000;
 const IO_UNUSED_LEN: u32 = 0x1;
 const PALETTE_RAM_LEN: u32 = 0x400;
 const VRAM_LEN: u32 = 0x18_000;
+const OAM_LEN: u32 = 0x400;
+const GAME_PAK_START: u32 = 0x08_000_000;
+const GAME_PAK_END: u32 = 0x0D_FFF_FFF;
+// The ROM is mirrored in each of the three wait state regions
+const GAME_PAK_MAX_LEN: u32 = 0x2_000_000;
+const SAVE_START: u32 = 0x0E_000_000;
+const SAVE_END: u32 = 0x0E_00F_FFF;
+const EEPROM_START: u32 = 0x0D_000_000;
+// ROMs over 16MB reach into the EEPROM region, only its last 256 bytes are left for the EEPROM then
+const EEPROM_LARGE_ROM_START: u32 = 0x0D_FFF_F00;
+const LARGE_ROM_LEN: usize = 0x1_000_000;
+const EEPROM_END: u32 = 0x0D_FFF_FFF;
+pub const BIOS_LEN: usize = 0x4000;
+// Reading the BIOS after boot returns the instruction following the one that jumps to the cartridge
+const BIOS_BOOT_LATCH: u32 = 0xE129F000;
+
+pub const REG_WAITCNT: u32 = 0x04_000_204;
+// WAITCNT bits
+const WAITCNT_PREFETCH: u16 = 1 << 14;
+// Wait states selected by the 2 bit fields of WAITCNT for SRAM and non-sequential game pak accesses
+const WAIT_STATES: [u32; 4] = [4, 3, 2, 8];
+// Wait states selected by the sequential bit of WAITCNT for each of the three game pak wait state regions
+const SEQUENTIAL_WAIT_STATES: [[u32; 2]; 3] = [[2, 1], [4, 1], [8, 1]];
+
+/// I/O registers the BIOS initializes before jumping to the cartridge
+const POST_BOOT_IO: [(u32, u16); 7] = [
+    (0x04_000_020, 0x100),  // BG2PA
+    (0x04_000_026, 0x100),  // BG2PD
+    (0x04_000_030, 0x100),  // BG3PA
+    (0x04_000_036, 0x100),  // BG3PD
+    (0x04_000_088, 0x200),  // SOUNDBIAS
+    (0x04_000_134, 0x8000), // RCNT
+    (0x04_000_300, 0x1),    // POSTFLG
+];
 
 fn normal_index() -> impl Fn(u32, u32) -> usize {
     move |address: u32, start: u32| (address - start) as usize
@@ -76,6 +210,20 @@ fn wrapping_index(len: u32) -> impl Fn(u32, u32) -> usize {
     move |address: u32, start: u32| ((address - start) % len) as usize
 }
 
+/// The ROM is whatever size the image is, up to the 32MB the cartridge bus can address
+fn fit_game_pak(mut game_pak: Vec<u8>) -> Vec<u8> {
+    if game_pak.len() > GAME_PAK_MAX_LEN as usize {
+        warn!("ROM of {} bytes is larger than 32MB, the rest is not mapped", game_pak.len());
+        game_pak.truncate(GAME_PAK_MAX_LEN as usize);
+    }
+    game_pak
+}
+
+/// The offset into the ROM of an address in any of its mirrors
+fn game_pak_offset(address: u32) -> u32 {
+    (address - GAME_PAK_START) % GAME_PAK_MAX_LEN
+}
+
 fn vram_index() -> impl Fn(u32, u32) -> usize {
     move |mut address: u32, start: u32| {
         address = (address - start) % 0x20_000;
@@ -90,11 +238,35 @@ gen_memory! {
     0x00_000_000..=0x00_003_FFF => (bios, normal_index(), false),
     0x02_000_000..=0x02_FFF_FFF => (wram1, wrapping_index(WRAM1_LEN), true),
     0x03_000_000..=0x03_FFF_FFF => (wram2, wrapping_index(WRAM2_LEN), true),
-    0x04_000_000..=0x04_000_3FE => (io_registers, normal_index(), true),
     0x04_000_410..=0x04_000_410 => (io_unused, normal_index(), true),
     0x05_000_000..=0x05_FFF_FFF => (palette_ram, wrapping_index(PALETTE_RAM_LEN), true),
     0x06_000_000..=0x06_FFF_FFF => (vram, vram_index(), true),
-    0x08_000_000..=0x09_FFF_FFF => (game_pak, normal_index(), false),
+    0x07_000_000..=0x07_FFF_FFF => (oam, wrapping_index(OAM_LEN), true),
+    0x08_000_000..=0x0D_FFF_FFF => (game_pak, wrapping_index(GAME_PAK_MAX_LEN), false);
+    io: IoRegisters,
+    keypad: Keypad,
+    apu: Apu,
+    dma: Dma,
+    timers: Timers,
+    save: SaveMemory,
+    save_path: Option<PathBuf>,
+    save_dirty: bool,
+    // BGxX/BGxY of BG2 and BG3 were written since the ppu last reloaded its reference points from them
+    affine_reference_written: [bool; 2],
+    watchpoints: Vec<(u32, WatchKind)>,
+    // Reads record hits too, so this needs interior mutability
+    watch_hit: Cell<Option<WatchHit>>,
+    // The last fetched instruction, which unmapped reads return
+    open_bus: Cell<u32>,
+    // The BIOS can only be read while executing it, otherwise reads return the last opcode prefetched from it
+    executing_bios: Cell<bool>,
+    bios_latch: Cell<u32>,
+    // Panics on faults instead of ignoring them
+    strict: bool,
+    fault_hook: Option<Box<dyn Fn(MemoryFault) + Send>>,
+    // Faults abort the cpu access instead of being ignored, `abort` is set until the cpu takes it
+    abort_on_fault: bool,
+    abort: Cell<bool>,
 }
 
 impl Memory {
@@ -103,45 +275,620 @@ impl Memory {
             bios,
             wram1: vec![0; WRAM1_LEN as usize],
             wram2: vec![0; WRAM2_LEN as usize],
-            io_registers: vec![0; IO_REGISTERS_LEN as usize],
             io_unused: vec![0; IO_UNUSED_LEN as usize],
             palette_ram: vec![0; PALETTE_RAM_LEN as usize],
             vram: vec![0; VRAM_LEN as usize],
-            game_pak,
+            oam: vec![0; OAM_LEN as usize],
+            game_pak: fit_game_pak(game_pak),
+            io: IoRegisters::new(),
+            keypad: Keypad::default(),
+            apu: Apu::default(),
+            dma: Dma::default(),
+            timers: Timers::default(),
+            save: SaveMemory::new(SaveType::Sram),
+            save_path: None,
+            save_dirty: false,
+            affine_reference_written: [false; 2],
+            watchpoints: Vec::new(),
+            watch_hit: Cell::new(None),
+            open_bus: Cell::new(0),
+            executing_bios: Cell::new(true),
+            bios_latch: Cell::new(0),
+            strict: false,
+            fault_hook: None,
+            abort_on_fault: false,
+            abort: Cell::new(false),
+        }
+    }
+
+    pub fn set_save_type(&mut self, save_type: SaveType) {
+        self.save = SaveMemory::new(save_type);
+    }
+
+    /// Fills the memory that is undefined at power on from a generator seeded with `seed`, instead of zeroes
+    pub fn randomize(&mut self, seed: u64) {
+        let mut rng = Rng::new(seed);
+        for region in [&mut self.wram1, &mut self.wram2, &mut self.palette_ram, &mut self.vram, &mut self.oam] {
+            rng.fill(region);
+        }
+        // SRAM without a save file, flash and EEPROM come erased
+        if let SaveMemory::Sram(data) = &mut self.save {
+            rng.fill(data);
+        }
+        // Open bus before the first fetch
+        self.open_bus.set(rng.next_u32());
+    }
+
+    /// Loads the save memory from `path` if it exists and persists it there on `flush_save`
+    pub fn load_save(&mut self, path: impl Into<PathBuf>) -> io::Result<()> {
+        let path = path.into();
+        match fs::read(&path) {
+            Ok(data) => {
+                let save = self.save.data_mut();
+                let len = data.len().min(save.len());
+                save[..len].copy_from_slice(&data[..len]);
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        self.save_path = Some(path);
+        Ok(())
+    }
+
+    /// Writes the save memory to the save file if it changed since the last flush
+    pub fn flush_save(&mut self) -> io::Result<()> {
+        if let (true, Some(path)) = (self.save_dirty, &self.save_path) {
+            fs::write(path, self.save.data())?;
+            self.save_dirty = false;
+        }
+        Ok(())
+    }
+
+    /// Replaces the cartridge, the save of the old one is flushed and a new empty save memory of `save_type` is inserted
+    pub fn load_game_pak(&mut self, game_pak: Vec<u8>, save_type: SaveType) -> io::Result<()> {
+        self.flush_save()?;
+        self.game_pak = fit_game_pak(game_pak);
+        self.set_save_type(save_type);
+        self.save_path = None;
+        Ok(())
+    }
+
+    /// Clears the RAM, I/O registers, DMA and timers as at power on, the cartridge and its save memory are kept
+    pub fn reset(&mut self) {
+        for region in [&mut self.wram1, &mut self.wram2, &mut self.io_unused, &mut self.palette_ram, &mut self.vram, &mut self.oam] {
+            region.fill(0);
+        }
+        self.io = IoRegisters::new();
+        self.apu.reset();
+        self.dma = Dma::default();
+        self.timers = Timers::default();
+        self.affine_reference_written = [false; 2];
+        self.open_bus.set(0);
+        self.executing_bios.set(true);
+        self.bios_latch.set(0);
+        self.abort.set(false);
+    }
+
+    /// Sets the I/O registers to what the BIOS leaves behind, for booting without one
+    pub fn skip_bios(&mut self) {
+        for (address, value) in POST_BOOT_IO {
+            self.set_io_u16(address, value);
+        }
+        // The opcode after the BIOS returns to the cartridge
+        self.bios_latch.set(BIOS_BOOT_LATCH);
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        for region in [&self.wram1[..], &self.wram2, self.io.data(), &self.io_unused, &self.palette_ram, &self.vram, &self.oam] {
+            state.write_block(region);
+        }
+        state.write_block(self.save.data());
+        self.timers.save_state(state);
+        self.dma.save_state(state);
+        self.apu.save_state(state);
+        state.write_u32(self.bios_latch.get());
+        for written in self.affine_reference_written {
+            state.write_u16(written as u16);
+        }
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        for region in [
+            &mut self.wram1[..],
+            &mut self.wram2,
+            self.io.data_mut(),
+            &mut self.io_unused,
+            &mut self.palette_ram,
+            &mut self.vram,
+            &mut self.oam,
+        ] {
+            state.read_block(region)?;
+        }
+        state.read_block(self.save.data_mut())?;
+        // Keep the save file in sync with the restored save memory
+        self.save_dirty = true;
+        self.timers.load_state(state)?;
+        self.dma.load_state(state)?;
+        self.apu.load_state(state)?;
+        self.bios_latch.set(state.read_u32()?);
+        for written in &mut self.affine_reference_written {
+            *written = state.read_u16()? != 0;
+        }
+        Ok(())
+    }
+
+    pub fn add_watchpoint(&mut self, address: u32, kind: WatchKind) {
+        self.watchpoints.push((address, kind));
+    }
+
+    /// Returns the last watchpoint hit since the previous call
+    pub fn take_watch_hit(&self) -> Option<WatchHit> {
+        self.watch_hit.take()
+    }
+
+    fn check_watchpoints(&self, address: u32, kind: WatchKind, value: u8) {
+        if !self.watchpoints.is_empty() && self.watchpoints.contains(&(address, kind)) {
+            self.watch_hit.set(Some(WatchHit { kind, address, value }));
         }
     }
 
+    /// Makes faulting accesses panic, which helps finding emulation bugs
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    /// Makes faulting accesses raise prefetch and data aborts in the cpu, which the GBA never does
+    pub fn set_abort_on_fault(&mut self, abort_on_fault: bool) {
+        self.abort_on_fault = abort_on_fault;
+    }
+
+    /// Whether BGxX/BGxY of BG2 (`bg` 0) or BG3 (1) were written since the previous call
+    pub fn take_affine_reference_written(&mut self, bg: usize) -> bool {
+        std::mem::take(&mut self.affine_reference_written[bg])
+    }
+
+    /// Whether an access faulted since the previous call, for the cpu to raise an abort
+    pub fn take_abort(&self) -> bool {
+        self.abort.replace(false)
+    }
+
+    /// Calls `hook` on every faulting access, for example to log them
+    pub fn set_fault_hook(&mut self, hook: impl Fn(MemoryFault) + Send + 'static) {
+        self.fault_hook = Some(Box::new(hook));
+    }
+
+    /// Reads an instruction for the cpu, which also decides whether the BIOS is readable
+    pub fn fetch_u32(&self, address: u32) -> u32 {
+        self.executing_bios.set((address as usize) < BIOS_LEN);
+        let instruction = self.read_u32(address);
+        self.open_bus.set(instruction);
+        // The pipeline has already prefetched two instructions ahead
+        if let Some(prefetch) = self.bios_word(address.wrapping_add(8)) {
+            self.bios_latch.set(prefetch);
+        }
+        instruction
+    }
+
+    pub fn fetch_u16(&self, address: u32) -> u16 {
+        self.executing_bios.set((address as usize) < BIOS_LEN);
+        let instruction = self.read_u16(address);
+        // Thumb instructions are on both halves of the bus
+        self.open_bus.set(instruction as u32 * 0x0001_0001);
+        if let Some(prefetch) = self.bios_word(address.wrapping_add(4)) {
+            let halfword = prefetch >> (8 * (address & 2));
+            self.bios_latch.set((halfword & 0xFFFF) * 0x0001_0001);
+        }
+        instruction
+    }
+
+    /// Cycles of a non-sequential access of `width` bytes, including the wait states of the region
+    pub fn access_cycles(&self, address: u32, width: u32) -> u32 {
+        self.cycles(address, width, false)
+    }
+
+    /// Cycles of an instruction fetch, which with the prefetch buffer enabled mostly hides the game pak wait states
+    pub fn fetch_cycles(&self, address: u32, width: u32, sequential: bool) -> u32 {
+        let game_pak = matches!(address >> 24, 0x08..=0x0D);
+        // Coarse: the buffer is assumed to always have the next sequential opcode ready
+        if game_pak && sequential && self.io_u16(REG_WAITCNT) & WAITCNT_PREFETCH != 0 {
+            1
+        } else {
+            self.cycles(address, width, sequential)
+        }
+    }
+
+    fn cycles(&self, address: u32, width: u32, sequential: bool) -> u32 {
+        let waitcnt = self.io_u16(REG_WAITCNT) as usize;
+        // Word accesses take two accesses on the 16 bit buses
+        let accesses = if width == 4 { 2 } else { 1 };
+        match address >> 24 {
+            // On-board WRAM has 2 wait states
+            0x02 => accesses * 3,
+            0x05..=0x06 => accesses,
+            0x08..=0x0D => {
+                let region = ((address >> 24) - 0x08) as usize / 2;
+                let non_sequential = 1 + WAIT_STATES[waitcnt >> (2 + region * 3) & 0b11];
+                let sequential_cycles = 1 + SEQUENTIAL_WAIT_STATES[region][waitcnt >> (4 + region * 3) & 1];
+                // The second half of a word is always sequential
+                let first = if sequential { sequential_cycles } else { non_sequential };
+                first + (accesses - 1) * sequential_cycles
+            }
+            // The 8 bit save memory bus only transfers the addressed byte
+            0x0E..=0x0F => 1 + WAIT_STATES[waitcnt & 0b11],
+            _ => 1,
+        }
+    }
+
+    /// The BIOS word containing `address`, while executing the BIOS
+    fn bios_word(&self, address: u32) -> Option<u32> {
+        let index = (address & !3) as usize;
+        let bytes = self.bios.get(index..index + 4).filter(|_| self.executing_bios.get())?;
+        Some(u32::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn fault(&self, fault: MemoryFault) {
+        if self.strict {
+            panic!("{}", fault);
+        }
+        if self.abort_on_fault {
+            self.abort.set(true);
+        }
+        if let Some(hook) = &self.fault_hook {
+            hook(fault);
+        }
+    }
+
+    fn unmapped_read(&self, address: u32) -> u8 {
+        self.fault(MemoryFault::UnmappedRead(address));
+        (self.open_bus.get() >> (8 * (address & 3))) as u8
+    }
+
+    pub fn is_mapped(&self, address: u32) -> bool {
+        matches!(address, IO_START..=IO_END | SAVE_START..=SAVE_END) || self.eeprom(address).is_some() || self._is_mapped(address)
+    }
+
+    pub fn is_writable(&self, address: u32) -> bool {
+        matches!(address, IO_START..=IO_END | SAVE_START..=SAVE_END) || self.eeprom(address).is_some() || self._is_writable(address)
+    }
+
+    /// The EEPROM if it is mapped at `address`
+    fn eeprom(&self, address: u32) -> Option<&Eeprom> {
+        match &self.save {
+            SaveMemory::Eeprom(eeprom) if self.eeprom_window()?.contains(&address) => Some(eeprom),
+            _ => None,
+        }
+    }
+
+    /// The addresses that access the EEPROM, if there is one
+    fn eeprom_window(&self) -> Option<RangeInclusive<u32>> {
+        let start = if self.game_pak.len() > LARGE_ROM_LEN { EEPROM_LARGE_ROM_START } else { EEPROM_START };
+        matches!(self.save, SaveMemory::Eeprom(_)).then_some(start..=EEPROM_END)
+    }
+
+    /// Sets the EEPROM size from the length of a DMA transfer to it
+    pub fn detect_eeprom_size(&mut self, address: u32, stream_len: u32) {
+        if self.eeprom_window().is_some_and(|window| window.contains(&address)) {
+            if let SaveMemory::Eeprom(eeprom) = &mut self.save {
+                eeprom.detect_size(stream_len);
+            }
+        }
+    }
+
+    fn write_eeprom_bit(&mut self, value: u8) {
+        if let SaveMemory::Eeprom(eeprom) = &mut self.save {
+            if eeprom.write_bit(value & 1 != 0) {
+                self.save_dirty = true;
+            }
+        }
+    }
+
+    /// Like `read_u32`, but reports reads from unmapped memory instead of returning open bus
+    pub fn try_read_u32(&self, address: u32) -> Result<u32, MemoryFault> {
+        match (0..4).map(|i| address.wrapping_add(i)).find(|&address| !self.is_mapped(address)) {
+            Some(address) => Err(MemoryFault::UnmappedRead(address)),
+            None => Ok(self.read_u32(address)),
+        }
+    }
+
+    /// Like `write_u32`, but reports writes to unmapped or read-only memory instead of ignoring them
+    pub fn try_write_u32(&mut self, address: u32, value: u32) -> Result<(), MemoryFault> {
+        if let Some(address) = (0..4).map(|i| address.wrapping_add(i)).find(|&address| !self.is_writable(address)) {
+            return Err(if self.is_mapped(address) {
+                MemoryFault::ReadOnlyWrite(address)
+            } else {
+                MemoryFault::UnmappedWrite(address)
+            });
+        }
+        self.write_u32(address, value);
+        Ok(())
+    }
+
+    /// Whether the `len` bytes at `address` are plain memory in a single region, which can be copied without side effects.
+    /// The BIOS isn't, reading it depends on what is executing.
+    pub fn is_plain_memory(&self, address: u32, len: u32) -> bool {
+        address > 0x00_003_FFF && self.region(address, len).is_some()
+    }
+
+    /// Whether the `len` bytes at `dest` start inside the ones at `src` in the backing memory, also through a mirror.
+    /// Copying those from the start reads bytes that were already written.
+    pub fn overlaps_ahead(&self, dest: u32, src: u32, len: u32) -> bool {
+        match (self.region(dest, len), self.region(src, len)) {
+            (Some(dest), Some(src)) => {
+                let offset = (dest.as_ptr() as usize).wrapping_sub(src.as_ptr() as usize);
+                offset != 0 && offset < len as usize
+            }
+            _ => false,
+        }
+    }
+
+    /// Writes `src` to `dest` with a single copy when it fits into one region, otherwise byte by byte.
+    /// Nothing is written if any of the bytes isn't writable.
+    pub fn copy_into(&mut self, dest: u32, src: &[u8]) -> Result<(), MemoryFault> {
+        let len = src.len() as u32;
+        if let Some(region) = self.region_mut(dest, len) {
+            region.copy_from_slice(src);
+            for (i, &value) in src.iter().enumerate().filter(|_| !self.watchpoints.is_empty()) {
+                self.check_watchpoints(dest + i as u32, WatchKind::Write, value);
+            }
+            return Ok(());
+        }
+        if let Some(address) = (0..len).map(|i| dest.wrapping_add(i)).find(|&address| !self.is_writable(address)) {
+            return Err(if self.is_mapped(address) {
+                MemoryFault::ReadOnlyWrite(address)
+            } else {
+                MemoryFault::UnmappedWrite(address)
+            });
+        }
+        for (address, &value) in (0..len).map(|i| dest.wrapping_add(i)).zip(src) {
+            self.write_byte(address, value);
+        }
+        Ok(())
+    }
+
+    /// Copies `len` bytes from `src` to `dest`, see `copy_into`
+    pub fn copy_within(&mut self, dest: u32, src: u32, len: u32) -> Result<(), MemoryFault> {
+        let data = match self.region(src, len) {
+            Some(region) if self.is_plain_memory(src, len) => {
+                let data = region.to_vec();
+                for (i, &value) in data.iter().enumerate().filter(|_| !self.watchpoints.is_empty()) {
+                    self.check_watchpoints(src + i as u32, WatchKind::Read, value);
+                }
+                data
+            }
+            _ => (0..len).map(|i| self.read_u8(src.wrapping_add(i))).collect(),
+        };
+        self.copy_into(dest, &data)
+    }
+
     pub fn read_u8(&self, address: u32) -> u8 {
-        self._read_u8(address)
+        let value = self.read_byte(address);
+        self.check_watchpoints(address, WatchKind::Read, value);
+        value
+    }
+
+    fn read_byte(&self, address: u32) -> u8 {
+        if let Some(eeprom) = self.eeprom(address) {
+            return eeprom.read_bit() as u8;
+        }
+        match address {
+            REG_KEYINPUT => self.keypad.keyinput() as u8,
+            // The low bits of SOUNDCNT_X show which PSG channels are playing
+            REG_SOUNDCNT_X => self.io.read(address) | self.apu.psg.status(),
+            REG_WAVE_RAM..=WAVE_RAM_END => self.apu.psg.read_wave_ram(address, &self.io),
+            0x04_000_131 => (self.keypad.keyinput() >> 8) as u8,
+            SAVE_START..=SAVE_END => self.save.read(address - SAVE_START),
+            // Past the end of the ROM the cartridge bus returns the halfword address
+            GAME_PAK_START..=GAME_PAK_END if game_pak_offset(address) as usize >= self.game_pak.len() => (game_pak_offset(address) >> 1 >> (8 * (address & 1))) as u8,
+            0x00_000_000..=0x00_003_FFF if !self.executing_bios.get() => (self.bios_latch.get() >> (8 * (address & 3))) as u8,
+            // TMxCNT_L reads the live counter
+            _ if (REG_TM0CNT_L..=REG_TM3CNT_H + 1).contains(&address) && address & 2 == 0 => {
+                let counter = self.timers.counter(((address - REG_TM0CNT_L) / TIMER_LEN) as usize);
+                (counter >> (8 * (address & 1))) as u8
+            }
+            IO_START..=IO_END => self.io.read(address),
+            _ => self._read_u8(address),
+        }
+    }
+
+    fn write_byte(&mut self, address: u32, value: u8) {
+        self.check_watchpoints(address, WatchKind::Write, value);
+        if self.eeprom(address).is_some() {
+            return self.write_eeprom_bit(value);
+        }
+        match address {
+            // Enabling a DMA channel latches its registers
+            _ if (REG_DMA0CNT_H..=REG_DMA3CNT_H + 1).contains(&address) && (address - REG_DMA0CNT_H) % DMA_CHANNEL_LEN == 1 => {
+                let was_enabled = self.io.get(address) & (DMACNT_ENABLE >> 8) as u8 != 0;
+                self.io.write(address, value);
+                if !was_enabled && value & (DMACNT_ENABLE >> 8) as u8 != 0 {
+                    let channel = ((address - REG_DMA0CNT_H) / DMA_CHANNEL_LEN) as usize;
+                    self.with_dma(|dma, mem| dma.enable(channel, mem));
+                }
+            }
+            SAVE_START..=SAVE_END => {
+                self.save.write(address - SAVE_START, value);
+                self.save_dirty = true;
+            }
+            // Writing BGxX/BGxY reloads the internal reference point, even with the same value
+            _ if (REG_BG2X..=REG_BG3Y + 3).contains(&address) && (address - REG_BG2X) % AFFINE_BG_STRIDE < 8 => {
+                self.io.write(address, value);
+                self.affine_reference_written[((address - REG_BG2X) / AFFINE_BG_STRIDE) as usize] = true;
+            }
+            REG_FIFO_A..=0x04_000_0A7 => self.apu.write_fifo(((address - REG_FIFO_A) / 4) as usize, value),
+            // The FIFO reset bits are in the upper byte
+            0x04_000_083 => {
+                self.io.write(address, value);
+                self.apu.write_control(self.io.get_u16(REG_SOUNDCNT_H));
+            }
+            REG_SOUND1CNT_L..=PSG_REGISTERS_END => {
+                self.io.write(address, value);
+                self.apu.psg.write(address, value, &self.io);
+            }
+            REG_SOUNDCNT_X => {
+                self.io.write(address, value);
+                if value as u16 & SOUNDCNT_X_ENABLE == 0 {
+                    self.apu.psg.power_off(&mut self.io);
+                }
+            }
+            REG_WAVE_RAM..=WAVE_RAM_END => self.apu.psg.write_wave_ram(address, value, &self.io),
+            _ if (REG_TM0CNT_L..=REG_TM3CNT_H + 1).contains(&address) => {
+                self.io.write(address, value);
+                let timer = ((address - REG_TM0CNT_L) / TIMER_LEN) as usize;
+                let value = self.io.get_u16(address & !1);
+                if address & 2 == 0 {
+                    self.timers.set_reload(timer, value);
+                } else {
+                    self.timers.set_control(timer, value);
+                }
+            }
+            IO_START..=IO_END => self.io.write(address, value),
+            _ => self._write_u8(address, value),
+        }
+    }
+
+    pub fn palette_ram(&self) -> &[u8] {
+        &self.palette_ram
+    }
+
+    pub fn vram(&self) -> &[u8] {
+        &self.vram
+    }
+
+    pub fn oam(&self) -> &[u8] {
+        &self.oam
+    }
+
+    pub fn keypad(&self) -> &Keypad {
+        &self.keypad
+    }
+
+    /// Raises the keypad interrupt if the buttons selected in KEYCNT are pressed
+    pub fn poll_keypad_interrupt(&mut self) {
+        if self.keypad.interrupt_condition(self.read_u16(REG_KEYCNT)) {
+            self.request_interrupt(Interrupt::Keypad);
+        }
+    }
+
+    fn with_dma(&mut self, f: impl FnOnce(&mut Dma, &mut Memory)) {
+        // The DMA controller transfers through the bus it is part of
+        let mut dma = std::mem::take(&mut self.dma);
+        f(&mut dma, self);
+        self.dma = dma;
+    }
+
+    /// Starts the DMA transfers waiting for `timing`
+    pub fn trigger_dma(&mut self, timing: DmaTiming) {
+        self.with_dma(|dma, mem| dma.trigger(timing, mem));
+    }
+
+    /// Advances the timers by the given number of cpu cycles, their overflows play the sound FIFOs
+    pub fn step_timers(&mut self, cycles: u32) {
+        for interrupt in self.timers.step(cycles).into_iter().flatten() {
+            self.request_interrupt(interrupt);
+        }
+        // Only timers 0 and 1 can drive the FIFOs
+        for (timer, overflows) in self.timers.overflows().into_iter().enumerate().take(2) {
+            if overflows == 0 {
+                continue;
+            }
+            let refill = self.apu.timer_overflow(timer, overflows, self.io_u16(REG_SOUNDCNT_H));
+            for (fifo_address, refill) in FIFO_ADDRESSES.into_iter().zip(refill) {
+                if refill {
+                    self.with_dma(|dma, mem| dma.trigger_fifo(fifo_address, mem));
+                }
+            }
+        }
+    }
+
+    /// Produces the sound output for the given number of cpu cycles
+    pub fn step_apu(&mut self, cycles: u32) {
+        self.apu.step(cycles, &mut self.io);
+    }
+
+    /// The sound output, for the host to play
+    pub fn audio_samples(&self) -> &SampleBuffer {
+        self.apu.samples()
+    }
+
+    /// Sets an I/O register from the hardware side, bypassing the behavior of cpu writes
+    pub fn set_io_u16(&mut self, address: u32, value: u16) {
+        self.io.set_u16(address, value);
+    }
+
+    /// Reads an I/O register from the hardware side, including its write-only bits
+    pub fn io_u16(&self, address: u32) -> u16 {
+        self.io.get_u16(address)
+    }
+
+    pub fn io_u32(&self, address: u32) -> u32 {
+        self.io_u16(address) as u32 | (self.io_u16(address + 2) as u32) << 16
+    }
+
+    /// Flags an interrupt in IF, regardless of whether it is enabled
+    pub fn request_interrupt(&mut self, interrupt: Interrupt) {
+        let flags = self.io_u16(REG_IF) | interrupt.mask();
+        self.set_io_u16(REG_IF, flags);
+    }
+
+    /// Whether the interrupt controller signals an IRQ to the cpu
+    pub fn interrupt_pending(&self) -> bool {
+        self.io_u16(REG_IME) & 1 != 0 && self.io_u16(REG_IE) & self.io_u16(REG_IF) != 0
     }
 
     pub fn read_u16(&self, address: u32) -> u16 {
-        let low = self.read_u8(address) as u16;
-        let high = self.read_u8(address + 1) as u16;
-        (high << 8) | low
+        // The save memory has an 8bit bus, wider reads repeat the byte
+        if (SAVE_START..=SAVE_END).contains(&address) {
+            return self.read_u8(address) as u16 * 0x0101;
+        }
+        // The EEPROM transfers a single bit per access
+        if let Some(eeprom) = self.eeprom(address) {
+            return eeprom.read_bit();
+        }
+        // Halves past the end of a region come from wherever the next address maps to, the bus wraps at the top of the address space
+        u16::from_le_bytes([self.read_u8(address), self.read_u8(address.wrapping_add(1))])
     }
 
     pub fn read_u32(&self, address: u32) -> u32 {
-        let low = self.read_u16(address) as u32;
-        let high = self.read_u16(address + 2) as u32;
-        (high << 16) | low
+        if (SAVE_START..=SAVE_END).contains(&address) {
+            return self.read_u8(address) as u32 * 0x01010101;
+        }
+        if let Some(eeprom) = self.eeprom(address) {
+            return eeprom.read_bit() as u32;
+        }
+        let [b0, b1] = self.read_u16(address).to_le_bytes();
+        let [b2, b3] = self.read_u16(address.wrapping_add(2)).to_le_bytes();
+        u32::from_le_bytes([b0, b1, b2, b3])
     }
 
     pub fn write_u8(&mut self, address: u32, value: u8) {
         if matches!(address, 0x05_000_000..=0x07_FFF_FFF) {
             panic!("8bit writes into Video Memory are not supported");
         }
-        self._write_u8(address, value);
+        self.write_byte(address, value);
     }
 
     pub fn write_u16(&mut self, address: u32, value: u16) {
-        self._write_u8(address, value as u8);
-        self._write_u8(address + 1, (value >> 8) as u8);
+        // The save memory has an 8bit bus, wider writes only store the addressed byte
+        if (SAVE_START..=SAVE_END).contains(&address) {
+            return self.write_byte(address, (value >> (8 * (address & 1))) as u8);
+        }
+        if self.eeprom(address).is_some() {
+            return self.write_byte(address, value as u8);
+        }
+        let [low, high] = value.to_le_bytes();
+        self.write_byte(address, low);
+        self.write_byte(address.wrapping_add(1), high);
     }
 
     pub fn write_u32(&mut self, address: u32, value: u32) {
+        if (SAVE_START..=SAVE_END).contains(&address) {
+            return self.write_byte(address, (value >> (8 * (address & 3))) as u8);
+        }
+        if self.eeprom(address).is_some() {
+            return self.write_byte(address, value as u8);
+        }
         self.write_u16(address, value as u16);
-        self.write_u16(address + 2, (value >> 16) as u16);
+        self.write_u16(address.wrapping_add(2), (value >> 16) as u16);
     }
 }
 
@@ -158,4 +905,481 @@ mod tests {
         assert_eq!(vram(vram_start + 0x18_000, vram_start), 0x0000); // Mirrored region
         assert_eq!(vram(vram_start + 0x1F_FFF, vram_start), 0x7_FFF); // End of VRAM mirror
     }
+
+    #[test]
+    fn test_region_boundaries() {
+        let mut mem = Memory::new(vec![0; 0x4000], vec![0x11; 0x100]);
+        // A word straddling the end of the palette ram continues at its start through the mirror
+        mem.write_u32(0x05_000_3FE, 0x44332211);
+        assert_eq!(mem.read_u16(0x05_000_000), 0x4433);
+        assert_eq!(mem.read_u32(0x05_000_3FE), 0x44332211);
+        assert_eq!(mem.read_u8(0x05_000_3FF), 0x22);
+
+        // Past the end of the ROM the halfword address is read
+        assert_eq!(mem.read_u32(0x08_000_0FE), 0x00801111);
+        assert_eq!(mem.read_u32(0x08_123_454), 0x1A2B_1A2A);
+
+        // The address space wraps instead of overflowing
+        mem.read_u32(0xFF_FFF_FFE);
+        mem.write_u32(0xFF_FFF_FFE, 0);
+    }
+
+    #[test]
+    fn test_mirrors() {
+        let mut mem = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+        // On-board WRAM is 256 KB and on-chip WRAM 32 KB, both mirrored up to the end of their 16 MB region
+        for (start, len, end) in [(0x02_000_000, WRAM1_LEN, 0x02_FFF_FFC), (0x03_000_000, WRAM2_LEN, 0x03_FFF_FFC)] {
+            mem.write_u32(start + len - 4, 0x44332211);
+            mem.write_u32(start, 0x88776655);
+            assert_eq!(mem.read_u32(end), 0x44332211);
+            assert_eq!(mem.read_u32(start + len), 0x88776655);
+            assert_eq!(mem.read_u32(end - len + 4), 0x88776655);
+        }
+        // The stack and BIOS variables at the top of on-chip WRAM are separate from its start
+        mem.write_u32(0x03_007_FFC, 0x03_000_000);
+        assert_eq!(mem.read_u32(0x03_FFF_FFC), 0x03_000_000);
+        assert_eq!(mem.read_u32(0x03_000_7FC), 0);
+
+        // The I/O registers end with the upper byte of the halfword at 0x040003FE
+        assert!(mem.is_mapped(0x04_000_3FF));
+        assert!(!mem.is_mapped(0x04_000_400));
+
+        // The ROM is mirrored in all three wait state regions, including the part past its end
+        let mut rom = vec![0; 0x100];
+        rom[0x42] = 0x5A;
+        mem.load_game_pak(rom, SaveType::Sram).unwrap();
+        for base in [0x08_000_000, 0x0A_000_000, 0x0C_000_000] {
+            assert_eq!(mem.read_u8(base + 0x42), mem.read_u8(0x08_000_042));
+            assert_eq!(mem.read_u32(base + 0x123_454), 0x1A2B_1A2A);
+        }
+        assert_eq!(mem.read_u8(0x0A_000_042), 0x5A);
+        assert!(mem.is_mapped(0x0D_FFF_FFF));
+        assert_eq!(mem.try_write_u32(0x0A_000_040, 0), Err(MemoryFault::ReadOnlyWrite(0x0A_000_040)));
+    }
+
+    #[test]
+    fn test_interrupt_flags() {
+        let mut mem = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+        // Flagged but not enabled in IE
+        mem.request_interrupt(Interrupt::VBlank);
+        mem.write_u16(REG_IME, 1);
+        assert!(!mem.interrupt_pending());
+
+        // IME is the master enable
+        mem.write_u16(REG_IE, Interrupt::VBlank.mask());
+        mem.write_u16(REG_IME, 0);
+        assert!(!mem.interrupt_pending());
+        mem.write_u16(REG_IME, 1);
+        assert!(mem.interrupt_pending());
+
+        // Writing 0 leaves flags untouched, writing 1 acknowledges them
+        mem.write_u16(REG_IF, 0);
+        assert!(mem.interrupt_pending());
+        mem.write_u16(REG_IF, Interrupt::VBlank.mask());
+        assert_eq!(mem.read_u16(REG_IF), 0);
+        assert!(!mem.interrupt_pending());
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let mut mem = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+        mem.add_watchpoint(0x02_000_101, WatchKind::Write);
+        mem.add_watchpoint(0x02_000_200, WatchKind::Read);
+
+        mem.write_u8(0x02_000_100, 0x12);
+        mem.read_u8(0x02_000_101);
+        assert_eq!(mem.take_watch_hit(), None);
+
+        mem.write_u16(0x02_000_100, 0xABCD);
+        assert_eq!(
+            mem.take_watch_hit(),
+            Some(WatchHit {
+                kind: WatchKind::Write,
+                address: 0x02_000_101,
+                value: 0xAB,
+            })
+        );
+        assert_eq!(mem.take_watch_hit(), None);
+
+        mem.read_u32(0x02_000_200);
+        assert_eq!(mem.take_watch_hit().map(|hit| hit.kind), Some(WatchKind::Read));
+    }
+
+    #[test]
+    fn test_keypad() {
+        use crate::system::keypad::Button;
+
+        let mut mem = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+        let keypad = mem.keypad().clone();
+        assert_eq!(mem.read_u16(REG_KEYINPUT), 0x3FF);
+        keypad.set_pressed(Button::A, true);
+        assert_eq!(mem.read_u16(REG_KEYINPUT), 0x3FE);
+
+        mem.write_u16(REG_KEYCNT, 1 << 14 | 1 << Button::Start as u16);
+        mem.poll_keypad_interrupt();
+        assert_eq!(mem.read_u16(REG_IF), 0);
+        keypad.set_pressed(Button::Start, true);
+        mem.poll_keypad_interrupt();
+        assert_eq!(mem.read_u16(REG_IF), Interrupt::Keypad.mask());
+    }
+
+    #[test]
+    fn test_sram() {
+        let mut mem = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+        assert_eq!(mem.read_u8(0x0E_000_000), 0xFF);
+        mem.write_u8(0x0E_000_010, 0x42);
+        assert_eq!(mem.read_u8(0x0E_000_010), 0x42);
+
+        // Only the addressed byte takes part in wider accesses
+        assert_eq!(mem.read_u16(0x0E_000_010), 0x4242);
+        assert_eq!(mem.read_u32(0x0E_000_010), 0x42424242);
+        mem.write_u32(0x0E_000_022, 0x11223344);
+        assert_eq!(mem.read_u8(0x0E_000_022), 0x22);
+        assert_eq!(mem.read_u8(0x0E_000_023), 0xFF);
+    }
+
+    #[test]
+    fn test_save_file() {
+        let path = std::env::temp_dir().join(format!("gbae_test_{}.sav", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut mem = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+        mem.load_save(&path).unwrap();
+        mem.flush_save().unwrap();
+        assert!(!path.exists());
+        mem.write_u8(0x0E_00F_FFF, 0x12);
+        mem.flush_save().unwrap();
+
+        let mut mem = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+        mem.load_save(&path).unwrap();
+        assert_eq!(mem.read_u8(0x0E_00F_FFF), 0x12);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_load_game_pak() {
+        let path = std::env::temp_dir().join(format!("gbae_test_swap_{}.sav", std::process::id()));
+        let mut mem = Memory::new(vec![0; 0x4000], vec![0x11; 0x100]);
+        mem.load_save(&path).unwrap();
+        mem.write_u8(0x0E_000_000, 0x42);
+        assert_eq!(mem.read_u32(0x08_000_000), 0x11111111);
+
+        mem.load_game_pak(vec![0x22; 0x100], SaveType::Sram).unwrap();
+        assert_eq!(mem.read_u32(0x08_000_000), 0x22222222);
+        // The old save was written out and the new cartridge starts with an empty one
+        assert_eq!(fs::read(&path).unwrap()[0], 0x42);
+        assert_eq!(mem.read_u8(0x0E_000_000), 0xFF);
+        mem.write_u8(0x0E_000_000, 0x43);
+        mem.flush_save().unwrap();
+        assert_eq!(fs::read(&path).unwrap()[0], 0x42);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_flash_save() {
+        let mut mem = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+        mem.set_save_type(SaveType::Flash64);
+        mem.write_u8(0x0E_005_555, 0xAA);
+        mem.write_u8(0x0E_002_AAA, 0x55);
+        mem.write_u8(0x0E_005_555, 0xA0);
+        mem.write_u8(0x0E_000_100, 0x42);
+        assert_eq!(mem.read_u8(0x0E_000_100), 0x42);
+
+        mem.write_u8(0x0E_000_101, 0x43);
+        assert_eq!(mem.read_u8(0x0E_000_101), 0xFF);
+    }
+
+    #[test]
+    fn test_eeprom() {
+        use crate::system::dma::REG_DMA0SAD;
+
+        let mut mem = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+        mem.set_save_type(SaveType::Eeprom);
+        let dma3 = |mem: &mut Memory, source: u32, destination: u32, count: u16| {
+            mem.write_u32(REG_DMA0SAD + 3 * DMA_CHANNEL_LEN, source);
+            mem.write_u32(REG_DMA0SAD + 3 * DMA_CHANNEL_LEN + 4, destination);
+            mem.write_u16(REG_DMA0SAD + 3 * DMA_CHANNEL_LEN + 8, count);
+            mem.write_u16(REG_DMA3CNT_H, DMACNT_ENABLE);
+        };
+        let write_bits = |mem: &mut Memory, bits: &[u16]| {
+            for (i, bit) in bits.iter().enumerate() {
+                mem.write_u16(0x02_000_000 + i as u32 * 2, *bit);
+            }
+        };
+
+        // Write 0xA5 repeated to block 1 of a 512 Byte EEPROM
+        let mut command = vec![1, 0, 0, 0, 0, 0, 0, 1];
+        command.extend((0..64).map(|i| [1, 0, 1, 0, 0, 1, 0, 1][i % 8]));
+        command.push(0);
+        write_bits(&mut mem, &command);
+        dma3(&mut mem, 0x02_000_000, 0x0D_000_000, 73);
+        assert_eq!(mem.read_u16(0x0D_000_000) & 1, 1);
+        assert_eq!(mem.save.data().len(), 0x200);
+        assert_eq!(mem.save.data()[8..16], [0xA5; 8]);
+        assert!(mem.save_dirty);
+
+        // Read it back
+        write_bits(&mut mem, &[1, 1, 0, 0, 0, 0, 0, 1, 0]);
+        dma3(&mut mem, 0x02_000_000, 0x0D_000_000, 9);
+        dma3(&mut mem, 0x0D_000_000, 0x03_000_000, 68);
+        let bits: Vec<u16> = (0..68).map(|i| mem.read_u16(0x03_000_000 + i * 2) & 1).collect();
+        assert_eq!(bits[..4], [0; 4]);
+        assert_eq!(bits[4..12], [1, 0, 1, 0, 0, 1, 0, 1]);
+        // The EEPROM window can't be copied like ROM
+        assert!(!mem.is_plain_memory(0x0D_000_000, 4));
+        assert!(!mem.is_plain_memory(0x0C_FFF_FFE, 4));
+        assert!(mem.is_plain_memory(0x08_000_000, 4));
+
+        // With a ROM over 16MB only the end of the region is the EEPROM
+        let mut rom = vec![0; 0x1_000_100];
+        rom[0x1_000_000..0x1_000_002].copy_from_slice(&0x1234u16.to_le_bytes());
+        let mut mem = Memory::new(vec![0; 0x4000], rom);
+        mem.set_save_type(SaveType::Eeprom);
+        assert_eq!(mem.read_u16(0x0D_000_000), 0x1234);
+        assert!(mem.is_plain_memory(0x0D_000_000, 4));
+        assert!(!mem.is_plain_memory(0x0D_FFF_F00, 4));
+        assert_eq!(mem.read_u16(0x0D_FFF_F00), 1);
+    }
+
+    #[test]
+    fn test_timer_registers() {
+        let mut mem = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+        mem.write_u16(REG_IE, Interrupt::Timer2.mask());
+        mem.write_u16(REG_IME, 1);
+
+        // Timer 2: reload 0xFF00, prescaler 1, IRQ enabled
+        mem.write_u32(REG_TM0CNT_L + 8, (1 << 7 | 1 << 6) << 16 | 0xFF00);
+        mem.step_timers(0x80);
+        assert_eq!(mem.read_u16(REG_TM0CNT_L + 8), 0xFF80);
+        assert_eq!(mem.read_u16(REG_TM0CNT_L + 10), 1 << 7 | 1 << 6);
+
+        // Writing the reload value doesn't change the running counter
+        mem.write_u16(REG_TM0CNT_L + 8, 0xFFF0);
+        assert_eq!(mem.read_u16(REG_TM0CNT_L + 8), 0xFF80);
+        assert!(!mem.interrupt_pending());
+        mem.step_timers(0x80);
+        assert_eq!(mem.read_u16(REG_TM0CNT_L + 8), 0xFFF0);
+        assert!(mem.interrupt_pending());
+    }
+
+    #[test]
+    fn test_copy() {
+        let mut mem = Memory::new(vec![0; 0x4000], (0..=255).collect());
+        let data: Vec<u8> = (0..200).map(|i| i as u8 ^ 0x5A).collect();
+
+        mem.copy_into(0x02_000_010, &data).unwrap();
+        for (i, &value) in data.iter().enumerate() {
+            assert_eq!(mem.read_u8(0x02_000_010 + i as u32), value);
+        }
+        // Copies across the end of the mirrored region wrap like single writes
+        mem.copy_into(0x03_007_FFE, &data[..4]).unwrap();
+        assert_eq!(mem.read_u32(0x03_007_FFE), u32::from_le_bytes([data[0], data[1], data[2], data[3]]));
+        assert_eq!(mem.read_u16(0x03_000_000), u16::from_le_bytes([data[2], data[3]]));
+        // Copies spanning two regions are written byte by byte
+        mem.copy_into(0x02_FFF_FFE, &data[4..8]).unwrap();
+        assert_eq!(mem.read_u16(0x02_FFF_FFE), u16::from_le_bytes([data[4], data[5]]));
+        assert_eq!(mem.read_u16(0x03_000_000), u16::from_le_bytes([data[6], data[7]]));
+
+        mem.copy_within(0x06_000_000, 0x08_000_040, 16).unwrap();
+        assert_eq!(mem.read_u32(0x06_000_00C), 0x4F4E4D4C);
+
+        // The ROM rejects the whole copy
+        assert_eq!(mem.copy_into(0x08_000_000, &data), Err(MemoryFault::ReadOnlyWrite(0x08_000_000)));
+        assert_eq!(mem.copy_into(0x01_FFF_FFE, &data[..4]), Err(MemoryFault::UnmappedWrite(0x01_FFF_FFE)));
+        assert_eq!(mem.read_u8(0x08_000_000), 0);
+        assert!(!mem.is_plain_memory(0x00_000_000, 4));
+    }
+
+    #[test]
+    fn test_faults() {
+        use std::sync::{Arc, Mutex};
+
+        let mut bios = vec![0; 0x4000];
+        bios[0..4].copy_from_slice(&0xE3A00001u32.to_le_bytes()); // MOV R0, #1
+        let mut mem = Memory::new(bios, vec![0; 0x100]);
+        let faults = Arc::new(Mutex::new(Vec::new()));
+        let hook_faults = faults.clone();
+        mem.set_fault_hook(move |fault| hook_faults.lock().unwrap().push(fault));
+
+        // Unmapped reads return the last prefetched instruction
+        assert_eq!(mem.read_u32(0x01_000_000), 0);
+        crate::system::cpu::CPU::new().cycle(&mut mem);
+        assert_eq!(mem.read_u32(0x01_000_000), 0xE3A00001);
+        assert_eq!(mem.read_u8(0x01_000_003), 0xE3);
+        assert_eq!(mem.try_read_u32(0x04_000_3FE), Err(MemoryFault::UnmappedRead(0x04_000_400)));
+        assert_eq!(mem.try_read_u32(0x02_000_000), Ok(0));
+
+        // Writes to unmapped and read-only memory are ignored
+        mem.write_u8(0x01_000_000, 0x42);
+        mem.write_u32(0x00_000_000, 0);
+        assert_eq!(mem.read_u32(0x00_000_000), 0xE3A00001);
+        assert_eq!(mem.try_write_u32(0x00_000_000, 0), Err(MemoryFault::ReadOnlyWrite(0x00_000_000)));
+        assert_eq!(mem.try_write_u32(0x10_000_000, 0), Err(MemoryFault::UnmappedWrite(0x10_000_000)));
+        assert_eq!(mem.try_write_u32(0x02_000_000, 0x12345678), Ok(()));
+        assert_eq!(mem.read_u32(0x02_000_000), 0x12345678);
+
+        let faults = faults.lock().unwrap();
+        assert_eq!(faults[0], MemoryFault::UnmappedRead(0x01_000_000));
+        assert!(faults.contains(&MemoryFault::UnmappedWrite(0x01_000_000)));
+        assert!(faults.contains(&MemoryFault::ReadOnlyWrite(0x00_000_003)));
+    }
+
+    #[test]
+    fn test_randomize() {
+        let randomized = |seed| {
+            let mut mem = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+            mem.randomize(seed);
+            mem
+        };
+        let mem = randomized(1);
+        let other = randomized(1);
+        assert_eq!(mem.read_u32(0x01_000_000), other.read_u32(0x01_000_000));
+        for address in [0x02_000_000, 0x03_007_FFC, 0x06_000_000, 0x0E_000_000] {
+            assert_eq!(mem.read_u32(address), other.read_u32(address));
+        }
+        assert_ne!(mem.read_u32(0x02_000_000), randomized(2).read_u32(0x02_000_000));
+    }
+
+    #[test]
+    fn test_bios_protection() {
+        use crate::system::cpu::{CPU, REGISTER_PC};
+
+        let mut bios = vec![0; 0x4000];
+        bios[0..4].copy_from_slice(&0xE3A00001u32.to_le_bytes()); // MOV R0, #1
+        bios[4..8].copy_from_slice(&0xE3A00002u32.to_le_bytes()); // MOV R0, #2
+        bios[8..12].copy_from_slice(&0xE3A00004u32.to_le_bytes()); // MOV R0, #4
+        let mut rom = vec![0; 0x100];
+        rom[0..4].copy_from_slice(&0xE3A00003u32.to_le_bytes()); // MOV R0, #3
+        let mut mem = Memory::new(bios, rom);
+        let mut cpu = CPU::new();
+        cpu.cycle(&mut mem);
+        assert_eq!(mem.read_u32(0x00_000_004), 0xE3A00002);
+
+        // Outside of the BIOS it reads as the last opcode prefetched from it, two instructions after the one executed
+        cpu.set_r(REGISTER_PC, 0x08_000_000);
+        cpu.cycle(&mut mem);
+        assert_eq!(mem.read_u32(0x00_000_004), 0xE3A00004);
+        assert_eq!(mem.read_u16(0x00_000_002), 0xE3A0);
+        assert_eq!(mem.read_u32(0x08_000_000), 0xE3A00003);
+
+        // In thumb state the prefetch is one word ahead, on both halves of the bus
+        mem.fetch_u16(0x00_000_004);
+        mem.fetch_u16(0x08_000_000);
+        assert_eq!(mem.read_u32(0x00_000_000), 0x00040004);
+        mem.fetch_u16(0x00_000_006);
+        mem.fetch_u16(0x08_000_000);
+        assert_eq!(mem.read_u32(0x00_000_000), 0xE3A0E3A0);
+
+        // The latch is part of save states
+        let mut state = crate::system::state::StateWriter::new();
+        mem.save_state(&mut state);
+        let data = state.into_bytes();
+        let mut loaded = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+        loaded.load_state(&mut crate::system::state::StateReader::new(&data).unwrap()).unwrap();
+        loaded.fetch_u32(0x08_000_000);
+        assert_eq!(loaded.read_u32(0x00_000_004), 0xE3A0E3A0);
+
+        mem.skip_bios();
+        assert_eq!(mem.read_u32(0x00_000_000), 0xE129F000);
+    }
+
+    #[test]
+    fn test_bios_latch_after_exceptions() {
+        use crate::system::cpu::{CPU, MODE_SVC, MODE_SYS, REGISTER_LR, REGISTER_PC};
+
+        // The instructions around the ends of the IRQ and SWI handlers of the BIOS
+        let mut bios = vec![0; 0x4000];
+        bios[0x134..0x138].copy_from_slice(&0xE510F004u32.to_le_bytes()); // LDR PC, [R0, #-4]
+        bios[0x13C..0x140].copy_from_slice(&0xE25EF004u32.to_le_bytes()); // SUBS PC, LR, #4
+        bios[0x188..0x18C].copy_from_slice(&0xE1B0F00Eu32.to_le_bytes()); // MOVS PC, LR
+        bios[0x190..0x194].copy_from_slice(&0xE3A02004u32.to_le_bytes()); // MOV R2, #4
+        let mut mem = Memory::new(bios, vec![0; 0x100]);
+        let mut cpu = CPU::new();
+        mem.write_u32(0x02_000_000, 0xE1A00000); // MOV R0, R0
+
+        // Calling the user IRQ handler from the BIOS
+        mem.write_u32(0x03_007_FFC, 0x02_000_000);
+        cpu.set_r(0, 0x04_000_000);
+        cpu.set_r(REGISTER_PC, 0x00_000_134);
+        cpu.cycle(&mut mem);
+        assert_eq!(cpu.get_r(REGISTER_PC), 0x02_000_000);
+        cpu.cycle(&mut mem);
+        assert_eq!(mem.read_u32(0x00_000_000), 0xE25EF004);
+
+        // Returning from a SWI
+        cpu.set_mode(MODE_SVC);
+        cpu.set_spsr(MODE_SYS as u32);
+        cpu.set_r(REGISTER_LR, 0x02_000_000);
+        cpu.set_r(REGISTER_PC, 0x00_000_188);
+        cpu.cycle(&mut mem);
+        assert_eq!(cpu.get_r(REGISTER_PC), 0x02_000_000);
+        cpu.cycle(&mut mem);
+        assert_eq!(mem.read_u32(0x00_000_000), 0xE3A02004);
+    }
+
+    #[test]
+    fn test_access_cycles() {
+        let mut mem = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+        // On-board WRAM has 2 wait states on a 16 bit bus, on-chip WRAM none on a 32 bit one
+        assert_eq!(mem.access_cycles(0x02_000_000, 4), 6);
+        assert_eq!(mem.access_cycles(0x02_000_000, 2), 3);
+        assert_eq!(mem.access_cycles(0x03_000_000, 4), 1);
+        assert_eq!(mem.access_cycles(0x06_000_000, 4), 2);
+        assert_eq!(mem.access_cycles(0x07_000_000, 4), 1);
+
+        // Game pak at the power on WAITCNT: 4 non-sequential and 2 sequential wait states
+        assert_eq!(mem.access_cycles(0x08_000_000, 2), 5);
+        assert_eq!(mem.access_cycles(0x08_000_000, 4), 8);
+        assert_eq!(mem.fetch_cycles(0x08_000_004, 4, true), 6);
+        assert_eq!(mem.access_cycles(0x0E_000_000, 1), 5);
+
+        // What most games set: 3 and 1 wait states for WS0, 8 bit save memory with 8 and the prefetch buffer
+        mem.write_u16(REG_WAITCNT, 0x4317);
+        assert_eq!(mem.access_cycles(0x08_000_000, 4), 6);
+        assert_eq!(mem.access_cycles(0x0E_000_000, 1), 9);
+        assert_eq!(mem.fetch_cycles(0x08_000_004, 4, true), 1);
+        assert_eq!(mem.fetch_cycles(0x08_000_004, 4, false), 6);
+        // WS2 has its own bits, set to 8 wait states
+        assert_eq!(mem.access_cycles(0x0C_000_000, 2), 9);
+    }
+
+    #[test]
+    fn test_sound_dma() {
+        let mut mem = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+        for i in 0..0x20 {
+            mem.write_u8(0x02_000_000 + i, i as u8 + 1);
+        }
+        // DMA 1 refills FIFO A from EWRAM, 32 bit with sound timing and repeat
+        mem.write_u32(REG_DMA0CNT_H - 10 + DMA_CHANNEL_LEN, 0x02_000_000);
+        mem.write_u32(REG_DMA0CNT_H - 6 + DMA_CHANNEL_LEN, REG_FIFO_A);
+        mem.write_u16(REG_DMA0CNT_H + DMA_CHANNEL_LEN, DMACNT_ENABLE | 1 << 12 | 1 << 13 | 1 << 10 | 1 << 9);
+        // FIFO A at full volume on both sides, driven by timer 0
+        mem.write_u16(REG_SOUNDCNT_H, 1 << 2 | 1 << 8 | 1 << 9);
+        mem.write_u16(REG_SOUNDCNT_X, 1 << 7);
+        // Timer 0 overflows every cycle
+        mem.write_u32(REG_TM0CNT_L, 1 << 7 << 16 | 0xFFFF);
+
+        // The first overflow finds the FIFO empty and requests a refill, the second plays its first byte
+        mem.step_timers(1);
+        mem.step_apu(1024);
+        mem.step_timers(1);
+        mem.step_apu(1024);
+        let mut samples = [[0; 2]; 4];
+        assert_eq!(mem.audio_samples().pop_into(&mut samples), 4);
+        assert_eq!(samples, [[0, 0], [0, 0], [0x100, 0x100], [0x100, 0x100]]);
+
+        // Each refill continues where the last one stopped
+        mem.step_timers(16);
+        mem.step_apu(512);
+        assert_eq!(mem.audio_samples().pop_into(&mut samples), 1);
+        assert_eq!(samples[0], [0x1100, 0x1100]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Write to unmapped address")]
+    fn test_strict_faults() {
+        let mut mem = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+        mem.set_strict(true);
+        mem.write_u8(0x01_000_000, 0);
+    }
 }
@@ -1,5 +1,359 @@
+pub mod apu;
+pub mod bios;
+pub mod color;
 pub mod cpu;
 pub mod display;
+pub mod dma;
 pub mod instructions;
+pub mod interrupts;
+pub mod io_registers;
+pub mod keypad;
 pub mod memory;
 pub mod ppu;
+pub mod psg;
+pub mod rng;
+pub mod save;
+pub mod state;
+pub mod timers;
+pub mod trace;
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use cpu::CPU;
+use memory::Memory;
+use ppu::{Framebuffer, PPU};
+use save::SaveType;
+use state::{StateReader, StateWriter};
+
+pub struct GbaSystem {
+    pub cpu: CPU,
+    pub mem: Memory,
+    pub ppu: PPU,
+    // Loaded into RAM again on reset
+    program: Option<Program>,
+}
+
+/// A piece of a program loaded into memory, with the part that isn't in the file (like .bss) zero filled
+#[derive(Clone)]
+pub struct Segment {
+    pub address: u32,
+    pub data: Vec<u8>,
+}
+
+/// A program started from RAM instead of the cartridge
+struct Program {
+    segments: Vec<Segment>,
+    entry: u32,
+    hle_bios: bool,
+}
+
+impl GbaSystem {
+    /// Powers on with the cpu at the reset vector, the framebuffer is shared with the display
+    pub fn new(bios: Vec<u8>, game_pak: Vec<u8>) -> (Self, Arc<RwLock<Framebuffer>>) {
+        let (ppu, framebuffer) = PPU::new();
+        let system = Self {
+            cpu: CPU::new(),
+            mem: Memory::new(bios, game_pak),
+            ppu,
+            program: None,
+        };
+        (system, framebuffer)
+    }
+
+    /// Executes one instruction and advances the timers and the ppu by the cycles it took, returns whether VBlank started
+    pub fn step_instruction(&mut self) -> bool {
+        let cycles = self.cpu.get_cycles();
+        self.cpu.cycle(&mut self.mem);
+        let cycles = (self.cpu.get_cycles() - cycles) as u32;
+        self.mem.step_timers(cycles);
+        self.mem.step_apu(cycles);
+
+        let was_in_vblank = self.ppu.in_vblank();
+        self.ppu.step(cycles, &mut self.mem);
+        let vblank_started = self.ppu.in_vblank() && !was_in_vblank;
+        if vblank_started {
+            self.mem.poll_keypad_interrupt();
+        }
+        vblank_started
+    }
+
+    /// Executes instructions until the next VBlank starts, when the frame is in the framebuffer
+    pub fn run_frame(&mut self) {
+        while !self.step_instruction() {}
+    }
+
+    /// Starts the cartridge directly, with the state the BIOS would leave behind and the common BIOS calls emulated
+    pub fn skip_bios(&mut self) {
+        self.cpu.skip_bios();
+        self.mem.skip_bios();
+    }
+
+    /// Copies `segments` into RAM and starts at `entry` instead of the cartridge, a reset loads them again.
+    /// With `hle_bios` the BIOS calls are emulated, otherwise they go to the BIOS that was given.
+    pub fn load_program(&mut self, segments: Vec<Segment>, entry: u32, hle_bios: bool) {
+        let program = Program { segments, entry, hle_bios };
+        self.start_program(&program);
+        self.program = Some(program);
+    }
+
+    fn start_program(&mut self, program: &Program) {
+        for segment in &program.segments {
+            // The ROM is fixed when the cartridge is inserted, so only RAM can be loaded
+            if self.mem.copy_into(segment.address, &segment.data).is_err() {
+                let end = segment.address.saturating_add(segment.data.len().max(1) as u32 - 1);
+                warn!("Skipping segment at {:08X}-{:08X}, which isn't in RAM", segment.address, end);
+            }
+        }
+
+        if program.hle_bios {
+            self.skip_bios();
+        } else {
+            self.cpu.skip_boot();
+            self.mem.skip_bios();
+        }
+        self.cpu.set_thumb_state(program.entry & 1 != 0);
+        self.cpu.set_r(cpu::REGISTER_PC, program.entry & !1);
+    }
+
+    /// Reboots like pressing the power button, the cartridge and its save memory stay
+    pub fn reset(&mut self) {
+        // Without a BIOS to boot through, start the cartridge directly again
+        let skip_bios = self.cpu.hle_bios.is_some();
+        self.cpu.power_on();
+        self.mem.reset();
+        self.ppu.reset();
+        if let Some(program) = self.program.take() {
+            self.start_program(&program);
+            self.program = Some(program);
+        } else if skip_bios {
+            self.skip_bios();
+        }
+    }
+
+    /// Swaps the cartridge and reboots into it, with the save loaded from `save_path` if given
+    pub fn insert_cartridge(&mut self, game_pak: Vec<u8>, save_type: SaveType, save_path: Option<PathBuf>) -> io::Result<()> {
+        self.mem.load_game_pak(game_pak, save_type)?;
+        self.program = None;
+        if let Some(save_path) = save_path {
+            self.mem.load_save(save_path)?;
+        }
+        self.reset();
+        Ok(())
+    }
+
+    /// Writes the cpu, memory and ppu state to `path`
+    pub fn save_state(&mut self, path: impl AsRef<Path>) -> io::Result<()> {
+        fs::write(path, self.state_bytes())
+    }
+
+    /// Restores a state written by `save_state`, the loaded ROM stays the same. A state that fails to load leaves the system unchanged.
+    pub fn load_state(&mut self, path: impl AsRef<Path>) -> io::Result<()> {
+        let data = fs::read(path)?;
+        // A corrupt state is only noticed partway through, the current state is restored then
+        let backup = self.state_bytes();
+        self.apply_state(&data).inspect_err(|_| self.apply_state(&backup).expect("Failed to restore the state before loading"))
+    }
+
+    fn state_bytes(&mut self) -> Vec<u8> {
+        let mut state = StateWriter::new();
+        self.cpu.save_state(&mut state);
+        self.mem.save_state(&mut state);
+        self.ppu.save_state(&mut state);
+        state.into_bytes()
+    }
+
+    fn apply_state(&mut self, data: &[u8]) -> io::Result<()> {
+        let mut state = StateReader::new(data)?;
+        self.cpu.load_state(&mut state)?;
+        self.mem.load_state(&mut state)?;
+        self.ppu.load_state(&mut state)?;
+        state.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_skip_bios() {
+        let mut system = GbaSystem::new(vec![0; memory::BIOS_LEN], vec![0; 0x100]).0;
+        system.skip_bios();
+
+        assert_eq!(system.cpu.get_mode(), cpu::MODE_SYS);
+        assert!(!system.cpu.get_thumb_state());
+        assert_eq!(system.cpu.get_r(cpu::REGISTER_PC), cpu::CARTRIDGE_ENTRY);
+        assert_eq!(system.cpu.get_r(cpu::REGISTER_SP), 0x03007F00);
+        assert_eq!(system.cpu.get_r_in_mode(cpu::REGISTER_SP, cpu::MODE_IRQ), 0x03007FA0);
+        assert_eq!(system.cpu.get_r_in_mode(cpu::REGISTER_SP, cpu::MODE_SVC), 0x03007FE0);
+        assert_eq!(system.mem.io_u16(0x04_000_020), 0x100);
+        assert_eq!(system.mem.read_u8(0x04_000_300), 1);
+    }
+
+    #[test]
+    fn test_step_instruction() {
+        let mut system = GbaSystem::new(vec![0; memory::BIOS_LEN], vec![0; 0x100]).0;
+        // A loop of MOV R0, R0 and B back to the start
+        for i in 0..15 {
+            system.mem.write_u32(0x02_000_000 + i * 4, 0xE1A00000);
+        }
+        system.mem.write_u32(0x02_000_03C, 0xEAFFFFF0);
+        system.cpu.set_r(cpu::REGISTER_PC, 0x02_000_000);
+        // Timer 0 counting every cycle
+        system.mem.write_u16(timers::REG_TM0CNT_L + 2, 0x80);
+
+        let start = system.cpu.get_cycles();
+        for _ in 0..2000 {
+            system.step_instruction();
+        }
+        let cycles = system.cpu.get_cycles() - start;
+        assert!(cycles >= 2000);
+        assert_eq!(system.mem.read_u16(timers::REG_TM0CNT_L) as u64, cycles % 0x10000);
+        assert_eq!(system.mem.read_u16(ppu::REG_VCOUNT) as u64, cycles / 1232);
+    }
+
+    #[test]
+    fn test_new() {
+        let (mut system, framebuffer) = GbaSystem::new(vec![0; memory::BIOS_LEN], vec![0x11; 0x100]);
+        assert_eq!(system.cpu.get_r(cpu::REGISTER_PC), 0);
+        assert_eq!(system.cpu.get_mode(), cpu::MODE_SVC);
+        assert_eq!(system.mem.read_u8(0x08_000_000), 0x11);
+        // The ppu draws into the returned framebuffer, white during forced blank
+        system.mem.write_u16(ppu::REG_DISPCNT, 0x80);
+        system.run_frame();
+        assert_eq!(framebuffer.read().unwrap()[0][0], [255, 255, 255]);
+    }
+
+    #[test]
+    fn test_run_frame() {
+        let mut system = GbaSystem::new(vec![0; memory::BIOS_LEN], vec![0; 0x100]).0;
+        // B to itself
+        system.mem.write_u32(0x02_000_000, 0xEAFFFFFE);
+        system.cpu.set_r(cpu::REGISTER_PC, 0x02_000_000);
+
+        // The first frame ends when VBlank starts at line 160, the next one a whole frame later
+        let start = system.cpu.get_cycles();
+        system.run_frame();
+        assert_eq!(system.mem.read_u16(ppu::REG_VCOUNT), 160);
+        let first = system.cpu.get_cycles() - start;
+        assert!((160 * 1232..160 * 1232 + 16).contains(&first));
+        system.run_frame();
+        assert_eq!(system.mem.read_u16(ppu::REG_VCOUNT), 160);
+        assert!((280_896 - 16..280_896 + 16).contains(&(system.cpu.get_cycles() - start - first)));
+        assert_eq!(system.cpu.get_r(cpu::REGISTER_PC), 0x02_000_000);
+    }
+
+    #[test]
+    fn test_insert_cartridge() {
+        let mut system = GbaSystem::new(vec![0; memory::BIOS_LEN], vec![0x11; 0x100]).0;
+        system.skip_bios();
+        system.cpu.set_r(cpu::REGISTER_PC, 0x08000040);
+
+        system.insert_cartridge(vec![0x22; 0x100], SaveType::Flash64, None).unwrap();
+        assert_eq!(system.mem.read_u8(0x08_000_000), 0x22);
+        assert_eq!(system.cpu.get_r(cpu::REGISTER_PC), cpu::CARTRIDGE_ENTRY);
+        assert_eq!(system.cpu.get_mode(), cpu::MODE_SYS);
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut system = GbaSystem::new(vec![0; memory::BIOS_LEN], vec![0x11; 0x100]).0;
+        system.cpu.set_r(3, 0x1234);
+        system.cpu.set_r_in_mode(cpu::REGISTER_SP, cpu::MODE_IRQ, 0x03007FA0);
+        system.cpu.cpsr = cpu::MODE_USR as u32 | 1 << 5;
+        system.cpu.set_r(cpu::REGISTER_PC, 0x08_000_010);
+        system.mem.write_u32(0x02_000_000, 0xDEADBEEF);
+        system.mem.write_u16(0x06_000_000, 0x7FFF);
+        system.mem.write_u16(ppu::REG_DISPCNT, 3);
+        system.mem.write_u8(0x0E_000_010, 0x42);
+        system.ppu.step(5000, &mut system.mem);
+
+        system.reset();
+        // Reset starts the BIOS in ARM state and SVC mode with interrupts disabled
+        assert_eq!(system.cpu.get_r(cpu::REGISTER_PC), 0);
+        assert_eq!(system.cpu.get_mode(), cpu::MODE_SVC);
+        assert!(!system.cpu.get_thumb_state());
+        assert!(system.cpu.get_irq_disable());
+        assert!(system.cpu.get_fiq_disable());
+        assert_eq!(system.cpu.get_r(3), 0);
+        assert_eq!(system.cpu.get_r_in_mode(cpu::REGISTER_SP, cpu::MODE_IRQ), 0);
+
+        assert_eq!(system.mem.read_u32(0x02_000_000), 0);
+        assert_eq!(system.mem.read_u16(0x06_000_000), 0);
+        assert_eq!(system.mem.read_u16(ppu::REG_DISPCNT), 0);
+        assert_eq!(system.mem.read_u16(ppu::REG_VCOUNT), 0);
+        // The cartridge and save memory are kept
+        assert_eq!(system.mem.read_u8(0x08_000_000), 0x11);
+        assert_eq!(system.mem.read_u8(0x0E_000_010), 0x42);
+
+        // Booted without a BIOS, reset starts the cartridge again
+        system.skip_bios();
+        system.cpu.set_r(cpu::REGISTER_PC, 0x08_000_010);
+        system.reset();
+        assert_eq!(system.cpu.get_r(cpu::REGISTER_PC), cpu::CARTRIDGE_ENTRY);
+        assert_eq!(system.mem.io_u16(0x04_000_020), 0x100);
+    }
+
+    #[test]
+    fn test_save_state() {
+        let path = std::env::temp_dir().join(format!("gbae_test_{}.state", std::process::id()));
+        let mut system = GbaSystem::new(vec![0; 0x4000], vec![0; 0x100]).0;
+        system.cpu.set_r(3, 0x1234);
+        system.cpu.set_r_in_mode(13, cpu::MODE_IRQ, 0x03007FA0);
+        system.mem.write_u32(0x02_000_000, 0xDEADBEEF);
+        system.mem.write_u16(0x05_000_002, 0x7FFF);
+        system.mem.write_u8(0x0E_000_010, 0x42);
+        system.ppu.step(2000, &mut system.mem);
+        system.save_state(&path).unwrap();
+
+        system.cpu.set_r(3, 0);
+        system.cpu.set_r_in_mode(13, cpu::MODE_IRQ, 0);
+        system.cpu.cpsr = cpu::MODE_SYS as u32;
+        system.mem.write_u32(0x02_000_000, 0);
+        system.mem.write_u16(0x05_000_002, 0);
+        system.mem.write_u8(0x0E_000_010, 0);
+        system.ppu.step(5000, &mut system.mem);
+        system.load_state(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(system.cpu.get_r(3), 0x1234);
+        assert_eq!(system.cpu.get_r_in_mode(13, cpu::MODE_IRQ), 0x03007FA0);
+        assert_eq!(system.cpu.get_mode(), cpu::MODE_SVC);
+        assert_eq!(system.mem.read_u32(0x02_000_000), 0xDEADBEEF);
+        assert_eq!(system.mem.read_u16(0x05_000_002), 0x7FFF);
+        assert_eq!(system.mem.read_u8(0x0E_000_010), 0x42);
+        // VCOUNT is restored with the IO registers and has to match the ppu
+        assert_eq!(system.mem.read_u16(ppu::REG_VCOUNT), 1);
+        system.ppu.step(1232 - 2000 % 1232, &mut system.mem);
+        assert_eq!(system.mem.read_u16(ppu::REG_VCOUNT), 2);
+    }
+
+    #[test]
+    fn test_load_truncated_state() {
+        let path = std::env::temp_dir().join(format!("gbae_test_truncated_{}.state", std::process::id()));
+        let mut system = GbaSystem::new(vec![0; 0x4000], vec![0; 0x100]).0;
+        system.mem.write_u32(0x02_000_000, 0xDEADBEEF);
+        let mut data = system.state_bytes();
+
+        system.cpu.set_r(3, 0x1234);
+        system.mem.write_u32(0x02_000_000, 0x12345678);
+        let before = system.state_bytes();
+        // Cut off inside the ppu, the cpu and memory would already be loaded
+        data.truncate(data.len() - 4);
+        fs::write(&path, &data).unwrap();
+        assert!(system.load_state(&path).is_err());
+        // Trailing data is rejected too
+        data.extend([0; 12]);
+        fs::write(&path, &data).unwrap();
+        assert!(system.load_state(&path).is_err());
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(system.state_bytes(), before);
+        assert_eq!(system.cpu.get_r(3), 0x1234);
+        assert_eq!(system.mem.read_u32(0x02_000_000), 0x12345678);
+    }
+}
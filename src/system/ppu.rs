@@ -1,15 +1,174 @@
-use std::sync::{Arc, RwLock};
+use std::{
+    io,
+    sync::{Arc, RwLock},
+};
 
-use super::memory::Memory;
+use super::{
+    color::bgr555_to_rgb888,
+    dma::DmaTiming,
+    interrupts::Interrupt,
+    memory::Memory,
+    state::{StateReader, StateWriter},
+};
 
 pub const FRAMEBUFFER_WIDTH: usize = 240;
 pub const FRAMEBUFFER_HEIGHT: usize = 160;
 
+pub const REG_DISPCNT: u32 = 0x04_000_000;
+pub const REG_DISPSTAT: u32 = 0x04_000_004;
+pub const REG_VCOUNT: u32 = 0x04_000_006;
+pub const REG_BG0CNT: u32 = 0x04_000_008;
+pub const REG_BG0HOFS: u32 = 0x04_000_010;
+pub const REG_BG0VOFS: u32 = 0x04_000_012;
+// Affine parameters of BG2, BG3 follows AFFINE_BG_STRIDE bytes later
+pub const REG_BG2PA: u32 = 0x04_000_020;
+pub const REG_BG2PB: u32 = 0x04_000_022;
+pub const REG_BG2PC: u32 = 0x04_000_024;
+pub const REG_BG2PD: u32 = 0x04_000_026;
+pub const REG_BG2X: u32 = 0x04_000_028;
+pub const REG_BG2Y: u32 = 0x04_000_02C;
+pub const REG_BG3Y: u32 = 0x04_000_03C;
+pub const AFFINE_BG_STRIDE: u32 = 0x10;
+pub const REG_WIN0H: u32 = 0x04_000_040;
+pub const REG_WIN1H: u32 = 0x04_000_042;
+pub const REG_WIN0V: u32 = 0x04_000_044;
+pub const REG_WIN1V: u32 = 0x04_000_046;
+pub const REG_WININ: u32 = 0x04_000_048;
+pub const REG_WINOUT: u32 = 0x04_000_04A;
+
+// DISPCNT bits
+const DISPCNT_MODE: u16 = 0b111;
+const DISPCNT_FRAME_SELECT: u16 = 1 << 4;
+const DISPCNT_OBJ_1D_MAPPING: u16 = 1 << 6;
+const DISPCNT_FORCED_BLANK: u16 = 1 << 7;
+const DISPCNT_BG0: u16 = 1 << 8;
+const DISPCNT_BG2: u16 = 1 << 10;
+const DISPCNT_OBJ: u16 = 1 << 12;
+const DISPCNT_WIN0: u16 = 1 << 13;
+const DISPCNT_WIN1: u16 = 1 << 14;
+const DISPCNT_OBJ_WINDOW: u16 = 1 << 15;
+
+// BGxCNT bits
+const BGCNT_PRIORITY: u16 = 0b11;
+const BGCNT_256_COLORS: u16 = 1 << 7;
+const BGCNT_AFFINE_WRAP: u16 = 1 << 13;
+
+// OBJ attribute bits
+const OBJ_ATTR0_AFFINE: u16 = 1 << 8;
+const OBJ_ATTR0_DISABLE: u16 = 1 << 9;
+const OBJ_ATTR0_MODE_WINDOW: u16 = 2 << 10;
+const OBJ_ATTR0_256_COLORS: u16 = 1 << 13;
+const OBJ_ATTR1_H_FLIP: u16 = 1 << 12;
+const OBJ_ATTR1_V_FLIP: u16 = 1 << 13;
+
+// Tile map entry bits
+const MAP_ENTRY_H_FLIP: u16 = 1 << 10;
+const MAP_ENTRY_V_FLIP: u16 = 1 << 11;
+
+// WININ/WINOUT bits of each window, enabling BG0-BG3, OBJs and color effects
+const WINDOW_OBJ: u16 = 1 << 4;
+const WINDOW_ALL: u16 = 0x3F;
+
+// DISPSTAT bits
+const DISPSTAT_VBLANK: u16 = 1 << 0;
+const DISPSTAT_HBLANK: u16 = 1 << 1;
+const DISPSTAT_VCOUNT: u16 = 1 << 2;
+const DISPSTAT_VBLANK_IRQ: u16 = 1 << 3;
+const DISPSTAT_HBLANK_IRQ: u16 = 1 << 4;
+const DISPSTAT_VCOUNT_IRQ: u16 = 1 << 5;
+
+// Offset of the second bitmap frame in VRAM
+const BITMAP_PAGE_OFFSET: usize = 0xA000;
+// Backgrounds can't read tiles from the OBJ part of VRAM
+const BG_VRAM_LEN: usize = 0x10000;
+const OBJ_PALETTE_OFFSET: usize = 0x200;
+
+// Scanline timing in cpu cycles: 240 visible dots and 68 dots of HBlank, 4 cycles each
+const HDRAW_CYCLES: u32 = 960;
+const SCANLINE_CYCLES: u32 = 1232;
+// 160 visible lines followed by 68 lines of VBlank
+const VDRAW_LINES: u16 = 160;
+const TOTAL_LINES: u16 = 228;
+
 pub type Framebuffer = [[[u8; 3]; FRAMEBUFFER_WIDTH]; FRAMEBUFFER_HEIGHT];
 
+/// One line of a layer in BGR555, None where it is transparent
+type Layer = [Option<u16>; FRAMEBUFFER_WIDTH];
+
+/// Width and height of an OBJ, indexed by shape and size
+const OBJ_DIMENSIONS: [[(usize, usize); 4]; 3] = [[(8, 8), (16, 16), (32, 32), (64, 64)], [(16, 8), (32, 8), (32, 16), (64, 32)], [(8, 16), (8, 32), (16, 32), (32, 64)]];
+
+/// FNV-1a hash over all bytes of the framebuffer, row by row, for comparing against known good frames
+pub fn hash_framebuffer(framebuffer: &Framebuffer) -> u64 {
+    framebuffer
+        .as_flattened()
+        .as_flattened()
+        .iter()
+        .fold(0xCBF2_9CE4_8422_2325, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x0100_0000_01B3))
+}
+
+/// Writes `framebuffer` to `out` as RGBA8888 with opaque alpha, `out` has to hold 4 bytes for each pixel
+pub fn copy_rgba(framebuffer: &Framebuffer, out: &mut [u8]) {
+    assert_eq!(out.len(), FRAMEBUFFER_WIDTH * FRAMEBUFFER_HEIGHT * 4);
+    for (pixel, color) in out.chunks_exact_mut(4).zip(framebuffer.as_flattened()) {
+        pixel[..3].copy_from_slice(color);
+        pixel[3] = 255;
+    }
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+/// Sign extends the 28 bit reference point registers, which are 20.8 fixed point
+fn reference_point(value: u32) -> i32 {
+    ((value << 4) as i32) >> 4
+}
+
+/// Color index of a pixel in an 8x8 tile, 0 being transparent
+fn tile_pixel(vram: &[u8], tile_offset: usize, color_256: bool, x: usize, y: usize) -> u8 {
+    if color_256 {
+        vram[tile_offset + y * 8 + x]
+    } else {
+        // Two pixels per byte, the left one in the lower nibble
+        let byte = vram[tile_offset + y * 4 + x / 2];
+        if x.is_multiple_of(2) {
+            byte & 0xF
+        } else {
+            byte >> 4
+        }
+    }
+}
+
+/// Picks the color on top of the (priority, color) pairs of the opaque BG and OBJ pixels, or the backdrop if there are none.
+/// Lower priority values are drawn on top, ties go to the OBJ and then the lower background.
+fn resolve_priority(backgrounds: [Option<(u16, u16)>; 4], object: Option<(u16, u16)>, backdrop: u16) -> u16 {
+    // min_by_key returns the first of equal elements
+    object
+        .into_iter()
+        .chain(backgrounds.into_iter().flatten())
+        .min_by_key(|&(priority, _)| priority)
+        .map_or(backdrop, |(_, color)| color)
+}
+
+/// Whether `position` lies within the start (upper byte) and end (lower byte, exclusive) of a window register
+fn in_window_range(range: u16, position: usize, limit: usize) -> bool {
+    let start = (range >> 8) as usize;
+    let end = (range & 0xFF) as usize;
+    // Garbage values of end > limit or start > end are interpreted as end = limit
+    let end = if end > limit || start > end { limit } else { end };
+    (start..end).contains(&position)
+}
+
 pub struct PPU {
     framebuffer: Arc<RwLock<Framebuffer>>,
+    // Lines are rendered in here and published to the framebuffer at VBlank
+    back_buffer: Box<Framebuffer>,
     frame_counter: u64,
+    line_cycles: u32,
+    vcount: u16,
+    // Internal reference points of BG2 and BG3, advanced by PB/PD every line
+    affine_references: [(i32, i32); 2],
 }
 
 impl PPU {
@@ -39,17 +198,402 @@ impl PPU {
         (
             PPU {
                 framebuffer: framebuffer.clone(),
+                back_buffer: Box::new([[[0; 3]; FRAMEBUFFER_WIDTH]; FRAMEBUFFER_HEIGHT]),
                 frame_counter: 0,
+                line_cycles: 0,
+                vcount: 0,
+                affine_references: [(0, 0); 2],
             },
             framebuffer,
         )
     }
 
-    pub fn get_frame_counter(&self) -> u64 {
-        self.frame_counter
+    /// Restarts at the first line of a frame, the last published frame stays on screen
+    pub fn reset(&mut self) {
+        *self.back_buffer = [[[0; 3]; FRAMEBUFFER_WIDTH]; FRAMEBUFFER_HEIGHT];
+        self.frame_counter = 0;
+        self.line_cycles = 0;
+        self.vcount = 0;
+        self.affine_references = [(0, 0); 2];
+    }
+
+    /// The lines drawn so far this frame aren't saved, so the current frame may be incomplete after loading
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_u64(self.frame_counter);
+        state.write_u32(self.line_cycles);
+        state.write_u16(self.vcount);
+        for &(x, y) in &self.affine_references {
+            state.write_u32(x as u32);
+            state.write_u32(y as u32);
+        }
     }
 
-    pub fn draw_frame(&mut self, _mem: &mut Memory) {
+    pub fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        self.frame_counter = state.read_u64()?;
+        self.line_cycles = state.read_u32()?;
+        self.vcount = state.read_u16()?;
+        for reference in &mut self.affine_references {
+            *reference = (state.read_u32()? as i32, state.read_u32()? as i32);
+        }
+        Ok(())
+    }
+
+    /// Calls `f` with the last published frame, without copying it
+    pub fn with_framebuffer<R>(&self, f: impl FnOnce(&Framebuffer) -> R) -> R {
+        f(&self.framebuffer.read().unwrap())
+    }
+
+    /// Hash of the last published frame
+    pub fn framebuffer_hash(&self) -> u64 {
+        hash_framebuffer(&self.framebuffer.read().unwrap())
+    }
+
+    pub fn in_vblank(&self) -> bool {
+        // The VBlank flag is cleared again on the last line
+        (VDRAW_LINES..TOTAL_LINES - 1).contains(&self.vcount)
+    }
+
+    /// Advances the PPU by the given number of cpu cycles
+    pub fn step(&mut self, cycles: u32, mem: &mut Memory) {
+        let mut remaining = cycles;
+        while remaining > 0 {
+            let next_event = if self.line_cycles < HDRAW_CYCLES { HDRAW_CYCLES } else { SCANLINE_CYCLES };
+            let elapsed = remaining.min(next_event - self.line_cycles);
+            self.line_cycles += elapsed;
+            remaining -= elapsed;
+
+            if self.line_cycles == HDRAW_CYCLES {
+                self.enter_hblank(mem);
+            } else if self.line_cycles == SCANLINE_CYCLES {
+                self.line_cycles = 0;
+                self.next_line(mem);
+            }
+        }
+    }
+
+    fn enter_hblank(&mut self, mem: &mut Memory) {
+        if self.vcount < VDRAW_LINES {
+            self.update_affine_references(mem);
+            self.render_line(mem);
+            self.advance_affine_references(mem);
+            // HBlank DMA only runs on visible lines
+            mem.trigger_dma(DmaTiming::HBlank);
+        }
+
+        let dispstat = mem.io_u16(REG_DISPSTAT) | DISPSTAT_HBLANK;
+        mem.set_io_u16(REG_DISPSTAT, dispstat);
+        if dispstat & DISPSTAT_HBLANK_IRQ != 0 {
+            mem.request_interrupt(Interrupt::HBlank);
+        }
+    }
+
+    fn next_line(&mut self, mem: &mut Memory) {
+        self.vcount = (self.vcount + 1) % TOTAL_LINES;
+        mem.set_io_u16(REG_VCOUNT, self.vcount);
+
+        let mut dispstat = mem.io_u16(REG_DISPSTAT) & !(DISPSTAT_HBLANK | DISPSTAT_VBLANK | DISPSTAT_VCOUNT);
+        if self.in_vblank() {
+            dispstat |= DISPSTAT_VBLANK;
+        }
+        // The upper byte of DISPSTAT holds the line to compare VCOUNT against
+        if self.vcount == dispstat >> 8 {
+            dispstat |= DISPSTAT_VCOUNT;
+            if dispstat & DISPSTAT_VCOUNT_IRQ != 0 {
+                mem.request_interrupt(Interrupt::VCount);
+            }
+        }
+        mem.set_io_u16(REG_DISPSTAT, dispstat);
+
+        if self.vcount == VDRAW_LINES {
+            if dispstat & DISPSTAT_VBLANK_IRQ != 0 {
+                mem.request_interrupt(Interrupt::VBlank);
+            }
+            mem.trigger_dma(DmaTiming::VBlank);
+            if mem.io_u16(REG_DISPCNT) == 0 {
+                self.draw_test_pattern();
+            } else if let Ok(mut fb) = self.framebuffer.write() {
+                *fb = *self.back_buffer;
+            }
+        }
+    }
+
+    fn render_line(&mut self, mem: &Memory) {
+        let dispcnt = mem.io_u16(REG_DISPCNT);
+        // Forced blank lets the cpu access VRAM freely while the screen shows white
+        if dispcnt & DISPCNT_FORCED_BLANK != 0 {
+            self.back_buffer[self.vcount as usize] = [[255; 3]; FRAMEBUFFER_WIDTH];
+            return;
+        }
+        let mut layers = [[None; FRAMEBUFFER_WIDTH]; 4];
+
+        let (text_bgs, affine_bgs) = match dispcnt & DISPCNT_MODE {
+            0 => (0..4, 0..0),
+            1 => (0..2, 2..3),
+            2 => (0..0, 2..4),
+            _ => (0..0, 0..0),
+        };
+        for bg in text_bgs {
+            if dispcnt & DISPCNT_BG0 << bg != 0 {
+                self.render_text_bg(bg, mem, &mut layers[bg]);
+            }
+        }
+        for bg in affine_bgs {
+            if dispcnt & DISPCNT_BG0 << bg != 0 {
+                self.render_affine_bg(bg, mem, &mut layers[bg]);
+            }
+        }
+
+        if dispcnt & DISPCNT_BG2 != 0 {
+            match dispcnt & DISPCNT_MODE {
+                3 => self.render_mode3(mem, &mut layers[2]),
+                4 => self.render_mode4(dispcnt, mem, &mut layers[2]),
+                _ => {}
+            }
+        }
+
+        let (objects, obj_window) = if dispcnt & DISPCNT_OBJ != 0 {
+            self.render_objects(dispcnt, mem)
+        } else {
+            ([None; FRAMEBUFFER_WIDTH], [false; FRAMEBUFFER_WIDTH])
+        };
+        let windows = self.window_masks(dispcnt, mem, &obj_window);
+
+        let priorities = [0, 1, 2, 3].map(|bg| mem.io_u16(REG_BG0CNT + bg as u32 * 2) & BGCNT_PRIORITY);
+        let backdrop = read_u16(mem.palette_ram(), 0);
+        for (x, pixel) in self.back_buffer[self.vcount as usize].iter_mut().enumerate() {
+            let backgrounds = [0, 1, 2, 3].map(|bg| layers[bg][x].filter(|_| windows[x] & 1 << bg != 0).map(|color| (priorities[bg], color)));
+            let object = objects[x].filter(|_| windows[x] & WINDOW_OBJ != 0);
+            *pixel = bgr555_to_rgb888(resolve_priority(backgrounds, object, backdrop));
+        }
+    }
+
+    /// The WININ/WINOUT enable bits of the window each pixel on the current line is in.
+    /// WIN0 takes precedence over WIN1, which takes precedence over the OBJ window.
+    fn window_masks(&self, dispcnt: u16, mem: &Memory, obj_window: &[bool; FRAMEBUFFER_WIDTH]) -> [u16; FRAMEBUFFER_WIDTH] {
+        // Without any windows everything is enabled
+        if dispcnt & (DISPCNT_WIN0 | DISPCNT_WIN1 | DISPCNT_OBJ_WINDOW) == 0 {
+            return [WINDOW_ALL; FRAMEBUFFER_WIDTH];
+        }
+
+        let winin = mem.io_u16(REG_WININ);
+        let winout = mem.io_u16(REG_WINOUT);
+        let y = self.vcount as usize;
+        let in_window = |enable: u16, h: u32, v: u32, x: usize| dispcnt & enable != 0 && in_window_range(mem.io_u16(h), x, FRAMEBUFFER_WIDTH) && in_window_range(mem.io_u16(v), y, FRAMEBUFFER_HEIGHT);
+
+        let mut masks = [0; FRAMEBUFFER_WIDTH];
+        for (x, mask) in masks.iter_mut().enumerate() {
+            *mask = if in_window(DISPCNT_WIN0, REG_WIN0H, REG_WIN0V, x) {
+                winin
+            } else if in_window(DISPCNT_WIN1, REG_WIN1H, REG_WIN1V, x) {
+                winin >> 8
+            } else if dispcnt & DISPCNT_OBJ_WINDOW != 0 && obj_window[x] {
+                winout >> 8
+            } else {
+                winout
+            } & WINDOW_ALL;
+        }
+        masks
+    }
+
+    /// Renders the regular OBJs on the current line as (priority, color) pairs,
+    /// along with the pixels covered by OBJ window OBJs
+    fn render_objects(&self, dispcnt: u16, mem: &Memory) -> ([Option<(u16, u16)>; FRAMEBUFFER_WIDTH], [bool; FRAMEBUFFER_WIDTH]) {
+        let mut line = [None; FRAMEBUFFER_WIDTH];
+        let mut window = [false; FRAMEBUFFER_WIDTH];
+        let vram = mem.vram();
+        let palette_ram = mem.palette_ram();
+
+        // Lower OAM entries are drawn on top of higher ones
+        for entry in mem.oam().chunks_exact(8) {
+            let attr0 = read_u16(entry, 0);
+            let attr1 = read_u16(entry, 2);
+            let attr2 = read_u16(entry, 4);
+
+            // Affine OBJs aren't supported yet
+            if attr0 & OBJ_ATTR0_AFFINE != 0 || attr0 & OBJ_ATTR0_DISABLE != 0 {
+                continue;
+            }
+            let shape = (attr0 >> 14) as usize;
+            if shape == 3 {
+                continue;
+            }
+            let (width, height) = OBJ_DIMENSIONS[shape][(attr1 >> 14) as usize];
+
+            // Y wraps around at 256 and X is a signed 9 bit value
+            let obj_y = (self.vcount as usize).wrapping_sub((attr0 & 0xFF) as usize) & 0xFF;
+            if obj_y >= height {
+                continue;
+            }
+            let x = (attr1 & 0x1FF) as i32;
+            let x = if x >= 256 { x - 512 } else { x };
+
+            let color_256 = attr0 & OBJ_ATTR0_256_COLORS != 0;
+            let tile = (attr2 & 0x3FF) as usize;
+            let priority = (attr2 >> 10) & 0b11;
+            let palette_bank = (attr2 >> 12) as usize;
+            let is_window = attr0 & (0b11 << 10) == OBJ_ATTR0_MODE_WINDOW;
+            let y = if attr1 & OBJ_ATTR1_V_FLIP != 0 { height - 1 - obj_y } else { obj_y };
+
+            for obj_x in 0..width {
+                let screen_x = x + obj_x as i32;
+                if !(0..FRAMEBUFFER_WIDTH as i32).contains(&screen_x) {
+                    continue;
+                }
+                let screen_x = screen_x as usize;
+                if !is_window && matches!(line[screen_x], Some((p, _)) if p <= priority) {
+                    continue;
+                }
+
+                let x = if attr1 & OBJ_ATTR1_H_FLIP != 0 { width - 1 - obj_x } else { obj_x };
+                // Tiles are counted in 32 byte units, so 256 color tiles take up two
+                let tile_size = if color_256 { 2 } else { 1 };
+                let row_stride = if dispcnt & DISPCNT_OBJ_1D_MAPPING != 0 { width / 8 * tile_size } else { 32 };
+                let tile = (tile + y / 8 * row_stride + x / 8 * tile_size) & 0x3FF;
+
+                let color = tile_pixel(vram, BG_VRAM_LEN + tile * 32, color_256, x % 8, y % 8) as usize;
+                if color == 0 {
+                    continue;
+                }
+                // OBJ window OBJs aren't drawn, their opaque pixels only make up the window
+                if is_window {
+                    window[screen_x] = true;
+                    continue;
+                }
+                let index = if color_256 { color } else { palette_bank * 16 + color };
+                line[screen_x] = Some((priority, read_u16(palette_ram, OBJ_PALETTE_OFFSET + index * 2)));
+            }
+        }
+
+        (line, window)
+    }
+
+    fn render_text_bg(&self, bg: usize, mem: &Memory, layer: &mut Layer) {
+        let bgcnt = mem.io_u16(REG_BG0CNT + bg as u32 * 2);
+        let hofs = (mem.io_u16(REG_BG0HOFS + bg as u32 * 4) & 0x1FF) as usize;
+        let vofs = (mem.io_u16(REG_BG0VOFS + bg as u32 * 4) & 0x1FF) as usize;
+
+        let char_base = ((bgcnt >> 2) & 0b11) as usize * 0x4000;
+        let screen_base = ((bgcnt >> 8) & 0x1F) as usize * 0x800;
+        let color_256 = bgcnt & BGCNT_256_COLORS != 0;
+        let (width, height) = match bgcnt >> 14 {
+            0 => (256, 256),
+            1 => (512, 256),
+            2 => (256, 512),
+            _ => (512, 512),
+        };
+
+        let vram = mem.vram();
+        let palette_ram = mem.palette_ram();
+        let y = (self.vcount as usize + vofs) % height;
+        for (screen_x, pixel) in layer.iter_mut().enumerate() {
+            let x = (screen_x + hofs) % width;
+
+            // The map consists of 32x32 tile screen blocks, laid out left to right, then top to bottom
+            let block = x / 256 + y / 256 * (width / 256);
+            let entry = read_u16(vram, screen_base + block * 0x800 + (y % 256 / 8 * 32 + x % 256 / 8) * 2);
+
+            let tile = (entry & 0x3FF) as usize;
+            let tile_offset = char_base + tile * if color_256 { 64 } else { 32 };
+            if tile_offset >= BG_VRAM_LEN {
+                continue;
+            }
+
+            let tile_x = if entry & MAP_ENTRY_H_FLIP != 0 { 7 - x % 8 } else { x % 8 };
+            let tile_y = if entry & MAP_ENTRY_V_FLIP != 0 { 7 - y % 8 } else { y % 8 };
+            let color = tile_pixel(vram, tile_offset, color_256, tile_x, tile_y) as usize;
+            if color == 0 {
+                continue;
+            }
+
+            // 16 color tiles select one of 16 palette banks in the map entry
+            let index = if color_256 { color } else { (entry >> 12) as usize * 16 + color };
+            *pixel = Some(read_u16(palette_ram, index * 2));
+        }
+    }
+
+    /// Reloads the internal reference points at the start of the frame and whenever BGxX/BGxY were written
+    fn update_affine_references(&mut self, mem: &mut Memory) {
+        for i in 0..2 {
+            // Also taken on the first line, so a write during VBlank doesn't reload twice
+            let written = mem.take_affine_reference_written(i);
+            if self.vcount == 0 || written {
+                let offset = i as u32 * AFFINE_BG_STRIDE;
+                self.affine_references[i] = (reference_point(mem.io_u32(REG_BG2X + offset)), reference_point(mem.io_u32(REG_BG2Y + offset)));
+            }
+        }
+    }
+
+    fn advance_affine_references(&mut self, mem: &Memory) {
+        for i in 0..2 {
+            let offset = i as u32 * AFFINE_BG_STRIDE;
+            let (x, y) = &mut self.affine_references[i];
+            *x = x.wrapping_add(mem.io_u16(REG_BG2PB + offset) as i16 as i32);
+            *y = y.wrapping_add(mem.io_u16(REG_BG2PD + offset) as i16 as i32);
+        }
+    }
+
+    /// Rotated and scaled 256 color background with a map of one byte tile indices
+    fn render_affine_bg(&self, bg: usize, mem: &Memory, layer: &mut Layer) {
+        let bgcnt = mem.io_u16(REG_BG0CNT + bg as u32 * 2);
+        let offset = (bg as u32 - 2) * AFFINE_BG_STRIDE;
+        let pa = mem.io_u16(REG_BG2PA + offset) as i16 as i32;
+        let pc = mem.io_u16(REG_BG2PC + offset) as i16 as i32;
+        let (reference_x, reference_y) = self.affine_references[bg - 2];
+
+        let char_base = ((bgcnt >> 2) & 0b11) as usize * 0x4000;
+        let screen_base = ((bgcnt >> 8) & 0x1F) as usize * 0x800;
+        let size = 128 << (bgcnt >> 14);
+        let wrap = bgcnt & BGCNT_AFFINE_WRAP != 0;
+
+        let vram = mem.vram();
+        let palette_ram = mem.palette_ram();
+        for (screen_x, pixel) in layer.iter_mut().enumerate() {
+            let mut x = reference_x.wrapping_add(pa * screen_x as i32) >> 8;
+            let mut y = reference_y.wrapping_add(pc * screen_x as i32) >> 8;
+            if wrap {
+                x = x.rem_euclid(size);
+                y = y.rem_euclid(size);
+            } else if !(0..size).contains(&x) || !(0..size).contains(&y) {
+                continue;
+            }
+            let (x, y) = (x as usize, y as usize);
+
+            let tile = vram[screen_base + y / 8 * (size as usize / 8) + x / 8] as usize;
+            let tile_offset = char_base + tile * 64;
+            if tile_offset >= BG_VRAM_LEN {
+                continue;
+            }
+            let color = tile_pixel(vram, tile_offset, true, x % 8, y % 8) as usize;
+            if color != 0 {
+                *pixel = Some(read_u16(palette_ram, color * 2));
+            }
+        }
+    }
+
+    /// 16bit direct color bitmap
+    fn render_mode3(&self, mem: &Memory, layer: &mut Layer) {
+        let vram = mem.vram();
+        let line_offset = self.vcount as usize * FRAMEBUFFER_WIDTH * 2;
+        for (x, pixel) in layer.iter_mut().enumerate() {
+            *pixel = Some(read_u16(vram, line_offset + x * 2));
+        }
+    }
+
+    /// 8bit paletted bitmap with two frames
+    fn render_mode4(&self, dispcnt: u16, mem: &Memory, layer: &mut Layer) {
+        let vram = mem.vram();
+        let palette_ram = mem.palette_ram();
+        let page_offset = if dispcnt & DISPCNT_FRAME_SELECT != 0 { BITMAP_PAGE_OFFSET } else { 0 };
+        let line_offset = page_offset + self.vcount as usize * FRAMEBUFFER_WIDTH;
+        for (x, pixel) in layer.iter_mut().enumerate() {
+            let index = vram[line_offset + x] as usize;
+            if index != 0 {
+                *pixel = Some(read_u16(palette_ram, index * 2));
+            }
+        }
+    }
+
+    fn draw_test_pattern(&mut self) {
         self.frame_counter += 1;
         // Get write access to framebuffer
         if let Ok(mut fb) = self.framebuffer.write() {
@@ -65,14 +609,540 @@ impl PPU {
 
                     // Keep corner markers
                     if x < 10 && y < 10 {
-                        fb[y][x] = [255, 0, 0];
+                        fb[y][x] = bgr555_to_rgb888(0x001F);
                     } else if x >= FRAMEBUFFER_WIDTH - 10 && y < 10 {
-                        fb[y][x] = [0, 255, 0];
+                        fb[y][x] = bgr555_to_rgb888(0x03E0);
                     } else if x < 10 && y >= FRAMEBUFFER_HEIGHT - 10 {
-                        fb[y][x] = [0, 0, 255];
+                        fb[y][x] = bgr555_to_rgb888(0x7C00);
                     }
                 }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::system::interrupts::{REG_IE, REG_IF, REG_IME};
+
+    #[test]
+    fn test_scanline_timing() {
+        let (mut ppu, _) = PPU::new();
+        let mut mem = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+        mem.write_u16(REG_DISPSTAT, DISPSTAT_HBLANK_IRQ | DISPSTAT_VBLANK_IRQ);
+
+        ppu.step(HDRAW_CYCLES - 1, &mut mem);
+        assert_eq!(mem.read_u16(REG_DISPSTAT) & DISPSTAT_HBLANK, 0);
+        ppu.step(1, &mut mem);
+        assert_ne!(mem.read_u16(REG_DISPSTAT) & DISPSTAT_HBLANK, 0);
+        assert_eq!(mem.read_u16(REG_IF), Interrupt::HBlank.mask());
+
+        ppu.step(SCANLINE_CYCLES - HDRAW_CYCLES, &mut mem);
+        assert_eq!(mem.read_u16(REG_VCOUNT), 1);
+        assert_eq!(mem.read_u16(REG_DISPSTAT) & DISPSTAT_HBLANK, 0);
+
+        // Run into VBlank
+        mem.write_u16(REG_IF, 0xFFFF);
+        ppu.step(SCANLINE_CYCLES * (VDRAW_LINES as u32 - 1), &mut mem);
+        assert_eq!(mem.read_u16(REG_VCOUNT), VDRAW_LINES);
+        assert!(ppu.in_vblank());
+        assert_ne!(mem.read_u16(REG_DISPSTAT) & DISPSTAT_VBLANK, 0);
+        assert_ne!(mem.read_u16(REG_IF) & Interrupt::VBlank.mask(), 0);
+
+        // The flag is cleared on the last line and VCOUNT wraps around
+        ppu.step(SCANLINE_CYCLES * 66, &mut mem);
+        assert!(ppu.in_vblank());
+        ppu.step(SCANLINE_CYCLES, &mut mem);
+        assert_eq!(mem.read_u16(REG_VCOUNT), TOTAL_LINES - 1);
+        assert!(!ppu.in_vblank());
+        assert_eq!(mem.read_u16(REG_DISPSTAT) & DISPSTAT_VBLANK, 0);
+        ppu.step(SCANLINE_CYCLES, &mut mem);
+        assert_eq!(mem.read_u16(REG_VCOUNT), 0);
+    }
+
+    #[test]
+    fn test_vcount_match() {
+        let (mut ppu, _) = PPU::new();
+        let mut mem = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+        mem.write_u16(REG_IE, Interrupt::VCount.mask());
+        mem.write_u16(REG_IME, 1);
+        mem.write_u16(REG_DISPSTAT, 5 << 8 | DISPSTAT_VCOUNT_IRQ | DISPSTAT_VCOUNT);
+
+        // The status bits can't be written by the cpu
+        assert_eq!(mem.read_u16(REG_DISPSTAT) & DISPSTAT_VCOUNT, 0);
+        mem.write_u16(REG_VCOUNT, 100);
+        assert_eq!(mem.read_u16(REG_VCOUNT), 0);
+
+        ppu.step(SCANLINE_CYCLES * 4, &mut mem);
+        assert!(!mem.interrupt_pending());
+        ppu.step(SCANLINE_CYCLES, &mut mem);
+        assert!(mem.interrupt_pending());
+        assert_ne!(mem.read_u16(REG_DISPSTAT) & DISPSTAT_VCOUNT, 0);
+        ppu.step(SCANLINE_CYCLES, &mut mem);
+        assert_eq!(mem.read_u16(REG_DISPSTAT) & DISPSTAT_VCOUNT, 0);
+    }
+
+    #[test]
+    fn test_vcount_match_lines() {
+        let (mut ppu, _) = PPU::new();
+        let mut mem = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+        // Lines with the match flag set and with the interrupt requested, over two frames
+        let run = |ppu: &mut PPU, mem: &mut Memory| {
+            let (mut flagged, mut requested) = (Vec::new(), Vec::new());
+            for _ in 0..2 * TOTAL_LINES {
+                ppu.step(SCANLINE_CYCLES, mem);
+                let vcount = mem.read_u16(REG_VCOUNT);
+                if mem.read_u16(REG_DISPSTAT) & DISPSTAT_VCOUNT != 0 {
+                    flagged.push(vcount);
+                }
+                if mem.read_u16(REG_IF) & Interrupt::VCount.mask() != 0 {
+                    requested.push(vcount);
+                    mem.write_u16(REG_IF, Interrupt::VCount.mask());
+                }
+            }
+            (flagged, requested)
+        };
+
+        // A line in VBlank matches as well as a visible one
+        for line in [0, 80, 200, TOTAL_LINES - 1] {
+            mem.write_u16(REG_DISPSTAT, line << 8 | DISPSTAT_VCOUNT_IRQ);
+            assert_eq!(run(&mut ppu, &mut mem), (vec![line, line], vec![line, line]));
+        }
+
+        // Without the enable bit only the flag is set
+        mem.write_u16(REG_DISPSTAT, 80 << 8);
+        assert_eq!(run(&mut ppu, &mut mem), (vec![80, 80], vec![]));
+    }
+
+    fn run_frame(ppu: &mut PPU, mem: &mut Memory) {
+        ppu.step(SCANLINE_CYCLES * TOTAL_LINES as u32, mem);
+    }
+
+    #[test]
+    fn test_framebuffer_hash() {
+        let (mut ppu, _) = PPU::new();
+        let mut mem = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+        mem.write_u16(REG_DISPCNT, 3 | DISPCNT_BG2);
+        run_frame(&mut ppu, &mut mem);
+        let hash = ppu.framebuffer_hash();
+        run_frame(&mut ppu, &mut mem);
+        assert_eq!(ppu.framebuffer_hash(), hash);
+
+        mem.write_u16(0x06_000_000 + (159 * 240 + 239) * 2, 0x0001);
+        run_frame(&mut ppu, &mut mem);
+        assert_ne!(ppu.framebuffer_hash(), hash);
+
+        let mut framebuffer = *ppu.framebuffer.read().unwrap();
+        assert_eq!(hash_framebuffer(&framebuffer), ppu.framebuffer_hash());
+        framebuffer[0][0][2] ^= 1;
+        assert_ne!(hash_framebuffer(&framebuffer), ppu.framebuffer_hash());
+    }
+
+    #[test]
+    fn test_copy_rgba() {
+        let (ppu, framebuffer) = PPU::new();
+        {
+            let mut framebuffer = framebuffer.write().unwrap();
+            framebuffer[0][0] = [1, 2, 3];
+            framebuffer[0][1] = [4, 5, 6];
+            framebuffer[1][0] = [7, 8, 9];
+            framebuffer[159][239] = [10, 11, 12];
+        }
+
+        let mut rgba = vec![0; FRAMEBUFFER_WIDTH * FRAMEBUFFER_HEIGHT * 4];
+        ppu.with_framebuffer(|framebuffer| copy_rgba(framebuffer, &mut rgba));
+        assert_eq!(rgba[..8], [1, 2, 3, 255, 4, 5, 6, 255]);
+        assert_eq!(rgba[FRAMEBUFFER_WIDTH * 4..FRAMEBUFFER_WIDTH * 4 + 4], [7, 8, 9, 255]);
+        assert_eq!(rgba[rgba.len() - 4..], [10, 11, 12, 255]);
+        assert!(rgba.chunks_exact(4).all(|pixel| pixel[3] == 255));
+    }
+
+    #[test]
+    fn test_mode3() {
+        let (mut ppu, framebuffer) = PPU::new();
+        let mut mem = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+        mem.write_u16(REG_DISPCNT, 3 | DISPCNT_BG2);
+        mem.write_u16(0x06_000_000, 0x001F);
+        mem.write_u16(0x06_000_000 + 239 * 2, 0x03E0);
+        mem.write_u16(0x06_000_000 + (159 * 240 + 10) * 2, 0x7C00);
+
+        run_frame(&mut ppu, &mut mem);
+        let fb = framebuffer.read().unwrap();
+        assert_eq!(fb[0][0], [255, 0, 0]);
+        assert_eq!(fb[0][1], [0, 0, 0]);
+        assert_eq!(fb[0][239], [0, 255, 0]);
+        assert_eq!(fb[159][10], [0, 0, 255]);
+    }
+
+    #[test]
+    fn test_mode4() {
+        let (mut ppu, framebuffer) = PPU::new();
+        let mut mem = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+        mem.write_u16(REG_DISPCNT, 4 | DISPCNT_BG2);
+        mem.write_u16(0x05_000_000 + 5 * 2, 0x001F);
+        mem.write_u16(0x05_000_000 + 7 * 2, 0x7C00);
+        // Pixels are two indices per halfword
+        mem.write_u16(0x06_000_000 + 240 + 2, 7 << 8 | 5);
+
+        run_frame(&mut ppu, &mut mem);
+        let fb = framebuffer.read().unwrap();
+        assert_eq!(fb[1][2], [255, 0, 0]);
+        assert_eq!(fb[1][3], [0, 0, 255]);
+        assert_eq!(fb[1][4], [0, 0, 0]);
+    }
+
+    #[test]
+    fn test_mode4_frame_select() {
+        let (mut ppu, framebuffer) = PPU::new();
+        let mut mem = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+        mem.write_u16(REG_DISPCNT, 4 | DISPCNT_BG2);
+        mem.write_u16(0x05_000_000 + 2, 0x001F);
+        mem.write_u16(0x05_000_000 + 4, 0x03E0);
+        mem.write_u16(0x06_000_000, 1);
+        mem.write_u16(0x06_00A_000, 2);
+
+        run_frame(&mut ppu, &mut mem);
+        assert_eq!(framebuffer.read().unwrap()[0][0], [255, 0, 0]);
+
+        mem.write_u16(REG_DISPCNT, 4 | DISPCNT_BG2 | DISPCNT_FRAME_SELECT);
+        run_frame(&mut ppu, &mut mem);
+        assert_eq!(framebuffer.read().unwrap()[0][0], [0, 255, 0]);
+    }
+
+    #[test]
+    fn test_forced_blank() {
+        let (mut ppu, framebuffer) = PPU::new();
+        let mut mem = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+        mem.write_u16(REG_DISPCNT, 3 | DISPCNT_BG2 | DISPCNT_FORCED_BLANK);
+        mem.write_u16(0x06_000_000, 0x001F);
+
+        run_frame(&mut ppu, &mut mem);
+        assert!(framebuffer.read().unwrap().as_flattened().iter().all(|&color| color == [255, 255, 255]));
+
+        mem.write_u16(REG_DISPCNT, 3 | DISPCNT_BG2);
+        run_frame(&mut ppu, &mut mem);
+        assert_eq!(framebuffer.read().unwrap()[0][0], [255, 0, 0]);
+    }
+
+    #[test]
+    fn test_disabled_bg() {
+        let (mut ppu, framebuffer) = PPU::new();
+        let mut mem = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+        // A red pixel in mode 3, but only OBJs are enabled
+        mem.write_u16(REG_DISPCNT, 3 | DISPCNT_OBJ);
+        mem.write_u16(0x05_000_000, 0x03E0);
+        mem.write_u16(0x06_000_000, 0x001F);
+
+        run_frame(&mut ppu, &mut mem);
+        assert_eq!(framebuffer.read().unwrap()[0][0], [0, 255, 0]);
+    }
+
+    #[test]
+    fn test_text_bg() {
+        let (mut ppu, framebuffer) = PPU::new();
+        let mut mem = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+        mem.write_u16(REG_DISPCNT, DISPCNT_BG0);
+        // Characters in block 0, map in screen block 1
+        mem.write_u16(REG_BG0CNT, 1 << 8);
+        mem.write_u16(0x05_000_000, 0x7FFF);
+        mem.write_u16(0x05_000_000 + (2 * 16 + 1) * 2, 0x001F);
+        mem.write_u16(0x05_000_000 + (2 * 16 + 2) * 2, 0x03E0);
+        mem.write_u16(0x05_000_000 + (2 * 16 + 3) * 2, 0x7C00);
+        // First row of tile 1: colors 1, 2, 0, 0, 0, 0, 0, 3
+        mem.write_u32(0x06_000_020, 0x30000021);
+        // Tile 1 with palette bank 2 at the top left
+        mem.write_u16(0x06_000_800, 2 << 12 | 1);
+
+        let white = [255, 255, 255];
+        let line = |framebuffer: &Arc<RwLock<Framebuffer>>| framebuffer.read().unwrap()[0][..10].to_vec();
+
+        run_frame(&mut ppu, &mut mem);
+        assert_eq!(line(&framebuffer), [[255, 0, 0], [0, 255, 0], white, white, white, white, white, [0, 0, 255], white, white]);
+
+        mem.write_u16(0x06_000_800, 2 << 12 | MAP_ENTRY_H_FLIP | 1);
+        run_frame(&mut ppu, &mut mem);
+        assert_eq!(line(&framebuffer), [[0, 0, 255], white, white, white, white, white, [0, 255, 0], [255, 0, 0], white, white]);
+
+        // Scrolling wraps around the 256 pixel wide map
+        mem.write_u16(0x06_000_800, 2 << 12 | 1);
+        mem.write_u16(REG_BG0HOFS, 255);
+        run_frame(&mut ppu, &mut mem);
+        assert_eq!(line(&framebuffer), [white, [255, 0, 0], [0, 255, 0], white, white, white, white, white, [0, 0, 255], white]);
+
+        // Only the first row of the tile is set
+        mem.write_u16(REG_BG0HOFS, 0);
+        mem.write_u16(0x06_000_800, 2 << 12 | MAP_ENTRY_V_FLIP | 1);
+        run_frame(&mut ppu, &mut mem);
+        assert_eq!(line(&framebuffer), [white; 10]);
+        assert_eq!(framebuffer.read().unwrap()[7][0], [255, 0, 0]);
+    }
+
+    #[test]
+    fn test_resolve_priority() {
+        let backdrop = 0x7FFF;
+        assert_eq!(resolve_priority([None; 4], None, backdrop), backdrop);
+        // BG1 at priority 0 overlaps BG0 at priority 2
+        assert_eq!(resolve_priority([Some((2, 0x001F)), Some((0, 0x03E0)), None, None], None, backdrop), 0x03E0);
+        assert_eq!(resolve_priority([Some((1, 0x001F)), Some((1, 0x03E0)), None, None], None, backdrop), 0x001F);
+        assert_eq!(resolve_priority([None, None, None, Some((3, 0x001F))], Some((3, 0x7C00)), backdrop), 0x7C00);
+        assert_eq!(resolve_priority([None, None, Some((0, 0x001F)), None], Some((1, 0x7C00)), backdrop), 0x001F);
+    }
+
+    #[test]
+    fn test_text_bg_priority() {
+        let (mut ppu, framebuffer) = PPU::new();
+        let mut mem = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+        mem.write_u16(REG_DISPCNT, DISPCNT_BG0 | DISPCNT_BG0 << 1);
+        // 256 color tile 1 filled with color 1 and tile 2 with color 2
+        for offset in (0..64).step_by(2) {
+            mem.write_u16(0x06_000_040 + offset, 0x0101);
+            mem.write_u16(0x06_000_080 + offset, 0x0202);
+        }
+        mem.write_u16(0x05_000_002, 0x001F);
+        mem.write_u16(0x05_000_004, 0x03E0);
+        mem.write_u16(REG_BG0CNT, 1 | BGCNT_256_COLORS | 1 << 8);
+        mem.write_u16(REG_BG0CNT + 2, BGCNT_256_COLORS | 2 << 8);
+        mem.write_u16(0x06_000_800, 1);
+        mem.write_u16(0x06_001_000, 2);
+
+        run_frame(&mut ppu, &mut mem);
+        assert_eq!(framebuffer.read().unwrap()[0][0], [0, 255, 0]);
+
+        mem.write_u16(REG_BG0CNT + 2, 1 | BGCNT_256_COLORS | 2 << 8);
+        run_frame(&mut ppu, &mut mem);
+        assert_eq!(framebuffer.read().unwrap()[0][0], [255, 0, 0]);
+    }
+
+    /// Fills 256 color tile 1 with stripes of colors 1 and 2 that alternate every pixel, maps it to the top left
+    fn setup_affine_bg(mem: &mut Memory) {
+        mem.write_u16(REG_DISPCNT, 2 | DISPCNT_BG2);
+        // 128x128 map in screen block 1
+        mem.write_u16(REG_BG0CNT + 4, 1 << 8);
+        mem.write_u16(0x05_000_002, 0x001F);
+        mem.write_u16(0x05_000_004, 0x03E0);
+        for offset in (0..64).step_by(2) {
+            mem.write_u16(0x06_000_040 + offset, 0x0201);
+        }
+        mem.write_u16(0x06_000_800, 1);
+    }
+
+    #[test]
+    fn test_affine_bg_identity() {
+        let (mut ppu, framebuffer) = PPU::new();
+        let mut mem = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+        setup_affine_bg(&mut mem);
+        mem.write_u16(REG_BG2PA, 0x100);
+        mem.write_u16(REG_BG2PD, 0x100);
+
+        run_frame(&mut ppu, &mut mem);
+        let (red, green, black) = ([255, 0, 0], [0, 255, 0], [0, 0, 0]);
+        assert_eq!(framebuffer.read().unwrap()[0][..10], [red, green, red, green, red, green, red, green, black, black]);
+        assert_eq!(framebuffer.read().unwrap()[7][..2], [red, green]);
+        assert_eq!(framebuffer.read().unwrap()[8][0], black);
+
+        // Outside of the 128x128 map is transparent, unless wrapping
+        assert_eq!(framebuffer.read().unwrap()[0][128], black);
+        mem.write_u16(REG_BG0CNT + 4, 1 << 8 | BGCNT_AFFINE_WRAP);
+        run_frame(&mut ppu, &mut mem);
+        assert_eq!(framebuffer.read().unwrap()[0][128..130], [red, green]);
+        assert_eq!(framebuffer.read().unwrap()[128][0], red);
+
+        // The same tile in a text background
+        let (mut text_ppu, text_framebuffer) = PPU::new();
+        let mut text_mem = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+        setup_affine_bg(&mut text_mem);
+        text_mem.write_u16(REG_DISPCNT, DISPCNT_BG0);
+        text_mem.write_u16(REG_BG0CNT, BGCNT_256_COLORS | 1 << 8);
+        run_frame(&mut text_ppu, &mut text_mem);
+        assert_eq!(
+            text_framebuffer.read().unwrap()[..8].iter().map(|line| &line[..8]).collect::<Vec<_>>(),
+            framebuffer.read().unwrap()[..8].iter().map(|line| &line[..8]).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_affine_bg_scale() {
+        let (mut ppu, framebuffer) = PPU::new();
+        let mut mem = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+        setup_affine_bg(&mut mem);
+        // Half a texel per pixel doubles the size
+        mem.write_u16(REG_BG2PA, 0x80);
+        mem.write_u16(REG_BG2PD, 0x80);
+
+        run_frame(&mut ppu, &mut mem);
+        let (red, green, black) = ([255, 0, 0], [0, 255, 0], [0, 0, 0]);
+        assert_eq!(framebuffer.read().unwrap()[0][..6], [red, red, green, green, red, red]);
+        assert_eq!(framebuffer.read().unwrap()[15][15], green);
+        assert_eq!(framebuffer.read().unwrap()[16][0], black);
+
+        // Moving the reference point scrolls by whole texels
+        mem.write_u32(REG_BG2X, 1 << 8);
+        run_frame(&mut ppu, &mut mem);
+        assert_eq!(framebuffer.read().unwrap()[0][..4], [green, green, red, red]);
+        assert_eq!(framebuffer.read().unwrap()[15][..2], [green, green]);
+    }
+
+    #[test]
+    fn test_affine_reference_write() {
+        let (mut ppu, framebuffer) = PPU::new();
+        let mut mem = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+        setup_affine_bg(&mut mem);
+        mem.write_u16(REG_BG2PA, 0x100);
+        mem.write_u16(REG_BG2PD, 0x100);
+
+        // Writing BGxY mid-frame restarts at it, even when the value stays the same
+        ppu.step(SCANLINE_CYCLES * 4, &mut mem);
+        mem.write_u32(REG_BG2Y, 0);
+        ppu.step(SCANLINE_CYCLES * (TOTAL_LINES as u32 - 4), &mut mem);
+        let (red, green, black) = ([255, 0, 0], [0, 255, 0], [0, 0, 0]);
+        assert_eq!(framebuffer.read().unwrap()[4][..2], [red, green]);
+        assert_eq!(framebuffer.read().unwrap()[11][..2], [red, green]);
+        assert_eq!(framebuffer.read().unwrap()[12][0], black);
+
+        // Only written registers are reloaded, the next frame starts at the top again
+        run_frame(&mut ppu, &mut mem);
+        assert_eq!(framebuffer.read().unwrap()[7][..2], [red, green]);
+        assert_eq!(framebuffer.read().unwrap()[8][0], black);
+    }
+
+    #[test]
+    fn test_window() {
+        let (mut ppu, framebuffer) = PPU::new();
+        let mut mem = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+        mem.write_u16(REG_DISPCNT, 3 | DISPCNT_BG2 | DISPCNT_WIN0);
+        for offset in (0..FRAMEBUFFER_WIDTH * FRAMEBUFFER_HEIGHT * 2).step_by(2) {
+            mem.write_u16(0x06_000_000 + offset as u32, 0x001F);
+        }
+        // BG2 is only enabled inside of WIN0, from (10, 0) to (20, 5)
+        mem.write_u16(REG_WIN0H, 10 << 8 | 20);
+        mem.write_u16(REG_WIN0V, 5);
+        mem.write_u16(REG_WININ, 1 << 2);
+        mem.write_u16(REG_WINOUT, 0);
+
+        let (red, black) = ([255, 0, 0], [0, 0, 0]);
+        run_frame(&mut ppu, &mut mem);
+        assert_eq!(framebuffer.read().unwrap()[2][9..=20], [black, red, red, red, red, red, red, red, red, red, red, black]);
+        assert_eq!(framebuffer.read().unwrap()[5][15], black);
+
+        // An end before the start extends the window to the edge of the screen
+        mem.write_u16(REG_WIN0H, 200 << 8 | 20);
+        run_frame(&mut ppu, &mut mem);
+        assert_eq!(framebuffer.read().unwrap()[2][15], black);
+        assert_eq!(framebuffer.read().unwrap()[2][199..], [[black].as_slice(), &[red; 40]].concat());
+
+        // Enabled windows without the pixel in them use WINOUT
+        mem.write_u16(REG_WINOUT, 1 << 2);
+        run_frame(&mut ppu, &mut mem);
+        assert_eq!(framebuffer.read().unwrap()[100][0], red);
+    }
+
+    #[test]
+    fn test_obj_window() {
+        let (mut ppu, framebuffer) = PPU::new();
+        let mut mem = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+        mem.write_u16(REG_DISPCNT, 3 | DISPCNT_BG2 | DISPCNT_OBJ | DISPCNT_OBJ_WINDOW);
+        for offset in (0..FRAMEBUFFER_WIDTH * 2).step_by(2) {
+            mem.write_u16(0x06_000_000 + offset as u32, 0x001F);
+        }
+        // An 8x8 OBJ window OBJ at (4, 0), using tile 512 as the bitmap modes take the lower half of OBJ VRAM
+        for offset in (0..32).step_by(4) {
+            mem.write_u32(0x06_014_000 + offset, 0x11111111);
+        }
+        mem.write_u16(0x07_000_000, OBJ_ATTR0_MODE_WINDOW);
+        mem.write_u16(0x07_000_002, 4);
+        mem.write_u16(0x07_000_004, 512);
+        for entry in 1..128 {
+            mem.write_u16(0x07_000_000 + entry * 8, 160);
+        }
+        // BG2 only shows outside of the OBJ window
+        mem.write_u16(REG_WINOUT, 1 << 2);
+
+        let (red, black) = ([255, 0, 0], [0, 0, 0]);
+        run_frame(&mut ppu, &mut mem);
+        assert_eq!(framebuffer.read().unwrap()[0][3..=12], [red, black, black, black, black, black, black, black, black, red]);
+    }
+
+    #[test]
+    fn test_objects() {
+        let (mut ppu, framebuffer) = PPU::new();
+        let mut mem = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+        mem.write_u16(REG_DISPCNT, DISPCNT_OBJ | DISPCNT_OBJ_1D_MAPPING);
+        mem.write_u16(0x05_000_200 + (3 * 16 + 1) * 2, 0x001F);
+        // Tile 1 filled with color 1 in OBJ VRAM
+        for offset in (0..32).step_by(4) {
+            mem.write_u32(0x06_010_020 + offset, 0x11111111);
+        }
+        // An 8x8 OBJ at (20, 30) using tile 1 and palette bank 3
+        mem.write_u16(0x07_000_000, 30);
+        mem.write_u16(0x07_000_002, 20);
+        mem.write_u16(0x07_000_004, 3 << 12 | 1);
+        // Every other entry is placed offscreen
+        for entry in 1..128 {
+            mem.write_u16(0x07_000_000 + entry * 8, 160);
+        }
+
+        run_frame(&mut ppu, &mut mem);
+        let fb = framebuffer.read().unwrap();
+        for y in 29..=38 {
+            for x in 19..=28 {
+                let expected = if (30..38).contains(&y) && (20..28).contains(&x) { [255, 0, 0] } else { [0, 0, 0] };
+                assert_eq!(fb[y][x], expected, "({x}, {y})");
+            }
+        }
+    }
+
+    #[test]
+    fn test_objects_mapping() {
+        let (mut ppu, framebuffer) = PPU::new();
+        let mut mem = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+        mem.write_u16(0x05_000_202, 0x001F);
+        mem.write_u16(0x05_000_204, 0x03E0);
+        // A 16x16 OBJ at the top left with its lower tiles at 2 and 3 in 1D, or at 32 and 33 in 2D
+        mem.write_u16(0x07_000_000, 0);
+        mem.write_u16(0x07_000_002, 1 << 14 | OBJ_ATTR1_H_FLIP);
+        mem.write_u32(0x06_010_000 + 3 * 32, 0x00000001);
+        mem.write_u32(0x06_010_000 + 33 * 32, 0x00000002);
+        for entry in 1..128 {
+            mem.write_u16(0x07_000_000 + entry * 8, 160);
+        }
+
+        // The OBJ is flipped, so the left pixel of tile 3 ends up at x = 15 - 8
+        mem.write_u16(REG_DISPCNT, DISPCNT_OBJ | DISPCNT_OBJ_1D_MAPPING);
+        run_frame(&mut ppu, &mut mem);
+        assert_eq!(framebuffer.read().unwrap()[8][7], [255, 0, 0]);
+
+        mem.write_u16(REG_DISPCNT, DISPCNT_OBJ);
+        run_frame(&mut ppu, &mut mem);
+        assert_eq!(framebuffer.read().unwrap()[8][7], [0, 255, 0]);
+    }
+
+    #[test]
+    fn test_objects_priority() {
+        let (mut ppu, framebuffer) = PPU::new();
+        let mut mem = Memory::new(vec![0; 0x4000], vec![0; 0x100]);
+        mem.write_u16(REG_DISPCNT, DISPCNT_BG0 | DISPCNT_OBJ);
+        mem.write_u16(0x05_000_002, 0x001F);
+        mem.write_u16(0x05_000_202, 0x03E0);
+        // BG0 is covered by tile 1, which is filled with color 1 in both BG and OBJ VRAM
+        mem.write_u16(REG_BG0CNT, 1 | 1 << 8);
+        for offset in (0..32).step_by(4) {
+            mem.write_u32(0x06_000_020 + offset, 0x11111111);
+            mem.write_u32(0x06_010_020 + offset, 0x11111111);
+        }
+        for offset in (0..0x800).step_by(2) {
+            mem.write_u16(0x06_000_800 + offset, 1);
+        }
+        mem.write_u16(0x07_000_004, 1 << 10 | 1);
+        for entry in 1..128 {
+            mem.write_u16(0x07_000_000 + entry * 8, 160);
+        }
+
+        run_frame(&mut ppu, &mut mem);
+        assert_eq!(framebuffer.read().unwrap()[0][0], [0, 255, 0]);
+
+        mem.write_u16(0x07_000_004, 2 << 10 | 1);
+        run_frame(&mut ppu, &mut mem);
+        assert_eq!(framebuffer.read().unwrap()[0][0], [255, 0, 0]);
+    }
+}
@@ -0,0 +1,463 @@
+use std::io;
+
+use super::{
+    apu::REG_SOUNDCNT_H,
+    cpu::CPU_FREQUENCY,
+    io_registers::IoRegisters,
+    state::{StateReader, StateWriter},
+};
+
+pub const REG_SOUND1CNT_L: u32 = 0x04_000_060;
+pub const REG_SOUND1CNT_H: u32 = 0x04_000_062;
+pub const REG_SOUND1CNT_X: u32 = 0x04_000_064;
+pub const REG_SOUND2CNT_L: u32 = 0x04_000_068;
+pub const REG_SOUND2CNT_H: u32 = 0x04_000_06C;
+pub const REG_SOUND3CNT_L: u32 = 0x04_000_070;
+pub const REG_SOUND3CNT_H: u32 = 0x04_000_072;
+pub const REG_SOUND3CNT_X: u32 = 0x04_000_074;
+pub const REG_SOUND4CNT_L: u32 = 0x04_000_078;
+pub const REG_SOUND4CNT_H: u32 = 0x04_000_07C;
+// The last byte of SOUND4CNT_H
+pub const PSG_REGISTERS_END: u32 = 0x04_000_07F;
+pub const REG_SOUNDCNT_L: u32 = 0x04_000_080;
+pub const REG_WAVE_RAM: u32 = 0x04_000_090;
+pub const WAVE_RAM_END: u32 = 0x04_000_09F;
+
+// The registers with the length, duty and envelope of each channel, the wave channel has its volume there
+const LENGTH_REGISTERS: [u32; 4] = [REG_SOUND1CNT_H, REG_SOUND2CNT_L, REG_SOUND3CNT_H, REG_SOUND4CNT_L];
+// The registers with the frequency, length enable and restart bits of each channel
+const CONTROL_REGISTERS: [u32; 4] = [REG_SOUND1CNT_X, REG_SOUND2CNT_H, REG_SOUND3CNT_X, REG_SOUND4CNT_H];
+const MAX_LENGTHS: [u16; 4] = [64, 64, 256, 64];
+
+// Length and envelope bits
+const DUTY_SHIFT: u16 = 6;
+const ENVELOPE_INCREASE: u16 = 1 << 11;
+// Without an initial volume or increase the channel is off
+const ENVELOPE_DAC: u16 = 0xF800;
+
+// Control bits
+const FREQUENCY: u16 = 0x7FF;
+const LENGTH_ENABLE: u16 = 1 << 14;
+const RESTART: u16 = 1 << 15;
+
+// SOUND1CNT_L bits
+const SWEEP_SHIFT: u16 = 0b111;
+const SWEEP_DECREASE: u16 = 1 << 3;
+
+// SOUND3CNT_L bits
+const WAVE_TWO_BANKS: u16 = 1 << 5;
+const WAVE_BANK: u16 = 1 << 6;
+const WAVE_DAC: u16 = 1 << 7;
+
+// SOUND3CNT_H bits
+const WAVE_FORCE_VOLUME: u16 = 1 << 15;
+
+// SOUND4CNT_H bits
+const NOISE_SHORT: u16 = 1 << 3;
+
+// The duty cycles of 12.5%, 25%, 50% and 75%, played from the lowest bit
+const DUTY_PATTERNS: [u8; 4] = [0b0000_0001, 0b1000_0001, 0b1000_0111, 0b0111_1110];
+// The PSG volume in SOUNDCNT_H is 25%, 50% or 100%, the last value is prohibited
+const VOLUME_SHIFTS: [u32; 4] = [2, 1, 0, 0];
+
+// The frame sequencer runs at 512 Hz and clocks the length counters, the sweep and the envelopes
+const SEQUENCER_PERIOD: u32 = (CPU_FREQUENCY / 512) as u32;
+const SEQUENCER_STEPS: u8 = 8;
+
+// The PSG counts at 4 MHz, a quarter of the cpu clock
+fn square_period(control: u16) -> u32 {
+    (2048 - (control & FREQUENCY) as u32) * 16
+}
+
+fn wave_period(control: u16) -> u32 {
+    (2048 - (control & FREQUENCY) as u32) * 8
+}
+
+fn noise_period(control: u16) -> u32 {
+    let divisor = match control & 0b111 {
+        0 => 8,
+        ratio => ratio as u32 * 16,
+    };
+    (divisor << (control >> 4 & 0xF)) * 4
+}
+
+/// Adds `cycles` to the cycles since the last step and returns how many steps of `period` that completes
+fn advance(elapsed: &mut u32, cycles: u32, period: u32) -> u32 {
+    *elapsed += cycles;
+    let steps = *elapsed / period;
+    *elapsed %= period;
+    steps
+}
+
+#[derive(Debug, Default)]
+struct Envelope {
+    volume: u8,
+    period: u8,
+    increase: bool,
+    timer: u8,
+}
+
+impl Envelope {
+    fn restart(&mut self, control: u16) {
+        self.volume = (control >> 12) as u8;
+        self.period = (control >> 8 & 0b111) as u8;
+        self.increase = control & ENVELOPE_INCREASE != 0;
+        self.timer = self.period;
+    }
+
+    fn clock(&mut self) {
+        // A period of 0 holds the volume
+        if self.period == 0 {
+            return;
+        }
+        self.timer -= 1;
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.increase && self.volume < 15 {
+                self.volume += 1;
+            } else if !self.increase && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+
+    /// A high or low output at the current volume
+    fn output(&self, high: bool) -> i32 {
+        if high {
+            self.volume as i32
+        } else {
+            -(self.volume as i32)
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Sweep {
+    enabled: bool,
+    // The frequency the sweep works on, written back to SOUND1CNT_X
+    shadow: u16,
+    timer: u8,
+}
+
+impl Sweep {
+    fn period(control: u16) -> u8 {
+        // A period of 0 reloads the timer with 8
+        match (control >> 4 & 0b111) as u8 {
+            0 => 8,
+            period => period,
+        }
+    }
+
+    fn next_frequency(&self, control: u16) -> u16 {
+        let delta = self.shadow >> (control & SWEEP_SHIFT);
+        if control & SWEEP_DECREASE != 0 {
+            self.shadow - delta
+        } else {
+            self.shadow + delta
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Square {
+    envelope: Envelope,
+    // Cycles since the last duty step, and that step
+    elapsed: u32,
+    duty_step: u8,
+}
+
+#[derive(Debug, Default)]
+struct Wave {
+    elapsed: u32,
+    // The sample being played out of the 64 in both banks
+    position: u8,
+}
+
+#[derive(Debug, Default)]
+struct Noise {
+    envelope: Envelope,
+    elapsed: u32,
+    lfsr: u16,
+}
+
+/// The four channels carried over from the Game Boy, two square waves with channel 1 sweeping its frequency,
+/// a channel playing a pattern from the wave RAM and a noise channel.
+/// Only the wave RAM is part of save states, the channels are restarted by the game.
+#[derive(Debug, Default)]
+pub struct Psg {
+    // Whether each channel is playing and its remaining length
+    enabled: [bool; 4],
+    lengths: [u16; 4],
+    squares: [Square; 2],
+    sweep: Sweep,
+    wave: Wave,
+    noise: Noise,
+    // Two banks of 32 4 bit samples, the cpu sees the one that isn't played
+    wave_ram: [u8; 32],
+    // Cycles since the last frame sequencer step, and the next step
+    sequencer_elapsed: u32,
+    sequencer_step: u8,
+}
+
+impl Psg {
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_block(&self.wave_ram);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        state.read_block(&mut self.wave_ram)
+    }
+
+    /// The playing channels, as shown in the low bits of SOUNDCNT_X
+    pub fn status(&self) -> u8 {
+        self.enabled.iter().enumerate().fold(0, |status, (i, &enabled)| status | (enabled as u8) << i)
+    }
+
+    /// Turning the sound off stops the channels and clears their registers, the wave RAM keeps its contents
+    pub fn power_off(&mut self, io: &mut IoRegisters) {
+        *self = Self {
+            wave_ram: self.wave_ram,
+            ..Default::default()
+        };
+        for address in REG_SOUND1CNT_L..=REG_SOUNDCNT_L + 1 {
+            io.set(address, 0);
+        }
+    }
+
+    fn dac_enabled(channel: usize, io: &IoRegisters) -> bool {
+        match channel {
+            2 => io.get_u16(REG_SOUND3CNT_L) & WAVE_DAC != 0,
+            _ => io.get_u16(LENGTH_REGISTERS[channel]) & ENVELOPE_DAC != 0,
+        }
+    }
+
+    /// Applies a byte written to the channel registers, after it was stored in `io`
+    pub fn write(&mut self, address: u32, value: u8, io: &IoRegisters) {
+        for channel in 0..4 {
+            if address == LENGTH_REGISTERS[channel] {
+                self.lengths[channel] = MAX_LENGTHS[channel] - (value as u16 & (MAX_LENGTHS[channel] - 1));
+            }
+            // The restart bit is the top bit of the control register
+            if address == CONTROL_REGISTERS[channel] + 1 && value as u16 & (RESTART >> 8) != 0 {
+                self.restart(channel, io);
+            }
+            if !Self::dac_enabled(channel, io) {
+                self.enabled[channel] = false;
+            }
+        }
+    }
+
+    fn restart(&mut self, channel: usize, io: &IoRegisters) {
+        self.enabled[channel] = Self::dac_enabled(channel, io);
+        if self.lengths[channel] == 0 {
+            self.lengths[channel] = MAX_LENGTHS[channel];
+        }
+        let length_control = io.get_u16(LENGTH_REGISTERS[channel]);
+        let control = io.get_u16(CONTROL_REGISTERS[channel]);
+        match channel {
+            0 | 1 => {
+                let square = &mut self.squares[channel];
+                square.envelope.restart(length_control);
+                square.elapsed = 0;
+            }
+            2 => self.wave = Wave::default(),
+            _ => {
+                self.noise.envelope.restart(length_control);
+                self.noise.elapsed = 0;
+                self.noise.lfsr = 0x7FFF;
+            }
+        }
+
+        if channel == 0 {
+            let sweep_control = io.get_u16(REG_SOUND1CNT_L);
+            self.sweep.shadow = control & FREQUENCY;
+            self.sweep.timer = Sweep::period(sweep_control);
+            self.sweep.enabled = sweep_control & 0x77 != 0;
+            // A sweep that would overflow right away stops the channel
+            if sweep_control & SWEEP_SHIFT != 0 && self.sweep.next_frequency(sweep_control) > FREQUENCY {
+                self.enabled[0] = false;
+            }
+        }
+    }
+
+    /// Reads a byte of the wave RAM bank that isn't played
+    pub fn read_wave_ram(&self, address: u32, io: &IoRegisters) -> u8 {
+        self.wave_ram[Self::wave_ram_index(address, io)]
+    }
+
+    pub fn write_wave_ram(&mut self, address: u32, value: u8, io: &IoRegisters) {
+        self.wave_ram[Self::wave_ram_index(address, io)] = value;
+    }
+
+    fn wave_ram_index(address: u32, io: &IoRegisters) -> usize {
+        let bank = io.get_u16(REG_SOUND3CNT_L) & WAVE_BANK == 0;
+        (address - REG_WAVE_RAM) as usize + 16 * bank as usize
+    }
+
+    /// Advances the channels and the frame sequencer by the given number of cpu cycles
+    pub fn step(&mut self, cycles: u32, io: &mut IoRegisters) {
+        for _ in 0..advance(&mut self.sequencer_elapsed, cycles, SEQUENCER_PERIOD) {
+            self.clock_sequencer(io);
+        }
+
+        for (i, square) in self.squares.iter_mut().enumerate() {
+            let steps = advance(&mut square.elapsed, cycles, square_period(io.get_u16(CONTROL_REGISTERS[i])));
+            square.duty_step = ((square.duty_step as u32 + steps) % 8) as u8;
+        }
+
+        let steps = advance(&mut self.wave.elapsed, cycles, wave_period(io.get_u16(REG_SOUND3CNT_X)));
+        self.wave.position = ((self.wave.position as u32 + steps) % 64) as u8;
+
+        let control = io.get_u16(REG_SOUND4CNT_H);
+        let noise = &mut self.noise;
+        for _ in 0..advance(&mut noise.elapsed, cycles, noise_period(control)) {
+            let bit = (noise.lfsr ^ noise.lfsr >> 1) & 1;
+            noise.lfsr = noise.lfsr >> 1 | bit << 14;
+            if control & NOISE_SHORT != 0 {
+                noise.lfsr = noise.lfsr & !(1 << 6) | bit << 6;
+            }
+        }
+    }
+
+    fn clock_sequencer(&mut self, io: &mut IoRegisters) {
+        let step = self.sequencer_step;
+        self.sequencer_step = (step + 1) % SEQUENCER_STEPS;
+
+        // Lengths at 256 Hz
+        if step.is_multiple_of(2) {
+            for (channel, &register) in CONTROL_REGISTERS.iter().enumerate() {
+                if io.get_u16(register) & LENGTH_ENABLE != 0 && self.lengths[channel] > 0 {
+                    self.lengths[channel] -= 1;
+                    if self.lengths[channel] == 0 {
+                        self.enabled[channel] = false;
+                    }
+                }
+            }
+        }
+
+        // Sweep at 128 Hz
+        if step == 2 || step == 6 {
+            self.clock_sweep(io);
+        }
+
+        // Envelopes at 64 Hz
+        if step == 7 {
+            self.squares[0].envelope.clock();
+            self.squares[1].envelope.clock();
+            self.noise.envelope.clock();
+        }
+    }
+
+    fn clock_sweep(&mut self, io: &mut IoRegisters) {
+        let control = io.get_u16(REG_SOUND1CNT_L);
+        // Before the first restart the timer is reloaded right away
+        self.sweep.timer = self.sweep.timer.saturating_sub(1);
+        if self.sweep.timer > 0 {
+            return;
+        }
+        self.sweep.timer = Sweep::period(control);
+        if !self.sweep.enabled || control >> 4 & 0b111 == 0 {
+            return;
+        }
+
+        let frequency = self.sweep.next_frequency(control);
+        if frequency > FREQUENCY {
+            self.enabled[0] = false;
+        } else if control & SWEEP_SHIFT != 0 {
+            self.sweep.shadow = frequency;
+            let sound1cnt_x = io.get_u16(REG_SOUND1CNT_X);
+            io.set_u16(REG_SOUND1CNT_X, sound1cnt_x & !FREQUENCY | frequency);
+            if self.sweep.next_frequency(control) > FREQUENCY {
+                self.enabled[0] = false;
+            }
+        }
+    }
+
+    /// The output of each channel, between -15 and 15
+    fn outputs(&self, io: &IoRegisters) -> [i32; 4] {
+        let squares = [0, 1].map(|i| {
+            let square = &self.squares[i];
+            let duty = DUTY_PATTERNS[(io.get_u16(LENGTH_REGISTERS[i]) >> DUTY_SHIFT & 0b11) as usize];
+            square.envelope.output(duty >> square.duty_step & 1 != 0)
+        });
+
+        let wave_control = io.get_u16(REG_SOUND3CNT_L);
+        let position = match wave_control & WAVE_TWO_BANKS {
+            0 => self.wave.position % 32,
+            _ => self.wave.position,
+        } as usize;
+        // Playing starts at the selected bank, the high nibble of each byte comes first
+        let index = (position + 32 * (wave_control & WAVE_BANK != 0) as usize) % 64;
+        let sample = self.wave_ram[index / 2] >> (4 * (1 - index % 2)) & 0xF;
+        let volume = io.get_u16(REG_SOUND3CNT_H);
+        // The volume is 0%, 100%, 50% or 25% in quarters, unless forced to 75%
+        let quarters = if volume & WAVE_FORCE_VOLUME != 0 { 3 } else { [0, 4, 2, 1][(volume >> 13 & 0b11) as usize] };
+        let wave = (sample as i32 * 2 - 15) * quarters / 4;
+
+        let noise = self.noise.envelope.output(self.noise.lfsr & 1 == 0);
+
+        let outputs = [squares[0], squares[1], wave, noise];
+        std::array::from_fn(|i| if self.enabled[i] { outputs[i] } else { 0 })
+    }
+
+    /// Mixes the channels as the left and right output, up to a quarter of the 16 bit range each
+    pub fn mix(&self, io: &IoRegisters) -> [i16; 2] {
+        let outputs = self.outputs(io);
+        let soundcnt_l = io.get_u16(REG_SOUNDCNT_L);
+        let shift = VOLUME_SHIFTS[(io.get_u16(REG_SOUNDCNT_H) & 0b11) as usize];
+        // The right side has its volume in bits 0-2 and enables in bits 8-11, the left side in bits 4-6 and 12-15
+        [4, 0].map(|side| {
+            let volume = (soundcnt_l >> side & 0b111) as i32 + 1;
+            let enables = soundcnt_l >> (8 + side);
+            let sum: i32 = outputs.iter().enumerate().filter(|&(i, _)| enables >> i & 1 != 0).map(|(_, &output)| output).sum();
+            ((sum * volume * 64) >> shift) as i16
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::system::apu::REG_SOUNDCNT_X;
+
+    const CYCLES_PER_SAMPLE: u32 = 511;
+
+    fn write(psg: &mut Psg, io: &mut IoRegisters, address: u32, value: u16) {
+        for (i, byte) in value.to_le_bytes().into_iter().enumerate() {
+            io.write(address + i as u32, byte);
+            psg.write(address + i as u32, byte, io);
+        }
+    }
+
+    #[test]
+    fn test_square_channel() {
+        let mut psg = Psg::default();
+        let mut io = IoRegisters::new();
+        io.set_u16(REG_SOUNDCNT_X, 1 << 7);
+        // Channel 1 on both sides at full volume
+        io.set_u16(REG_SOUNDCNT_L, 0x1177);
+        io.set_u16(REG_SOUNDCNT_H, 2);
+        // 50% duty at the initial volume 15, without an envelope or sweep
+        write(&mut psg, &mut io, REG_SOUND1CNT_H, 2 << DUTY_SHIFT | 0xF000);
+        // A period of 128 samples
+        write(&mut psg, &mut io, REG_SOUND1CNT_X, RESTART | 1537);
+        assert_eq!(psg.status(), 0b0001);
+
+        let samples: Vec<[i16; 2]> = (0..256)
+            .map(|_| {
+                psg.step(CYCLES_PER_SAMPLE, &mut io);
+                psg.mix(&io)
+            })
+            .collect();
+        assert!(samples.contains(&[15 * 8 * 64; 2]));
+        assert!(samples.contains(&[-15 * 8 * 64; 2]));
+        assert_eq!(samples[..128], samples[128..]);
+
+        // The length counter stops the channel after 64 - 60 steps at 256 Hz
+        write(&mut psg, &mut io, REG_SOUND1CNT_H, 2 << DUTY_SHIFT | 0xF000 | 60);
+        write(&mut psg, &mut io, REG_SOUND1CNT_X, RESTART | LENGTH_ENABLE | 1537);
+        psg.step(SEQUENCER_PERIOD * 8, &mut io);
+        assert_eq!(psg.status(), 0);
+        assert_eq!(psg.mix(&io), [0, 0]);
+    }
+}
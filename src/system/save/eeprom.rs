@@ -0,0 +1,179 @@
+use std::cell::Cell;
+
+const EEPROM_512_LEN: usize = 0x200;
+const EEPROM_8K_LEN: usize = 0x2000;
+const BLOCK_LEN: usize = 8;
+
+// Commands start with two bits, followed by the address and for writes 64 data bits, then a 0 bit
+const COMMAND_READ: u128 = 0b11;
+const COMMAND_WRITE: u128 = 0b10;
+// Reads start with 4 bits that are ignored before the 64 data bits
+const READ_PADDING_BITS: u32 = 4;
+const DATA_BITS: u32 = 64;
+
+/// 512 Byte or 8 KByte EEPROM, accessed one bit at a time by DMA
+#[derive(Debug)]
+pub struct Eeprom {
+    data: Vec<u8>,
+    // 6 bits for the 512 Byte and 14 bits for the 8 KByte version, only known from the length of the first command
+    address_bits: Option<u32>,
+    // Received bits of the current command, the last one in bit 0
+    command: u128,
+    command_len: u32,
+    read_block: Option<usize>,
+    // Reads go through a shared reference like the rest of the bus
+    read_position: Cell<u32>,
+}
+
+impl Default for Eeprom {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Eeprom {
+    pub fn new() -> Self {
+        Self {
+            data: vec![0xFF; EEPROM_8K_LEN],
+            address_bits: None,
+            command: 0,
+            command_len: 0,
+            read_block: None,
+            read_position: Cell::new(0),
+        }
+    }
+
+    /// Detects the size from the number of bits transferred by a DMA, which is the length of a whole command
+    pub fn detect_size(&mut self, stream_len: u32) {
+        if self.address_bits.is_none() {
+            self.address_bits = match stream_len {
+                9 | 73 => Some(6),
+                17 | 81 => Some(14),
+                _ => None,
+            };
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self.address_bits {
+            Some(6) => EEPROM_512_LEN,
+            _ => EEPROM_8K_LEN,
+        }
+    }
+
+    fn block_offset(&self, address: u128) -> usize {
+        // Only the low 10 bits of the 14 bit address are used
+        (address as usize * BLOCK_LEN) % self.len()
+    }
+
+    /// Returns the next bit of a requested block, or 1 when ready for a new command
+    pub fn read_bit(&self) -> u16 {
+        let Some(offset) = self.read_block else {
+            return 1;
+        };
+        let position = self.read_position.get();
+        if position >= READ_PADDING_BITS + DATA_BITS {
+            return 1;
+        }
+        self.read_position.set(position + 1);
+        match position.checked_sub(READ_PADDING_BITS) {
+            Some(bit) => (self.data[offset + bit as usize / 8] >> (7 - bit % 8)) as u16 & 1,
+            None => 0,
+        }
+    }
+
+    /// Receives the next bit of a command, returns whether it completed a write
+    pub fn write_bit(&mut self, bit: bool) -> bool {
+        self.command = self.command << 1 | bit as u128;
+        self.command_len += 1;
+        if self.command_len < 2 {
+            return false;
+        }
+
+        // Without a DMA to detect the size from, assume the more common 8 KByte version
+        let address_bits = self.address_bits.unwrap_or(14);
+        let opcode = self.command >> (self.command_len - 2);
+        let (complete, written) = match (self.command_len, opcode) {
+            (len, COMMAND_READ) if len == 2 + address_bits + 1 => {
+                self.read_block = Some(self.block_offset(self.command >> 1 & ((1 << address_bits) - 1)));
+                self.read_position.set(0);
+                (true, false)
+            }
+            (len, COMMAND_WRITE) if len == 2 + address_bits + DATA_BITS + 1 => {
+                let offset = self.block_offset(self.command >> (DATA_BITS + 1) & ((1 << address_bits) - 1));
+                let data = (self.command >> 1) as u64;
+                self.data[offset..offset + BLOCK_LEN].copy_from_slice(&data.to_be_bytes());
+                self.read_block = None;
+                (true, true)
+            }
+            (_, COMMAND_READ | COMMAND_WRITE) => (false, false),
+            // Not a valid command, start over
+            _ => (true, false),
+        };
+        if complete {
+            self.command = 0;
+            self.command_len = 0;
+        }
+        written
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data[..self.len()]
+    }
+
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        let len = self.len();
+        &mut self.data[..len]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn send(eeprom: &mut Eeprom, value: u128, len: u32) -> bool {
+        (0..len).rev().fold(false, |written, i| eeprom.write_bit(value >> i & 1 != 0) | written)
+    }
+
+    fn receive(eeprom: &Eeprom) -> (u8, u64) {
+        let padding = (0..4).fold(0, |value, _| value << 1 | eeprom.read_bit() as u8);
+        let data = (0..64).fold(0, |value, _| value << 1 | eeprom.read_bit() as u64);
+        (padding, data)
+    }
+
+    #[test]
+    fn test_eeprom_8k() {
+        let mut eeprom = Eeprom::new();
+        eeprom.detect_size(81);
+
+        // Write 0x0123456789ABCDEF to block 0x3FF
+        assert!(send(&mut eeprom, COMMAND_WRITE << 79 | 0x3FF << 65 | 0x0123_4567_89AB_CDEF << 1, 81));
+        assert_eq!(eeprom.read_bit(), 1);
+        assert_eq!(eeprom.data()[0x1FF8..], [0x01, 0x23, 0x45, 0x67, 0x89, 0xAB, 0xCD, 0xEF]);
+
+        assert!(!send(&mut eeprom, COMMAND_READ << 15 | 0x3FF << 1, 17));
+        assert_eq!(receive(&eeprom), (0, 0x0123_4567_89AB_CDEF));
+        assert_eq!(eeprom.read_bit(), 1);
+
+        // Unwritten blocks are erased
+        send(&mut eeprom, COMMAND_READ << 15 | 0x10 << 1, 17);
+        assert_eq!(receive(&eeprom), (0, u64::MAX));
+    }
+
+    #[test]
+    fn test_eeprom_512() {
+        let mut eeprom = Eeprom::new();
+        eeprom.detect_size(9);
+        assert_eq!(eeprom.data().len(), 0x200);
+
+        assert!(send(&mut eeprom, COMMAND_WRITE << 71 | 0x3F << 65 | 0xFEDC_BA98_7654_3210 << 1, 73));
+        send(&mut eeprom, COMMAND_READ << 7 | 0x3F << 1, 9);
+        assert_eq!(receive(&eeprom), (0, 0xFEDC_BA98_7654_3210));
+        assert_eq!(eeprom.data()[0x1F8], 0xFE);
+
+        // Invalid commands are ignored
+        assert!(!send(&mut eeprom, 0b01, 2));
+        send(&mut eeprom, COMMAND_READ << 7 | 0x3F << 1, 9);
+        assert_eq!(receive(&eeprom).1, 0xFEDC_BA98_7654_3210);
+    }
+}
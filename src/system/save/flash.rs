@@ -0,0 +1,181 @@
+const BANK_LEN: usize = 0x10_000;
+const SECTOR_LEN: usize = 0x1000;
+
+// Addresses of the command sequence: 0xAA to 0x5555, 0x55 to 0x2AAA, then the command to 0x5555
+const COMMAND_ADDRESS_1: u32 = 0x5555;
+const COMMAND_ADDRESS_2: u32 = 0x2AAA;
+
+const COMMAND_ENTER_ID_MODE: u8 = 0x90;
+const COMMAND_EXIT_ID_MODE: u8 = 0xF0;
+const COMMAND_PREPARE_ERASE: u8 = 0x80;
+const COMMAND_ERASE_CHIP: u8 = 0x10;
+const COMMAND_ERASE_SECTOR: u8 = 0x30;
+const COMMAND_PROGRAM_BYTE: u8 = 0xA0;
+const COMMAND_SELECT_BANK: u8 = 0xB0;
+
+// Manufacturer and device IDs of the emulated chips
+const ID_FLASH64: [u8; 2] = [0xBF, 0xD4]; // SST 39VF512
+const ID_FLASH128: [u8; 2] = [0xC2, 0x09]; // Macronix MX29L010
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Ready,
+    // Received the first and second byte of the command sequence
+    Unlock1,
+    Unlock2,
+    // The next write is data for a command
+    ProgramByte,
+    SelectBank,
+}
+
+/// 64 or 128 KByte flash memory, controlled by writing command sequences
+#[derive(Debug)]
+pub struct Flash {
+    data: Vec<u8>,
+    bank: usize,
+    state: State,
+    id_mode: bool,
+    erase_prepared: bool,
+}
+
+impl Flash {
+    pub fn new(banks: usize) -> Self {
+        Self {
+            data: vec![0xFF; banks * BANK_LEN],
+            bank: 0,
+            state: State::Ready,
+            id_mode: false,
+            erase_prepared: false,
+        }
+    }
+
+    fn id(&self) -> [u8; 2] {
+        if self.data.len() > BANK_LEN {
+            ID_FLASH128
+        } else {
+            ID_FLASH64
+        }
+    }
+
+    pub fn read(&self, offset: u32) -> u8 {
+        match offset {
+            0 | 1 if self.id_mode => self.id()[offset as usize],
+            _ => self.data[self.bank * BANK_LEN + offset as usize],
+        }
+    }
+
+    pub fn write(&mut self, offset: u32, value: u8) {
+        self.state = match (self.state, offset, value) {
+            (State::ProgramByte, _, _) => {
+                self.data[self.bank * BANK_LEN + offset as usize] = value;
+                State::Ready
+            }
+            (State::SelectBank, 0, _) => {
+                self.bank = value as usize % (self.data.len() / BANK_LEN);
+                State::Ready
+            }
+            (State::Ready, COMMAND_ADDRESS_1, 0xAA) => State::Unlock1,
+            (State::Unlock1, COMMAND_ADDRESS_2, 0x55) => State::Unlock2,
+            // Sector erase is the only command not written to 0x5555
+            (State::Unlock2, _, COMMAND_ERASE_SECTOR) if self.erase_prepared => {
+                let sector = (self.bank * BANK_LEN + offset as usize) & !(SECTOR_LEN - 1);
+                self.data[sector..sector + SECTOR_LEN].fill(0xFF);
+                self.erase_prepared = false;
+                State::Ready
+            }
+            (State::Unlock2, COMMAND_ADDRESS_1, command) => self.command(command),
+            _ => State::Ready,
+        };
+    }
+
+    fn command(&mut self, command: u8) -> State {
+        let erase_prepared = std::mem::take(&mut self.erase_prepared);
+        match command {
+            COMMAND_ENTER_ID_MODE => self.id_mode = true,
+            COMMAND_EXIT_ID_MODE => self.id_mode = false,
+            COMMAND_PREPARE_ERASE => self.erase_prepared = true,
+            COMMAND_ERASE_CHIP if erase_prepared => self.data.fill(0xFF),
+            COMMAND_PROGRAM_BYTE => return State::ProgramByte,
+            COMMAND_SELECT_BANK if self.data.len() > BANK_LEN => return State::SelectBank,
+            _ => {}
+        }
+        State::Ready
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(flash: &mut Flash, command: u8) {
+        flash.write(COMMAND_ADDRESS_1, 0xAA);
+        flash.write(COMMAND_ADDRESS_2, 0x55);
+        flash.write(COMMAND_ADDRESS_1, command);
+    }
+
+    #[test]
+    fn test_id_mode() {
+        let mut flash = Flash::new(2);
+        assert_eq!(flash.read(0), 0xFF);
+
+        command(&mut flash, COMMAND_ENTER_ID_MODE);
+        assert_eq!([flash.read(0), flash.read(1)], ID_FLASH128);
+        command(&mut flash, COMMAND_EXIT_ID_MODE);
+        assert_eq!(flash.read(0), 0xFF);
+
+        let mut flash = Flash::new(1);
+        command(&mut flash, COMMAND_ENTER_ID_MODE);
+        assert_eq!([flash.read(0), flash.read(1)], ID_FLASH64);
+    }
+
+    #[test]
+    fn test_erase_and_program() {
+        let mut flash = Flash::new(1);
+        command(&mut flash, COMMAND_PROGRAM_BYTE);
+        flash.write(0x1234, 0x42);
+        command(&mut flash, COMMAND_PROGRAM_BYTE);
+        flash.write(0x2000, 0x43);
+        assert_eq!(flash.read(0x1234), 0x42);
+
+        // Writes outside of a command sequence are ignored
+        flash.write(0x1234, 0x00);
+        assert_eq!(flash.read(0x1234), 0x42);
+
+        command(&mut flash, COMMAND_PREPARE_ERASE);
+        flash.write(COMMAND_ADDRESS_1, 0xAA);
+        flash.write(COMMAND_ADDRESS_2, 0x55);
+        flash.write(0x1000, COMMAND_ERASE_SECTOR);
+        assert_eq!(flash.read(0x1234), 0xFF);
+        assert_eq!(flash.read(0x2000), 0x43);
+
+        command(&mut flash, COMMAND_PROGRAM_BYTE);
+        flash.write(0x1234, 0x24);
+        assert_eq!(flash.read(0x1234), 0x24);
+
+        command(&mut flash, COMMAND_PREPARE_ERASE);
+        command(&mut flash, COMMAND_ERASE_CHIP);
+        assert!(flash.data().iter().all(|&b| b == 0xFF));
+    }
+
+    #[test]
+    fn test_bank_switching() {
+        let mut flash = Flash::new(2);
+        command(&mut flash, COMMAND_SELECT_BANK);
+        flash.write(0, 1);
+        command(&mut flash, COMMAND_PROGRAM_BYTE);
+        flash.write(0x10, 0x42);
+        assert_eq!(flash.data()[BANK_LEN + 0x10], 0x42);
+
+        command(&mut flash, COMMAND_SELECT_BANK);
+        flash.write(0, 0);
+        assert_eq!(flash.read(0x10), 0xFF);
+    }
+}
@@ -0,0 +1,84 @@
+use std::str::FromStr;
+
+use eeprom::Eeprom;
+use flash::Flash;
+
+pub mod eeprom;
+pub mod flash;
+
+pub const SRAM_LEN: usize = 0x10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SaveType {
+    Eeprom,
+    Sram,
+    Flash64,
+    Flash128,
+}
+
+impl FromStr for SaveType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "eeprom" => Ok(SaveType::Eeprom),
+            "sram" => Ok(SaveType::Sram),
+            "flash64" => Ok(SaveType::Flash64),
+            "flash128" => Ok(SaveType::Flash128),
+            _ => Err(format!("Unknown save type: {}", s)),
+        }
+    }
+}
+
+/// Backup memory of the game pak, mapped at 0x0E000000 except for the EEPROM
+#[derive(Debug)]
+pub enum SaveMemory {
+    Eeprom(Eeprom),
+    Sram(Vec<u8>),
+    Flash(Flash),
+}
+
+impl SaveMemory {
+    pub fn new(save_type: SaveType) -> Self {
+        match save_type {
+            SaveType::Eeprom => SaveMemory::Eeprom(Eeprom::new()),
+            SaveType::Sram => SaveMemory::Sram(vec![0xFF; SRAM_LEN]),
+            SaveType::Flash64 => SaveMemory::Flash(Flash::new(1)),
+            SaveType::Flash128 => SaveMemory::Flash(Flash::new(2)),
+        }
+    }
+
+    pub fn read(&self, offset: u32) -> u8 {
+        match self {
+            // Nothing is connected to the save memory region
+            SaveMemory::Eeprom(_) => 0xFF,
+            SaveMemory::Sram(data) => data[offset as usize],
+            SaveMemory::Flash(flash) => flash.read(offset),
+        }
+    }
+
+    pub fn write(&mut self, offset: u32, value: u8) {
+        match self {
+            SaveMemory::Eeprom(_) => {}
+            SaveMemory::Sram(data) => data[offset as usize] = value,
+            SaveMemory::Flash(flash) => flash.write(offset, value),
+        }
+    }
+
+    /// Contents as stored in the save file
+    pub fn data(&self) -> &[u8] {
+        match self {
+            SaveMemory::Eeprom(eeprom) => eeprom.data(),
+            SaveMemory::Sram(data) => data,
+            SaveMemory::Flash(flash) => flash.data(),
+        }
+    }
+
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        match self {
+            SaveMemory::Eeprom(eeprom) => eeprom.data_mut(),
+            SaveMemory::Sram(data) => data,
+            SaveMemory::Flash(flash) => flash.data_mut(),
+        }
+    }
+}
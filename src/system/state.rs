@@ -0,0 +1,141 @@
+use std::io;
+
+pub const STATE_MAGIC: &[u8; 4] = b"GBAE";
+pub const STATE_VERSION: u32 = 5;
+
+/// Little endian encoder for save states
+#[derive(Default)]
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        let mut writer = Self::default();
+        writer.write_bytes(STATE_MAGIC);
+        writer.write_u32(STATE_VERSION);
+        writer
+    }
+
+    pub fn write_u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    /// Writes the length first, so it can be checked against the buffer it's loaded into
+    pub fn write_block(&mut self, bytes: &[u8]) {
+        self.write_u32(bytes.len() as u32);
+        self.write_bytes(bytes);
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+/// Decoder for data written by `StateWriter`
+pub struct StateReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> io::Result<Self> {
+        let mut reader = Self { data };
+        if reader.read_bytes(STATE_MAGIC.len())? != STATE_MAGIC {
+            return Err(invalid_data("Not a save state"));
+        }
+        let version = reader.read_u32()?;
+        if version != STATE_VERSION {
+            return Err(invalid_data(&format!("Unsupported save state version: {}", version)));
+        }
+        Ok(reader)
+    }
+
+    pub fn read_u16(&mut self) -> io::Result<u16> {
+        Ok(u16::from_le_bytes(self.read_bytes(2)?.try_into().unwrap()))
+    }
+
+    pub fn read_u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.read_bytes(4)?.try_into().unwrap()))
+    }
+
+    pub fn read_u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.read_bytes(8)?.try_into().unwrap()))
+    }
+
+    pub fn read_bytes(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.data.len() < len {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    /// Fails if anything is left after the last value, which would mean the state doesn't match what was read
+    pub fn finish(self) -> io::Result<()> {
+        if !self.data.is_empty() {
+            return Err(invalid_data(&format!("{} bytes left after the save state", self.data.len())));
+        }
+        Ok(())
+    }
+
+    /// Reads a block written by `write_block` into `buffer`, which must have the same length
+    pub fn read_block(&mut self, buffer: &mut [u8]) -> io::Result<()> {
+        let len = self.read_u32()? as usize;
+        if len != buffer.len() {
+            return Err(invalid_data(&format!("Expected a block of {} bytes, got {}", buffer.len(), len)));
+        }
+        buffer.copy_from_slice(self.read_bytes(len)?);
+        Ok(())
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_state_round_trip() {
+        let mut writer = StateWriter::new();
+        writer.write_u16(0x1234);
+        writer.write_u64(0x0123_4567_89AB_CDEF);
+        writer.write_block(&[1, 2, 3]);
+        let data = writer.into_bytes();
+
+        let mut reader = StateReader::new(&data).unwrap();
+        assert_eq!(reader.read_u16().unwrap(), 0x1234);
+        assert_eq!(reader.read_u64().unwrap(), 0x0123_4567_89AB_CDEF);
+        let mut block = [0; 3];
+        reader.read_block(&mut block).unwrap();
+        assert_eq!(block, [1, 2, 3]);
+        assert_eq!(reader.read_u32().unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        reader.finish().unwrap();
+        let mut reader = StateReader::new(&data).unwrap();
+        reader.read_u16().unwrap();
+        assert_eq!(reader.finish().unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        // Blocks have to fit the buffer exactly
+        let mut reader = StateReader::new(&data).unwrap();
+        reader.read_bytes(10).unwrap();
+        assert_eq!(reader.read_block(&mut [0; 4]).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        assert!(StateReader::new(b"GBAE\x04\x00\x00\x00").is_err());
+        assert!(StateReader::new(b"NOPE\x05\x00\x00\x00").is_err());
+    }
+}
@@ -0,0 +1,190 @@
+use std::io;
+
+use super::{
+    interrupts::Interrupt,
+    state::{StateReader, StateWriter},
+};
+
+pub const REG_TM0CNT_L: u32 = 0x04_000_100;
+pub const REG_TM3CNT_H: u32 = 0x04_000_10E;
+// Each timer has CNT_L and CNT_H
+pub const TIMER_LEN: u32 = 4;
+
+// TMxCNT_H bits
+const TMCNT_PRESCALER: u16 = 0b11;
+const TMCNT_CASCADE: u16 = 1 << 2;
+const TMCNT_IRQ: u16 = 1 << 6;
+const TMCNT_ENABLE: u16 = 1 << 7;
+
+// The prescaler divides the cpu clock by 1, 64, 256 or 1024
+const PRESCALER_SHIFTS: [u32; 4] = [0, 6, 8, 10];
+const INTERRUPTS: [Interrupt; 4] = [Interrupt::Timer0, Interrupt::Timer1, Interrupt::Timer2, Interrupt::Timer3];
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Timer {
+    reload: u16,
+    control: u16,
+    counter: u16,
+    // Cycles not yet counted because of the prescaler
+    prescaler_cycles: u32,
+}
+
+impl Timer {
+    /// Counts `ticks` and returns the number of overflows
+    fn count(&mut self, mut ticks: u32) -> u32 {
+        let mut overflows = 0;
+        while ticks > 0 {
+            let until_overflow = 0x10000 - self.counter as u32;
+            if ticks < until_overflow {
+                self.counter += ticks as u16;
+                break;
+            }
+            ticks -= until_overflow;
+            self.counter = self.reload;
+            overflows += 1;
+        }
+        overflows
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Timers {
+    timers: [Timer; 4],
+    // Overflows of each timer during the last step, which drive the sound FIFOs
+    overflows: [u32; 4],
+}
+
+impl Timers {
+    /// Reads return the live counter, while writes set the value it is reloaded with
+    pub fn counter(&self, timer: usize) -> u16 {
+        self.timers[timer].counter
+    }
+
+    pub fn overflows(&self) -> [u32; 4] {
+        self.overflows
+    }
+
+    pub fn set_reload(&mut self, timer: usize, reload: u16) {
+        self.timers[timer].reload = reload;
+    }
+
+    pub fn set_control(&mut self, timer: usize, control: u16) {
+        let timer = &mut self.timers[timer];
+        // Starting a timer reloads the counter
+        if timer.control & TMCNT_ENABLE == 0 && control & TMCNT_ENABLE != 0 {
+            timer.counter = timer.reload;
+            timer.prescaler_cycles = 0;
+        }
+        timer.control = control;
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        for timer in &self.timers {
+            state.write_u16(timer.reload);
+            state.write_u16(timer.control);
+            state.write_u16(timer.counter);
+            state.write_u32(timer.prescaler_cycles);
+        }
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        for timer in &mut self.timers {
+            timer.reload = state.read_u16()?;
+            timer.control = state.read_u16()?;
+            timer.counter = state.read_u16()?;
+            timer.prescaler_cycles = state.read_u32()?;
+        }
+        Ok(())
+    }
+
+    /// Advances the running timers by the given number of cpu cycles, returning the interrupts raised by overflows
+    pub fn step(&mut self, cycles: u32) -> [Option<Interrupt>; 4] {
+        let mut interrupts = [None; 4];
+        let mut previous_overflows = 0;
+        self.overflows = [0; 4];
+
+        for (i, timer) in self.timers.iter_mut().enumerate() {
+            if timer.control & TMCNT_ENABLE == 0 {
+                previous_overflows = 0;
+                continue;
+            }
+
+            // Cascading timers count overflows of the previous one instead of cycles
+            let ticks = if i > 0 && timer.control & TMCNT_CASCADE != 0 {
+                previous_overflows
+            } else {
+                let shift = PRESCALER_SHIFTS[(timer.control & TMCNT_PRESCALER) as usize];
+                timer.prescaler_cycles += cycles;
+                let ticks = timer.prescaler_cycles >> shift;
+                timer.prescaler_cycles &= (1 << shift) - 1;
+                ticks
+            };
+
+            previous_overflows = timer.count(ticks);
+            self.overflows[i] = previous_overflows;
+            if previous_overflows > 0 && timer.control & TMCNT_IRQ != 0 {
+                interrupts[i] = Some(INTERRUPTS[i]);
+            }
+        }
+
+        interrupts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_overflow() {
+        let mut timers = Timers::default();
+        timers.set_reload(0, 0xFFF0);
+        timers.set_control(0, TMCNT_ENABLE | TMCNT_IRQ);
+        assert_eq!(timers.counter(0), 0xFFF0);
+
+        assert_eq!(timers.step(15), [None; 4]);
+        assert_eq!(timers.counter(0), 0xFFFF);
+        assert_eq!(timers.step(1), [Some(Interrupt::Timer0), None, None, None]);
+        assert_eq!(timers.counter(0), 0xFFF0);
+
+        // Multiple overflows in one step
+        timers.step(40);
+        assert_eq!(timers.counter(0), 0xFFF8);
+    }
+
+    #[test]
+    fn test_prescaler() {
+        let mut timers = Timers::default();
+        timers.set_control(1, TMCNT_ENABLE | 1);
+        timers.step(63);
+        assert_eq!(timers.counter(1), 0);
+        timers.step(1);
+        assert_eq!(timers.counter(1), 1);
+        timers.step(64 * 3 + 10);
+        assert_eq!(timers.counter(1), 4);
+
+        // Restarting resets the prescaler
+        timers.set_control(1, 0);
+        timers.set_control(1, TMCNT_ENABLE | 1);
+        assert_eq!(timers.counter(1), 0);
+        timers.step(60);
+        assert_eq!(timers.counter(1), 0);
+    }
+
+    #[test]
+    fn test_cascade() {
+        let mut timers = Timers::default();
+        timers.set_reload(0, 0xFFFE);
+        timers.set_control(0, TMCNT_ENABLE);
+        // Cascading ignores the prescaler
+        timers.set_reload(1, 0xFFF0);
+        timers.set_control(1, TMCNT_ENABLE | TMCNT_CASCADE | TMCNT_IRQ | 3);
+
+        assert_eq!(timers.step(2), [None; 4]);
+        assert_eq!(timers.counter(1), 0xFFF1);
+        assert_eq!(timers.step(28), [None; 4]);
+        assert_eq!(timers.counter(1), 0xFFFF);
+        assert_eq!(timers.step(2), [None, Some(Interrupt::Timer1), None, None]);
+        assert_eq!(timers.counter(1), 0xFFF0);
+    }
+}
@@ -0,0 +1,119 @@
+use std::{collections::BTreeMap, fmt::Write};
+
+/// An executed instruction, formatting is left to whoever reads the trace
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TraceEntry {
+    pub pc: u32,
+    // Thumb instructions have the following halfword in the upper bits, as BL spans two
+    pub instruction: u32,
+    pub cpsr: u32,
+}
+
+/// Ring buffer of the last executed instructions
+#[derive(Debug, Default)]
+pub struct InstructionTrace {
+    entries: Vec<TraceEntry>,
+    next: usize,
+    len: usize,
+}
+
+impl InstructionTrace {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: vec![TraceEntry::default(); capacity],
+            next: 0,
+            len: 0,
+        }
+    }
+
+    pub fn record(&mut self, entry: TraceEntry) {
+        if self.entries.is_empty() {
+            return;
+        }
+        self.entries[self.next] = entry;
+        self.next = (self.next + 1) % self.entries.len();
+        self.len = (self.len + 1).min(self.entries.len());
+    }
+
+    /// Returns up to `count` of the most recent entries, oldest first
+    pub fn last(&self, count: usize) -> impl Iterator<Item = &TraceEntry> {
+        let count = count.min(self.len);
+        let start = self.next + self.entries.len() - count;
+        (start..start + count).map(|i| &self.entries[i % self.entries.len()])
+    }
+}
+
+/// A taken branch, calls are the branches that set the link register
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct BranchEdge {
+    pub source: u32,
+    pub target: u32,
+    pub call: bool,
+}
+
+/// Every taken branch with how often it was taken, for mapping the control flow of a program
+#[derive(Debug, Default)]
+pub struct BranchTrace {
+    edges: BTreeMap<BranchEdge, u64>,
+}
+
+impl BranchTrace {
+    pub fn record(&mut self, edge: BranchEdge) {
+        *self.edges.entry(edge).or_default() += 1;
+    }
+
+    /// The recorded branches ordered by source, with how often each was taken
+    pub fn edges(&self) -> impl Iterator<Item = (&BranchEdge, &u64)> {
+        self.edges.iter()
+    }
+
+    /// A Graphviz call graph, the functions are the call targets and a call belongs to the closest function starting before it
+    pub fn export_dot(&self) -> String {
+        let calls = || self.edges.keys().filter(|edge| edge.call);
+        let mut functions: Vec<u32> = calls().map(|edge| edge.target).collect();
+        functions.sort();
+        functions.dedup();
+
+        let mut graph: Vec<(Option<u32>, u32)> = calls()
+            .map(|edge| {
+                let caller = functions.iter().rev().find(|&&function| function <= edge.source).copied();
+                (caller, edge.target)
+            })
+            .collect();
+        graph.sort();
+        graph.dedup();
+
+        let mut dot = String::from("digraph calls {\n");
+        for (caller, callee) in graph {
+            // Calls before the first discovered function come from the entry point
+            let caller = caller.map_or("entry".to_owned(), |caller| format!("{:08X}", caller));
+            writeln!(dot, "    \"{}\" -> \"{:08X}\";", caller, callee).unwrap();
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trace_wraps() {
+        let entry = |pc| TraceEntry { pc, instruction: 0, cpsr: 0 };
+        let mut trace = InstructionTrace::new(3);
+        trace.record(entry(0));
+        trace.record(entry(4));
+        assert_eq!(trace.last(5).map(|e| e.pc).collect::<Vec<_>>(), [0, 4]);
+
+        trace.record(entry(8));
+        trace.record(entry(12));
+        assert_eq!(trace.last(5).map(|e| e.pc).collect::<Vec<_>>(), [4, 8, 12]);
+        assert_eq!(trace.last(2).map(|e| e.pc).collect::<Vec<_>>(), [8, 12]);
+
+        // Tracing can be disabled
+        let mut trace = InstructionTrace::new(0);
+        trace.record(entry(0));
+        assert_eq!(trace.last(1).count(), 0);
+    }
+}
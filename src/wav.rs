@@ -0,0 +1,34 @@
+use std::{fs, io, path::Path};
+
+use crate::system::apu::SAMPLE_RATE;
+
+/// Encodes stereo samples as a 16 bit PCM WAV
+pub fn encode_wav(samples: &[[i16; 2]]) -> Vec<u8> {
+    let data_len = samples.len() as u32 * 4;
+    let mut wav = Vec::new();
+    wav.extend_from_slice(b"RIFF");
+    wav.extend_from_slice(&(36 + data_len).to_le_bytes());
+    wav.extend_from_slice(b"WAVE");
+
+    wav.extend_from_slice(b"fmt ");
+    wav.extend_from_slice(&16u32.to_le_bytes());
+    // PCM, 2 channels
+    wav.extend_from_slice(&1u16.to_le_bytes());
+    wav.extend_from_slice(&2u16.to_le_bytes());
+    wav.extend_from_slice(&SAMPLE_RATE.to_le_bytes());
+    // Bytes per second, bytes per frame and bits per sample
+    wav.extend_from_slice(&(SAMPLE_RATE * 4).to_le_bytes());
+    wav.extend_from_slice(&4u16.to_le_bytes());
+    wav.extend_from_slice(&16u16.to_le_bytes());
+
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&data_len.to_le_bytes());
+    for sample in samples.as_flattened() {
+        wav.extend_from_slice(&sample.to_le_bytes());
+    }
+    wav
+}
+
+pub fn write_wav(path: impl AsRef<Path>, samples: &[[i16; 2]]) -> io::Result<()> {
+    fs::write(path, encode_wav(samples))
+}